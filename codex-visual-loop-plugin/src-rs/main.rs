@@ -2,18 +2,18 @@ use anyhow::{bail, Context, Result};
 use chrono::Utc;
 use clap::{ArgAction, Args, Parser, Subcommand};
 use font8x8::{UnicodeFonts, BASIC_FONTS};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
 use image::imageops::FilterType;
-use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba, RgbaImage};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgba, RgbaImage};
 use rand::Rng;
 use serde_json::{json, Map, Value};
 use std::env;
-use std::f64::consts::PI;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use wait_timeout::ChildExt;
 
 const PLUGIN_ROOT: &str = env!("CARGO_MANIFEST_DIR");
@@ -30,7 +30,8 @@ const SPEC_HELP: &str = r##"Spec JSON schema (minimal):
     "fit_target": "dark",
     "fit_min_pixels": 30,
     "fit_min_coverage": 0.6,
-    "fit_pad": 0
+    "fit_pad": 0,
+    "preprocess": {"crop": {"x": 0, "y": 28, "w": "100%", "h": "90%"}, "scale": 0.5}
   },
   "annotations": [
     {"type": "rect", "x": "10%", "y": "20%", "w": "35%", "h": "12%", "intent": "target", "action": "inspect", "color": "#FF3B30"},
@@ -41,11 +42,34 @@ const SPEC_HELP: &str = r##"Spec JSON schema (minimal):
 }
 
 Notes:
-- auto-fit is enabled by default for rect/spotlight; disable with "fit": false or defaults.auto_fit=false.
-- auto-fit snaps the original rect/spotlight to detected pixels (keeps size and recenters if detected area is smaller).
+- auto-fit is enabled by default for rect/ellipse/spotlight; disable with "fit": false or defaults.auto_fit=false.
+- auto-fit snaps the original rect/ellipse/spotlight to detected pixels (keeps size and recenters if detected area is smaller).
 - coordinate fields accept px (default), "%" strings, and rel/fraction units via defaults.units="rel".
 - anchor text/arrow endpoints via id/index/nearest with optional pos+offset.
+- "relative_to": "<id>" (or true with defaults.reference = id or {x,y,w,h} px) resolves x/y/w/h as fractions of that rect/spotlight box; sidecars add geometry_ref.
+- every annotation gets an id in the sidecar (spec id, else "ann-<index>"); duplicates are suffixed "-2", "-3", ... with a warning.
+- arrows take "head_shape": "triangle" (default) | "open" | "diamond" | "dot".
+- text takes "align": "left"|"center"|"right" and "valign": "top"|"middle"|"bottom" to place that side of the text box on x/y (default top-left); anchored text with align center centers over the anchor point.
+- text takes "direction": "rtl" (right-aligned, first char rightmost) | "vertical" (glyphs stacked downward, each line a column to the left).
+- text takes "font": "<path.ttf>" to override `annotate --font` / defaults.font; unreadable fonts fall back to the global font, then the bitmap font (TrueType needs the `ttf` build feature).
+- arrows with "cx"/"cy" draw as a quadratic curve; "avoid": true picks that control point to bend around rects crossed by the straight path ("avoid_margin", default 12).
+- pos "centroid" uses the target's "centroid": [x, y] (emitted by diff specs), falling back to its center.
 - semantic fields like severity/issue/hypothesis/next_action/verify are preserved in metadata sidecars.
+- {"type": "point", "x": .., "y": ..} draws a crosshair marker ("size" arm length, "width"); "show_coords": true labels it "(x, y)" in px, plus "coords_rel": true for the rel fraction.
+- any annotation takes "clip": {"x", "y", "w", "h"} (or [x, y, w, h], same units as the annotation); its drawing is confined to that rect.
+- rect accepts "radius" (px, clamped to half the shorter side) for rounded corners on the fill, outline and stroke.
+- rect and arrow accept "dash": [on, off] in px (a single number means equal runs) for dashed rect outlines and arrow shafts; arrowheads stay solid and no "dash" stays solid.
+- {"type": "ellipse", "x": .., "y": .., "w": .., "h": ..} (alias "circle") circles the box like a rect would outline it: same color/width/fill/outline fields, fit and anchoring.
+- {"type": "measure", "x1": .., "y1": .., "x2": .., "y2": ..} draws a dimension line with end ticks labeled with its px length ("label" overrides, false hides).
+- repeated --spec files layer in order: later "defaults" keys win, "annotations" arrays concatenate.
+- defaults.ruler=true draws px rulers along the top and left edges under the annotations (ruler_step label spacing, default 50; ruler_size strip thickness, default 14; ruler_color/ruler_bg).
+- defaults.show_severity=true draws each rect's "severity" as a colored badge in its top-right corner.
+- defaults.theme="default"|"dark"|"high_contrast" seeds color/text_bg/outline_color per type; spec defaults and annotation fields override it.
+- units="pt" reads numeric positions as screen points: (v - defaults.pt_origin) * defaults.pt_scale; sizes only scale. annotate --use-sidecar seeds pt_origin/pt_scale/scale from the capture sidecar.
+- defaults.stroke_units="rel" reads width/head_len/head_width/outline_width as fractions of the image diagonal (default px).
+- defaults.dim_unfocused=true applies one dim layer (dim_color/dim_opacity) with every rect/spotlight box kept visible.
+- defaults.coordinate_origin="bottom_left" flips y/y1/y2 (and y offsets) from a bottom-left origin; default is top_left.
+- defaults.preprocess crops (px/%/rel) then scales the input before annotating; coordinates are relative to the preprocessed image.
 "##;
 
 #[derive(Parser, Debug)]
@@ -55,8 +79,32 @@ Notes:
     about = "Rust CLI for codex-visual-loop-plugin visual capture/annotation/diff workflows"
 )]
 struct Cli {
+    /// Print the exit-code table as JSON and exit
+    #[arg(long, action = ArgAction::SetTrue)]
+    print_exit_help: bool,
+    /// Warning shape in JSON output: text (message strings) or json ({code, message, severity})
+    #[arg(long, global = true, default_value = "text", value_parser = ["text", "json"])]
+    warnings_format: String,
+    /// Drop warnings below this severity from JSON output (they are still noted on stderr)
+    #[arg(long, global = true, default_value = "info", value_parser = ["info", "warning", "error"])]
+    min_warning_severity: String,
+    /// Shorthand for --min-warning-severity warning: hide informational notes
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    quiet_warnings: bool,
+    /// Re-open every written image and fail unless it decodes at the written dimensions
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    verify_output: bool,
+    /// Replacement AppleScript for window discovery (emits index\tx\ty\tw\th\ttitle rows)
+    #[arg(long, global = true, value_name = "PATH")]
+    window_script: Option<PathBuf>,
+    /// Replacement AppleScript for the AX tree (emits depth\tclass\tname\trole\tenabled\tx\ty\tw\th rows)
+    #[arg(long, global = true, value_name = "PATH")]
+    ax_script: Option<PathBuf>,
+    /// Number formatting for human-facing reports (CSV, markdown), e.g. de or fr-FR; JSON is unaffected
+    #[arg(long, global = true, value_name = "TAG", value_parser = parse_number_locale)]
+    locale: Option<NumberLocale>,
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -66,24 +114,47 @@ enum Commands {
     Commands,
     /// Print plugin manifest JSON
     Manifest,
+    /// Print JSON Schema documents for the capture/diff/annotate-meta/observe payloads
+    #[command(visible_alias = "json-schema")]
+    Schema(SchemaArgs),
     /// Capture app window (or fallback image) and emit metadata sidecar JSON
     Capture(CaptureArgs),
     /// Render annotation metadata/spec with semantic fields and relative units
     Annotate(AnnotateArgs),
     /// Compare baseline/current screenshots and emit diff-to-bbox outputs
     Diff(DiffArgs),
+    /// Diff a directory of current screenshots against baselines paired by filename
+    #[command(name = "diff-dir")]
+    DiffDir(DiffDirArgs),
+    /// Perceptual-hash distance between two screenshots (near-duplicate check, not a pixel diff)
+    Phash(PhashArgs),
     /// Run baseline/history loop with diff reports and optional annotated output
     Loop(LoopArgs),
+    /// Capture the same window repeatedly and score how much it changes between samples
+    Stability(StabilityArgs),
+    /// List displays with point/pixel sizes and backing scale factors (read-only, no capture)
+    #[command(name = "display-info")]
+    DisplayInfo(DisplayInfoArgs),
     /// Build one observation packet (before/after + action + clip + diff)
     Observe(ObserveArgs),
+    /// Remove generated artifact subtrees under the artifact root
+    Clean(CleanArgs),
+    /// Run a JSON/YAML workflow manifest of capture/diff/annotate steps in order
+    Run(RunArgs),
     /// Dump accessibility tree snapshot JSON
     #[command(name = "ax-tree")]
     AxTree(AxTreeArgs),
+    /// Poll the AX tree until an element matching a name/role appears, then capture
+    #[command(name = "await-element")]
+    AwaitElement(AwaitElementArgs),
     /// Perform UI actions (click/type/hotkey) against a target app process
     Act(ActArgs),
     /// Capture app + AX packet and optionally ask Codex CLI for a detailed explanation report
     #[command(name = "explain-app")]
     ExplainApp(ExplainArgs),
+    /// Time diff/region/annotate hot paths on synthetic images (contributor perf harness)
+    #[command(hide = true)]
+    Bench(BenchArgs),
 }
 
 #[derive(Args, Debug)]
@@ -116,23 +187,144 @@ struct CaptureArgs {
     /// Fail with non-zero status when capture falls back to generated placeholder output
     #[arg(long, action = ArgAction::SetTrue)]
     strict: bool,
+    /// Blur AX secure text fields (password inputs) in the saved PNG
+    #[arg(long, action = ArgAction::SetTrue)]
+    redact_secure: bool,
+    /// Skip app activation and window probing; take an immediate full-screen capture
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_probe: bool,
+    /// Screen region X,Y,W,H (points) to capture with --no-probe
+    #[arg(long, requires = "no_probe")]
+    region: Option<String>,
+    /// Number of frames to capture periodically (paths get a -fNNN suffix when > 1)
+    #[arg(long, default_value_t = 1)]
+    frames: u32,
+    /// Delay between periodic frames in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    interval_ms: u64,
+    /// Emit one NDJSON event per saved frame as it is written
+    #[arg(long, action = ArgAction::SetTrue)]
+    json_stream: bool,
+    /// PNG compression for captures this tool re-encodes (redaction, shadow trim, placeholder):
+    /// default|fast|best
+    #[arg(long, default_value = "default", value_parser = ["default", "fast", "best"])]
+    png_compression: String,
+    /// Retry a failed screencapture up to N times with exponential backoff before falling back
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    capture_retry: u32,
+    /// include|exclude: exclude captures the window by id with -o and trims translucent shadow borders
+    #[arg(long, default_value = "include", value_parser = ["include", "exclude"])]
+    capture_window_shadow: String,
+    /// Shorthand for --capture-window-shadow exclude
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_shadow: bool,
+    /// Probe and activate the window and emit the metadata JSON without capturing or writing a PNG
+    #[arg(
+        long,
+        visible_alias = "capture-json-only",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["no_probe", "redact_secure", "strict", "frames", "json_stream"]
+    )]
+    meta_only: bool,
+    /// Minimum usable window width in points (default: CVLP_MIN_WINDOW_WIDTH, then 220)
+    #[arg(long, value_name = "PT", value_parser = clap::value_parser!(i64).range(0..))]
+    min_window_width: Option<i64>,
+    /// Minimum usable window height in points (default: CVLP_MIN_WINDOW_HEIGHT, then 140)
+    #[arg(long, value_name = "PT", value_parser = clap::value_parser!(i64).range(0..))]
+    min_window_height: Option<i64>,
+    /// Minimum usable window area in square points (default: CVLP_MIN_WINDOW_AREA, then 40000)
+    #[arg(long, value_name = "PT2", value_parser = clap::value_parser!(i64).range(0..))]
+    min_window_area: Option<i64>,
 }
 
 #[derive(Args, Debug)]
 struct AnnotateArgs {
     /// Input PNG path
     input: PathBuf,
-    /// Output PNG path
-    output: PathBuf,
-    /// JSON spec file path (or - for stdin)
-    #[arg(long)]
-    spec: String,
+    /// Output PNG path or directory (default: <input-stem>.annotated.png next to the input)
+    output: Option<PathBuf>,
+    /// JSON spec file path (or - for stdin); repeat to layer specs in order
+    #[arg(long, required = true)]
+    spec: Vec<String>,
     /// Path to write annotation metadata sidecar (default: <output>.json)
     #[arg(long)]
     meta_out: Option<PathBuf>,
     /// Disable metadata sidecar output
     #[arg(long, action = ArgAction::SetTrue)]
     no_meta: bool,
+    /// Path to write a flat CSV of annotations (one row per annotation)
+    #[arg(long)]
+    meta_csv: Option<PathBuf>,
+    /// Path to write a sprite sheet with each annotation rendered in isolation
+    #[arg(long)]
+    preview_grid: Option<PathBuf>,
+    /// PNG compression: default|fast|best (fast trades file size for write speed)
+    #[arg(long, default_value = "default", value_parser = ["default", "fast", "best"])]
+    png_compression: String,
+    /// Print a JSON summary (paths, size, annotation count) instead of the bare output path
+    #[arg(long, action = ArgAction::SetTrue)]
+    json: bool,
+    /// Print each annotation's final px geometry and anchor target to stderr (and "probe" in --json)
+    #[arg(long, visible_alias = "annotate-probe", action = ArgAction::SetTrue)]
+    probe: bool,
+    /// Also write the annotations alone on a transparent PNG of the same size
+    #[arg(long, value_name = "PATH")]
+    overlay_only: Option<PathBuf>,
+    /// Also write a multi-page TIFF with the backdrop and annotation overlay as separate layers
+    #[arg(long, value_name = "PATH")]
+    layered_out: Option<PathBuf>,
+    /// Split the --layered-out overlay into one layer per annotation type
+    #[arg(long, action = ArgAction::SetTrue, requires = "layered_out")]
+    layered_by_type: bool,
+    /// Also write one JSON {image_base64, format, meta} with the output image inline and the sidecar payload
+    #[arg(long, value_name = "PATH")]
+    bundle_out: Option<PathBuf>,
+    /// Also write an agent to-do list of annotations with an action/next_action and their px centers
+    #[arg(long, value_name = "PATH")]
+    actions_out: Option<PathBuf>,
+    /// Also write a simulated color-vision copy (<stem>.<kind>.png) of the output image
+    #[arg(long, visible_alias = "color-blind-sim", value_name = "KIND", value_parser = CB_SIM_KINDS)]
+    cb_sim: Option<String>,
+    /// Composite the input over this solid color before processing (e.g. #FFFFFF)
+    #[arg(long, value_name = "COLOR")]
+    flatten_bg: Option<String>,
+    /// Render only these annotation types (comma-separated, e.g. rect,arrow)
+    #[arg(long, value_delimiter = ',', value_name = "TYPES")]
+    only_types: Vec<String>,
+    /// Skip these annotation types when rendering (comma-separated)
+    #[arg(long, value_delimiter = ',', value_name = "TYPES")]
+    skip_types: Vec<String>,
+    /// Keep filtered-out annotations in the sidecar/CSV/export (marked "rendered": false)
+    #[arg(long, action = ArgAction::SetTrue)]
+    meta_all_types: bool,
+    /// Seed defaults (pt_scale/pt_origin/scale) from the input's capture sidecar (<input-stem>.json)
+    #[arg(long, action = ArgAction::SetTrue)]
+    use_sidecar: bool,
+    /// TrueType font (.ttf/.otf/.ttc) for text annotations (needs the `ttf` build feature); a
+    /// per-annotation "font" overrides it
+    #[arg(long, value_name = "TTF")]
+    font: Option<PathBuf>,
+    /// Add measure annotations for the gaps between adjacent sibling elements in this ax-tree JSON
+    #[arg(long, value_name = "AX_JSON")]
+    ax_spacing: Option<PathBuf>,
+    /// Crop uniform border rows/columns from the annotated output (geometry is re-offset)
+    #[arg(long, visible_alias = "trim-whitespace", action = ArgAction::SetTrue)]
+    trim: bool,
+    /// Max per-channel difference from the corner color still treated as border for --trim
+    #[arg(long, default_value_t = 8)]
+    trim_tolerance: u8,
+    /// Margin in px kept around the content when trimming
+    #[arg(long, default_value_t = 0)]
+    trim_pad: u32,
+    /// Export resolved annotations as a design-tool scene: --export figma|excalidraw PATH
+    #[arg(long, num_args = 2, value_names = ["FORMAT", "PATH"])]
+    export: Option<Vec<String>>,
+    /// Refuse inputs with more pixels than this (0 disables the guard)
+    #[arg(long, default_value_t = DEFAULT_MAX_TOTAL_PIXELS)]
+    max_total_pixels: u64,
+    /// Downscale oversized inputs to fit --max-total-pixels instead of failing
+    #[arg(long, action = ArgAction::SetTrue)]
+    downscale_oversize: bool,
     /// Print spec schema and exit
     #[arg(long, action = ArgAction::SetTrue)]
     spec_help: bool,
@@ -159,6 +351,9 @@ struct DiffArgs {
     /// Minimum changed pixels per region
     #[arg(long, default_value_t = 64)]
     bbox_min_area: u32,
+    /// Also drop regions whose changed pixels are below this percent of the image area
+    #[arg(long, default_value_t = 0.0, value_name = "PERCENT")]
+    compare_ignore_below_area_percent: f64,
     /// Padding around each bbox
     #[arg(long, default_value_t = 2)]
     bbox_pad: u32,
@@ -171,14 +366,120 @@ struct DiffArgs {
     /// Path to write annotate-compatible JSON spec
     #[arg(long)]
     annotate_spec_out: Option<PathBuf>,
+    /// Minimum pixel difference tinted in the diff overlay (region extraction is unaffected)
+    #[arg(long, default_value_t = 0)]
+    overlay_threshold: u8,
+    /// Write --diff-out as red-on-transparent (alpha = diff magnitude) instead of over the current image
+    #[arg(long, action = ArgAction::SetTrue, requires = "diff_out")]
+    overlay_transparent: bool,
+    /// none|auto (auto rotates current 90° when its dimensions are swapped vs baseline)
+    #[arg(long, default_value = "none", value_parser = ["none", "auto"])]
+    orient: String,
+    /// Ignore pixels that differ only by antialiasing (pixelmatch-style neighbor test)
+    #[arg(long, action = ArgAction::SetTrue)]
+    ignore_aa: bool,
+    /// rgb|lab|ycbcr distance metric (lab = CIE76 ΔE scaled so ΔE 100 maps to 255)
+    #[arg(long, default_value = "rgb", value_parser = ["rgb", "lab", "ycbcr"])]
+    colorspace: String,
+    /// color|shape (shape binarizes both images and diffs only the silhouettes, ignoring color)
+    #[arg(long, default_value = "color", value_parser = ["color", "shape"])]
+    mode: String,
+    /// auto|alpha|luma: what --mode shape binarizes (auto = alpha unless both images are opaque)
+    #[arg(long, default_value = "auto", value_parser = ["auto", "alpha", "luma"])]
+    shape_channel: String,
+    /// --mode shape cutoff: alpha >= N (or luma < N) counts as part of the shape
+    #[arg(long, default_value_t = 128)]
+    shape_threshold: u8,
+    /// Ignore mask PNG: black (or transparent) pixels are excluded from the diff
+    #[arg(long)]
+    mask: Option<PathBuf>,
+    /// Skip per-pixel work on rows whose bytes are identical in both images (tall, mostly static shots)
+    #[arg(long = "diff-ignore-identical-rows", visible_alias = "ignore-identical-rows", action = ArgAction::SetTrue)]
+    ignore_identical_rows: bool,
+    /// Grayscale importance map: luma/255 weights each pixel in percent_changed/avg_diff_percent
+    #[arg(long, value_name = "PATH")]
+    weight_map: Option<PathBuf>,
+    /// PNG compression: default|fast|best (fast trades file size for write speed)
+    #[arg(long, default_value = "default", value_parser = ["default", "fast", "best"])]
+    png_compression: String,
+    /// Refuse inputs with more pixels than this (0 disables the guard)
+    #[arg(long, default_value_t = DEFAULT_MAX_TOTAL_PIXELS)]
+    max_total_pixels: u64,
+    /// Downscale oversized inputs to fit --max-total-pixels instead of failing
+    #[arg(long, action = ArgAction::SetTrue)]
+    downscale_oversize: bool,
+    /// Include a 256-bin histogram of per-pixel diff magnitudes in the result JSON
+    #[arg(long, action = ArgAction::SetTrue)]
+    compare_subpixel_report: bool,
+    /// Where --annotate-spec-out places Δn labels: centroid|top_left|top|center|auto
+    #[arg(
+        long,
+        default_value = "centroid",
+        value_parser = ["centroid", "top_left", "top", "center", "auto"]
+    )]
+    region_label_position: String,
+    /// Region box colors in generated specs: fixed (red) or stable (hashed from position/size)
+    #[arg(long, default_value = "fixed", value_parser = ["fixed", "stable"])]
+    region_colors: String,
+    /// Resampling filter used by --resize: nearest|triangle|catmullrom|lanczos3
+    #[arg(
+        long,
+        default_value = "lanczos3",
+        value_parser = ["nearest", "triangle", "catmullrom", "lanczos3"]
+    )]
+    resize_filter: String,
+    /// Composite both inputs over this solid color before processing (e.g. #FFFFFF)
+    #[arg(long, value_name = "COLOR")]
+    flatten_bg: Option<String>,
+    /// Also report change_regions_current remapped into the original (pre-resize) current size
+    #[arg(long, action = ArgAction::SetTrue)]
+    current_coords: bool,
+    /// Write one PNG tiling a crop of each change region from the current image, labeled by id
+    #[arg(
+        long,
+        visible_alias = "compare-only-changed-regions-image",
+        value_name = "PATH"
+    )]
+    regions_collage: Option<PathBuf>,
+    /// Also write an agent to-do list with one inspect item (px bbox center) per change region
+    #[arg(long, value_name = "PATH")]
+    actions_out: Option<PathBuf>,
+    /// Report changed-pixel percentages for an R×C grid of tiles (e.g. 4x6)
+    #[arg(long, visible_alias = "compare-tile-report", value_name = "RxC")]
+    tiles: Option<String>,
+    /// Also render the --tiles grid as a heatmap PNG over the current image
+    #[arg(long, value_name = "PATH", requires = "tiles")]
+    tiles_heatmap: Option<PathBuf>,
+    /// Also write simulated color-vision copies (<stem>.<kind>.png) of --diff-out/--annotated-out
+    #[arg(long, visible_alias = "color-blind-sim", value_name = "KIND", value_parser = CB_SIM_KINDS)]
+    cb_sim: Option<String>,
+    /// Only compute regions/stats: no overlay or annotated image buffers are built or encoded
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["diff_out", "annotated_out", "regions_collage", "tiles_heatmap"]
+    )]
+    regions_only: bool,
+    /// Exit with status 7 (after printing the result) when no pixel changed
+    #[arg(long, action = ArgAction::SetTrue)]
+    exit_on_identical: bool,
+    /// Stop comparing once more than PERCENT of pixels changed and return a minimal "exceeded" result
+    #[arg(long, visible_alias = "compare-progressive", value_name = "PERCENT")]
+    fail_fast: Option<f64>,
+    /// Include per-stage wall-clock timings (ms) in the result JSON
+    #[arg(long, action = ArgAction::SetTrue)]
+    timings: bool,
 }
 
 #[derive(Args, Debug)]
 struct LoopArgs {
-    /// Current screenshot/image path
-    current_path: PathBuf,
+    /// Current screenshot/image path (omit when using --process)
+    current_path: Option<PathBuf>,
     /// Baseline key name
-    baseline_name: String,
+    baseline_name: Option<String>,
+    /// Capture this app's window as the current frame (`loop --process MyApp home`)
+    #[arg(long)]
+    process: Option<String>,
     /// Loop storage directory override
     #[arg(long)]
     loop_dir: Option<PathBuf>,
@@ -188,9 +489,28 @@ struct LoopArgs {
     /// Replace baseline with current after comparison
     #[arg(long, action = ArgAction::SetTrue)]
     update_baseline: bool,
+    /// Fail (exit 2) instead of silently creating a missing baseline from this frame
+    #[arg(long, action = ArgAction::SetTrue)]
+    explicit_baseline: bool,
+    /// Overwrite the baseline with this frame without comparing (seeds it under --explicit-baseline)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["update_baseline", "auto_update_below"])]
+    reset_baseline: bool,
+    /// Replace baseline with current only when percent_changed is below PERCENT (absorbs benign drift)
+    #[arg(long, value_name = "PERCENT")]
+    auto_update_below: Option<f64>,
     /// Skip generating annotated output/spec
     #[arg(long, action = ArgAction::SetTrue)]
     no_annotated: bool,
+    /// Region box colors in generated specs: fixed (red) or stable (hashed from position/size)
+    #[arg(long, default_value = "fixed", value_parser = ["fixed", "stable"])]
+    region_colors: String,
+    /// Where the generated spec places each region's Δn label: centroid|top_left|top|center|auto
+    #[arg(
+        long,
+        default_value = "centroid",
+        value_parser = ["centroid", "top_left", "top", "center", "auto"]
+    )]
+    region_label_position: String,
     /// Pixel diff threshold for bbox extraction
     #[arg(long, default_value_t = 24)]
     bbox_threshold: u8,
@@ -203,6 +523,102 @@ struct LoopArgs {
     /// Maximum number of change regions
     #[arg(long, default_value_t = 16)]
     max_boxes: usize,
+    /// Print a one-line status summary to stderr (JSON stays on stdout)
+    #[arg(long, action = ArgAction::SetTrue)]
+    ci_summary: bool,
+    /// Also emit a GitHub Actions ::warning:: line when changes are found
+    #[arg(long, action = ArgAction::SetTrue)]
+    ci_github: bool,
+}
+
+#[derive(Args, Debug)]
+struct SchemaArgs {
+    /// Payload to describe (default: all, keyed by name)
+    #[arg(value_parser = SCHEMA_NAMES)]
+    name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct DisplayInfoArgs {
+    /// Also locate this app's window and report the display/scale a capture would use
+    #[arg(long)]
+    process: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct DiffDirArgs {
+    /// Directory of baseline images
+    baseline_dir: PathBuf,
+    /// Directory of current images (paired with baselines by filename)
+    current_dir: PathBuf,
+    /// Output directory for per-file reports and summary.json (default: .codex-visual-loop/diff-dir/<ts>)
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+    /// Resize current to baseline size if dimensions differ
+    #[arg(long, action = ArgAction::SetTrue)]
+    resize: bool,
+    /// Pixel diff threshold for bbox extraction
+    #[arg(long, default_value_t = 24)]
+    bbox_threshold: u8,
+    /// Minimum changed pixels per region
+    #[arg(long, default_value_t = 64)]
+    bbox_min_area: u32,
+    /// Also write <name>-annotated.png with change boxes for each changed pair
+    #[arg(long, action = ArgAction::SetTrue)]
+    annotated: bool,
+    /// Number of worst offenders (highest percent_changed) listed in the summary
+    #[arg(long, default_value_t = 5)]
+    top: usize,
+}
+
+#[derive(Args, Debug)]
+struct PhashArgs {
+    /// Path to baseline image
+    baseline: PathBuf,
+    /// Path to current image
+    current: PathBuf,
+    /// average (downscaled luma vs its mean) | blockhash (block sums vs their band median)
+    #[arg(
+        long,
+        visible_alias = "mode",
+        default_value = "blockhash",
+        value_parser = ["average", "blockhash"]
+    )]
+    compare_mode: String,
+    /// Hash grid side; the hash has SIZE² bits
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(4..=32))]
+    hash_size: u32,
+    /// Largest Hamming distance still reported as the same screen (default: 10% of the bits)
+    #[arg(long, value_name = "BITS")]
+    max_distance: Option<u32>,
+    /// Path to write JSON report
+    #[arg(long)]
+    json_out: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct StabilityArgs {
+    /// App process name to sample (default: frontmost app)
+    #[arg(long)]
+    process: Option<String>,
+    /// Number of captures to take (at least 2)
+    #[arg(long, default_value_t = 5)]
+    samples: u32,
+    /// Delay between captures in milliseconds
+    #[arg(long, default_value_t = 500)]
+    interval_ms: u64,
+    /// Output directory for sample captures (default: .codex-visual-loop/stability/<app>-<ts>)
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+    /// Maximum pairwise percent_changed still considered stable
+    #[arg(long, default_value_t = 0.1)]
+    max_percent: f64,
+    /// Pixel diff threshold for changed pixels
+    #[arg(long, default_value_t = 24)]
+    bbox_threshold: u8,
+    /// Resize samples to the first capture's size if dimensions differ
+    #[arg(long, action = ArgAction::SetTrue)]
+    resize: bool,
 }
 
 #[derive(Args, Debug)]
@@ -231,7 +647,7 @@ struct ObserveArgs {
     /// Generate contact sheet metadata flag
     #[arg(long, action = ArgAction::SetTrue)]
     summary_sheet: bool,
-    /// Generate preview gif metadata flag
+    /// Encode the summary frames into a looping preview-*.gif
     #[arg(long, action = ArgAction::SetTrue)]
     summary_gif: bool,
     /// Skip clip summary generation
@@ -240,6 +656,30 @@ struct ObserveArgs {
     /// Print final observation packet JSON to stdout
     #[arg(long, action = ArgAction::SetTrue)]
     json: bool,
+    /// Write/update a .gitignore in the artifact root ignoring generated subdirectories
+    #[arg(long, action = ArgAction::SetTrue)]
+    gitignore: bool,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// Workflow manifest JSON/YAML path (or - for JSON on stdin)
+    manifest: String,
+    /// Also write the run result JSON to this path
+    #[arg(long)]
+    json_out: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CleanArgs {
+    /// Subtrees of the artifact root to remove (default: every generated one; loop baselines are kept)
+    targets: Vec<String>,
+    /// Remove the whole artifact root, loop baselines included
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "targets")]
+    all: bool,
+    /// Delete without asking (without it, a non-interactive run only lists what would go)
+    #[arg(long, short = 'y', action = ArgAction::SetTrue)]
+    yes: bool,
 }
 
 #[derive(Args, Debug)]
@@ -256,6 +696,45 @@ struct AxTreeArgs {
     /// Print tree JSON to stdout
     #[arg(long, action = ArgAction::SetTrue)]
     json: bool,
+    /// screen|window: window subtracts the window origin so bounds match a window capture
+    #[arg(
+        long,
+        visible_alias = "ax-coordinate-space",
+        default_value = "screen",
+        value_parser = ["screen", "window"]
+    )]
+    ax_origin: String,
+    /// Capture sidecar whose window bounds give the origin for --ax-origin window
+    #[arg(long)]
+    sidecar: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct AwaitElementArgs {
+    /// App process name (default: frontmost app)
+    #[arg(long)]
+    process: Option<String>,
+    /// Element name to wait for (case-insensitive substring)
+    #[arg(long)]
+    name: Option<String>,
+    /// Element role description or class to wait for (case-insensitive, e.g. button)
+    #[arg(long)]
+    role: Option<String>,
+    /// Traversal depth for accessibility recursion
+    #[arg(long, default_value_t = 4)]
+    depth: u32,
+    /// Delay between AX polls in milliseconds
+    #[arg(long, default_value_t = 500)]
+    poll_ms: u64,
+    /// Give up (exit 8) after this many milliseconds without a match
+    #[arg(long, default_value_t = 10000)]
+    timeout_ms: u64,
+    /// Capture output path (default: .codex-visual-loop/await/await-<app>-<ts>.png)
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// Only wait for the element; skip the capture
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_capture: bool,
 }
 
 #[derive(Args, Debug)]
@@ -327,17 +806,45 @@ struct ExplainArgs {
     /// Timeout seconds for codex exec
     #[arg(long, default_value_t = 300)]
     codex_timeout: u64,
+    /// Estimated prompt token budget (chars/4); larger packets get AX elements trimmed (0 disables)
+    #[arg(long, default_value_t = 60_000)]
+    prompt_token_budget: usize,
+    /// Blur AX secure text fields (password inputs) in the saved PNG
+    #[arg(long, action = ArgAction::SetTrue)]
+    redact_secure: bool,
     /// Skip codex exec and emit fallback markdown report
     #[arg(long, action = ArgAction::SetTrue)]
     no_codex: bool,
+    /// Markdown template for the fallback report ({process}, {element_count}, {top_roles}, {reason}, ...)
+    #[arg(long, value_name = "PATH")]
+    report_template: Option<PathBuf>,
     /// Exit non-zero if codex exec fails (instead of fallback report)
     #[arg(long, action = ArgAction::SetTrue)]
     strict_llm: bool,
+    /// Fold the truncated codex stdout/stderr tails into the output JSON and the fallback report
+    #[arg(long, visible_alias = "explain-attach-logs", action = ArgAction::SetTrue)]
+    attach_logs: bool,
     /// Emit JSON payload
     #[arg(long, action = ArgAction::SetTrue)]
     json: bool,
 }
 
+#[derive(Args, Debug)]
+struct BenchArgs {
+    /// Synthetic image width in px
+    #[arg(long, default_value_t = 1920)]
+    width: u32,
+    /// Synthetic image height in px
+    #[arg(long, default_value_t = 1080)]
+    height: u32,
+    /// Timed iterations per benchmark (after one warm-up run)
+    #[arg(long, default_value_t = 5)]
+    iterations: u32,
+    /// Number of synthetic annotations drawn per render iteration
+    #[arg(long, default_value_t = 24)]
+    annotations: usize,
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 struct ChangeRegion {
     x: u32,
@@ -349,6 +856,7 @@ struct ChangeRegion {
     pixels: u32,
     area: u32,
     coverage: f64,
+    centroid: [f64; 2],
     intent: String,
     action: String,
     id: String,
@@ -405,6 +913,7 @@ struct AnchorTarget {
     index: usize,
     ann_type: String,
     bbox: (f64, f64, f64, f64),
+    centroid: Option<(f64, f64)>,
 }
 
 #[derive(Debug, Clone)]
@@ -439,12 +948,211 @@ struct AxTreeNode {
     children: Vec<AxTreeNode>,
 }
 
+// One normal-layer window of the target app from the CoreGraphics window list.
+#[derive(Debug, Clone, PartialEq)]
+struct CgWindow {
+    number: i64,
+    onscreen: bool,
+    x: i64,
+    y: i64,
+    w: i64,
+    h: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct DisplayInfo {
+    index: usize,
+    x: i64,
+    y: i64,
+    w: i64,
+    h: i64,
+    scale: f64,
+}
+
 #[derive(Debug, Clone)]
 struct AxQueryResult {
     elements: Vec<Value>,
     tree: Vec<Value>,
     diagnostics: QueryDiagnostic,
-    warnings: Vec<String>,
+    warnings: Warnings,
+}
+
+// Global output flags from `Cli`, passed down to every command that emits warnings.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct OutputOptions {
+    warnings_json: bool,
+    // A warning_severity_rank from --min-warning-severity / --quiet-warnings.
+    min_warning_rank: u8,
+    // --verify-output: save_png re-reads what it wrote.
+    verify_output: bool,
+    // --locale for the CSV/markdown report builders; JSON never uses it.
+    locale: NumberLocale,
+    // --window-script / --ax-script for the window probe and AX queries.
+    scripts: ScriptOverrides,
+}
+
+impl OutputOptions {
+    fn from_cli(cli: &Cli) -> Self {
+        let min_rank = warning_severity_rank(&cli.min_warning_severity);
+        Self {
+            warnings_json: cli.warnings_format == "json",
+            min_warning_rank: if cli.quiet_warnings {
+                min_rank.max(warning_severity_rank("warning"))
+            } else {
+                min_rank
+            },
+            verify_output: cli.verify_output,
+            locale: cli.locale.unwrap_or_default(),
+            scripts: ScriptOverrides {
+                window: cli.window_script.clone(),
+                ax: cli.ax_script.clone(),
+            },
+        }
+    }
+}
+
+// Global --window-script / --ax-script paths.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ScriptOverrides {
+    window: Option<PathBuf>,
+    ax: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct ToolWarning {
+    code: String,
+    message: String,
+    severity: String,
+    // `--warnings-format text` lines when they differ from `message` (legacy diagnostic pairs).
+    #[serde(skip)]
+    text: Vec<String>,
+}
+
+impl ToolWarning {
+    fn from_value(value: &Value) -> Option<Self> {
+        if let Some(text) = value.as_str() {
+            return Some(Self {
+                code: "unclassified".to_string(),
+                message: text.to_string(),
+                severity: "warning".to_string(),
+                text: Vec::new(),
+            });
+        }
+        let obj = value.as_object()?;
+        Some(Self {
+            code: value_to_string(obj.get("code")).unwrap_or_else(|| "unclassified".to_string()),
+            message: value_to_string(obj.get("message"))?,
+            severity: value_to_string(obj.get("severity")).unwrap_or_else(|| "warning".to_string()),
+            text: Vec::new(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Warnings(Vec<ToolWarning>);
+
+impl Warnings {
+    fn push_with(&mut self, severity: &str, code: &str, message: impl Into<String>) {
+        self.0.push(ToolWarning {
+            code: code.to_string(),
+            message: message.into(),
+            severity: severity.to_string(),
+            text: Vec::new(),
+        });
+    }
+
+    fn warn(&mut self, code: &str, message: impl Into<String>) {
+        self.push_with("warning", code, message);
+    }
+
+    fn info(&mut self, code: &str, message: impl Into<String>) {
+        self.push_with("info", code, message);
+    }
+
+    fn error(&mut self, code: &str, message: impl Into<String>) {
+        self.push_with("error", code, message);
+    }
+
+    // One warning per failed query, keeping the diagnostic's own error code in the message.
+    // Text format keeps the original `{key}:{error_code}` + `{label}: {message}` line pair.
+    fn push_diagnostic(&mut self, code: &str, key: &str, label: &str, diag: &QueryDiagnostic) {
+        if diag.ok {
+            return;
+        }
+        let (err, message) = (diag.error_code.as_deref(), diag.message.as_deref());
+        let detail = match (err, message) {
+            (Some(err), Some(message)) => format!("{label} ({err}): {message}"),
+            (Some(err), None) => format!("{label} ({err})"),
+            (None, Some(message)) => format!("{label}: {message}"),
+            (None, None) => return,
+        };
+        self.warn(code, detail);
+        if let Some(warning) = self.0.last_mut() {
+            warning.text = err
+                .map(|err| format!("{key}:{err}"))
+                .into_iter()
+                .chain(message.map(|message| format!("{label}: {message}")))
+                .collect();
+        }
+    }
+
+    // Warnings at or above `min_rank`, in order.
+    fn visible(&self, min_rank: u8) -> Vec<&ToolWarning> {
+        self.0
+            .iter()
+            .filter(|w| warning_severity_rank(&w.severity) >= min_rank)
+            .collect()
+    }
+
+    // Messages --min-warning-severity drops from the emitted arrays.
+    fn hidden_messages(&self, output_opts: &OutputOptions) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|w| warning_severity_rank(&w.severity) < output_opts.min_warning_rank)
+            .map(|w| w.message.as_str())
+            .collect()
+    }
+
+    // Called once per command, next to its final output, so hidden notes still reach stderr.
+    fn note_hidden(&self, output_opts: &OutputOptions) {
+        for message in self.hidden_messages(output_opts) {
+            eprintln!("note: {message}");
+        }
+    }
+
+    fn extend_from_value(&mut self, value: Option<&Value>) {
+        if let Some(items) = value.and_then(Value::as_array) {
+            self.0
+                .extend(items.iter().filter_map(ToolWarning::from_value));
+        }
+    }
+}
+
+impl Warnings {
+    // The emitted `warnings` array: message strings, or {code, message, severity} objects.
+    fn to_value(&self, output_opts: &OutputOptions) -> Value {
+        let visible = self.visible(output_opts.min_warning_rank);
+        if output_opts.warnings_json {
+            return json!(visible);
+        }
+        let lines = visible.into_iter().flat_map(|warning| {
+            if warning.text.is_empty() {
+                vec![warning.message.clone()]
+            } else {
+                warning.text.clone()
+            }
+        });
+        Value::Array(lines.map(Value::String).collect())
+    }
+}
+
+// info < warning < error; unknown severities count as warning.
+fn warning_severity_rank(severity: &str) -> u8 {
+    match severity {
+        "info" => 0,
+        "error" => 2,
+        _ => 1,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -455,67 +1163,488 @@ struct HotkeySpec {
     key_code: Option<u16>,
 }
 
+#[derive(Debug, Clone)]
+struct CaptureOptions {
+    png_compression: String,
+    redact_secure: bool,
+    no_probe: bool,
+    region: Option<(i64, i64, i64, i64)>,
+    capture_retry: u32,
+    exclude_shadow: bool,
+    meta_only: bool,
+    min_window: (Option<i64>, Option<i64>, Option<i64>),
+    output: OutputOptions,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            png_compression: "default".to_string(),
+            redact_secure: false,
+            no_probe: false,
+            region: None,
+            capture_retry: 0,
+            exclude_shadow: false,
+            meta_only: false,
+            min_window: (None, None, None),
+            output: OutputOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DiffOptions {
+    resize: bool,
+    bbox_threshold: u8,
+    bbox_min_area: u32,
+    min_area_percent: f64,
+    bbox_pad: u32,
+    max_boxes: usize,
+    overlay_threshold: u8,
+    overlay_transparent: bool,
+    orient_auto: bool,
+    ignore_aa: bool,
+    colorspace: String,
+    shape_mode: bool,
+    shape_channel: String,
+    shape_threshold: u8,
+    mask: Option<PathBuf>,
+    weight_map: Option<PathBuf>,
+    png_compression: String,
+    verify_output: bool,
+    max_total_pixels: u64,
+    downscale_oversize: bool,
+    histogram: bool,
+    ignore_identical_rows: bool,
+    label_position: String,
+    region_colors: String,
+    regions_only: bool,
+    fail_fast: Option<f64>,
+    regions_collage: Option<PathBuf>,
+    actions_out: Option<PathBuf>,
+    tiles: Option<(u32, u32)>,
+    tiles_heatmap: Option<PathBuf>,
+    cb_sim: Option<String>,
+    timings: bool,
+    resize_filter: String,
+    current_coords: bool,
+    flatten_bg: Option<Rgba<u8>>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            resize: false,
+            bbox_threshold: 24,
+            bbox_min_area: 64,
+            min_area_percent: 0.0,
+            bbox_pad: 2,
+            max_boxes: 16,
+            overlay_threshold: 0,
+            overlay_transparent: false,
+            orient_auto: false,
+            ignore_aa: false,
+            colorspace: "rgb".to_string(),
+            shape_mode: false,
+            shape_channel: "auto".to_string(),
+            shape_threshold: 128,
+            mask: None,
+            weight_map: None,
+            png_compression: "default".to_string(),
+            verify_output: false,
+            max_total_pixels: DEFAULT_MAX_TOTAL_PIXELS,
+            downscale_oversize: false,
+            histogram: false,
+            ignore_identical_rows: false,
+            label_position: "centroid".to_string(),
+            region_colors: "fixed".to_string(),
+            regions_only: false,
+            fail_fast: None,
+            regions_collage: None,
+            actions_out: None,
+            tiles: None,
+            tiles_heatmap: None,
+            cb_sim: None,
+            timings: false,
+            resize_filter: "lanczos3".to_string(),
+            current_coords: false,
+            flatten_bg: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct DiffRunOutput {
     json: Value,
 }
 
+// ~100 MP: larger than any real display capture, small enough to keep RGBA + gray buffers sane.
+const DEFAULT_MAX_TOTAL_PIXELS: u64 = 100_000_000;
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_INPUT: i32 = 2;
+const EXIT_CAPTURE: i32 = 3;
+const EXIT_CODEX: i32 = 4;
+const EXIT_ACTION: i32 = 5;
+const EXIT_UNSUPPORTED: i32 = 6;
+const EXIT_IDENTICAL: i32 = 7;
+const EXIT_TIMEOUT: i32 = 8;
+
+const EXIT_CODES: &[(i32, &str, &str)] = &[
+    (0, "ok", "success"),
+    (EXIT_ERROR, "error", "unclassified failure"),
+    (
+        EXIT_INPUT,
+        "input",
+        "missing/invalid input file, spec, or argument (also clap usage errors)",
+    ),
+    (
+        EXIT_CAPTURE,
+        "capture",
+        "capture fell back to placeholder output under --strict, or loop --process would seed a baseline from it (permissions or no usable window)",
+    ),
+    (EXIT_CODEX, "codex", "Codex CLI failed under --strict-llm"),
+    (EXIT_ACTION, "action", "one or more UI actions failed"),
+    (
+        EXIT_UNSUPPORTED,
+        "unsupported_platform",
+        "command requires macOS",
+    ),
+    (
+        EXIT_IDENTICAL,
+        "identical",
+        "diff found no changed pixels under --exit-on-identical",
+    ),
+    (
+        EXIT_TIMEOUT,
+        "timeout",
+        "await-element timed out before a matching AX element appeared",
+    ),
+];
+
+#[derive(Debug)]
+struct CliFailure {
+    code: i32,
+    message: String,
+}
+
+impl std::fmt::Display for CliFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CliFailure {}
+
+fn cli_failure(code: i32, message: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(CliFailure {
+        code,
+        message: message.into(),
+    })
+}
+
+fn exit_code_for(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<CliFailure>()
+        .map(|failure| failure.code)
+        .unwrap_or(EXIT_ERROR)
+}
+
+fn print_exit_help() -> Result<()> {
+    let rows: Vec<Value> = EXIT_CODES
+        .iter()
+        .map(|(code, name, description)| {
+            json!({"code": code, "name": name, "description": description})
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&rows)?);
+    Ok(())
+}
+
 fn main() {
     if let Err(err) = run() {
         eprintln!("error: {err:#}");
-        std::process::exit(1);
+        std::process::exit(exit_code_for(&err));
     }
 }
 
 fn run() -> Result<()> {
     let cli = Cli::parse();
+    if cli.print_exit_help {
+        return print_exit_help();
+    }
+    let output_opts = OutputOptions::from_cli(&cli);
+    let Some(command) = cli.command else {
+        use clap::CommandFactory;
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required",
+            )
+            .exit();
+    };
+    let audit = env::var("CVLP_AUDIT_LOG")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(|path| (PathBuf::from(path), audit_entry(&command)));
+    let started = Instant::now();
 
-    match cli.command {
+    let result = match command {
         Commands::Commands => print_commands(),
         Commands::Manifest => print_manifest(),
-        Commands::Capture(args) => command_capture(args),
-        Commands::Annotate(args) => command_annotate(args),
-        Commands::Diff(args) => command_diff(args),
-        Commands::Loop(args) => command_loop(args),
-        Commands::Observe(args) => command_observe(args),
-        Commands::AxTree(args) => command_ax_tree(args),
-        Commands::Act(args) => command_act(args),
-        Commands::ExplainApp(args) => command_explain_app(args),
+        Commands::Schema(args) => print_schema(args),
+        Commands::Capture(args) => command_capture(args, &output_opts),
+        Commands::Annotate(args) => command_annotate(args, &output_opts),
+        Commands::Diff(args) => command_diff(args, &output_opts),
+        Commands::DiffDir(args) => command_diff_dir(args, &output_opts),
+        Commands::Phash(args) => command_phash(args),
+        Commands::Loop(args) => command_loop(args, &output_opts),
+        Commands::Stability(args) => command_stability(args, &output_opts),
+        Commands::DisplayInfo(args) => command_display_info(args, &output_opts),
+        Commands::Clean(args) => command_clean(args),
+        Commands::Run(args) => command_run(args, &output_opts),
+        Commands::Observe(args) => command_observe(args, &output_opts),
+        Commands::AxTree(args) => command_ax_tree(args, &output_opts),
+        Commands::AwaitElement(args) => command_await_element(args, &output_opts),
+        Commands::Act(args) => command_act(args, &output_opts),
+        Commands::ExplainApp(args) => command_explain_app(args, &output_opts),
+        Commands::Bench(args) => command_bench(args),
+    };
+
+    if let Some((path, mut entry)) = audit {
+        if let Some(obj) = entry.as_object_mut() {
+            obj.insert(
+                "duration_ms".to_string(),
+                json!(started.elapsed().as_millis() as u64),
+            );
+            obj.insert("success".to_string(), json!(result.is_ok()));
+            obj.insert(
+                "exit_code".to_string(),
+                json!(result.as_ref().err().map(exit_code_for).unwrap_or(0)),
+            );
+            obj.insert(
+                "error".to_string(),
+                json!(result.as_ref().err().map(|err| format!("{err:#}"))),
+            );
+        }
+        if let Err(err) = append_json_line(&path, &entry) {
+            eprintln!("warning: failed to append audit log: {err:#}");
+        }
     }
+
+    result
 }
 
-fn print_commands() -> Result<()> {
-    let rows = vec![
-        json!({
-            "name": "capture",
-            "description": "Capture an app window and emit metadata JSON sidecars.",
-            "runner": "rust"
-        }),
-        json!({
-            "name": "annotate",
-            "description": "Render annotation specs with semantic fields + relative units.",
-            "runner": "rust"
-        }),
-        json!({
-            "name": "diff",
-            "description": "Compare screenshots and emit diff-to-bbox annotation specs.",
-            "runner": "rust"
-        }),
-        json!({
-            "name": "loop",
-            "description": "Run baseline/history diff loops with auto-annotated change boxes.",
-            "runner": "rust"
+fn audit_entry(command: &Commands) -> Value {
+    fn paths(items: &[Option<&PathBuf>]) -> Vec<String> {
+        items
+            .iter()
+            .flatten()
+            .map(|p| p.display().to_string())
+            .collect()
+    }
+
+    let (name, inputs, outputs): (&str, Vec<String>, Vec<String>) = match command {
+        Commands::Commands => ("commands", Vec::new(), Vec::new()),
+        Commands::Manifest => ("manifest", Vec::new(), Vec::new()),
+        Commands::Schema(args) => ("schema", args.name.iter().cloned().collect(), Vec::new()),
+        Commands::Capture(args) => (
+            "capture",
+            args.process
+                .iter()
+                .chain(args.process_name.iter())
+                .cloned()
+                .collect(),
+            paths(&[
+                args.out.as_ref().or(args.out_path.as_ref()),
+                args.sidecar.as_ref(),
+            ]),
+        ),
+        Commands::Annotate(args) => (
+            "annotate",
+            std::iter::once(args.input.display().to_string())
+                .chain(args.spec.iter().cloned())
+                .collect(),
+            paths(&[
+                args.output.as_ref(),
+                args.meta_out.as_ref(),
+                args.meta_csv.as_ref(),
+                args.preview_grid.as_ref(),
+                args.overlay_only.as_ref(),
+                args.layered_out.as_ref(),
+                args.actions_out.as_ref(),
+                args.bundle_out.as_ref(),
+            ])
+            .into_iter()
+            .chain(args.export.iter().filter_map(|v| v.get(1).cloned()))
+            .collect(),
+        ),
+        Commands::Diff(args) => (
+            "diff",
+            paths(&[Some(&args.baseline), Some(&args.current)]),
+            paths(&[
+                args.diff_out.as_ref(),
+                args.json_out.as_ref(),
+                args.annotated_out.as_ref(),
+                args.annotate_spec_out.as_ref(),
+                args.actions_out.as_ref(),
+            ]),
+        ),
+        Commands::DiffDir(args) => (
+            "diff-dir",
+            paths(&[Some(&args.baseline_dir), Some(&args.current_dir)]),
+            paths(&[args.out_dir.as_ref()]),
+        ),
+        Commands::Phash(args) => (
+            "phash",
+            paths(&[Some(&args.baseline), Some(&args.current)]),
+            paths(&[args.json_out.as_ref()]),
+        ),
+        Commands::Loop(args) => (
+            "loop",
+            args.current_path
+                .iter()
+                .map(|p| p.display().to_string())
+                .chain(args.baseline_name.iter().cloned())
+                .chain(args.process.iter().cloned())
+                .collect(),
+            paths(&[args.loop_dir.as_ref()]),
+        ),
+        Commands::Stability(args) => (
+            "stability",
+            args.process.iter().cloned().collect(),
+            paths(&[args.out_dir.as_ref()]),
+        ),
+        Commands::DisplayInfo(args) => (
+            "display-info",
+            args.process.iter().cloned().collect(),
+            Vec::new(),
+        ),
+        Commands::Observe(args) => (
+            "observe",
+            args.process
+                .iter()
+                .cloned()
+                .chain(std::iter::once(args.action.clone()))
+                .collect(),
+            paths(&[args.out_dir.as_ref()]),
+        ),
+        Commands::Clean(args) => ("clean", args.targets.clone(), Vec::new()),
+        Commands::Run(args) => (
+            "run",
+            vec![args.manifest.clone()],
+            paths(&[args.json_out.as_ref()]),
+        ),
+        Commands::AxTree(args) => (
+            "ax-tree",
+            args.process.iter().cloned().collect(),
+            paths(&[args.out.as_ref()]),
+        ),
+        Commands::AwaitElement(args) => (
+            "await-element",
+            args.process
+                .iter()
+                .chain(args.name.iter())
+                .chain(args.role.iter())
+                .cloned()
+                .collect(),
+            paths(&[args.out.as_ref()]),
+        ),
+        Commands::Act(args) => ("act", args.process.iter().cloned().collect(), Vec::new()),
+        Commands::ExplainApp(args) => (
+            "explain-app",
+            args.process.iter().cloned().collect(),
+            paths(&[
+                args.out_dir.as_ref(),
+                args.report.as_ref(),
+                args.packet_out.as_ref(),
+                args.prompt_out.as_ref(),
+            ]),
+        ),
+        Commands::Bench(_) => ("bench", Vec::new(), Vec::new()),
+    };
+
+    json!({
+        "timestamp": timestamp_iso(),
+        "command": name,
+        "pid": std::process::id(),
+        "cwd": env::current_dir().ok().map(|p| p.display().to_string()),
+        "inputs": inputs,
+        "outputs": outputs,
+    })
+}
+
+fn print_commands() -> Result<()> {
+    let rows = vec![
+        json!({
+            "name": "schema",
+            "description": "Print JSON Schema contracts for capture/diff/annotate-meta/observe payloads.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "capture",
+            "description": "Capture an app window and emit metadata JSON sidecars.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "annotate",
+            "description": "Render annotation specs with semantic fields + relative units.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "diff",
+            "description": "Compare screenshots and emit diff-to-bbox annotation specs.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "diff-dir",
+            "description": "Diff baseline/current screenshot directories paired by filename with an aggregate summary.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "phash",
+            "description": "Perceptual-hash Hamming distance between two screenshots for near-duplicate checks.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "loop",
+            "description": "Run baseline/history diff loops with auto-annotated change boxes.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "stability",
+            "description": "Score screen determinism from pairwise diffs of repeated captures.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "display-info",
+            "description": "List displays with point/pixel sizes and backing scale factors.",
+            "runner": "rust"
         }),
         json!({
             "name": "observe",
             "description": "Build observation packet JSON (before/after/clip/diff).",
             "runner": "rust"
         }),
+        json!({
+            "name": "run",
+            "description": "Run a JSON/YAML workflow manifest of capture/diff/annotate steps with named references.",
+            "runner": "rust"
+        }),
+        json!({
+            "name": "clean",
+            "description": "Remove generated artifact subtrees under the artifact root.",
+            "runner": "rust"
+        }),
         json!({
             "name": "ax-tree",
             "description": "Dump accessibility tree snapshots for UI grounding.",
             "runner": "rust"
         }),
+        json!({
+            "name": "await-element",
+            "description": "Poll the AX tree until a matching element appears, then capture.",
+            "runner": "rust"
+        }),
         json!({
             "name": "act",
             "description": "Perform click/type/hotkey UI actions against a target app.",
@@ -545,11 +1674,474 @@ fn print_manifest() -> Result<()> {
     Ok(())
 }
 
-fn command_capture(args: CaptureArgs) -> Result<()> {
+const SCHEMA_NAMES: [&str; 4] = ["capture", "diff", "annotate-meta", "observe"];
+
+fn print_schema(args: SchemaArgs) -> Result<()> {
+    let payload = match args.name.as_deref() {
+        Some(name) => payload_schema(name).expect("clap restricts names to SCHEMA_NAMES"),
+        None => {
+            let schemas: Map<String, Value> = SCHEMA_NAMES
+                .iter()
+                .filter_map(|name| Some((name.to_string(), payload_schema(name)?)))
+                .collect();
+            json!({ "schemas": schemas })
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&payload)?);
+    Ok(())
+}
+
+// Hand-maintained contracts for the JSON the CLI emits; the schema tests run the real producers
+// and fail when a key is added or retyped without updating these. Objects stay open
+// (additionalProperties is not restricted) so additive fields are not breaking.
+fn payload_schema(name: &str) -> Option<Value> {
+    let (title, body) = match name {
+        "capture" => ("capture sidecar", capture_schema()),
+        "diff" => ("diff result", diff_schema()),
+        "annotate-meta" => ("annotate metadata sidecar", annotate_meta_schema()),
+        "observe" => ("observe packet", observe_schema()),
+        _ => return None,
+    };
+    let mut schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("codex-visual-loop/{name}.schema.json"),
+        "title": format!("codex-visual-loop {title}"),
+    });
+    if let (Some(obj), Value::Object(body)) = (schema.as_object_mut(), body) {
+        obj.extend(body);
+    }
+    Some(schema)
+}
+
+fn schema_type(kind: &str) -> Value {
+    json!({ "type": kind })
+}
+
+fn schema_nullable(schema: Value) -> Value {
+    json!({ "anyOf": [schema, {"type": "null"}] })
+}
+
+// Every listed property is required: the CLI always emits its keys, using null when unset.
+fn schema_object(properties: Vec<(&str, Value)>) -> Value {
+    let required: Vec<&str> = properties.iter().map(|(key, _)| *key).collect();
+    let properties: Map<String, Value> = properties
+        .into_iter()
+        .map(|(key, schema)| (key.to_string(), schema))
+        .collect();
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+fn schema_array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn warnings_schema() -> Value {
+    schema_array(json!({
+        "anyOf": [
+            {"type": "string"},
+            schema_object(vec![
+                ("code", schema_type("string")),
+                ("message", schema_type("string")),
+                ("severity", json!({"enum": ["info", "warning", "error"]})),
+            ]),
+        ]
+    }))
+}
+
+fn query_diagnostic_schema() -> Value {
+    schema_object(vec![
+        ("ok", schema_type("boolean")),
+        ("attempts", schema_type("integer")),
+        ("error_code", schema_nullable(schema_type("string"))),
+        ("message", schema_nullable(schema_type("string"))),
+    ])
+}
+
+fn box_schema(keys: &[&str], kind: &str, units: bool) -> Value {
+    let mut properties: Vec<(&str, Value)> =
+        keys.iter().map(|key| (*key, schema_type(kind))).collect();
+    if units {
+        properties.push(("units", schema_type("string")));
+    }
+    schema_object(properties)
+}
+
+fn capture_schema() -> Value {
+    schema_object(vec![
+        ("capture_sidecar_version", json!({"const": 1})),
+        ("capture_tool", schema_type("string")),
+        ("image_path", schema_nullable(schema_type("string"))),
+        ("capture_path", schema_nullable(schema_type("string"))),
+        ("sidecar_path", schema_nullable(schema_type("string"))),
+        (
+            "captured_at",
+            json!({"type": "string", "format": "date-time"}),
+        ),
+        ("captured_at_epoch_ms", schema_type("integer")),
+        ("app_name", schema_type("string")),
+        ("app_slug", schema_type("string")),
+        ("window_title", schema_nullable(schema_type("string"))),
+        ("step", schema_nullable(schema_type("string"))),
+        ("note", schema_nullable(schema_type("string"))),
+        (
+            "capture_mode",
+            json!({"enum": ["window", "screen", "screen_fast", "fallback", "meta_only"]}),
+        ),
+        ("fallback_used", schema_type("boolean")),
+        ("bounds", box_schema(&["x", "y", "w", "h"], "integer", true)),
+        (
+            "window",
+            box_schema(&["x", "y", "w", "h", "x2", "y2"], "integer", true),
+        ),
+        (
+            "image_size",
+            schema_object(vec![
+                ("w", schema_nullable(schema_type("integer"))),
+                ("h", schema_nullable(schema_type("integer"))),
+                ("units", schema_type("string")),
+            ]),
+        ),
+        ("scale", schema_nullable(schema_type("number"))),
+        ("scale_x", schema_nullable(schema_type("number"))),
+        ("scale_y", schema_nullable(schema_type("number"))),
+        ("display_scale", schema_nullable(schema_type("number"))),
+        (
+            "display",
+            schema_nullable(schema_object(vec![
+                ("index", schema_type("integer")),
+                ("x", schema_type("integer")),
+                ("y", schema_type("integer")),
+                ("w", schema_type("integer")),
+                ("h", schema_type("integer")),
+                ("scale", schema_type("number")),
+            ])),
+        ),
+        (
+            "space",
+            schema_object(vec![
+                ("on_other_space", schema_nullable(schema_type("boolean"))),
+                ("switched", schema_type("boolean")),
+            ]),
+        ),
+        (
+            "redactions",
+            schema_nullable(schema_array(box_schema(
+                &["x", "y", "w", "h"],
+                "integer",
+                true,
+            ))),
+        ),
+        (
+            "shadow_trim",
+            schema_nullable(box_schema(
+                &["left", "top", "right", "bottom"],
+                "integer",
+                true,
+            )),
+        ),
+        (
+            "window_probe",
+            schema_object(vec![
+                ("selection_mode", schema_type("string")),
+                ("selected_index", schema_nullable(schema_type("integer"))),
+                ("candidate_count", schema_type("integer")),
+                ("usable_count", schema_type("integer")),
+                ("usable", schema_type("boolean")),
+                ("min_width", schema_type("integer")),
+                ("min_height", schema_type("integer")),
+                ("min_area", schema_type("integer")),
+            ]),
+        ),
+        (
+            "query",
+            schema_object(vec![
+                ("activation", query_diagnostic_schema()),
+                ("window", query_diagnostic_schema()),
+                ("space", schema_nullable(query_diagnostic_schema())),
+                ("screencapture", schema_nullable(query_diagnostic_schema())),
+            ]),
+        ),
+        ("warnings", warnings_schema()),
+    ])
+}
+
+fn change_region_schema() -> Value {
+    schema_object(vec![
+        ("id", schema_type("string")),
+        ("x", schema_type("integer")),
+        ("y", schema_type("integer")),
+        ("w", schema_type("integer")),
+        ("h", schema_type("integer")),
+        ("x2", schema_type("integer")),
+        ("y2", schema_type("integer")),
+        ("pixels", schema_type("integer")),
+        ("area", schema_type("integer")),
+        ("coverage", schema_type("number")),
+        (
+            "centroid",
+            json!({"type": "array", "items": {"type": "number"}, "minItems": 2, "maxItems": 2}),
+        ),
+        ("intent", schema_type("string")),
+        ("action", schema_type("string")),
+        ("rel", box_schema(&["x", "y", "w", "h"], "number", false)),
+    ])
+}
+
+fn diff_schema() -> Value {
+    let nullable_path = || schema_nullable(schema_type("string"));
+    let nullable_object = || schema_nullable(schema_type("object"));
+    schema_object(vec![
+        ("baseline", schema_type("string")),
+        ("current", schema_type("string")),
+        ("diff_image", nullable_path()),
+        ("annotated_image", nullable_path()),
+        ("annotate_spec", nullable_path()),
+        ("regions_collage", nullable_path()),
+        ("actions_path", nullable_path()),
+        (
+            "cb_sim",
+            schema_nullable(schema_object(vec![
+                ("kind", schema_type("string")),
+                ("diff_image", nullable_path()),
+                ("annotated_image", nullable_path()),
+            ])),
+        ),
+        ("identical", schema_type("boolean")),
+        ("changed_pixels", schema_type("integer")),
+        ("percent_changed", schema_type("number")),
+        ("avg_diff_percent", schema_nullable(schema_type("number"))),
+        ("mae", schema_nullable(schema_type("number"))),
+        ("psnr_db", schema_nullable(schema_type("number"))),
+        (
+            "size",
+            schema_object(vec![
+                ("width", schema_type("integer")),
+                ("height", schema_type("integer")),
+            ]),
+        ),
+        ("resized", schema_type("boolean")),
+        ("resize_filter", nullable_path()),
+        ("flatten_bg", schema_nullable(schema_type("string"))),
+        (
+            "downscaled_oversize",
+            schema_object(vec![
+                ("baseline", schema_type("boolean")),
+                ("current", schema_type("boolean")),
+            ]),
+        ),
+        ("colorspace", schema_type("string")),
+        ("mode", json!({"enum": ["color", "shape"]})),
+        ("shape", nullable_object()),
+        ("mask", nullable_object()),
+        ("weight_map", nullable_object()),
+        ("min_region_pixels", schema_type("integer")),
+        ("aa_ignored_pixels", schema_nullable(schema_type("integer"))),
+        (
+            "identical_rows_skipped",
+            schema_nullable(schema_type("integer")),
+        ),
+        ("tiles", nullable_object()),
+        ("diff_histogram", nullable_object()),
+        (
+            "orientation",
+            schema_object(vec![
+                ("mode", json!({"enum": ["none", "auto"]})),
+                ("rotated_degrees", schema_nullable(schema_type("integer"))),
+            ]),
+        ),
+        ("current_space", nullable_object()),
+        (
+            "change_regions_current",
+            schema_nullable(schema_array(change_region_schema())),
+        ),
+        ("change_regions", schema_array(change_region_schema())),
+        (
+            "change_region_count",
+            schema_nullable(schema_type("integer")),
+        ),
+        ("regions_only", schema_type("boolean")),
+        ("fail_fast", nullable_object()),
+        ("timings_ms", nullable_object()),
+    ])
+}
+
+fn annotate_meta_schema() -> Value {
+    let mut item = schema_object(vec![
+        ("index", schema_type("integer")),
+        ("id", schema_type("string")),
+        ("type", schema_type("string")),
+        ("geometry", schema_type("object")),
+    ]);
+    // Optional keys: semantic fields echo the spec value as-is, the rest appear when applicable.
+    if let Some(properties) = item.get_mut("properties").and_then(Value::as_object_mut) {
+        for key in [
+            "units",
+            "intent",
+            "action",
+            "severity",
+            "issue",
+            "hypothesis",
+            "next_action",
+            "verify",
+        ] {
+            properties.insert(key.to_string(), json!({}));
+        }
+        properties.insert("geometry_rel".to_string(), schema_type("object"));
+        properties.insert("text".to_string(), schema_type("string"));
+        properties.insert("rendered".to_string(), schema_type("boolean"));
+        properties.insert("relative_to".to_string(), json!({}));
+        properties.insert("geometry_ref".to_string(), schema_type("object"));
+    }
+    schema_object(vec![
+        ("annotation_meta_version", json!({"const": 1})),
+        ("input_path", schema_type("string")),
+        ("output_path", schema_type("string")),
+        ("meta_path", schema_nullable(schema_type("string"))),
+        (
+            "generated_at",
+            json!({"type": "string", "format": "date-time"}),
+        ),
+        ("size", box_schema(&["width", "height"], "integer", true)),
+        ("preprocess", schema_nullable(schema_type("object"))),
+        ("flatten_bg", schema_nullable(schema_type("string"))),
+        ("capture_sidecar", schema_nullable(schema_type("string"))),
+        ("trim", schema_nullable(schema_type("object"))),
+        ("defaults", schema_type("object")),
+        ("type_filter", schema_nullable(schema_type("object"))),
+        ("annotations", schema_array(item)),
+        ("warnings", warnings_schema()),
+    ])
+}
+
+fn observe_schema() -> Value {
+    schema_object(vec![
+        ("run_id", schema_type("string")),
+        ("process_name", schema_type("string")),
+        (
+            "action",
+            schema_object(vec![
+                ("label", schema_type("string")),
+                ("command", schema_nullable(schema_type("string"))),
+                ("status", schema_type("integer")),
+                ("started_at", schema_type("string")),
+                ("finished_at", schema_type("string")),
+                ("log_path", schema_type("string")),
+            ]),
+        ),
+        ("before_capture", capture_schema()),
+        ("after_capture", capture_schema()),
+        (
+            "clip",
+            schema_object(vec![
+                ("video_path", schema_type("string")),
+                ("duration_sec", schema_type("integer")),
+                ("summary_mode", schema_type("string")),
+                ("summary_max", schema_type("integer")),
+                ("summary_enabled", schema_type("boolean")),
+                ("summary_sheet", schema_type("boolean")),
+                ("summary_gif", schema_type("boolean")),
+                ("preview_gif_path", schema_nullable(schema_type("string"))),
+            ]),
+        ),
+        ("diff", diff_schema()),
+        ("gitignore", schema_nullable(schema_type("string"))),
+        ("warnings", warnings_schema()),
+    ])
+}
+
+fn command_capture(args: CaptureArgs, output_opts: &OutputOptions) -> Result<()> {
+    let (process, resolved_out, options) = resolve_capture_args(&args, output_opts)?;
+
+    let frames = args.frames.max(1);
+    for frame in 0..frames {
+        if frame > 0 {
+            thread::sleep(Duration::from_millis(args.interval_ms));
+        }
+        let frame_out = frame_sequence_path(&resolved_out, frame, frames);
+        let sidecar_path = capture_sidecar_path(&args, &frame_out, frame, frames);
+
+        let payload = capture_internal(
+            &frame_out,
+            process.clone(),
+            args.step.as_deref(),
+            args.note.as_deref(),
+            sidecar_path.as_deref(),
+            &options,
+        )?;
+
+        if args.json_stream {
+            emit_json_event(&json!({
+                "event": "frame",
+                "frame": frame,
+                "frames": frames,
+                "capture": payload,
+            }))?;
+        }
+
+        let fallback_used = payload
+            .get("fallback_used")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if args.strict && fallback_used {
+            if args.json && !args.json_stream {
+                println!("{}", serde_json::to_string(&payload)?);
+            }
+            return Err(strict_capture_failure());
+        }
+
+        if args.json_stream {
+            continue;
+        }
+        if args.json || args.meta_only {
+            println!("{}", serde_json::to_string(&payload)?);
+        } else {
+            let output_path = payload
+                .get("image_path")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            println!("{output_path}");
+        }
+    }
+
+    if args.json_stream {
+        emit_json_event(&json!({"event": "done", "frames": frames}))?;
+    }
+
+    Ok(())
+}
+
+fn strict_capture_failure() -> anyhow::Error {
+    cli_failure(
+        EXIT_CAPTURE,
+        "capture fell back to placeholder output. Check Screen Recording/Accessibility permissions or retry with a visible app window.",
+    )
+}
+
+fn capture_sidecar_path(
+    args: &CaptureArgs,
+    frame_out: &Path,
+    frame: u32,
+    frames: u32,
+) -> Option<PathBuf> {
+    if args.no_sidecar {
+        return None;
+    }
+    Some(match args.sidecar.as_deref() {
+        Some(path) => frame_sequence_path(path, frame, frames),
+        None => default_sidecar_for(frame_out),
+    })
+}
+
+// Process, output path and capture options shared by `capture` and `run` capture steps.
+fn resolve_capture_args(
+    args: &CaptureArgs,
+    output_opts: &OutputOptions,
+) -> Result<(Option<String>, PathBuf, CaptureOptions)> {
     let process = args
         .process
         .clone()
         .or_else(|| args.process_name.clone())
+        .or_else(env_process_name)
         .or_else(frontmost_app_name);
 
     let out_root = out_root();
@@ -569,73 +2161,183 @@ fn command_capture(args: CaptureArgs) -> Result<()> {
             ))
         });
 
-    let sidecar_path = if args.no_sidecar {
-        None
-    } else {
-        Some(
-            args.sidecar
-                .unwrap_or_else(|| default_sidecar_for(&resolved_out)),
-        )
+    let region = match args.region.as_deref() {
+        Some(raw) => Some(parse_capture_region(raw).ok_or_else(|| {
+            cli_failure(
+                EXIT_INPUT,
+                format!("invalid --region {raw:?}: expected X,Y,W,H with W,H > 0"),
+            )
+        })?),
+        None => None,
     };
 
-    let payload = capture_internal(
-        &resolved_out,
-        process.clone(),
-        args.step.as_deref(),
-        args.note.as_deref(),
-        sidecar_path.as_deref(),
-    )?;
-
-    let fallback_used = payload
-        .get("fallback_used")
-        .and_then(Value::as_bool)
-        .unwrap_or(false);
-    if args.strict && fallback_used {
-        if args.json {
-            println!("{}", serde_json::to_string(&payload)?);
-        }
-        bail!(
-            "capture fell back to placeholder output. Check Screen Recording/Accessibility permissions or retry with a visible app window."
-        );
-    }
+    let options = CaptureOptions {
+        output: output_opts.clone(),
+        png_compression: args.png_compression.clone(),
+        redact_secure: args.redact_secure,
+        no_probe: args.no_probe,
+        region,
+        capture_retry: args.capture_retry,
+        exclude_shadow: args.no_shadow || args.capture_window_shadow == "exclude",
+        meta_only: args.meta_only,
+        min_window: (
+            args.min_window_width,
+            args.min_window_height,
+            args.min_window_area,
+        ),
+    };
+    Ok((process, resolved_out, options))
+}
 
-    if args.json {
-        println!("{}", serde_json::to_string(&payload)?);
-    } else {
-        let output_path = payload
-            .get("image_path")
-            .and_then(Value::as_str)
-            .unwrap_or_default();
-        println!("{output_path}");
+fn frame_sequence_path(path: &Path, frame: u32, frames: u32) -> PathBuf {
+    if frames <= 1 {
+        return path.to_path_buf();
     }
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "frame".to_string());
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-f{frame:03}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-f{frame:03}"),
+    };
+    path.with_file_name(name)
+}
 
+fn emit_json_event(event: &Value) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", serde_json::to_string(event)?)?;
+    stdout.flush()?;
     Ok(())
 }
 
-fn command_annotate(args: AnnotateArgs) -> Result<()> {
+fn command_annotate(args: AnnotateArgs, output_opts: &OutputOptions) -> Result<()> {
     if args.spec_help {
         println!("{}", SPEC_HELP.trim());
         return Ok(());
     }
 
+    let summary = annotate_internal(&args, output_opts)?;
+    if let Some(items) = summary["probe"].as_array() {
+        eprint!("{}", probe_table(items));
+    }
+    if args.json {
+        println!("{}", serde_json::to_string(&summary)?);
+    } else {
+        println!("{}", summary["output_path"].as_str().unwrap_or_default());
+    }
+    Ok(())
+}
+
+// Renders the annotated output (plus sidecar/extras) and returns the --json summary.
+fn annotate_internal(args: &AnnotateArgs, output_opts: &OutputOptions) -> Result<Value> {
     if !args.input.exists() {
-        bail!("input not found: {}", args.input.display());
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!("input not found: {}", args.input.display()),
+        ));
     }
 
-    let spec = load_spec(&args.spec)?;
-    let defaults = spec
+    let output = annotate_output_path(&args.input, args.output.as_deref());
+    let only_types = canonical_type_filter(&args.only_types, "--only-types")?;
+    let skip_types = canonical_type_filter(&args.skip_types, "--skip-types")?;
+
+    let export = match args.export.as_deref() {
+        Some([format, path]) => {
+            let format = format.trim().to_ascii_lowercase();
+            if !matches!(format.as_str(), "figma" | "excalidraw") {
+                return Err(cli_failure(
+                    EXIT_INPUT,
+                    format!("unsupported --export format {format:?}: expected figma|excalidraw"),
+                ));
+            }
+            Some((format, PathBuf::from(path)))
+        }
+        _ => None,
+    };
+
+    let spec = merge_specs(
+        args.spec
+            .iter()
+            .map(|path| load_spec(path))
+            .collect::<Result<Vec<_>>>()?,
+    );
+    let mut defaults = spec
         .get("defaults")
         .and_then(Value::as_object)
         .cloned()
         .unwrap_or_default();
-    let annotations = spec
+    let mut annotations = spec
         .get("annotations")
         .and_then(Value::as_array)
         .cloned()
         .unwrap_or_default();
 
-    let input_image = image::open(&args.input)
-        .with_context(|| format!("failed to open input image: {}", args.input.display()))?;
+    let mut warnings = Warnings::default();
+    if let Some(ax_path) = args.ax_spacing.as_deref() {
+        let ax: Value = fs::read_to_string(ax_path)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .ok_or_else(|| {
+                cli_failure(
+                    EXIT_INPUT,
+                    format!("--ax-spacing: cannot read AX JSON {}", ax_path.display()),
+                )
+            })?;
+        annotations.extend(ax_spacing_measures(&ax));
+        if !args.use_sidecar && !defaults.contains_key("pt_scale") {
+            let warning = "--ax-spacing without --use-sidecar or defaults.pt_scale: AX points are drawn as px".to_string();
+            eprintln!("warning: {warning}");
+            warnings.warn("ax_spacing_unscaled", warning);
+        }
+    }
+    let (input_image, downscaled) = open_image_within_budget(
+        &args.input,
+        "input",
+        args.max_total_pixels,
+        args.downscale_oversize,
+    )?;
+    if downscaled {
+        let warning = format!(
+            "input downscaled to {}x{} to fit --max-total-pixels {}",
+            input_image.width(),
+            input_image.height(),
+            args.max_total_pixels
+        );
+        eprintln!("warning: {warning}");
+        warnings.info("input_downscaled", warning);
+    }
+    let mut capture_sidecar: Option<PathBuf> = None;
+    if args.use_sidecar {
+        let sidecar_path = default_sidecar_for(&args.input);
+        let sidecar = fs::read_to_string(&sidecar_path)
+            .ok()
+            .and_then(|raw| serde_json::from_str::<Value>(&raw).ok())
+            .filter(|value| value.get("image_size").is_some());
+        match sidecar {
+            Some(sidecar) => {
+                // Spec defaults still win over anything seeded from the capture.
+                for (key, value) in capture_sidecar_defaults(&sidecar, input_image.dimensions()) {
+                    defaults.entry(key).or_insert(value);
+                }
+                capture_sidecar = Some(sidecar_path);
+            }
+            None => {
+                let warning = format!(
+                    "--use-sidecar: no capture sidecar at {}; using spec defaults only",
+                    sidecar_path.display()
+                );
+                eprintln!("warning: {warning}");
+                warnings.warn("sidecar_not_found", warning);
+            }
+        }
+    }
+    let flatten_bg = parse_flatten_bg(args.flatten_bg.as_deref())?;
+    let input_image = match flatten_bg {
+        Some(bg) => flatten_onto(input_image, bg),
+        None => input_image,
+    };
+    let (input_image, preprocess_meta) = apply_preprocess(input_image, &defaults)?;
     let mut rendered = input_image.to_rgba8();
     let fit_image = input_image.to_rgb8();
     let (img_w, img_h) = rendered.dimensions();
@@ -643,6 +2345,29 @@ fn command_annotate(args: AnnotateArgs) -> Result<()> {
 
     let mut prepared_spotlights: Vec<(usize, Map<String, Value>)> = Vec::new();
     let mut prepared_others: Vec<(usize, Map<String, Value>)> = Vec::new();
+    let mut used_ids: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut deferred_relative: Vec<(usize, Map<String, Value>)> = Vec::new();
+    // --font beats defaults.font; an unreadable global font falls back to the bitmap font.
+    if let Some(font) = args.font.as_deref() {
+        defaults.insert("font".to_string(), json!(font.display().to_string()));
+    }
+    let mut fonts = FontCache::default();
+    let mut unreadable_fonts: std::collections::HashSet<String> = std::collections::HashSet::new();
+    if let Some(font) = value_to_string(defaults.get("font")) {
+        if let Err(reason) = fonts.load(&font) {
+            let warning = format!("font {font}: {reason}; using the built-in bitmap font");
+            eprintln!("warning: {warning}");
+            warnings.warn("font_unreadable", warning);
+            defaults.remove("font");
+            unreadable_fonts.insert(font);
+        }
+    }
+    let theme = value_to_string(defaults.get("theme")).unwrap_or_else(|| "default".to_string());
+    if theme_palette(&theme, "rect").is_none() {
+        let warning = format!("unknown defaults.theme {theme:?}; using default");
+        eprintln!("warning: {warning}");
+        warnings.warn("unknown_theme", warning);
+    }
 
     for (idx, ann) in annotations.iter().enumerate() {
         let ann_obj = match ann.as_object() {
@@ -650,11 +2375,45 @@ fn command_annotate(args: AnnotateArgs) -> Result<()> {
             None => continue,
         };
 
-        let mut merged = defaults.clone();
-        for (k, v) in ann_obj {
+        // Theme palette < spec defaults < per-annotation fields.
+        let ann_type = annotation_type(ann_obj);
+        let ann_type = if ann_type.is_empty() {
+            annotation_type(&defaults)
+        } else {
+            ann_type
+        };
+        let mut merged = theme_palette(&theme, &ann_type).unwrap_or_default();
+        for (k, v) in defaults.iter().chain(ann_obj) {
             merged.insert(k.clone(), v.clone());
         }
+        if let Some(font) = value_to_string(merged.get("font")) {
+            if let Err(reason) = fonts.load(&font) {
+                match defaults.get("font") {
+                    Some(global) => merged.insert("font".to_string(), global.clone()),
+                    None => merged.remove("font"),
+                };
+                if unreadable_fonts.insert(font.clone()) {
+                    let fallback = if merged.contains_key("font") {
+                        "the global --font"
+                    } else {
+                        "the built-in bitmap font"
+                    };
+                    let warning = format!("font {font}: {reason}; using {fallback}");
+                    eprintln!("warning: {warning}");
+                    warnings.warn("font_unreadable", warning);
+                }
+            }
+        }
 
+        if let Some(warning) = assign_annotation_id(&mut merged, idx, &mut used_ids) {
+            eprintln!("warning: {warning}");
+            warnings.warn("duplicate_id", warning);
+        }
+        if relative_reference_key(&merged).is_some() {
+            // Resolved once every rect/spotlight anchor target is known.
+            deferred_relative.push((idx, merged));
+            continue;
+        }
         resolve_annotation_units(&mut merged, img_w, img_h, &defaults);
         let ann_type = annotation_type(&merged);
         if is_spotlight_type(&ann_type) {
@@ -664,6 +2423,14 @@ fn command_annotate(args: AnnotateArgs) -> Result<()> {
             prepared_others.push((idx, merged));
         }
     }
+    #[cfg(not(feature = "ttf"))]
+    if fonts.files.values().any(|font| font.is_ok()) {
+        let warning = "TrueType rendering is not built in (build with --features ttf); \
+                       text uses the built-in bitmap font"
+            .to_string();
+        eprintln!("warning: {warning}");
+        warnings.warn("font_unsupported", warning);
+    }
 
     let mut anchor_targets: Vec<AnchorTarget> = Vec::new();
     for (idx, ann) in &prepared_spotlights {
@@ -676,6 +2443,7 @@ fn command_annotate(args: AnnotateArgs) -> Result<()> {
                 index: *idx,
                 ann_type: "spotlight".to_string(),
                 bbox,
+                centroid: parse_offset_value(ann.get("centroid")),
             });
         }
     }
@@ -683,7 +2451,7 @@ fn command_annotate(args: AnnotateArgs) -> Result<()> {
     let mut prepared_render_list: Vec<(usize, Map<String, Value>)> = Vec::new();
     for (idx, mut ann) in prepared_others {
         let ann_type = annotation_type(&ann);
-        if ann_type == "rect" {
+        if ann_type == "rect" || is_ellipse_type(&ann_type) {
             ann = apply_fit(&ann, &fit_image, img_w, img_h, &defaults);
             if let Some(bbox) = bbox_from_ann(&ann) {
                 anchor_targets.push(AnchorTarget {
@@ -692,105 +2460,575 @@ fn command_annotate(args: AnnotateArgs) -> Result<()> {
                         .and_then(Value::as_str)
                         .map(ToString::to_string),
                     index: idx,
-                    ann_type: "rect".to_string(),
+                    ann_type: canonical_annotation_type(&ann_type),
                     bbox,
+                    centroid: parse_offset_value(ann.get("centroid")),
                 });
             }
         }
         prepared_render_list.push((idx, ann));
     }
 
-    let mut processed_meta: Vec<Value> = Vec::new();
+    for (idx, mut ann) in deferred_relative {
+        let key = relative_reference_key(&ann).unwrap_or(Value::Null);
+        match resolve_reference_bbox(&key, &anchor_targets) {
+            Some(frame) => resolve_relative_units(&mut ann, frame),
+            None => {
+                let warning =
+                    format!("annotation {idx}: reference {key} not found; using image coordinates");
+                eprintln!("warning: {warning}");
+                warnings.warn("reference_not_found", warning);
+                resolve_annotation_units(&mut ann, img_w, img_h, &defaults);
+            }
+        }
 
-    for (idx, ann) in &prepared_spotlights {
-        let scale = ann
-            .get("scale")
-            .and_then(Value::as_f64)
-            .unwrap_or(base_scale);
-        draw_spotlight_annotation(&mut rendered, ann, scale, &defaults);
-        processed_meta.push(annotation_meta_item(*idx, ann, img_w, img_h));
+        let ann_type = annotation_type(&ann);
+        if ann_type == "rect" || is_ellipse_type(&ann_type) || is_spotlight_type(&ann_type) {
+            ann = apply_fit(&ann, &fit_image, img_w, img_h, &defaults);
+            if let Some(bbox) = bbox_from_ann(&ann) {
+                anchor_targets.push(AnchorTarget {
+                    id: ann
+                        .get("id")
+                        .and_then(Value::as_str)
+                        .map(ToString::to_string),
+                    index: idx,
+                    ann_type: canonical_annotation_type(&ann_type),
+                    bbox,
+                    centroid: parse_offset_value(ann.get("centroid")),
+                });
+            }
+        }
+        if is_spotlight_type(&ann_type) {
+            prepared_spotlights.push((idx, ann));
+        } else {
+            prepared_render_list.push((idx, ann));
+        }
     }
+    prepared_spotlights.sort_by_key(|(idx, _)| *idx);
+    prepared_render_list.sort_by_key(|(idx, _)| *idx);
 
+    // Every annotation is resolved (so anchors still find filtered-out rects); the type filter
+    // only decides what gets drawn.
+    let mut resolved_queue: Vec<(usize, Map<String, Value>)> = prepared_spotlights;
     for (idx, ann) in prepared_render_list {
-        let ann_type = annotation_type(&ann);
-        let scale = ann
-            .get("scale")
-            .and_then(Value::as_f64)
-            .unwrap_or(base_scale);
-        let mut rendered_ann = ann.clone();
-
-        match ann_type.as_str() {
-            "rect" => draw_rect_annotation(&mut rendered, &rendered_ann, scale),
+        let resolved = match annotation_type(&ann).as_str() {
             "arrow" => {
-                rendered_ann =
-                    apply_arrow_anchor(&rendered_ann, &anchor_targets, &defaults, img_w, img_h);
-                draw_arrow_annotation(&mut rendered, &rendered_ann, scale);
-            }
-            "text" => {
-                rendered_ann =
-                    apply_text_anchor(&rendered_ann, &anchor_targets, &defaults, img_w, img_h);
-                draw_text_annotation(&mut rendered, &rendered_ann, scale);
+                let anchored = apply_arrow_anchor(&ann, &anchor_targets, &defaults, img_w, img_h);
+                apply_arrow_avoid(&anchored, &anchor_targets)
             }
-            _ => {}
-        }
-
-        processed_meta.push(annotation_meta_item(idx, &rendered_ann, img_w, img_h));
+            "text" => apply_text_anchor(&ann, &anchor_targets, &defaults, img_w, img_h),
+            _ => ann,
+        };
+        resolved_queue.push((idx, resolved));
+    }
+    let type_selected = |ann: &Map<String, Value>| {
+        let ann_type = canonical_annotation_type(&annotation_type(ann));
+        (only_types.is_empty() || only_types.contains(&ann_type)) && !skip_types.contains(&ann_type)
+    };
+    let render_queue: Vec<(usize, Map<String, Value>)> = resolved_queue
+        .iter()
+        .filter(|(_, ann)| type_selected(ann))
+        .cloned()
+        .collect();
+    let meta_source = if args.meta_all_types {
+        &resolved_queue
+    } else {
+        &render_queue
+    };
+
+    draw_ruler(&mut rendered, &defaults);
+    let unannotated =
+        (args.preview_grid.is_some() || args.layered_out.is_some()).then(|| rendered.clone());
+    render_annotations(&mut rendered, &render_queue, base_scale, &defaults, &fonts);
+
+    // Trim runs on the final image, so annotation marks count as content.
+    let trim = if args.trim {
+        trim_bounds(&rendered, args.trim_tolerance, args.trim_pad)
+    } else {
+        None
+    };
+    if let Some((tx, ty, tw, th)) = trim {
+        rendered = image::imageops::crop_imm(&rendered, tx, ty, tw, th).to_image();
+    }
+    let (out_w, out_h) = rendered.dimensions();
+    let meta_queue: Vec<(usize, Map<String, Value>)> = match trim {
+        Some((tx, ty, _, _)) => meta_source
+            .iter()
+            .map(|(idx, ann)| (*idx, offset_annotation(ann, -f64::from(tx), -f64::from(ty))))
+            .collect(),
+        None => meta_source.clone(),
+    };
+
+    if let Some(overlay_path) = args.overlay_only.as_deref() {
+        // Marks only, on transparency; spotlights leave just their dim layer.
+        let mut overlay = RgbaImage::new(img_w, img_h);
+        render_annotations(&mut overlay, &render_queue, base_scale, &defaults, &fonts);
+        if let Some((tx, ty, tw, th)) = trim {
+            overlay = image::imageops::crop_imm(&overlay, tx, ty, tw, th).to_image();
+        }
+        ensure_parent_dir(overlay_path)?;
+        save_png(
+            &DynamicImage::ImageRgba8(overlay),
+            overlay_path,
+            &args.png_compression,
+            output_opts.verify_output,
+        )
+        .with_context(|| format!("failed to save overlay image: {}", overlay_path.display()))?;
     }
 
+    let mut processed_meta: Vec<Value> = meta_queue
+        .iter()
+        .map(|(idx, ann)| {
+            let mut item = annotation_meta_item(*idx, ann, out_w, out_h);
+            if !type_selected(ann) {
+                if let Some(obj) = item.as_object_mut() {
+                    obj.insert("rendered".to_string(), json!(false));
+                }
+            }
+            item
+        })
+        .collect();
     processed_meta.sort_by_key(|item| item.get("index").and_then(Value::as_u64).unwrap_or(0));
+    let probe = args.probe.then(|| {
+        let anchors: std::collections::BTreeMap<usize, &Value> = meta_queue
+            .iter()
+            .filter_map(|(idx, ann)| ann.get("anchored_to").map(|a| (*idx, a)))
+            .collect();
+        processed_meta
+            .iter()
+            .map(|item| probe_item(item, &anchors))
+            .collect::<Vec<_>>()
+    });
+
+    let cb_sim_path = match args.cb_sim.as_deref() {
+        Some(kind) => Some(write_cb_sim(
+            &rendered,
+            &output,
+            kind,
+            &args.png_compression,
+            output_opts.verify_output,
+        )?),
+        None => None,
+    };
+    ensure_parent_dir(&output)?;
+    save_png(
+        &DynamicImage::ImageRgba8(rendered),
+        &output,
+        &args.png_compression,
+        output_opts.verify_output,
+    )
+    .with_context(|| format!("failed to save output image: {}", output.display()))?;
+
+    if let (Some(layered_path), Some(base)) = (args.layered_out.as_deref(), unannotated.as_ref()) {
+        let mut layers = vec![("backdrop".to_string(), base.clone())];
+        layers.extend(annotation_layers(
+            base,
+            &render_queue,
+            base_scale,
+            &defaults,
+            &fonts,
+            args.layered_by_type,
+        ));
+        if let Some((tx, ty, tw, th)) = trim {
+            for (_, layer) in layers.iter_mut() {
+                *layer = image::imageops::crop_imm(layer, tx, ty, tw, th).to_image();
+            }
+        }
+        write_layered_tiff(layered_path, &layers, output_opts.verify_output)?;
+    }
 
-    ensure_parent_dir(&args.output)?;
-    DynamicImage::ImageRgba8(rendered)
-        .save(&args.output)
-        .with_context(|| format!("failed to save output image: {}", args.output.display()))?;
+    if let (Some(grid_path), Some(base)) = (args.preview_grid.as_deref(), unannotated.as_ref()) {
+        let sheet = build_preview_grid(base, &render_queue, base_scale, &defaults, &fonts);
+        ensure_parent_dir(grid_path)?;
+        save_png(
+            &DynamicImage::ImageRgba8(sheet),
+            grid_path,
+            &args.png_compression,
+            output_opts.verify_output,
+        )
+        .with_context(|| format!("failed to save preview grid: {}", grid_path.display()))?;
+    }
 
-    if !args.no_meta {
-        let meta_path = args
-            .meta_out
+    let meta_path = (!args.no_meta).then(|| {
+        args.meta_out
             .clone()
-            .unwrap_or_else(|| default_sidecar_for(&args.output));
-        ensure_parent_dir(&meta_path)?;
-
-        let payload = json!({
+            .unwrap_or_else(|| default_sidecar_for(&output))
+    });
+    let path_str = |p: Option<&Path>| p.map(|p| abs_path(p).display().to_string());
+    // --bundle-out embeds the sidecar payload, so it is built even under --no-meta.
+    let meta_payload = (meta_path.is_some() || args.bundle_out.is_some()).then(|| {
+        json!({
             "annotation_meta_version": 1,
             "input_path": abs_path(&args.input).display().to_string(),
-            "output_path": abs_path(&args.output).display().to_string(),
-            "meta_path": abs_path(&meta_path).display().to_string(),
+            "output_path": abs_path(&output).display().to_string(),
+            "meta_path": path_str(meta_path.as_deref()),
             "generated_at": timestamp_iso(),
-            "size": {"width": img_w, "height": img_h, "units": "px"},
+            "size": {"width": out_w, "height": out_h, "units": "px"},
+            "preprocess": preprocess_meta,
+            "flatten_bg": flatten_bg.map(color_hex),
+            "capture_sidecar": capture_sidecar.as_deref().map(|path| abs_path(path).display().to_string()),
+            "trim": trim.map(|(x, y, w, h)| json!({
+                "x": x, "y": y, "w": w, "h": h,
+                "source_size": {"width": img_w, "height": img_h},
+            })),
             "defaults": Value::Object(defaults),
-            "annotations": processed_meta,
-        });
+            "type_filter": (!only_types.is_empty() || !skip_types.is_empty()).then(|| json!({
+                "only": only_types,
+                "skip": skip_types,
+                "rendered_count": render_queue.len(),
+                "total_count": resolved_queue.len(),
+            })),
+            "annotations": &processed_meta,
+            "warnings": warnings.to_value(output_opts),
+        })
+    });
+    if let (Some(meta_path), Some(payload)) = (meta_path.as_deref(), meta_payload.as_ref()) {
+        ensure_parent_dir(meta_path)?;
+        write_json_pretty(meta_path, payload)?;
+    }
+
+    if let (Some(bundle_path), Some(payload)) = (args.bundle_out.as_deref(), meta_payload) {
+        let encoded = fs::read(&output)
+            .with_context(|| format!("failed to read output image: {}", output.display()))?;
+        // Non-PNG outputs are written by their extension's encoder, so name that format.
+        let format = image::ImageFormat::from_path(&output)
+            .ok()
+            .and_then(|format| format.extensions_str().first().copied())
+            .unwrap_or("png");
+        write_json_pretty(
+            bundle_path,
+            &json!({
+                "image_base64": base64_encode(&encoded),
+                "format": format,
+                "meta": payload,
+            }),
+        )?;
+    }
+
+    if let Some(csv_path) = args.meta_csv.as_deref() {
+        write_text_file(
+            csv_path,
+            &annotation_meta_csv(&processed_meta, output_opts.locale),
+        )?;
+    }
+
+    if let Some(actions_path) = args.actions_out.as_deref() {
+        write_json_pretty(
+            actions_path,
+            &actions_manifest("annotate", &output, (out_w, out_h), &processed_meta),
+        )?;
+    }
+
+    if let Some((format, path)) = &export {
+        let mut ordered = meta_queue.clone();
+        ordered.sort_by_key(|(idx, _)| *idx);
+        let scene = if format == "figma" {
+            figma_scene(&ordered, out_w, out_h, base_scale, &fonts)
+        } else {
+            excalidraw_scene(&ordered, base_scale, &fonts)
+        };
+        write_json_pretty(path, &scene)?;
+    }
+
+    warnings.note_hidden(output_opts);
+    Ok(json!({
+            "output_path": abs_path(&output).display().to_string(),
+            "meta_path": path_str(meta_path.as_deref()),
+            "meta_csv_path": path_str(args.meta_csv.as_deref()),
+            "preview_grid_path": path_str(args.preview_grid.as_deref()),
+            "overlay_path": path_str(args.overlay_only.as_deref()),
+            "layered_path": path_str(args.layered_out.as_deref()),
+            "bundle_path": path_str(args.bundle_out.as_deref()),
+            "cb_sim_path": cb_sim_path,
+            "actions_path": path_str(args.actions_out.as_deref()),
+            "export_path": path_str(export.as_ref().map(|(_, p)| p.as_path())),
+            "size": {"width": out_w, "height": out_h, "units": "px"},
+            "annotation_count": processed_meta.len(),
+            "probe": probe,
+            "warnings": warnings.to_value(output_opts),
+    }))
+}
+
+// Standard padded base64 (RFC 4648) for --bundle-out.
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 63]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Explicit file paths are used as given. Without an output, or when it names an existing
+// directory, write <input-stem>.annotated.png there (next to the input by default),
+// adding -2, -3, ... until the name is free.
+fn annotate_output_path(input: &Path, output: Option<&Path>) -> PathBuf {
+    let dir = match output {
+        Some(path) if !path.is_dir() => return path.to_path_buf(),
+        Some(dir) => dir.to_path_buf(),
+        None => input
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(".")),
+    };
+    let stem = input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+    let mut candidate = dir.join(format!("{stem}.annotated.png"));
+    let mut counter = 2;
+    while candidate.exists() {
+        candidate = dir.join(format!("{stem}.annotated-{counter}.png"));
+        counter += 1;
+    }
+    candidate
+}
 
-        write_json_pretty(&meta_path, &payload)?;
+fn command_diff(args: DiffArgs, output_opts: &OutputOptions) -> Result<()> {
+    let output = diff_from_args(&args, output_opts)?;
+    println!("{}", serde_json::to_string(&output.json)?);
+    if args.exit_on_identical && output.json["identical"] == json!(true) {
+        return Err(cli_failure(EXIT_IDENTICAL, "images are identical"));
     }
+    Ok(())
+}
 
-    println!("{}", abs_path(&args.output).display());
+fn command_diff_dir(args: DiffDirArgs, output_opts: &OutputOptions) -> Result<()> {
+    let out_dir = args
+        .out_dir
+        .clone()
+        .unwrap_or_else(|| out_root().join("diff-dir").join(timestamp_compact()));
+    let payload = diff_dir_internal(&args, &out_dir, output_opts)?;
+    write_json_pretty(&out_dir.join("summary.json"), &payload)?;
+    println!("{}", serde_json::to_string(&payload)?);
     Ok(())
 }
 
-fn command_diff(args: DiffArgs) -> Result<()> {
-    let output = run_diff_internal(
+// Pairs images by filename, diffs each pair into reports/<name>.json and aggregates the results.
+// A pair that fails to diff (e.g. size mismatch without --resize) is recorded, not fatal.
+fn diff_dir_internal(
+    args: &DiffDirArgs,
+    out_dir: &Path,
+    output_opts: &OutputOptions,
+) -> Result<Value> {
+    let baselines = list_image_files(&args.baseline_dir)?;
+    let currents = list_image_files(&args.current_dir)?;
+    let reports_dir = out_dir.join("reports");
+    fs::create_dir_all(&reports_dir)
+        .with_context(|| format!("failed to create reports dir: {}", reports_dir.display()))?;
+
+    let options = DiffOptions {
+        resize: args.resize,
+        bbox_threshold: args.bbox_threshold,
+        bbox_min_area: args.bbox_min_area,
+        verify_output: output_opts.verify_output,
+        ..DiffOptions::default()
+    };
+    let mut pairs: Vec<Value> = Vec::new();
+    let mut percents: Vec<f64> = Vec::new();
+    let mut total_changed_pixels: u64 = 0;
+    let (mut changed, mut identical, mut errors) = (0usize, 0usize, 0usize);
+    for (name, baseline) in &baselines {
+        let Some(current) = currents.get(name) else {
+            continue;
+        };
+        let report = reports_dir.join(format!("{name}.json"));
+        let annotated = args
+            .annotated
+            .then(|| reports_dir.join(format!("{name}-annotated.png")));
+        match run_diff_internal(
+            baseline,
+            current,
+            None,
+            Some(&report),
+            annotated.as_deref(),
+            None,
+            &options,
+        ) {
+            Ok(diff) => {
+                let percent = diff.json["percent_changed"].as_f64().unwrap_or(0.0);
+                let is_identical = diff.json["identical"] == json!(true);
+                percents.push(percent);
+                total_changed_pixels += diff.json["changed_pixels"].as_u64().unwrap_or(0);
+                if is_identical {
+                    identical += 1;
+                } else {
+                    changed += 1;
+                }
+                pairs.push(json!({
+                    "name": name,
+                    "status": if is_identical { "identical" } else { "changed" },
+                    "percent_changed": percent,
+                    "changed_pixels": diff.json["changed_pixels"],
+                    "change_region_count": diff.json["change_region_count"],
+                    "report": abs_path(&report).display().to_string(),
+                    "annotated": annotated.filter(|_| !is_identical).map(|p| abs_path(&p).display().to_string()),
+                }));
+            }
+            Err(err) => {
+                errors += 1;
+                pairs.push(json!({
+                    "name": name,
+                    "status": "error",
+                    "error": format!("{err:#}"),
+                }));
+            }
+        }
+    }
+
+    let mut worst: Vec<&Value> = pairs
+        .iter()
+        .filter(|pair| pair["status"] == json!("changed"))
+        .collect();
+    worst.sort_by(|a, b| {
+        let pa = a["percent_changed"].as_f64().unwrap_or(0.0);
+        let pb = b["percent_changed"].as_f64().unwrap_or(0.0);
+        pb.total_cmp(&pa)
+    });
+    let worst: Vec<Value> = worst
+        .into_iter()
+        .take(args.top)
+        .map(|pair| {
+            json!({
+                "name": pair["name"],
+                "percent_changed": pair["percent_changed"],
+                "change_region_count": pair["change_region_count"],
+            })
+        })
+        .collect();
+    let only_in = |from: &std::collections::BTreeMap<String, PathBuf>,
+                   other: &std::collections::BTreeMap<String, PathBuf>| {
+        from.keys()
+            .filter(|name| !other.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    Ok(json!({
+        "baseline_dir": abs_path(&args.baseline_dir).display().to_string(),
+        "current_dir": abs_path(&args.current_dir).display().to_string(),
+        "out_dir": abs_path(out_dir).display().to_string(),
+        "pair_count": pairs.len(),
+        "changed_count": changed,
+        "identical_count": identical,
+        "error_count": errors,
+        "total_changed_pixels": total_changed_pixels,
+        "percent_changed": percent_changed_stats(&percents),
+        "worst": worst,
+        "only_in_baseline": only_in(&baselines, &currents),
+        "only_in_current": only_in(&currents, &baselines),
+        "pairs": pairs,
+    }))
+}
+
+fn list_image_files(dir: &Path) -> Result<std::collections::BTreeMap<String, PathBuf>> {
+    let entries = fs::read_dir(dir).map_err(|err| {
+        cli_failure(
+            EXIT_INPUT,
+            format!("cannot read directory {}: {err}", dir.display()),
+        )
+    })?;
+    let mut files = std::collections::BTreeMap::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_image = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+            .unwrap_or(false);
+        if !path.is_file() || !is_image {
+            continue;
+        }
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            files.insert(name.to_string(), path.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn diff_from_args(args: &DiffArgs, output_opts: &OutputOptions) -> Result<DiffRunOutput> {
+    let options = DiffOptions {
+        verify_output: output_opts.verify_output,
+        resize: args.resize,
+        bbox_threshold: args.bbox_threshold,
+        bbox_min_area: args.bbox_min_area,
+        min_area_percent: args.compare_ignore_below_area_percent,
+        bbox_pad: args.bbox_pad,
+        max_boxes: args.max_boxes,
+        overlay_threshold: args.overlay_threshold,
+        overlay_transparent: args.overlay_transparent,
+        orient_auto: args.orient == "auto",
+        ignore_aa: args.ignore_aa,
+        colorspace: args.colorspace.clone(),
+        shape_mode: args.mode == "shape",
+        shape_channel: args.shape_channel.clone(),
+        shape_threshold: args.shape_threshold,
+        mask: args.mask.clone(),
+        weight_map: args.weight_map.clone(),
+        png_compression: args.png_compression.clone(),
+        max_total_pixels: args.max_total_pixels,
+        downscale_oversize: args.downscale_oversize,
+        histogram: args.compare_subpixel_report,
+        ignore_identical_rows: args.ignore_identical_rows,
+        label_position: args.region_label_position.clone(),
+        region_colors: args.region_colors.clone(),
+        regions_only: args.regions_only,
+        fail_fast: args.fail_fast,
+        regions_collage: args.regions_collage.clone(),
+        actions_out: args.actions_out.clone(),
+        tiles: parse_tile_grid(args.tiles.as_deref())?,
+        tiles_heatmap: args.tiles_heatmap.clone(),
+        cb_sim: args.cb_sim.clone(),
+        timings: args.timings,
+        resize_filter: args.resize_filter.clone(),
+        current_coords: args.current_coords,
+        flatten_bg: parse_flatten_bg(args.flatten_bg.as_deref())?,
+    };
+    run_diff_internal(
         &args.baseline,
         &args.current,
         args.diff_out.as_deref(),
         args.json_out.as_deref(),
-        args.resize,
-        args.bbox_threshold,
-        args.bbox_min_area,
-        args.bbox_pad,
-        args.max_boxes,
         args.annotated_out.as_deref(),
         args.annotate_spec_out.as_deref(),
-    )?;
+        &options,
+    )
+}
 
-    println!("{}", serde_json::to_string(&output.json)?);
-    Ok(())
+// With --process the lone positional is the baseline name, not an image path.
+fn loop_inputs(args: &LoopArgs) -> Result<(Option<PathBuf>, String)> {
+    match (&args.process, &args.current_path, &args.baseline_name) {
+        (Some(_), Some(_), Some(_)) => bail!("pass either <current_path> or --process, not both"),
+        (Some(_), Some(name), None) => Ok((None, name.display().to_string())),
+        (Some(_), None, Some(name)) => Ok((None, name.clone())),
+        (None, Some(path), Some(name)) => Ok((Some(path.clone()), name.clone())),
+        _ => bail!(
+            "usage: loop <current_path> <baseline_name> | loop --process <app> <baseline_name>"
+        ),
+    }
 }
 
-fn command_loop(args: LoopArgs) -> Result<()> {
-    if !args.current_path.exists() {
-        bail!("current image not found: {}", args.current_path.display());
+fn command_loop(args: LoopArgs, output_opts: &OutputOptions) -> Result<()> {
+    let (current_arg, baseline_name) = loop_inputs(&args)?;
+    if let Some(path) = &current_arg {
+        if !path.exists() {
+            return Err(cli_failure(
+                EXIT_INPUT,
+                format!("current image not found: {}", path.display()),
+            ));
+        }
     }
 
     let out_root = out_root();
@@ -811,7 +3049,7 @@ fn command_loop(args: LoopArgs) -> Result<()> {
         loop_dir = out_root;
     }
 
-    let safe_name = sanitize_baseline_name(&args.baseline_name);
+    let safe_name = sanitize_baseline_name(&baseline_name);
     let ts = timestamp_compact();
 
     let base_baselines = loop_dir.join("baselines");
@@ -820,6 +3058,7 @@ fn command_loop(args: LoopArgs) -> Result<()> {
     let base_diffs = loop_dir.join("diffs");
     let base_reports = loop_dir.join("reports");
     let base_annotations = loop_dir.join("annotations");
+    let base_captures = loop_dir.join("captures");
 
     for dir in [
         &base_baselines,
@@ -841,31 +3080,91 @@ fn command_loop(args: LoopArgs) -> Result<()> {
     let annotated_path = base_annotations.join(format!("{safe_name}-{ts}.png"));
     let annotate_spec_path = base_reports.join(format!("{safe_name}-{ts}-change-spec.json"));
 
-    copy_file(&args.current_path, &latest_path)?;
-    copy_file(&args.current_path, &history_path)?;
+    // Checked before any capture or copy, so a refused run writes no images.
+    let baseline_exists = baseline_path.exists();
+    if args.explicit_baseline && !baseline_exists && !args.reset_baseline {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!(
+                "no baseline {baseline_name:?} at {} and --explicit-baseline is set; seed it deliberately with --reset-baseline",
+                baseline_path.display()
+            ),
+        ));
+    }
 
-    if !baseline_path.exists() {
-        copy_file(&args.current_path, &baseline_path)?;
-        let payload = json!({
-            "baseline_created": abs_path(&baseline_path).display().to_string(),
+    let (current_path, capture) = match current_arg {
+        Some(path) => (path, None),
+        None => {
+            let capture_path = base_captures.join(format!("{safe_name}-{ts}.png"));
+            let payload = capture_internal(
+                &capture_path,
+                args.process.clone(),
+                Some("loop"),
+                Some(&baseline_name),
+                Some(&default_sidecar_for(&capture_path)),
+                &CaptureOptions {
+                    output: output_opts.clone(),
+                    ..CaptureOptions::default()
+                },
+            )?;
+            (capture_path, Some(payload))
+        }
+    };
+    // A placeholder frame never becomes (or replaces) the baseline; comparisons still run.
+    let placeholder = capture
+        .as_ref()
+        .is_some_and(|payload| value_to_bool(&payload["fallback_used"], false));
+    if placeholder && (args.reset_baseline || !baseline_exists) {
+        return Err(cli_failure(
+            EXIT_CAPTURE,
+            format!(
+                "loop capture fell back to placeholder output; refusing to seed baseline {baseline_name:?} from it. Check Screen Recording/Accessibility permissions or retry with a visible app window."
+            ),
+        ));
+    }
+
+    copy_file(&current_path, &latest_path)?;
+    copy_file(&current_path, &history_path)?;
+
+    if args.reset_baseline || !baseline_exists {
+        copy_file(&current_path, &baseline_path)?;
+        let (key, status) = if baseline_exists {
+            ("baseline_reset", "BASELINE_RESET")
+        } else {
+            ("baseline_created", "BASELINE_CREATED")
+        };
+        let mut payload = json!({
+            key: abs_path(&baseline_path).display().to_string(),
             "latest": abs_path(&latest_path).display().to_string(),
             "history": abs_path(&history_path).display().to_string(),
         });
+        if let (Some(capture), Some(obj)) = (capture, payload.as_object_mut()) {
+            obj.insert("capture".to_string(), capture);
+        }
         println!("{}", serde_json::to_string(&payload)?);
+        if args.ci_summary || args.ci_github {
+            eprintln!("CVLP baseline={safe_name} status={status}");
+        }
         return Ok(());
     }
 
     let emit_annotated = !args.no_annotated;
-    let diff_output = run_diff_internal(
+    let options = DiffOptions {
+        verify_output: output_opts.verify_output,
+        resize: args.resize,
+        bbox_threshold: args.bbox_threshold,
+        bbox_min_area: args.bbox_min_area,
+        bbox_pad: args.bbox_pad,
+        max_boxes: args.max_boxes,
+        region_colors: args.region_colors.clone(),
+        label_position: args.region_label_position.clone(),
+        ..DiffOptions::default()
+    };
+    let mut diff_output = run_diff_internal(
         &baseline_path,
-        &args.current_path,
+        &current_path,
         Some(&diff_path),
         Some(&json_path),
-        args.resize,
-        args.bbox_threshold,
-        args.bbox_min_area,
-        args.bbox_pad,
-        args.max_boxes,
         if emit_annotated {
             Some(&annotated_path)
         } else {
@@ -876,490 +3175,834 @@ fn command_loop(args: LoopArgs) -> Result<()> {
         } else {
             None
         },
+        &options,
     )?;
 
-    if args.update_baseline {
-        copy_file(&args.current_path, &baseline_path)?;
+    let percent_changed = diff_output
+        .json
+        .get("percent_changed")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+    let auto_updated = !placeholder
+        && !args.update_baseline
+        && should_auto_update_baseline(percent_changed, args.auto_update_below);
+    if (args.update_baseline && !placeholder) || auto_updated {
+        copy_file(&current_path, &baseline_path)?;
+    }
+    if let Some(obj) = diff_output.json.as_object_mut() {
+        if let Some(capture) = capture {
+            obj.insert("capture".to_string(), capture);
+            obj.insert("capture_placeholder".to_string(), json!(placeholder));
+        }
+        if let Some(threshold) = args.auto_update_below {
+            obj.insert("auto_update_below".to_string(), json!(threshold));
+            obj.insert("baseline_auto_updated".to_string(), json!(auto_updated));
+        }
+    }
+    if args.ci_summary || args.ci_github {
+        for line in loop_ci_lines(&safe_name, &diff_output.json, args.ci_github) {
+            eprintln!("{line}");
+        }
     }
 
     println!("{}", serde_json::to_string(&diff_output.json)?);
     Ok(())
 }
 
-fn command_observe(args: ObserveArgs) -> Result<()> {
-    let process = args
-        .process
-        .clone()
-        .or_else(frontmost_app_name)
-        .unwrap_or_else(|| "app".to_string());
+// Drift strictly below the threshold refreshes the baseline; anything at or above it is kept for review.
+fn should_auto_update_baseline(percent_changed: f64, threshold: Option<f64>) -> bool {
+    threshold.is_some_and(|limit| percent_changed < limit)
+}
 
-    let out_root = out_root();
-    let out_dir = args
-        .out_dir
-        .clone()
-        .unwrap_or_else(|| out_root.join("observe"));
-    fs::create_dir_all(&out_dir)
-        .with_context(|| format!("failed to create observe dir: {}", out_dir.display()))?;
+fn loop_ci_lines(baseline: &str, diff: &Value, github: bool) -> Vec<String> {
+    let changed = diff
+        .get("percent_changed")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+    let regions = diff
+        .get("change_region_count")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let status = if regions > 0 { "CHANGED" } else { "UNCHANGED" };
+
+    let mut lines = vec![format!(
+        "CVLP baseline={baseline} changed={changed}% regions={regions} status={status}"
+    )];
+    if github && regions > 0 {
+        lines.push(format!(
+            "::warning title=CVLP {baseline}::{changed}% of pixels changed across {regions} region(s)"
+        ));
+    }
+    lines
+}
 
-    let slug = slugify(&process);
-    let run_id = format!(
-        "{}-{}-{}",
-        timestamp_compact(),
-        std::process::id(),
-        rand::thread_rng().gen_range(1000..9999)
-    );
+fn command_display_info(args: DisplayInfoArgs, output_opts: &OutputOptions) -> Result<()> {
+    let mut warnings = Warnings::default();
+    let displays = if cfg!(target_os = "macos") {
+        let (displays, diag) = query_displays();
+        warnings.push_diagnostic(
+            "display_query_failed",
+            "display_query",
+            "display query failed",
+            &diag,
+        );
+        displays
+    } else {
+        warnings.info(
+            "unsupported_platform",
+            "display enumeration requires macOS AppKit",
+        );
+        Vec::new()
+    };
 
-    let before_png = out_dir.join(format!("before-{slug}-{run_id}.png"));
-    let after_png = out_dir.join(format!("after-{slug}-{run_id}.png"));
-    let video_path = out_dir.join(format!("action-clip-{slug}-{run_id}.mov"));
-    let action_log = out_dir.join(format!("action-{slug}-{run_id}.log"));
-    let compare_json_path = out_dir.join(format!("compare-{slug}-{run_id}.json"));
-    let diff_path = out_dir.join(format!("diff-{slug}-{run_id}.png"));
-    let annotated_diff_path = out_dir.join(format!("diff-annotated-{slug}-{run_id}.png"));
-    let annotate_spec_path = out_dir.join(format!("diff-annotate-spec-{slug}-{run_id}.json"));
-    let report_path = out_dir.join(format!("observe-{slug}-{run_id}.json"));
+    let window = args.process.as_deref().map(|process| {
+        let probe = query_window_probe(process, default_window_thresholds(), &output_opts.scripts);
+        warnings.push_diagnostic(
+            "window_query_failed",
+            "window_query",
+            "window query failed",
+            &probe.diagnostics,
+        );
+        let display = if probe.w > 0 && probe.h > 0 {
+            display_for_window(&displays, (probe.x, probe.y, probe.w, probe.h))
+        } else {
+            None
+        };
+        // What a window capture's scale_x/scale_y should come out as.
+        json!({
+            "process": process,
+            "title": probe.title,
+            "bounds": {"x": probe.x, "y": probe.y, "w": probe.w, "h": probe.h},
+            "display_index": display.map(|d| d.index),
+            "expected_scale": display.map(|d| d.scale),
+            "expected_pixel_size": display.map(|d| json!({
+                "width": (probe.w as f64 * d.scale).round() as i64,
+                "height": (probe.h as f64 * d.scale).round() as i64,
+            })),
+        })
+    });
 
-    let before_payload = capture_internal(
-        &before_png,
-        Some(process.clone()),
-        Some("before"),
-        Some(&args.action),
-        Some(&default_sidecar_for(&before_png)),
-    )?;
+    warnings.note_hidden(output_opts);
+    let payload = json!({
+        "displays": displays.iter().map(display_info_json).collect::<Vec<_>>(),
+        "window": window,
+        "warnings": warnings.to_value(output_opts),
+    });
+    println!("{}", serde_json::to_string(&payload)?);
+    Ok(())
+}
 
-    let action_started = timestamp_iso();
-    let action_status = if let Some(cmd) = args.action_cmd.as_deref() {
-        let output = Command::new("bash")
-            .arg("-lc")
-            .arg(cmd)
-            .output()
-            .with_context(|| format!("failed to run action command: {cmd}"))?;
-        let mut file = File::create(&action_log)
-            .with_context(|| format!("failed to write action log: {}", action_log.display()))?;
-        file.write_all(&output.stdout)?;
-        file.write_all(&output.stderr)?;
-        output.status.code().unwrap_or(1)
-    } else {
-        File::create(&action_log)
-            .with_context(|| format!("failed to create action log: {}", action_log.display()))?;
-        0
-    };
-    let action_finished = timestamp_iso();
+fn display_info_json(display: &DisplayInfo) -> Value {
+    json!({
+        "index": display.index,
+        "main": display.index == 0,
+        "points": {"x": display.x, "y": display.y, "width": display.w, "height": display.h},
+        "pixels": {
+            "width": (display.w as f64 * display.scale).round() as i64,
+            "height": (display.h as f64 * display.scale).round() as i64,
+        },
+        "scale": display.scale,
+    })
+}
 
-    let mut clip_file = File::create(&video_path).with_context(|| {
-        format!(
-            "failed to create clip placeholder: {}",
-            video_path.display()
-        )
-    })?;
-    clip_file.write_all(b"codex-visual-loop placeholder clip\n")?;
+fn command_phash(args: PhashArgs) -> Result<()> {
+    let hash_of = |path: &Path, label: &str| -> Result<(Vec<bool>, (u32, u32))> {
+        // Hashes only need a coarse view, so oversized inputs are downscaled rather than refused.
+        let (image, _) = open_image_within_budget(path, label, DEFAULT_MAX_TOTAL_PIXELS, true)?;
+        let hash = match args.compare_mode.as_str() {
+            "average" => average_hash(&image, args.hash_size),
+            _ => block_hash(&image, args.hash_size),
+        };
+        Ok((hash, image.dimensions()))
+    };
+    let (baseline_hash, baseline_size) = hash_of(&args.baseline, "baseline")?;
+    let (current_hash, current_size) = hash_of(&args.current, "current")?;
 
-    if args.duration > 0 {
-        thread::sleep(Duration::from_secs(args.duration.min(30)));
+    let bits = baseline_hash.len() as u32;
+    let distance = baseline_hash
+        .iter()
+        .zip(&current_hash)
+        .filter(|(a, b)| a != b)
+        .count() as u32;
+    let max_distance = args.max_distance.unwrap_or(bits / 10);
+    let payload = json!({
+        "baseline": abs_path(&args.baseline).display().to_string(),
+        "current": abs_path(&args.current).display().to_string(),
+        "baseline_size": {"width": baseline_size.0, "height": baseline_size.1},
+        "current_size": {"width": current_size.0, "height": current_size.1},
+        "compare_mode": args.compare_mode,
+        "hash_size": args.hash_size,
+        "bits": bits,
+        "baseline_hash": hash_hex(&baseline_hash),
+        "current_hash": hash_hex(&current_hash),
+        "distance": distance,
+        "similarity": round_to(1.0 - f64::from(distance) / f64::from(bits.max(1)), 4),
+        "max_distance": max_distance,
+        "same_screen": distance <= max_distance,
+    });
+    if let Some(path) = args.json_out.as_deref() {
+        write_json_pretty(path, &payload)?;
     }
+    println!("{}", serde_json::to_string(&payload)?);
+    Ok(())
+}
 
-    let after_payload = capture_internal(
-        &after_png,
-        Some(process.clone()),
-        Some("after"),
-        Some(&args.action),
-        Some(&default_sidecar_for(&after_png)),
-    )?;
+// aHash: luma downscaled to size×size, one bit per cell brighter than the mean.
+fn average_hash(image: &DynamicImage, size: u32) -> Vec<bool> {
+    let small = image
+        .resize_exact(size, size, FilterType::Triangle)
+        .to_luma8();
+    let mean = small.pixels().map(|p| f64::from(p[0])).sum::<f64>() / f64::from(size * size);
+    small.pixels().map(|p| f64::from(p[0]) > mean).collect()
+}
 
-    let diff_output = run_diff_internal(
-        &before_png,
-        &after_png,
-        Some(&diff_path),
-        Some(&compare_json_path),
-        true,
-        24,
-        16,
-        2,
-        16,
-        Some(&annotated_diff_path),
-        Some(&annotate_spec_path),
-    )?;
-
-    let clip_payload = json!({
-        "video_path": abs_path(&video_path).display().to_string(),
-        "duration_sec": args.duration,
-        "summary_mode": args.summary_mode,
-        "summary_max": args.summary_max,
-        "summary_enabled": !args.no_summary,
-        "summary_sheet": args.summary_sheet,
-        "summary_gif": args.summary_gif,
-    });
-
-    let payload = json!({
-        "run_id": run_id,
-        "process_name": process,
-        "action": {
-            "label": args.action,
-            "command": args.action_cmd,
-            "status": action_status,
-            "started_at": action_started,
-            "finished_at": action_finished,
-            "log_path": abs_path(&action_log).display().to_string(),
-        },
-        "before_capture": before_payload,
-        "after_capture": after_payload,
-        "clip": clip_payload,
-        "diff": diff_output.json,
-    });
-
-    write_json_pretty(&report_path, &payload)?;
-
-    if args.json {
-        println!("{}", serde_json::to_string_pretty(&payload)?);
-    } else {
-        println!("{}", abs_path(&report_path).display());
-        println!("{}", abs_path(&before_png).display());
-        println!("{}", abs_path(&after_png).display());
-        println!("{}", abs_path(&video_path).display());
-        println!("{}", abs_path(&annotated_diff_path).display());
+// Blockhash (Yang et al.): R+G+B summed over a size×size grid of blocks, with fractional
+// weights where pixels straddle block edges (transparent counts as white). Each block is
+// compared against the median of its quarter of the hash, so bits stay balanced per band.
+fn block_hash(image: &DynamicImage, size: u32) -> Vec<bool> {
+    let rgba = image.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let blocks = size as usize;
+    let axis_weights = |len: u32| -> Vec<Vec<(usize, f64)>> {
+        let per_pixel = blocks as f64 / f64::from(len.max(1));
+        (0..len)
+            .map(|i| {
+                let lo = f64::from(i) * per_pixel;
+                let hi = lo + per_pixel;
+                let first = lo.floor() as usize;
+                let last = (hi.ceil() as usize).min(blocks);
+                (first..last)
+                    .map(|b| (b, hi.min(b as f64 + 1.0) - lo.max(b as f64)))
+                    .filter(|(_, weight)| *weight > 0.0)
+                    .collect()
+            })
+            .collect()
+    };
+    let (x_weights, y_weights) = (axis_weights(w), axis_weights(h));
+    let mut sums = vec![0.0f64; blocks * blocks];
+    for (x, y, px) in rgba.enumerate_pixels() {
+        let value = if px[3] == 0 {
+            765.0
+        } else {
+            f64::from(px[0]) + f64::from(px[1]) + f64::from(px[2])
+        };
+        for &(by, wy) in &y_weights[y as usize] {
+            for &(bx, wx) in &x_weights[x as usize] {
+                sums[by * blocks + bx] += value * wx * wy;
+            }
+        }
     }
 
-    Ok(())
+    let half_block = 765.0 * f64::from(w) * f64::from(h) / (blocks * blocks) as f64 / 2.0;
+    let band_len = (sums.len() / 4).max(1);
+    sums.chunks(band_len)
+        .flat_map(|band| {
+            let mut sorted = band.to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let mid = sorted.len() / 2;
+            let median = if sorted.len() % 2 == 0 {
+                (sorted[mid - 1] + sorted[mid]) / 2.0
+            } else {
+                sorted[mid]
+            };
+            band.iter()
+                .map(move |v| *v > median || ((v - median).abs() < 1.0 && median > half_block))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Bits row-major, most significant first, as lowercase hex (size² bits is always a multiple of 4).
+fn hash_hex(bits: &[bool]) -> String {
+    bits.chunks(4)
+        .map(|nibble| {
+            let v = nibble
+                .iter()
+                .fold(0u32, |acc, bit| (acc << 1) | u32::from(*bit));
+            char::from_digit(v, 16).unwrap_or('0')
+        })
+        .collect()
 }
 
-fn command_ax_tree(args: AxTreeArgs) -> Result<()> {
+fn command_stability(args: StabilityArgs, output_opts: &OutputOptions) -> Result<()> {
     let process = args
         .process
         .clone()
+        .or_else(env_process_name)
         .or_else(frontmost_app_name)
         .unwrap_or_else(|| "app".to_string());
-
     let slug = slugify(&process);
-    let ts = timestamp_compact();
-    let out_root = out_root();
-    let out = args.out.clone().unwrap_or_else(|| {
-        out_root.join("ax").join(format!(
-            "ax-tree-{slug}-{ts}-{}-{}.json",
-            std::process::id(),
-            rand::thread_rng().gen_range(1000..9999)
-        ))
-    });
-    let ax = query_ax_tree(&process, args.depth.max(1));
-
-    let payload = json!({
-        "captured_at": timestamp_iso(),
-        "process_name": process,
-        "depth_limit": args.depth,
-        "element_count": ax.elements.len(),
-        "elements": ax.elements,
-        "tree": ax.tree,
-        "query": ax.diagnostics,
-        "warnings": ax.warnings,
+    let out_dir = args.out_dir.clone().unwrap_or_else(|| {
+        out_root()
+            .join("stability")
+            .join(format!("{slug}-{}", timestamp_compact()))
     });
+    fs::create_dir_all(&out_dir)
+        .with_context(|| format!("failed to create stability dir: {}", out_dir.display()))?;
+
+    let samples = args.samples.max(2);
+    let mut captures: Vec<PathBuf> = Vec::new();
+    let mut warnings = Warnings::default();
+    for sample in 0..samples {
+        if sample > 0 {
+            thread::sleep(Duration::from_millis(args.interval_ms));
+        }
+        let path = out_dir.join(format!("sample-{sample:03}.png"));
+        let payload = capture_internal(
+            &path,
+            Some(process.clone()),
+            Some("stability"),
+            None,
+            None,
+            &CaptureOptions {
+                output: output_opts.clone(),
+                ..CaptureOptions::default()
+            },
+        )?;
+        if payload
+            .get("fallback_used")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+        {
+            warnings.warn(
+                "capture_fallback",
+                format!("sample {sample} fell back to placeholder output; score is not meaningful"),
+            );
+        }
+        captures.push(path);
+    }
 
-    write_json_pretty(&out, &payload)?;
-
-    if args.json {
-        println!("{}", serde_json::to_string(&payload)?);
-    } else {
-        println!("{}", abs_path(&out).display());
+    let options = DiffOptions {
+        resize: args.resize,
+        bbox_threshold: args.bbox_threshold,
+        ..DiffOptions::default()
+    };
+    // Each sample is decoded once; every pair then diffs copies of the cached frames.
+    let frames = captures
+        .iter()
+        .enumerate()
+        .map(|(sample, path)| {
+            open_image_within_budget(
+                path,
+                &format!("sample {sample}"),
+                options.max_total_pixels,
+                options.downscale_oversize,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut pairs: Vec<Value> = Vec::new();
+    let mut percents: Vec<f64> = Vec::new();
+    for a in 0..captures.len() {
+        for b in (a + 1)..captures.len() {
+            let diff = run_diff_decoded(
+                Instant::now(),
+                (&captures[a], frames[a].clone()),
+                (&captures[b], frames[b].clone()),
+                None,
+                None,
+                None,
+                None,
+                &options,
+            )?;
+            let percent = diff
+                .json
+                .get("percent_changed")
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0);
+            percents.push(percent);
+            pairs.push(json!({
+                "a": a,
+                "b": b,
+                "percent_changed": percent,
+                "change_region_count": diff.json.get("change_region_count").cloned().unwrap_or(Value::Null),
+            }));
+        }
     }
 
+    let stats = percent_changed_stats(&percents);
+    let max_seen = stats.get("max").and_then(Value::as_f64).unwrap_or(0.0);
+    warnings.note_hidden(output_opts);
+    let payload = json!({
+        "app_name": process,
+        "samples": samples,
+        "interval_ms": args.interval_ms,
+        "out_dir": abs_path(&out_dir).display().to_string(),
+        "captures": captures.iter().map(|p| abs_path(p).display().to_string()).collect::<Vec<_>>(),
+        "pairs": pairs,
+        "percent_changed": stats,
+        "max_percent": args.max_percent,
+        "stable": max_seen <= args.max_percent,
+        "warnings": warnings.to_value(output_opts),
+    });
+    write_json_pretty(&out_dir.join("stability.json"), &payload)?;
+    println!("{}", serde_json::to_string(&payload)?);
     Ok(())
 }
 
-fn command_act(args: ActArgs) -> Result<()> {
-    let process = args
-        .process
-        .clone()
-        .or_else(frontmost_app_name)
-        .unwrap_or_else(|| "app".to_string());
-
-    let mut warnings = Vec::<String>::new();
-    let mut query_window_diag: Option<QueryDiagnostic> = None;
-    let mut selected_window: Option<Value> = None;
+fn percent_changed_stats(values: &[f64]) -> Value {
+    if values.is_empty() {
+        return json!({"min": null, "max": null, "mean": null, "stddev": null});
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    json!({
+        "min": round_to(values.iter().copied().fold(f64::INFINITY, f64::min), 3),
+        "max": round_to(values.iter().copied().fold(f64::NEG_INFINITY, f64::max), 3),
+        "mean": round_to(mean, 3),
+        "stddev": round_to(variance.sqrt(), 3),
+    })
+}
 
-    let mut actions = Vec::<Value>::new();
-    let mut click_abs: Option<(i64, i64)> = None;
-    let mut click_rel_payload: Option<Value> = None;
+fn command_bench(args: BenchArgs) -> Result<()> {
+    let (width, height) = (args.width.max(16), args.height.max(16));
+    let iterations = args.iterations.max(1);
+    let (baseline, current) = bench_images(width, height);
+
+    let dir = env::temp_dir().join(format!("cvlp-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create bench dir: {}", dir.display()))?;
+    let baseline_path = dir.join("baseline.png");
+    let current_path = dir.join("current.png");
+    baseline
+        .save(&baseline_path)
+        .with_context(|| format!("failed to write {}", baseline_path.display()))?;
+    current
+        .save(&current_path)
+        .with_context(|| format!("failed to write {}", current_path.display()))?;
+
+    let options = DiffOptions::default();
+    let diff_result = bench_timed(iterations, || {
+        run_diff_internal(
+            &baseline_path,
+            &current_path,
+            None,
+            None,
+            None,
+            None,
+            &options,
+        )
+        .map(|_| ())
+    });
 
-    if let Some(raw) = args.click.as_deref() {
-        let (x, y) = parse_coord_pair(raw)
-            .ok_or_else(|| anyhow::anyhow!("invalid --click coordinate: expected x,y"))?;
-        click_abs = Some((x, y));
-    }
+    let gray: Vec<u8> = baseline
+        .pixels()
+        .zip(current.pixels())
+        .map(|(a, b)| pixel_distance(a, b, "rgb"))
+        .collect();
+    let regions_result = bench_timed(iterations, || {
+        extract_change_regions(&gray, width, height, 24, 64, 2, 16);
+        Ok(())
+    });
 
-    if let Some(raw) = args.click_rel.as_deref() {
-        let (rx, ry) = parse_coord_pair(raw)
-            .ok_or_else(|| anyhow::anyhow!("invalid --click-rel coordinate: expected x,y"))?;
-        let probe = query_window_probe(&process);
-        query_window_diag = Some(probe.diagnostics.clone());
-        selected_window = Some(json!({
-            "index": probe.selected_index,
-            "x": probe.x,
-            "y": probe.y,
-            "w": probe.w,
-            "h": probe.h,
-            "title": probe.title,
-            "selection_mode": probe.selection_mode,
-            "candidate_count": probe.candidate_count,
-            "usable_count": probe.usable_count,
-            "usable": probe.usable,
-        }));
-        if !probe.diagnostics.ok || probe.w <= 0 || probe.h <= 0 {
-            bail!("unable to resolve --click-rel because target window bounds are unavailable");
+    let specs = bench_annotations(width, height, args.annotations);
+    let defaults = Map::new();
+    let fonts = FontCache::default();
+    let render_result = bench_timed(iterations, || {
+        let mut canvas = baseline.clone();
+        for ann in &specs {
+            draw_prepared_annotation(&mut canvas, ann, 1.0, &defaults, &fonts);
         }
-        let ax = probe.x.saturating_add(rx);
-        let ay = probe.y.saturating_add(ry);
-        click_rel_payload = Some(json!({
-            "x": rx,
-            "y": ry,
-            "units": "pt",
-            "resolved_abs": {"x": ax, "y": ay, "units": "pt"},
+        Ok(())
+    });
+    let _ = fs::remove_dir_all(&dir);
+
+    let megapixels = f64::from(width) * f64::from(height) / 1_000_000.0;
+    let mut results = Vec::new();
+    for (name, timings) in [
+        ("run_diff_internal", diff_result?),
+        ("extract_change_regions", regions_result?),
+        ("annotate_render", render_result?),
+    ] {
+        let mean = timings.iter().sum::<f64>() / timings.len() as f64;
+        let min = timings.iter().copied().fold(f64::INFINITY, f64::min);
+        results.push(json!({
+            "name": name,
+            "ms_mean": round_to(mean, 3),
+            "ms_min": round_to(min, 3),
+            "mp_per_s": if mean > 0.0 { Some(round_to(megapixels / (mean / 1000.0), 2)) } else { None },
         }));
-        click_abs = Some((ax, ay));
     }
 
-    if let Some((x, y)) = click_abs {
-        actions.push(json!({
-            "type": "click",
-            "x": x,
-            "y": y,
-            "units": "pt",
-        }));
+    let payload = json!({
+        "width": width,
+        "height": height,
+        "megapixels": round_to(megapixels, 3),
+        "iterations": iterations,
+        "annotations": specs.len(),
+        "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+        "results": results,
+    });
+    println!("{}", serde_json::to_string(&payload)?);
+    Ok(())
+}
+
+// One warm-up call, then `iterations` timed calls in milliseconds.
+fn bench_timed(iterations: u32, mut run: impl FnMut() -> Result<()>) -> Result<Vec<f64>> {
+    run()?;
+    let mut timings = Vec::with_capacity(iterations as usize);
+    for _ in 0..iterations {
+        let started = Instant::now();
+        run()?;
+        timings.push(started.elapsed().as_secs_f64() * 1000.0);
     }
+    Ok(timings)
+}
 
-    let text_value = match args.text.as_deref() {
-        Some("-") => {
-            let mut buf = String::new();
-            io::stdin()
-                .read_to_string(&mut buf)
-                .context("failed to read --text from stdin")?;
-            Some(buf)
+// Gradient baseline plus a copy with a grid of dark blocks, so diffs find many regions.
+fn bench_images(width: u32, height: u32) -> (RgbaImage, RgbaImage) {
+    let baseline = RgbaImage::from_fn(width, height, |x, y| {
+        Rgba([
+            (x * 255 / width) as u8,
+            (y * 255 / height) as u8,
+            ((x + y) % 256) as u8,
+            255,
+        ])
+    });
+    let mut current = baseline.clone();
+    let block = (width.min(height) / 12).max(4);
+    for by in (block..height.saturating_sub(block)).step_by((block * 3) as usize) {
+        for bx in (block..width.saturating_sub(block)).step_by((block * 3) as usize) {
+            fill_rect_alpha(
+                &mut current,
+                bx as i32,
+                by as i32,
+                (bx + block) as i32,
+                (by + block) as i32,
+                Rgba([20, 20, 20, 255]),
+                None,
+            );
         }
-        Some(value) => Some(value.to_string()),
-        None => None,
-    };
+    }
+    (baseline, current)
+}
 
-    if let Some(text) = text_value.as_ref() {
-        actions.push(json!({
-            "type": "type",
-            "text": text,
-        }));
+fn bench_annotations(width: u32, height: u32, count: usize) -> Vec<Map<String, Value>> {
+    (0..count)
+        .filter_map(|i| {
+            let x = (i as u32 * 97) % width.saturating_sub(120).max(1);
+            let y = (i as u32 * 61) % height.saturating_sub(60).max(1);
+            let ann = match i % 3 {
+                0 => json!({"type": "rect", "x": x, "y": y, "w": 110, "h": 50, "fill": "rgba(255,59,48,0.2)"}),
+                1 => json!({"type": "arrow", "x1": x, "y1": y + 50, "x2": x + 100, "y2": y}),
+                _ => json!({"type": "text", "x": x, "y": y, "text": format!("label {i}"), "text_bg": "rgba(0,0,0,0.6)"}),
+            };
+            ann.as_object().cloned()
+        })
+        .collect()
+}
+
+fn parse_workflow_manifest(path: &str, raw: &str) -> Result<Value> {
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".yaml") || lower.ends_with(".yml") {
+        return serde_yaml::from_str(raw)
+            .map_err(|err| cli_failure(EXIT_INPUT, format!("invalid manifest YAML: {err}")));
     }
+    serde_json::from_str(raw)
+        .map_err(|err| cli_failure(EXIT_INPUT, format!("invalid manifest JSON: {err}")))
+}
 
-    let hotkey_spec = if let Some(raw) = args.hotkey.as_deref() {
-        let spec = parse_hotkey_combo(raw)?;
-        actions.push(json!({
-            "type": "hotkey",
-            "combo": spec.combo,
-            "key": spec.key,
-            "modifiers": spec.modifiers,
-            "key_code": spec.key_code,
-        }));
-        Some(spec)
+fn command_run(args: RunArgs, output_opts: &OutputOptions) -> Result<()> {
+    let raw = if args.manifest == "-" {
+        let mut buf = String::new();
+        io::stdin()
+            .read_to_string(&mut buf)
+            .context("failed to read manifest from stdin")?;
+        buf
     } else {
-        None
+        fs::read_to_string(&args.manifest)
+            .with_context(|| format!("failed to read manifest: {}", args.manifest))?
     };
-
-    if args.tab > 0 {
-        actions.push(json!({
-            "type": "tab",
-            "count": args.tab,
-        }));
-    }
-    if args.enter {
-        actions.push(json!({
-            "type": "enter",
-        }));
+    let manifest = parse_workflow_manifest(&args.manifest, &raw)?;
+    let steps = manifest
+        .get("steps")
+        .or(Some(&manifest))
+        .and_then(Value::as_array)
+        .ok_or_else(|| {
+            cli_failure(
+                EXIT_INPUT,
+                "manifest must be a list of steps or an object with 'steps'",
+            )
+        })?;
+
+    let mut outputs = Map::new();
+    let mut results = Vec::new();
+    for (index, step) in steps.iter().enumerate() {
+        let id = value_to_string(step.get("id")).unwrap_or_else(|| format!("step{}", index + 1));
+        let command = value_to_string(step.get("command")).unwrap_or_default();
+        let raw_args = step
+            .get("args")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let mut argv = vec!["codex-visual-loop".to_string(), command.clone()];
+        for arg in &raw_args {
+            let text = value_to_string(Some(arg)).unwrap_or_default();
+            argv.push(
+                resolve_step_refs(&text, &outputs)
+                    .map_err(|err| cli_failure(EXIT_INPUT, format!("step {id}: {err}")))?,
+            );
+        }
+        let output = run_workflow_step(&argv, output_opts)
+            .with_context(|| format!("step {id} ({command}) failed"))?;
+        outputs.insert(id.clone(), output.clone());
+        results.push(json!({"id": id, "command": command, "output": output}));
     }
 
-    if actions.is_empty() {
-        bail!("no action specified. Use one or more of --click/--click-rel/--text/--hotkey/--tab/--enter");
+    let payload = json!({
+        "manifest": args.manifest,
+        "step_count": results.len(),
+        "steps": results,
+    });
+    if let Some(path) = args.json_out.as_deref() {
+        write_json_pretty(path, &payload)?;
     }
+    println!("{}", serde_json::to_string(&payload)?);
+    Ok(())
+}
 
-    let mut activation_diag = QueryDiagnostic {
-        ok: true,
-        attempts: 0,
-        error_code: None,
-        message: Some("activation skipped".to_string()),
-    };
-    let mut execution = Vec::<Value>::new();
-    let mut ok = true;
-
-    if !args.dry_run {
-        if !cfg!(target_os = "macos") {
-            bail!("act command requires macOS for UI automation");
-        }
-        if !args.no_activate {
-            activation_diag = activate_process_window(&process);
-            if !activation_diag.ok {
-                ok = false;
-                warnings.push("app activation failed; subsequent actions may fail".to_string());
+// Parse one step's argv with the normal CLI and call the same internals the subcommand uses.
+fn run_workflow_step(argv: &[String], output_opts: &OutputOptions) -> Result<Value> {
+    let cli = Cli::try_parse_from(argv).map_err(|err| {
+        let detail = err.to_string();
+        cli_failure(
+            EXIT_INPUT,
+            detail.lines().next().unwrap_or_default().to_string(),
+        )
+    })?;
+    match cli.command {
+        Some(Commands::Capture(args)) => {
+            if args.frames > 1 || args.json_stream {
+                return Err(cli_failure(
+                    EXIT_INPUT,
+                    "run capture steps take a single frame (no --frames/--json-stream)",
+                ));
             }
-            if args.activation_delay_ms > 0 {
-                thread::sleep(Duration::from_millis(args.activation_delay_ms.min(5000)));
+            let (process, out, options) = resolve_capture_args(&args, output_opts)?;
+            let sidecar = capture_sidecar_path(&args, &out, 0, 1);
+            let payload = capture_internal(
+                &out,
+                process,
+                args.step.as_deref(),
+                args.note.as_deref(),
+                sidecar.as_deref(),
+                &options,
+            )?;
+            if args.strict && value_to_bool(&payload["fallback_used"], false) {
+                return Err(strict_capture_failure());
             }
+            Ok(payload)
         }
+        Some(Commands::Diff(args)) => Ok(diff_from_args(&args, output_opts)?.json),
+        Some(Commands::Annotate(args)) if !args.spec_help => annotate_internal(&args, output_opts),
+        _ => Err(cli_failure(
+            EXIT_INPUT,
+            format!(
+                "unsupported step command {:?}: expected capture|diff|annotate",
+                argv.get(1).map(String::as_str).unwrap_or_default()
+            ),
+        )),
     }
+}
 
-    if let Some((x, y)) = click_abs {
-        if args.dry_run {
-            execution.push(json!({
-                "type": "click",
-                "status": "planned",
-                "x": x,
-                "y": y,
-            }));
-        } else {
-            let script = format!("tell application \"System Events\" to click at {{{x}, {y}}}");
-            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
-            if !diag.ok {
-                ok = false;
+// Expand `${step_id.path.to.field}` references (array items by index) from earlier outputs.
+fn resolve_step_refs(
+    raw: &str,
+    outputs: &Map<String, Value>,
+) -> std::result::Result<String, String> {
+    let mut out = String::new();
+    let mut rest = raw;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated reference in {raw:?}"))?;
+        let reference = after[..end].trim();
+        let mut parts = reference.split('.');
+        let step_id = parts.next().unwrap_or_default();
+        let mut value = outputs
+            .get(step_id)
+            .ok_or_else(|| format!("unknown step {step_id:?} in ${{{reference}}}"))?;
+        for key in parts {
+            value = match value {
+                Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+                Value::Object(map) => map.get(key),
+                _ => None,
             }
-            execution.push(json!({
-                "type": "click",
-                "status": if diag.ok { "ok" } else { "error" },
-                "x": x,
-                "y": y,
-                "query": diag,
-            }));
+            .ok_or_else(|| format!("${{{reference}}} not found in step output"))?;
+        }
+        match value {
+            Value::String(text) => out.push_str(text),
+            Value::Null => return Err(format!("${{{reference}}} is null")),
+            other => out.push_str(&other.to_string()),
         }
+        rest = &after[end + 1..];
     }
+    out.push_str(rest);
+    Ok(out)
+}
 
-    if let Some(text) = text_value {
-        if args.dry_run {
-            execution.push(json!({
-                "type": "type",
-                "status": "planned",
-                "text": text,
-            }));
-        } else {
-            let escaped = escape_osascript_string(&text);
-            let script = format!("tell application \"System Events\" to keystroke \"{escaped}\"");
-            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
-            if !diag.ok {
-                ok = false;
-            }
-            execution.push(json!({
-                "type": "type",
-                "status": if diag.ok { "ok" } else { "error" },
-                "text": text,
-                "query": diag,
-            }));
+// Generated subtrees of the artifact root. loop/baselines is left out on purpose: baselines
+// are often reviewed and committed.
+const GENERATED_ARTIFACT_DIRS: &[&str] = &[
+    "capture",
+    "observe",
+    "stability",
+    "ax",
+    "explain",
+    "loop/latest",
+    "loop/history",
+    "loop/diffs",
+    "loop/reports",
+    "loop/annotations",
+    "loop/captures",
+];
+
+const GITIGNORE_BEGIN: &str = "# >>> codex-visual-loop generated artifacts";
+const GITIGNORE_END: &str = "# <<< codex-visual-loop generated artifacts";
+
+// Write or refresh the managed block in <out_root>/.gitignore, keeping any user lines.
+fn ensure_artifact_gitignore(out_root: &Path) -> Result<PathBuf> {
+    let path = out_root.join(".gitignore");
+    let existing = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
         }
+    };
+    let updated = gitignore_with_artifact_block(&existing);
+    if updated != existing {
+        write_text_file(&path, &updated)?;
     }
+    Ok(path)
+}
 
-    if let Some(spec) = hotkey_spec {
-        if args.dry_run {
-            execution.push(json!({
-                "type": "hotkey",
-                "status": "planned",
-                "combo": spec.combo,
-                "key": spec.key,
-                "modifiers": spec.modifiers,
-                "key_code": spec.key_code,
-            }));
-        } else {
-            let script = hotkey_script(&spec);
-            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
-            if !diag.ok {
-                ok = false;
-            }
-            execution.push(json!({
-                "type": "hotkey",
-                "status": if diag.ok { "ok" } else { "error" },
-                "combo": spec.combo,
-                "key": spec.key,
-                "modifiers": spec.modifiers,
-                "key_code": spec.key_code,
-                "query": diag,
-            }));
+fn gitignore_with_artifact_block(existing: &str) -> String {
+    let mut kept: Vec<&str> = Vec::new();
+    let mut in_block = false;
+    for line in existing.lines() {
+        match line.trim() {
+            GITIGNORE_BEGIN => in_block = true,
+            GITIGNORE_END => in_block = false,
+            _ if !in_block => kept.push(line),
+            _ => {}
         }
     }
+    while kept.last().is_some_and(|line| line.trim().is_empty()) {
+        kept.pop();
+    }
+    let mut out = kept.join("\n");
+    if !out.is_empty() {
+        out.push_str("\n\n");
+    }
+    out.push_str(GITIGNORE_BEGIN);
+    out.push('\n');
+    for dir in GENERATED_ARTIFACT_DIRS {
+        out.push_str(&format!("/{dir}/\n"));
+    }
+    out.push_str(GITIGNORE_END);
+    out.push('\n');
+    out
+}
 
-    if args.tab > 0 {
-        if args.dry_run {
-            execution.push(json!({
-                "type": "tab",
-                "status": "planned",
-                "count": args.tab,
-            }));
-        } else {
-            let script = format!(
-                "tell application \"System Events\"\nrepeat {} times\nkey code 48\nend repeat\nend tell",
-                args.tab
-            );
-            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
-            if !diag.ok {
-                ok = false;
+fn command_clean(args: CleanArgs) -> Result<()> {
+    let root = out_root();
+    let mut targets: Vec<PathBuf> = Vec::new();
+    if args.all {
+        targets.push(root.clone());
+    } else if args.targets.is_empty() {
+        targets.extend(GENERATED_ARTIFACT_DIRS.iter().map(|dir| root.join(dir)));
+    } else {
+        for target in &args.targets {
+            let rel = Path::new(target);
+            if rel.is_absolute()
+                || rel
+                    .components()
+                    .any(|c| !matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(cli_failure(
+                    EXIT_INPUT,
+                    format!(
+                        "clean target must be a relative path inside the artifact root: {target}"
+                    ),
+                ));
             }
-            execution.push(json!({
-                "type": "tab",
-                "status": if diag.ok { "ok" } else { "error" },
-                "count": args.tab,
-                "query": diag,
-            }));
+            targets.push(root.join(rel));
         }
     }
-
-    if args.enter {
-        if args.dry_run {
-            execution.push(json!({
-                "type": "enter",
-                "status": "planned",
-            }));
-        } else {
-            let script = "tell application \"System Events\" to key code 36";
-            let diag = run_osascript_exec_with_retry(script, &[], 2, 80);
-            if !diag.ok {
-                ok = false;
+    targets.retain(|path| path.exists());
+
+    let confirmed = args.yes || (!targets.is_empty() && confirm_clean(&targets)?);
+    let mut removed = Vec::new();
+    if confirmed {
+        for path in &targets {
+            if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
             }
-            execution.push(json!({
-                "type": "enter",
-                "status": if diag.ok { "ok" } else { "error" },
-                "query": diag,
-            }));
+            .with_context(|| format!("failed to remove {}", path.display()))?;
+            removed.push(abs_path(path).display().to_string());
         }
     }
 
     let payload = json!({
-        "captured_at": timestamp_iso(),
-        "process_name": process,
-        "dry_run": args.dry_run,
-        "ok": ok,
-        "actions": actions,
-        "execution": execution,
-        "click_rel": click_rel_payload,
-        "window_probe": {
-            "selected_window": selected_window,
-            "query": query_window_diag,
+        "out_root": abs_path(&root).display().to_string(),
+        "dry_run": !confirmed,
+        "removed": removed,
+        "would_remove": if confirmed {
+            Vec::new()
+        } else {
+            targets.iter().map(|p| abs_path(p).display().to_string()).collect()
         },
-        "activation": activation_diag,
-        "warnings": warnings,
     });
+    println!("{}", serde_json::to_string(&payload)?);
+    Ok(())
+}
 
-    if args.json {
-        println!("{}", serde_json::to_string(&payload)?);
-    } else if ok {
-        println!("ok");
-    } else {
-        println!("{}", serde_json::to_string_pretty(&payload)?);
+// Ask on an interactive terminal; anything else without --yes is a dry run.
+fn confirm_clean(targets: &[PathBuf]) -> Result<bool> {
+    use std::io::IsTerminal;
+    if !io::stdin().is_terminal() {
+        return Ok(false);
     }
-
-    if !args.dry_run && !ok {
-        bail!("one or more actions failed");
+    eprintln!("about to remove:");
+    for path in targets {
+        eprintln!("  {}", abs_path(path).display());
     }
-
-    Ok(())
+    eprint!("proceed? [y/N] ");
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("failed to read confirmation")?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
 }
 
-fn command_explain_app(args: ExplainArgs) -> Result<()> {
+fn command_observe(args: ObserveArgs, output_opts: &OutputOptions) -> Result<()> {
     let process = args
         .process
         .clone()
+        .or_else(env_process_name)
         .or_else(frontmost_app_name)
         .unwrap_or_else(|| "app".to_string());
 
-    let out_root = args.out_dir.clone().unwrap_or_else(out_root);
-    let explain_dir = out_root.join("explain");
-    fs::create_dir_all(&explain_dir)
-        .with_context(|| format!("failed to create explain dir: {}", explain_dir.display()))?;
+    let out_root = out_root();
+    let out_dir = args
+        .out_dir
+        .clone()
+        .unwrap_or_else(|| out_root.join("observe"));
+    fs::create_dir_all(&out_dir)
+        .with_context(|| format!("failed to create observe dir: {}", out_dir.display()))?;
+    let gitignore = if args.gitignore {
+        Some(ensure_artifact_gitignore(&out_root)?)
+    } else {
+        None
+    };
 
     let slug = slugify(&process);
     let run_id = format!(
@@ -1368,3780 +4011,13310 @@ fn command_explain_app(args: ExplainArgs) -> Result<()> {
         std::process::id(),
         rand::thread_rng().gen_range(1000..9999)
     );
-    let base = format!("explain-{slug}-{run_id}");
 
-    let image_path = explain_dir.join(format!("{base}-capture.png"));
-    let packet_path = args
-        .packet_out
-        .clone()
-        .unwrap_or_else(|| explain_dir.join(format!("{base}-packet.json")));
-    let prompt_path = args
-        .prompt_out
-        .clone()
-        .unwrap_or_else(|| explain_dir.join(format!("{base}-prompt.txt")));
-    let report_path = args
-        .report
-        .clone()
-        .unwrap_or_else(|| explain_dir.join(format!("{base}-report.md")));
-    let codex_log_path = explain_dir.join(format!("{base}-codex.log"));
+    let before_png = out_dir.join(format!("before-{slug}-{run_id}.png"));
+    let after_png = out_dir.join(format!("after-{slug}-{run_id}.png"));
+    let video_path = out_dir.join(format!("action-clip-{slug}-{run_id}.mov"));
+    let action_log = out_dir.join(format!("action-{slug}-{run_id}.log"));
+    let compare_json_path = out_dir.join(format!("compare-{slug}-{run_id}.json"));
+    let diff_path = out_dir.join(format!("diff-{slug}-{run_id}.png"));
+    let annotated_diff_path = out_dir.join(format!("diff-annotated-{slug}-{run_id}.png"));
+    let annotate_spec_path = out_dir.join(format!("diff-annotate-spec-{slug}-{run_id}.json"));
+    let report_path = out_dir.join(format!("observe-{slug}-{run_id}.json"));
 
-    let capture = capture_internal(
-        &image_path,
+    let before_payload = capture_internal(
+        &before_png,
         Some(process.clone()),
-        Some("explain"),
-        Some("explain-app"),
-        Some(&default_sidecar_for(&image_path)),
+        Some("before"),
+        Some(&args.action),
+        Some(&default_sidecar_for(&before_png)),
+        &CaptureOptions {
+            output: output_opts.clone(),
+            ..CaptureOptions::default()
+        },
     )?;
-    let ax = query_ax_tree(&process, args.ax_depth.max(1));
-    let summary = summarize_ax_elements(&ax.elements);
 
-    let mut warnings = Vec::<String>::new();
-    if let Some(items) = capture.get("warnings").and_then(Value::as_array) {
-        for item in items {
-            if let Some(text) = item.as_str() {
-                warnings.push(text.to_string());
-            }
-        }
-    }
-    for warning in &ax.warnings {
-        warnings.push(warning.clone());
-    }
+    let action_started = timestamp_iso();
+    let action_status = if let Some(cmd) = args.action_cmd.as_deref() {
+        let output = Command::new("bash")
+            .arg("-lc")
+            .arg(cmd)
+            .output()
+            .with_context(|| format!("failed to run action command: {cmd}"))?;
+        let mut file = File::create(&action_log)
+            .with_context(|| format!("failed to write action log: {}", action_log.display()))?;
+        file.write_all(&output.stdout)?;
+        file.write_all(&output.stderr)?;
+        output.status.code().unwrap_or(1)
+    } else {
+        File::create(&action_log)
+            .with_context(|| format!("failed to create action log: {}", action_log.display()))?;
+        0
+    };
+    let action_finished = timestamp_iso();
 
-    let ax_payload = json!({
-        "captured_at": timestamp_iso(),
-        "process_name": process,
-        "depth_limit": args.ax_depth,
-        "element_count": ax.elements.len(),
-        "elements": ax.elements,
-        "tree": ax.tree,
-        "query": ax.diagnostics,
-        "warnings": ax.warnings,
-    });
+    let mut clip_file = File::create(&video_path).with_context(|| {
+        format!(
+            "failed to create clip placeholder: {}",
+            video_path.display()
+        )
+    })?;
+    clip_file.write_all(b"codex-visual-loop placeholder clip\n")?;
 
-    let packet = json!({
-        "packet_version": 1,
-        "generated_at": timestamp_iso(),
-        "process_name": process,
-        "capture": capture,
-        "ax_tree": ax_payload,
-        "summary": summary,
-        "warnings": warnings,
-    });
+    if args.duration > 0 {
+        thread::sleep(Duration::from_secs(args.duration.min(30)));
+    }
 
-    write_json_pretty(&packet_path, &packet)?;
-    let prompt_text = build_explain_prompt(&packet, args.prompt.as_deref());
-    write_text_file(&prompt_path, &prompt_text)?;
+    let after_payload = capture_internal(
+        &after_png,
+        Some(process.clone()),
+        Some("after"),
+        Some(&args.action),
+        Some(&default_sidecar_for(&after_png)),
+        &CaptureOptions {
+            output: output_opts.clone(),
+            ..CaptureOptions::default()
+        },
+    )?;
 
-    let mut mode = "fallback".to_string();
-    let mut fallback_reason: Option<String> = None;
-    let mut codex_meta = json!({
-        "attempted": false,
-        "success": false,
-    });
-
-    if args.no_codex {
-        fallback_reason = Some("Codex execution disabled via --no-codex".to_string());
-    } else if let Some(codex_bin) = resolve_codex_executable(args.codex_bin.as_deref()) {
-        codex_meta = run_codex_exec_report(
-            &codex_bin,
-            &prompt_text,
-            &image_path,
-            &report_path,
-            &codex_log_path,
-            args.model.as_deref(),
-            args.codex_timeout,
-        )?;
+    let diff_output = run_diff_internal(
+        &before_png,
+        &after_png,
+        Some(&diff_path),
+        Some(&compare_json_path),
+        Some(&annotated_diff_path),
+        Some(&annotate_spec_path),
+        &DiffOptions {
+            resize: true,
+            bbox_min_area: 16,
+            verify_output: output_opts.verify_output,
+            ..DiffOptions::default()
+        },
+    )?;
 
-        if codex_meta
-            .get("success")
-            .and_then(Value::as_bool)
-            .unwrap_or(false)
-        {
-            mode = "llm".to_string();
-        } else {
-            fallback_reason = Some(
-                codex_meta
-                    .get("error")
-                    .and_then(Value::as_str)
-                    .unwrap_or("codex exec did not produce a usable report")
-                    .to_string(),
+    let mut warnings = Warnings::default();
+    let mut preview_gif: Option<PathBuf> = None;
+    if args.summary_gif && !args.no_summary {
+        // The clip is still a placeholder, so the summary frames are the captures themselves.
+        let frames: Vec<PathBuf> = [&before_png, &after_png]
+            .into_iter()
+            .filter(|path| path.is_file())
+            .take(args.summary_max.max(1) as usize)
+            .cloned()
+            .collect();
+        if frames.is_empty() {
+            warnings.warn(
+                "summary_gif_no_frames",
+                "no summary frames were extracted; skipped preview gif",
             );
+        } else {
+            let gif_path = out_dir.join(format!("preview-{slug}-{run_id}.gif"));
+            let delay_ms = preview_gif_delay_ms(args.duration, frames.len());
+            write_preview_gif(&frames, &gif_path, delay_ms, PREVIEW_GIF_MAX_WIDTH)?;
+            preview_gif = Some(gif_path);
         }
-    } else {
-        fallback_reason = Some("Codex CLI not found on PATH (`codex`/`codex-auto`)".to_string());
     }
 
-    if mode != "llm" {
-        if args.strict_llm && !args.no_codex {
-            let payload = json!({
-                "mode": "error",
-                "error": fallback_reason,
-                "packet_path": abs_path(&packet_path).display().to_string(),
-                "prompt_path": abs_path(&prompt_path).display().to_string(),
-                "report_path": abs_path(&report_path).display().to_string(),
-                "codex": codex_meta,
-            });
-            if args.json {
-                println!("{}", serde_json::to_string_pretty(&payload)?);
-            }
-            bail!(
-                "{}",
-                payload
-                    .get("error")
-                    .and_then(Value::as_str)
-                    .unwrap_or("strict_llm enabled and codex execution failed")
-            );
-        }
-
-        let fallback_markdown = build_fallback_explain_report(
-            &packet,
-            fallback_reason
-                .as_deref()
-                .unwrap_or("codex execution unavailable"),
-            &codex_meta,
-        );
-        write_text_file(&report_path, &fallback_markdown)?;
-    }
+    let clip_payload = json!({
+        "video_path": abs_path(&video_path).display().to_string(),
+        "duration_sec": args.duration,
+        "summary_mode": args.summary_mode,
+        "summary_max": args.summary_max,
+        "summary_enabled": !args.no_summary,
+        "summary_sheet": args.summary_sheet,
+        "summary_gif": args.summary_gif,
+        "preview_gif_path": preview_gif.as_ref().map(|path| abs_path(path).display().to_string()),
+    });
 
-    let output = json!({
-        "mode": mode,
+    warnings.note_hidden(output_opts);
+    let payload = json!({
+        "run_id": run_id,
         "process_name": process,
-        "packet_path": abs_path(&packet_path).display().to_string(),
-        "prompt_path": abs_path(&prompt_path).display().to_string(),
-        "report_path": abs_path(&report_path).display().to_string(),
-        "codex": codex_meta,
-        "fallback_reason": fallback_reason,
+        "action": {
+            "label": args.action,
+            "command": args.action_cmd,
+            "status": action_status,
+            "started_at": action_started,
+            "finished_at": action_finished,
+            "log_path": abs_path(&action_log).display().to_string(),
+        },
+        "before_capture": before_payload,
+        "after_capture": after_payload,
+        "clip": clip_payload,
+        "diff": diff_output.json,
+        "gitignore": gitignore.map(|path| abs_path(&path).display().to_string()),
+        "warnings": warnings.to_value(output_opts),
     });
 
+    write_json_pretty(&report_path, &payload)?;
+
     if args.json {
-        println!("{}", serde_json::to_string_pretty(&output)?);
+        println!("{}", serde_json::to_string_pretty(&payload)?);
     } else {
         println!("{}", abs_path(&report_path).display());
+        println!("{}", abs_path(&before_png).display());
+        println!("{}", abs_path(&after_png).display());
+        println!("{}", abs_path(&video_path).display());
+        println!("{}", abs_path(&annotated_diff_path).display());
+        if let Some(path) = &preview_gif {
+            println!("{}", abs_path(path).display());
+        }
     }
 
     Ok(())
 }
 
-fn summarize_ax_elements(elements: &[Value]) -> Value {
-    let mut named_elements = 0usize;
-    let mut interactive_guess_count = 0usize;
-    let mut role_counts: std::collections::BTreeMap<String, usize> =
-        std::collections::BTreeMap::new();
+const PREVIEW_GIF_MAX_WIDTH: u32 = 480;
 
-    for element in elements {
-        let Some(obj) = element.as_object() else {
-            continue;
+// Spread the frames evenly over the observed duration; 500ms per frame when there was no wait.
+fn preview_gif_delay_ms(duration_sec: u64, frame_count: usize) -> u32 {
+    if duration_sec == 0 {
+        return 500;
+    }
+    let per_frame = duration_sec.saturating_mul(1000) / frame_count.max(1) as u64;
+    per_frame.clamp(20, 5000) as u32
+}
+
+fn write_preview_gif(frames: &[PathBuf], out: &Path, delay_ms: u32, max_width: u32) -> Result<()> {
+    let mut size: Option<(u32, u32)> = None;
+    let mut encoded = Vec::with_capacity(frames.len());
+    for path in frames {
+        let image = image::open(path)
+            .with_context(|| format!("failed to open gif frame: {}", path.display()))?;
+        // Every frame takes the first frame's (downscaled) size so the animation doesn't jump.
+        let (w, h) = *size.get_or_insert_with(|| {
+            let (w, h) = image.dimensions();
+            if w <= max_width {
+                (w.max(1), h.max(1))
+            } else {
+                let scaled_h = (f64::from(h) * f64::from(max_width) / f64::from(w)).round();
+                (max_width, (scaled_h as u32).max(1))
+            }
+        });
+        let rgba = if image.dimensions() == (w, h) {
+            image.into_rgba8()
+        } else {
+            image.resize_exact(w, h, FilterType::Triangle).into_rgba8()
         };
-        if obj
-            .get("name")
-            .and_then(Value::as_str)
-            .map(|s| !s.trim().is_empty())
-            .unwrap_or(false)
-        {
-            named_elements += 1;
-        }
+        encoded.push(image::Frame::from_parts(
+            rgba,
+            0,
+            0,
+            image::Delay::from_numer_denom_ms(delay_ms, 1),
+        ));
+    }
 
-        let role = obj
-            .get("role_description")
-            .and_then(Value::as_str)
-            .or_else(|| obj.get("class").and_then(Value::as_str))
-            .unwrap_or("unknown")
-            .trim()
-            .to_string();
-        *role_counts.entry(role.clone()).or_insert(0usize) += 1;
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = File::create(out)
+        .with_context(|| format!("failed to create preview gif: {}", out.display()))?;
+    let mut encoder = image::codecs::gif::GifEncoder::new(io::BufWriter::new(file));
+    encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
+    encoder
+        .encode_frames(encoded)
+        .with_context(|| format!("failed to encode preview gif: {}", out.display()))?;
+    Ok(())
+}
 
-        let haystack = format!(
-            "{} {}",
-            role.to_ascii_lowercase(),
-            obj.get("class")
-                .and_then(Value::as_str)
-                .unwrap_or_default()
-                .to_ascii_lowercase()
-        );
-        if [
-            "button", "checkbox", "menu", "tab", "slider", "text", "field", "link",
-        ]
-        .iter()
-        .any(|token| haystack.contains(token))
-        {
-            interactive_guess_count += 1;
+fn command_ax_tree(args: AxTreeArgs, output_opts: &OutputOptions) -> Result<()> {
+    let process = args
+        .process
+        .clone()
+        .or_else(env_process_name)
+        .or_else(frontmost_app_name)
+        .unwrap_or_else(|| "app".to_string());
+
+    let slug = slugify(&process);
+    let ts = timestamp_compact();
+    let out_root = out_root();
+    let out = args.out.clone().unwrap_or_else(|| {
+        out_root.join("ax").join(format!(
+            "ax-tree-{slug}-{ts}-{}-{}.json",
+            std::process::id(),
+            rand::thread_rng().gen_range(1000..9999)
+        ))
+    });
+    let mut ax = query_ax_tree(&process, args.depth.max(1), &output_opts.scripts);
+
+    let mut coordinate_space = json!({"space": "screen"});
+    if args.ax_origin == "window" {
+        match ax_window_origin(&process, args.sidecar.as_deref(), &output_opts.scripts) {
+            Ok((origin, source)) => {
+                offset_ax_bounds(&mut ax.elements, -origin.0, -origin.1);
+                offset_ax_bounds(&mut ax.tree, -origin.0, -origin.1);
+                coordinate_space = json!({
+                    "space": "window",
+                    "origin": {"x": origin.0, "y": origin.1, "units": "pt"},
+                    "origin_source": source,
+                });
+            }
+            Err(reason) => {
+                let warning = format!(
+                    "--ax-origin window unavailable ({reason}); bounds stay in screen space"
+                );
+                eprintln!("warning: {warning}");
+                ax.warnings.warn("ax_origin_unavailable", warning);
+            }
         }
     }
 
-    let mut top_roles: Vec<(String, usize)> = role_counts.into_iter().collect();
-    top_roles.sort_by(|a, b| b.1.cmp(&a.1));
-    top_roles.truncate(8);
+    ax.warnings.note_hidden(output_opts);
+    let payload = json!({
+        "captured_at": timestamp_iso(),
+        "process_name": process,
+        "depth_limit": args.depth,
+        "coordinate_space": coordinate_space,
+        "element_count": ax.elements.len(),
+        "elements": ax.elements,
+        "tree": ax.tree,
+        "query": ax.diagnostics,
+        "warnings": ax.warnings.to_value(output_opts),
+    });
 
-    json!({
-        "element_count": elements.len(),
-        "named_elements": named_elements,
-        "interactive_guess_count": interactive_guess_count,
-        "top_roles": top_roles.into_iter().map(|(role, count)| json!({"role": role, "count": count})).collect::<Vec<Value>>(),
-    })
+    write_json_pretty(&out, &payload)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&payload)?);
+    } else {
+        println!("{}", abs_path(&out).display());
+    }
+
+    Ok(())
 }
 
-fn build_explain_prompt(packet: &Value, extra_prompt: Option<&str>) -> String {
-    let mut out = String::new();
-    out.push_str(
-        "You are analyzing one screenshot of a macOS app and companion accessibility metadata.\n",
+fn command_await_element(args: AwaitElementArgs, output_opts: &OutputOptions) -> Result<()> {
+    let matcher = AxMatcher {
+        name: args.name.clone().filter(|v| !v.trim().is_empty()),
+        role: args.role.clone().filter(|v| !v.trim().is_empty()),
+    };
+    if matcher.name.is_none() && matcher.role.is_none() {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            "await-element needs --name and/or --role",
+        ));
+    }
+    if !cfg!(target_os = "macos") {
+        return Err(cli_failure(
+            EXIT_UNSUPPORTED,
+            "await-element requires macOS for AX queries",
+        ));
+    }
+    let process = args
+        .process
+        .clone()
+        .or_else(env_process_name)
+        .or_else(frontmost_app_name)
+        .unwrap_or_else(|| "app".to_string());
+
+    let depth = args.depth.max(1);
+    let outcome = await_ax_element(
+        || query_ax_tree(&process, depth, &output_opts.scripts),
+        &matcher,
+        args.poll_ms,
+        args.timeout_ms,
     );
-    out.push_str("Write a detailed UI explanation report in Markdown.\n\n");
-    out.push_str("Required sections:\n");
-    out.push_str("1) Executive summary\n");
-    out.push_str("2) Visual/layout walkthrough (top-to-bottom)\n");
-    out.push_str("3) Interactive elements and likely affordances\n");
-    out.push_str("4) Accessibility and usability observations\n");
-    out.push_str("5) Risks/unknowns\n");
-    out.push_str("6) Recommended next actions\n\n");
-    out.push_str("If something is uncertain, explicitly say so.\n");
-    if let Some(extra) = extra_prompt {
-        if !extra.trim().is_empty() {
-            out.push_str("\nExtra user instructions:\n");
-            out.push_str(extra.trim());
-            out.push('\n');
+
+    let capture = match (&outcome.element, args.no_capture) {
+        (Some(_), false) => {
+            let out = args.out.clone().unwrap_or_else(|| {
+                out_root().join("await").join(format!(
+                    "await-{}-{}.png",
+                    slugify(&process),
+                    timestamp_compact()
+                ))
+            });
+            Some(capture_internal(
+                &out,
+                Some(process.clone()),
+                Some("await-element"),
+                None,
+                None,
+                &CaptureOptions {
+                    output: output_opts.clone(),
+                    ..CaptureOptions::default()
+                },
+            )?)
         }
+        _ => None,
+    };
+
+    let found = outcome.element.is_some();
+    outcome.last.warnings.note_hidden(output_opts);
+    let payload = json!({
+        "process_name": process,
+        "match": {"name": matcher.name, "role": matcher.role},
+        "found": found,
+        "element": outcome.element,
+        "attempts": outcome.attempts,
+        "waited_ms": outcome.waited_ms,
+        "poll_ms": args.poll_ms,
+        "timeout_ms": args.timeout_ms,
+        "capture": capture,
+        "query": outcome.last.diagnostics,
+        "warnings": outcome.last.warnings.to_value(output_opts),
+    });
+    println!("{}", serde_json::to_string(&payload)?);
+    if !found {
+        return Err(cli_failure(
+            EXIT_TIMEOUT,
+            format!(
+                "no AX element matched within {}ms ({} polls)",
+                args.timeout_ms, outcome.attempts
+            ),
+        ));
     }
-    out.push_str("\nContext packet JSON:\n");
-    out.push_str(&serde_json::to_string_pretty(packet).unwrap_or_else(|_| "{}".to_string()));
-    out.push('\n');
-    out
+    Ok(())
 }
 
-fn build_fallback_explain_report(packet: &Value, reason: &str, codex_meta: &Value) -> String {
-    let summary = packet.get("summary").cloned().unwrap_or_else(|| json!({}));
-    let capture = packet.get("capture").cloned().unwrap_or_else(|| json!({}));
+#[derive(Debug, Clone, Default)]
+struct AxMatcher {
+    name: Option<String>,
+    role: Option<String>,
+}
 
-    let mut lines = vec![
-        "# App Explanation Report (fallback)".to_string(),
-        "".to_string(),
-        format!(
-            "- Generated at: {}",
-            packet
-                .get("generated_at")
-                .and_then(Value::as_str)
-                .unwrap_or("unknown")
-        ),
-        format!(
-            "- Process: {}",
-            packet
-                .get("process_name")
-                .and_then(Value::as_str)
-                .unwrap_or("unknown")
-        ),
-        format!(
-            "- Screenshot: {}",
-            capture
-                .get("capture_path")
+impl AxMatcher {
+    // Name is a case-insensitive substring; role must equal the role description or class.
+    fn matches(&self, element: &Value) -> bool {
+        let field = |key: &str| {
+            element
+                .get(key)
                 .and_then(Value::as_str)
-                .unwrap_or("unknown")
-        ),
-        "".to_string(),
-        "## Why fallback mode was used".to_string(),
-        reason.to_string(),
-        "".to_string(),
-        "## AX summary".to_string(),
-        format!(
-            "- Element count: {}",
-            summary
-                .get("element_count")
-                .and_then(Value::as_u64)
-                .unwrap_or(0)
-        ),
-        format!(
-            "- Named elements: {}",
-            summary
-                .get("named_elements")
-                .and_then(Value::as_u64)
-                .unwrap_or(0)
-        ),
-        format!(
-            "- Interactive guess count: {}",
-            summary
-                .get("interactive_guess_count")
-                .and_then(Value::as_u64)
-                .unwrap_or(0)
-        ),
-    ];
-
-    if let Some(roles) = summary.get("top_roles").and_then(Value::as_array) {
-        lines.push("- Top roles:".to_string());
-        for role in roles {
-            lines.push(format!(
-                "  - {}: {}",
-                role.get("role")
-                    .and_then(Value::as_str)
-                    .unwrap_or("unknown"),
-                role.get("count").and_then(Value::as_u64).unwrap_or(0)
-            ));
-        }
-    }
-
-    if let Some(error) = codex_meta.get("error").and_then(Value::as_str) {
-        lines.push("".to_string());
-        lines.push("## Codex attempt".to_string());
-        lines.push(format!("- Error: {error}"));
+                .map(|v| v.to_lowercase())
+        };
+        let name_ok = self.name.as_ref().is_none_or(|wanted| {
+            field("name").is_some_and(|name| name.contains(&wanted.trim().to_lowercase()))
+        });
+        let role_ok = self.role.as_ref().is_none_or(|wanted| {
+            let wanted = wanted.trim().to_lowercase();
+            field("role_description").as_deref() == Some(wanted.as_str())
+                || field("class").as_deref() == Some(wanted.as_str())
+        });
+        name_ok && role_ok
     }
+}
 
-    lines.join("\n") + "\n"
+struct AwaitOutcome {
+    element: Option<Value>,
+    attempts: u32,
+    waited_ms: u64,
+    last: AxQueryResult,
 }
 
-fn resolve_codex_executable(override_bin: Option<&str>) -> Option<String> {
-    if let Some(bin) = override_bin {
-        let trimmed = bin.trim();
-        if !trimmed.is_empty() {
-            return Some(trimmed.to_string());
+// Polls `query` every `poll_ms` until an element matches or `timeout_ms` has elapsed; always
+// queries at least once.
+fn await_ax_element(
+    mut query: impl FnMut() -> AxQueryResult,
+    matcher: &AxMatcher,
+    poll_ms: u64,
+    timeout_ms: u64,
+) -> AwaitOutcome {
+    let started = Instant::now();
+    let timeout = Duration::from_millis(timeout_ms);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let result = query();
+        let element = result
+            .elements
+            .iter()
+            .find(|element| matcher.matches(element))
+            .cloned();
+        let elapsed = started.elapsed();
+        if element.is_some() || elapsed >= timeout {
+            return AwaitOutcome {
+                element,
+                attempts,
+                waited_ms: elapsed.as_millis() as u64,
+                last: result,
+            };
         }
+        thread::sleep(Duration::from_millis(poll_ms).min(timeout - elapsed));
     }
-    if command_exists("codex") {
-        return Some("codex".to_string());
-    }
-    if command_exists("codex-auto") {
-        return Some("codex-auto".to_string());
-    }
-    None
 }
 
-fn run_codex_exec_report(
-    codex_bin: &str,
-    prompt_text: &str,
-    image_path: &Path,
-    report_path: &Path,
-    log_path: &Path,
-    model: Option<&str>,
-    timeout_sec: u64,
-) -> Result<Value> {
-    let mut cmd = Command::new(codex_bin);
-    cmd.arg("exec")
-        .arg("--output-last-message")
-        .arg(report_path)
-        .arg("--image")
-        .arg(image_path);
-    if let Some(model) = model {
-        cmd.arg("--model").arg(model);
-    }
-    cmd.arg("-");
-    cmd.stdin(Stdio::piped());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+fn command_act(args: ActArgs, output_opts: &OutputOptions) -> Result<()> {
+    let process = args
+        .process
+        .clone()
+        .or_else(env_process_name)
+        .or_else(frontmost_app_name)
+        .unwrap_or_else(|| "app".to_string());
 
-    let mut child = match cmd.spawn() {
-        Ok(child) => child,
-        Err(err) => {
-            return Ok(json!({
-                "attempted": true,
-                "success": false,
-                "command": format!("{codex_bin} exec ..."),
-                "error": err.to_string(),
-            }));
-        }
-    };
+    let mut warnings = Warnings::default();
+    let mut query_window_diag: Option<QueryDiagnostic> = None;
+    let mut selected_window: Option<Value> = None;
 
-    if let Some(mut stdin) = child.stdin.take() {
-        let _ = stdin.write_all(prompt_text.as_bytes());
+    let mut actions = Vec::<Value>::new();
+    let mut click_abs: Option<(i64, i64)> = None;
+    let mut click_rel_payload: Option<Value> = None;
+
+    if let Some(raw) = args.click.as_deref() {
+        let (x, y) = parse_coord_pair(raw)
+            .ok_or_else(|| anyhow::anyhow!("invalid --click coordinate: expected x,y"))?;
+        click_abs = Some((x, y));
     }
 
-    let timeout = Duration::from_secs(timeout_sec.max(10));
-    let status_opt = child.wait_timeout(timeout).map_err(anyhow::Error::from)?;
-    if status_opt.is_none() {
-        let _ = child.kill();
-        let _ = child.wait();
-        return Ok(json!({
-            "attempted": true,
-            "success": false,
-            "command": format!("{codex_bin} exec ..."),
-            "error": format!("codex exec timed out after {}s", timeout.as_secs()),
+    if let Some(raw) = args.click_rel.as_deref() {
+        let (rx, ry) = parse_coord_pair(raw)
+            .ok_or_else(|| anyhow::anyhow!("invalid --click-rel coordinate: expected x,y"))?;
+        let probe = query_window_probe(&process, default_window_thresholds(), &output_opts.scripts);
+        query_window_diag = Some(probe.diagnostics.clone());
+        selected_window = Some(json!({
+            "index": probe.selected_index,
+            "x": probe.x,
+            "y": probe.y,
+            "w": probe.w,
+            "h": probe.h,
+            "title": probe.title,
+            "selection_mode": probe.selection_mode,
+            "candidate_count": probe.candidate_count,
+            "usable_count": probe.usable_count,
+            "usable": probe.usable,
         }));
+        if !probe.diagnostics.ok || probe.w <= 0 || probe.h <= 0 {
+            bail!("unable to resolve --click-rel because target window bounds are unavailable");
+        }
+        let ax = probe.x.saturating_add(rx);
+        let ay = probe.y.saturating_add(ry);
+        click_rel_payload = Some(json!({
+            "x": rx,
+            "y": ry,
+            "units": "pt",
+            "resolved_abs": {"x": ax, "y": ay, "units": "pt"},
+        }));
+        click_abs = Some((ax, ay));
     }
 
-    let output = child
-        .wait_with_output()
-        .with_context(|| "failed to read codex exec output")?;
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-    let log_text = format!(
-        "command: {codex_bin} exec --output-last-message {} --image {} -\nreturncode: {}\n\nstdout:\n{}\n\nstderr:\n{}\n",
-        report_path.display(),
-        image_path.display(),
-        output.status.code().unwrap_or(1),
-        stdout,
-        stderr
-    );
-    write_text_file(log_path, &log_text)?;
+    if let Some((x, y)) = click_abs {
+        actions.push(json!({
+            "type": "click",
+            "x": x,
+            "y": y,
+            "units": "pt",
+        }));
+    }
 
-    let report_text = fs::read_to_string(report_path).unwrap_or_default();
-    let success = output.status.success() && !report_text.trim().is_empty();
-    Ok(json!({
-        "attempted": true,
-        "success": success,
-        "returncode": output.status.code().unwrap_or(1),
-        "report_path": abs_path(report_path).display().to_string(),
-        "log_path": abs_path(log_path).display().to_string(),
-        "stdout_tail": truncate_text(&stdout, 2400),
-        "stderr_tail": truncate_text(&stderr, 2400),
-        "error": if success { Value::Null } else { json!("codex exec did not produce a usable report") },
-    }))
-}
+    let text_value = match args.text.as_deref() {
+        Some("-") => {
+            let mut buf = String::new();
+            io::stdin()
+                .read_to_string(&mut buf)
+                .context("failed to read --text from stdin")?;
+            Some(buf)
+        }
+        Some(value) => Some(value.to_string()),
+        None => None,
+    };
 
-fn truncate_text(text: &str, limit: usize) -> String {
-    if text.chars().count() <= limit {
-        return text.to_string();
+    if let Some(text) = text_value.as_ref() {
+        actions.push(json!({
+            "type": "type",
+            "text": text,
+        }));
     }
-    text.chars().take(limit).collect::<String>() + "\n...<truncated>..."
-}
 
-fn write_text_file(path: &Path, content: &str) -> Result<()> {
-    ensure_parent_dir(path)?;
-    fs::write(path, content).with_context(|| format!("failed to write text: {}", path.display()))
-}
+    let hotkey_spec = if let Some(raw) = args.hotkey.as_deref() {
+        let spec = parse_hotkey_combo(raw)?;
+        actions.push(json!({
+            "type": "hotkey",
+            "combo": spec.combo,
+            "key": spec.key,
+            "modifiers": spec.modifiers,
+            "key_code": spec.key_code,
+        }));
+        Some(spec)
+    } else {
+        None
+    };
 
-#[allow(clippy::too_many_arguments)]
-fn run_diff_internal(
-    baseline_path: &Path,
-    current_path: &Path,
-    diff_out: Option<&Path>,
-    json_out: Option<&Path>,
-    resize: bool,
-    bbox_threshold: u8,
-    bbox_min_area: u32,
-    bbox_pad: u32,
-    max_boxes: usize,
-    annotated_out: Option<&Path>,
-    annotate_spec_out: Option<&Path>,
-) -> Result<DiffRunOutput> {
-    if !baseline_path.exists() {
-        bail!("baseline not found: {}", baseline_path.display());
+    if args.tab > 0 {
+        actions.push(json!({
+            "type": "tab",
+            "count": args.tab,
+        }));
     }
-    if !current_path.exists() {
-        bail!("current not found: {}", current_path.display());
+    if args.enter {
+        actions.push(json!({
+            "type": "enter",
+        }));
+    }
+
+    if actions.is_empty() {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            "no action specified. Use one or more of --click/--click-rel/--text/--hotkey/--tab/--enter",
+        ));
     }
 
-    let baseline_image = image::open(baseline_path)
-        .with_context(|| format!("failed to open baseline image: {}", baseline_path.display()))?;
-    let mut current_image = image::open(current_path)
-        .with_context(|| format!("failed to open current image: {}", current_path.display()))?;
+    let mut activation_diag = QueryDiagnostic {
+        ok: true,
+        attempts: 0,
+        error_code: None,
+        message: Some("activation skipped".to_string()),
+    };
+    let mut execution = Vec::<Value>::new();
+    let mut ok = true;
 
-    let mut resized = false;
-    if baseline_image.dimensions() != current_image.dimensions() {
-        if resize {
-            let (w, h) = baseline_image.dimensions();
-            current_image = current_image.resize_exact(w, h, FilterType::Lanczos3);
-            resized = true;
-        } else {
-            bail!("image sizes differ. Re-run with --resize to match baseline size.");
+    if !args.dry_run {
+        if !cfg!(target_os = "macos") {
+            return Err(cli_failure(
+                EXIT_UNSUPPORTED,
+                "act command requires macOS for UI automation",
+            ));
+        }
+        if !args.no_activate {
+            activation_diag = activate_process_window(&process);
+            if !activation_diag.ok {
+                ok = false;
+                warnings.warn(
+                    "activation_failed",
+                    "app activation failed; subsequent actions may fail",
+                );
+            }
+            if args.activation_delay_ms > 0 {
+                thread::sleep(Duration::from_millis(args.activation_delay_ms.min(5000)));
+            }
         }
     }
 
-    let baseline_rgba = baseline_image.to_rgba8();
-    let current_rgba = current_image.to_rgba8();
-    let (width, height) = baseline_rgba.dimensions();
-
-    let total_pixels = (width as u64) * (height as u64);
-    let mut changed_pixels: u64 = 0;
-    let mut diff_sum: u64 = 0;
-    let mut gray = vec![0u8; (width * height) as usize];
-
-    for y in 0..height {
-        for x in 0..width {
-            let idx = (y * width + x) as usize;
-            let a = baseline_rgba.get_pixel(x, y).channels();
-            let b = current_rgba.get_pixel(x, y).channels();
-
-            let dr = (a[0] as i16 - b[0] as i16).unsigned_abs() as u8;
-            let dg = (a[1] as i16 - b[1] as i16).unsigned_abs() as u8;
-            let db = (a[2] as i16 - b[2] as i16).unsigned_abs() as u8;
-            let diff_v = dr.max(dg).max(db);
-            gray[idx] = diff_v;
-            diff_sum += diff_v as u64;
-            if diff_v > 0 {
-                changed_pixels += 1;
+    if let Some((x, y)) = click_abs {
+        if args.dry_run {
+            execution.push(json!({
+                "type": "click",
+                "status": "planned",
+                "x": x,
+                "y": y,
+            }));
+        } else {
+            let script = format!("tell application \"System Events\" to click at {{{x}, {y}}}");
+            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
+            if !diag.ok {
+                ok = false;
             }
+            execution.push(json!({
+                "type": "click",
+                "status": if diag.ok { "ok" } else { "error" },
+                "x": x,
+                "y": y,
+                "query": diag,
+            }));
         }
     }
 
-    let regions = extract_change_regions(
-        &gray,
-        width,
-        height,
-        bbox_threshold,
-        bbox_min_area,
-        bbox_pad,
-        max_boxes,
-    );
-
-    if let Some(path) = diff_out {
-        write_diff_overlay(&current_rgba, &gray, width, height, path)?;
+    if let Some(text) = text_value {
+        if args.dry_run {
+            execution.push(json!({
+                "type": "type",
+                "status": "planned",
+                "text": text,
+            }));
+        } else {
+            let escaped = escape_osascript_string(&text);
+            let script = format!("tell application \"System Events\" to keystroke \"{escaped}\"");
+            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
+            if !diag.ok {
+                ok = false;
+            }
+            execution.push(json!({
+                "type": "type",
+                "status": if diag.ok { "ok" } else { "error" },
+                "text": text,
+                "query": diag,
+            }));
+        }
     }
 
-    let annotate_spec = build_annotate_spec(&regions);
-
-    if let Some(path) = annotate_spec_out {
-        write_json_pretty(path, &annotate_spec)?;
+    if let Some(spec) = hotkey_spec {
+        if args.dry_run {
+            execution.push(json!({
+                "type": "hotkey",
+                "status": "planned",
+                "combo": spec.combo,
+                "key": spec.key,
+                "modifiers": spec.modifiers,
+                "key_code": spec.key_code,
+            }));
+        } else {
+            let script = hotkey_script(&spec);
+            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
+            if !diag.ok {
+                ok = false;
+            }
+            execution.push(json!({
+                "type": "hotkey",
+                "status": if diag.ok { "ok" } else { "error" },
+                "combo": spec.combo,
+                "key": spec.key,
+                "modifiers": spec.modifiers,
+                "key_code": spec.key_code,
+                "query": diag,
+            }));
+        }
     }
 
-    if let Some(path) = annotated_out {
-        let mut annotated = current_rgba.clone();
-        for region in &regions {
-            draw_rect_outline(
-                &mut annotated,
-                region.x,
-                region.y,
-                region.w,
-                region.h,
-                Rgba([255, 69, 58, 255]),
-                3,
+    if args.tab > 0 {
+        if args.dry_run {
+            execution.push(json!({
+                "type": "tab",
+                "status": "planned",
+                "count": args.tab,
+            }));
+        } else {
+            let script = format!(
+                "tell application \"System Events\"\nrepeat {} times\nkey code 48\nend repeat\nend tell",
+                args.tab
             );
+            let diag = run_osascript_exec_with_retry(&script, &[], 2, 80);
+            if !diag.ok {
+                ok = false;
+            }
+            execution.push(json!({
+                "type": "tab",
+                "status": if diag.ok { "ok" } else { "error" },
+                "count": args.tab,
+                "query": diag,
+            }));
         }
-        ensure_parent_dir(path)?;
-        DynamicImage::ImageRgba8(annotated)
-            .save(path)
-            .with_context(|| format!("failed to save annotated image: {}", path.display()))?;
     }
 
-    let percent_changed = if total_pixels > 0 {
-        (changed_pixels as f64 / total_pixels as f64) * 100.0
-    } else {
-        0.0
-    };
-    let avg_diff_percent = if total_pixels > 0 {
-        (diff_sum as f64 / (255.0 * total_pixels as f64)) * 100.0
-    } else {
-        0.0
-    };
+    if args.enter {
+        if args.dry_run {
+            execution.push(json!({
+                "type": "enter",
+                "status": "planned",
+            }));
+        } else {
+            let script = "tell application \"System Events\" to key code 36";
+            let diag = run_osascript_exec_with_retry(script, &[], 2, 80);
+            if !diag.ok {
+                ok = false;
+            }
+            execution.push(json!({
+                "type": "enter",
+                "status": if diag.ok { "ok" } else { "error" },
+                "query": diag,
+            }));
+        }
+    }
 
-    let result = json!({
-        "baseline": abs_path(baseline_path).display().to_string(),
-        "current": abs_path(current_path).display().to_string(),
-        "diff_image": diff_out.map(|p| abs_path(p).display().to_string()),
-        "annotated_image": annotated_out.map(|p| abs_path(p).display().to_string()),
-        "annotate_spec": annotate_spec_out.map(|p| abs_path(p).display().to_string()),
-        "percent_changed": round_to(percent_changed, 3),
-        "avg_diff_percent": round_to(avg_diff_percent, 3),
-        "size": {"width": width, "height": height},
-        "resized": resized,
-        "change_regions": regions,
-        "change_region_count": regions.len(),
+    warnings.note_hidden(output_opts);
+    let payload = json!({
+        "captured_at": timestamp_iso(),
+        "process_name": process,
+        "dry_run": args.dry_run,
+        "ok": ok,
+        "actions": actions,
+        "execution": execution,
+        "click_rel": click_rel_payload,
+        "window_probe": {
+            "selected_window": selected_window,
+            "query": query_window_diag,
+        },
+        "activation": activation_diag,
+        "warnings": warnings.to_value(output_opts),
     });
 
-    if let Some(path) = json_out {
-        write_json_pretty(path, &result)?;
+    if args.json {
+        println!("{}", serde_json::to_string(&payload)?);
+    } else if ok {
+        println!("ok");
+    } else {
+        println!("{}", serde_json::to_string_pretty(&payload)?);
     }
 
-    Ok(DiffRunOutput { json: result })
-}
-
-fn extract_change_regions(
-    gray: &[u8],
-    width: u32,
-    height: u32,
-    threshold: u8,
-    min_pixels: u32,
-    pad: u32,
-    max_boxes: usize,
-) -> Vec<ChangeRegion> {
-    let total = (width * height) as usize;
-    let mut active = vec![false; total];
-    let mut visited = vec![false; total];
-
-    for (idx, val) in gray.iter().enumerate() {
-        if *val > threshold {
-            active[idx] = true;
-        }
+    if !args.dry_run && !ok {
+        return Err(cli_failure(EXIT_ACTION, "one or more actions failed"));
     }
 
-    let mut raw_regions: Vec<(u32, u32, u32, u32, u32)> = Vec::new();
-
-    for y in 0..height {
-        for x in 0..width {
-            let start = (y * width + x) as usize;
-            if visited[start] || !active[start] {
-                continue;
-            }
+    Ok(())
+}
 
-            let mut queue = std::collections::VecDeque::new();
-            queue.push_back(start);
-            visited[start] = true;
+fn command_explain_app(args: ExplainArgs, output_opts: &OutputOptions) -> Result<()> {
+    let report_template = match args.report_template.as_deref() {
+        Some(path) => Some(fs::read_to_string(path).map_err(|err| {
+            cli_failure(
+                EXIT_INPUT,
+                format!("failed to read --report-template {}: {err}", path.display()),
+            )
+        })?),
+        None => None,
+    };
 
-            let mut minx = x;
-            let mut maxx = x;
-            let mut miny = y;
-            let mut maxy = y;
-            let mut count: u32 = 0;
+    let process = args
+        .process
+        .clone()
+        .or_else(env_process_name)
+        .or_else(frontmost_app_name)
+        .unwrap_or_else(|| "app".to_string());
 
-            while let Some(node) = queue.pop_front() {
-                let cx = (node as u32) % width;
-                let cy = (node as u32) / width;
-                count += 1;
+    let out_root = args.out_dir.clone().unwrap_or_else(out_root);
+    let explain_dir = out_root.join("explain");
+    fs::create_dir_all(&explain_dir)
+        .with_context(|| format!("failed to create explain dir: {}", explain_dir.display()))?;
 
-                if cx < minx {
-                    minx = cx;
-                }
-                if cx > maxx {
-                    maxx = cx;
-                }
-                if cy < miny {
-                    miny = cy;
-                }
-                if cy > maxy {
-                    maxy = cy;
-                }
+    let slug = slugify(&process);
+    let run_id = format!(
+        "{}-{}-{}",
+        timestamp_compact(),
+        std::process::id(),
+        rand::thread_rng().gen_range(1000..9999)
+    );
+    let base = format!("explain-{slug}-{run_id}");
 
-                if cx > 0 {
-                    let left = node - 1;
-                    if active[left] && !visited[left] {
-                        visited[left] = true;
-                        queue.push_back(left);
-                    }
-                }
-                if cx + 1 < width {
-                    let right = node + 1;
-                    if active[right] && !visited[right] {
-                        visited[right] = true;
-                        queue.push_back(right);
-                    }
-                }
-                if cy > 0 {
-                    let up = node - width as usize;
-                    if active[up] && !visited[up] {
-                        visited[up] = true;
-                        queue.push_back(up);
-                    }
-                }
-                if cy + 1 < height {
-                    let down = node + width as usize;
-                    if active[down] && !visited[down] {
-                        visited[down] = true;
-                        queue.push_back(down);
-                    }
-                }
-            }
+    let image_path = explain_dir.join(format!("{base}-capture.png"));
+    let packet_path = args
+        .packet_out
+        .clone()
+        .unwrap_or_else(|| explain_dir.join(format!("{base}-packet.json")));
+    let prompt_path = args
+        .prompt_out
+        .clone()
+        .unwrap_or_else(|| explain_dir.join(format!("{base}-prompt.txt")));
+    let report_path = args
+        .report
+        .clone()
+        .unwrap_or_else(|| explain_dir.join(format!("{base}-report.md")));
+    let codex_log_path = explain_dir.join(format!("{base}-codex.log"));
 
-            if count < min_pixels.max(1) {
-                continue;
-            }
+    let capture = capture_internal(
+        &image_path,
+        Some(process.clone()),
+        Some("explain"),
+        Some("explain-app"),
+        Some(&default_sidecar_for(&image_path)),
+        &CaptureOptions {
+            output: output_opts.clone(),
+            redact_secure: args.redact_secure,
+            ..CaptureOptions::default()
+        },
+    )?;
+    let ax = query_ax_tree(&process, args.ax_depth.max(1), &output_opts.scripts);
+    let summary = summarize_ax_elements(&ax.elements);
+
+    let mut warnings = Warnings::default();
+    warnings.extend_from_value(capture.get("warnings"));
+    warnings.0.extend(ax.warnings.0.iter().cloned());
+
+    let ax_payload = json!({
+        "captured_at": timestamp_iso(),
+        "process_name": process,
+        "depth_limit": args.ax_depth,
+        "element_count": ax.elements.len(),
+        "elements": ax.elements,
+        "tree": ax.tree,
+        "query": ax.diagnostics,
+        "warnings": ax.warnings.to_value(output_opts),
+    });
+
+    let mut packet = json!({
+        "packet_version": 1,
+        "generated_at": timestamp_iso(),
+        "process_name": process,
+        "capture": capture,
+        "ax_tree": ax_payload,
+        "summary": summary,
+        "warnings": warnings.to_value(output_opts),
+    });
 
-            raw_regions.push((minx, miny, maxx, maxy, count));
+    let original_tokens =
+        estimate_prompt_tokens(&build_explain_prompt(&packet, args.prompt.as_deref()));
+    let mut prompt_budget = json!({
+        "estimated_tokens": original_tokens,
+        "budget": args.prompt_token_budget,
+        "trimmed": false,
+    });
+    if args.prompt_token_budget > 0 && original_tokens > args.prompt_token_budget {
+        if let Some((before, after)) = trim_packet_ax_for_prompt(&mut packet) {
+            let trimmed_tokens =
+                estimate_prompt_tokens(&build_explain_prompt(&packet, args.prompt.as_deref()));
+            let mut warning = format!(
+                "prompt estimate {original_tokens} tokens exceeded budget {}; trimmed AX elements {before} -> {after} (named/interactive only), now ~{trimmed_tokens} tokens",
+                args.prompt_token_budget
+            );
+            if trimmed_tokens > args.prompt_token_budget {
+                warning.push_str("; still over budget");
+            }
+            eprintln!("warning: {warning}");
+            warnings.info("prompt_trimmed", warning);
+            if let Some(obj) = packet.as_object_mut() {
+                obj.insert("warnings".to_string(), warnings.to_value(output_opts));
+            }
+            prompt_budget = json!({
+                "estimated_tokens": trimmed_tokens,
+                "original_estimated_tokens": original_tokens,
+                "budget": args.prompt_token_budget,
+                "trimmed": true,
+                "original_element_count": before,
+                "kept_element_count": after,
+            });
         }
     }
-
-    raw_regions.sort_by(|a, b| b.4.cmp(&a.4));
-    if max_boxes > 0 && raw_regions.len() > max_boxes {
-        raw_regions.truncate(max_boxes);
+    warnings.note_hidden(output_opts);
+    if let Some(obj) = packet.as_object_mut() {
+        obj.insert("prompt_budget".to_string(), prompt_budget.clone());
     }
+    let prompt_text = build_explain_prompt(&packet, args.prompt.as_deref());
 
-    let mut regions = Vec::new();
+    write_json_pretty(&packet_path, &packet)?;
+    write_text_file(&prompt_path, &prompt_text)?;
 
-    for (idx, (minx, miny, maxx, maxy, pixels)) in raw_regions.into_iter().enumerate() {
-        let x0 = minx.saturating_sub(pad);
-        let y0 = miny.saturating_sub(pad);
-        let x1 = (maxx + pad).min(width.saturating_sub(1));
-        let y1 = (maxy + pad).min(height.saturating_sub(1));
+    let mut mode = "fallback".to_string();
+    let mut fallback_reason: Option<String> = None;
+    let mut codex_meta = json!({
+        "attempted": false,
+        "success": false,
+    });
 
-        let box_w = (x1.saturating_sub(x0)) + 1;
-        let box_h = (y1.saturating_sub(y0)) + 1;
-        let area = box_w.saturating_mul(box_h);
+    if args.no_codex {
+        fallback_reason = Some("Codex execution disabled via --no-codex".to_string());
+    } else if let Some(codex_bin) = resolve_codex_executable(args.codex_bin.as_deref()) {
+        codex_meta = run_codex_exec_report(
+            &codex_bin,
+            &prompt_text,
+            &image_path,
+            &report_path,
+            &codex_log_path,
+            args.model.as_deref(),
+            args.codex_timeout,
+        )?;
 
-        let coverage = if area > 0 {
-            round_to(pixels as f64 / area as f64, 4)
+        if codex_meta
+            .get("success")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+        {
+            mode = "llm".to_string();
         } else {
-            0.0
-        };
-
-        regions.push(ChangeRegion {
-            x: x0,
-            y: y0,
-            w: box_w,
-            h: box_h,
-            x2: x0 + box_w,
-            y2: y0 + box_h,
-            pixels,
-            area,
-            coverage,
-            intent: "changed-region".to_string(),
-            action: "inspect".to_string(),
-            id: format!("change-{}", idx + 1),
-            rel: RegionRel {
-                x: if width > 0 {
-                    round_to(x0 as f64 / width as f64, 6)
-                } else {
-                    0.0
-                },
-                y: if height > 0 {
-                    round_to(y0 as f64 / height as f64, 6)
-                } else {
-                    0.0
-                },
-                w: if width > 0 {
-                    round_to(box_w as f64 / width as f64, 6)
-                } else {
-                    0.0
-                },
-                h: if height > 0 {
-                    round_to(box_h as f64 / height as f64, 6)
-                } else {
-                    0.0
-                },
-            },
-        });
+            fallback_reason = Some(
+                codex_meta
+                    .get("error")
+                    .and_then(Value::as_str)
+                    .unwrap_or("codex exec did not produce a usable report")
+                    .to_string(),
+            );
+        }
+    } else {
+        fallback_reason = Some("Codex CLI not found on PATH (`codex`/`codex-auto`)".to_string());
     }
 
-    regions
-}
+    let codex_logs = args
+        .attach_logs
+        .then(|| codex_log_attachment(&codex_meta))
+        .flatten();
 
-fn build_annotate_spec(regions: &[ChangeRegion]) -> Value {
-    let mut annotations = Vec::new();
+    if mode != "llm" {
+        if args.strict_llm && !args.no_codex {
+            let payload = json!({
+                "mode": "error",
+                "error": fallback_reason,
+                "packet_path": abs_path(&packet_path).display().to_string(),
+                "prompt_path": abs_path(&prompt_path).display().to_string(),
+                "report_path": abs_path(&report_path).display().to_string(),
+                "codex": codex_meta,
+                "codex_logs": codex_logs,
+            });
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&payload)?);
+            }
+            return Err(cli_failure(
+                EXIT_CODEX,
+                payload
+                    .get("error")
+                    .and_then(Value::as_str)
+                    .unwrap_or("strict_llm enabled and codex execution failed"),
+            ));
+        }
 
-    for (idx, region) in regions.iter().enumerate() {
-        let rect_id = region.id.clone();
-        annotations.push(json!({
-            "type": "rect",
-            "id": rect_id,
-            "x": region.x,
-            "y": region.y,
-            "w": region.w,
-            "h": region.h,
-            "color": "#FF453A",
-            "width": 3,
-            "intent": "changed-region",
-            "action": "inspect",
-        }));
-        annotations.push(json!({
-            "type": "text",
-            "text": format!("Δ{}", idx + 1),
-            "anchor": region.id,
-            "anchor_pos": "top_left",
-            "anchor_offset": [0, -18],
-            "color": "#FFFFFF",
-            "text_bg": "rgba(255,69,58,0.78)",
-            "intent": "change-label",
-            "action": "review-diff",
-        }));
+        let fallback_markdown = build_fallback_explain_report(
+            &packet,
+            fallback_reason
+                .as_deref()
+                .unwrap_or("codex execution unavailable"),
+            &codex_meta,
+            codex_logs.as_ref(),
+            report_template
+                .as_deref()
+                .unwrap_or(DEFAULT_FALLBACK_REPORT_TEMPLATE),
+            output_opts.locale,
+        );
+        write_text_file(&report_path, &fallback_markdown)?;
     }
 
-    json!({
-        "defaults": {
-            "auto_scale": true,
-            "outline": true,
-            "text_bg": "rgba(0,0,0,0.6)",
-        },
-        "annotations": annotations,
-    })
+    let output = json!({
+        "mode": mode,
+        "process_name": process,
+        "packet_path": abs_path(&packet_path).display().to_string(),
+        "prompt_path": abs_path(&prompt_path).display().to_string(),
+        "report_path": abs_path(&report_path).display().to_string(),
+        "codex": codex_meta,
+        "codex_logs": codex_logs,
+        "fallback_reason": fallback_reason,
+        "report_template": args.report_template.as_deref().map(|p| abs_path(p).display().to_string()),
+        "prompt_budget": prompt_budget,
+    });
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!("{}", abs_path(&report_path).display());
+    }
+
+    Ok(())
 }
 
-fn write_diff_overlay(
-    current: &RgbaImage,
-    gray: &[u8],
-    width: u32,
-    height: u32,
-    out_path: &Path,
-) -> Result<()> {
-    let mut out = current.clone();
+fn summarize_ax_elements(elements: &[Value]) -> Value {
+    let mut named_elements = 0usize;
+    let mut interactive_guess_count = 0usize;
+    let mut role_counts: std::collections::BTreeMap<String, usize> =
+        std::collections::BTreeMap::new();
 
-    for y in 0..height {
-        for x in 0..width {
-            let idx = (y * width + x) as usize;
-            let alpha = gray[idx] as f32 / 255.0;
-            if alpha <= 0.0 {
-                continue;
-            }
-            let base = out.get_pixel(x, y).0;
-            let red = [255f32, 0f32, 0f32];
-            let blended = [
-                ((1.0 - alpha) * base[0] as f32 + alpha * red[0]).round() as u8,
-                ((1.0 - alpha) * base[1] as f32 + alpha * red[1]).round() as u8,
-                ((1.0 - alpha) * base[2] as f32 + alpha * red[2]).round() as u8,
-                base[3],
-            ];
-            out.put_pixel(x, y, Rgba(blended));
+    for element in elements {
+        let Some(obj) = element.as_object() else {
+            continue;
+        };
+        if obj
+            .get("name")
+            .and_then(Value::as_str)
+            .map(|s| !s.trim().is_empty())
+            .unwrap_or(false)
+        {
+            named_elements += 1;
+        }
+
+        let role = obj
+            .get("role_description")
+            .and_then(Value::as_str)
+            .or_else(|| obj.get("class").and_then(Value::as_str))
+            .unwrap_or("unknown")
+            .trim()
+            .to_string();
+        *role_counts.entry(role.clone()).or_insert(0usize) += 1;
+
+        if ax_element_is_interactive(obj) {
+            interactive_guess_count += 1;
         }
     }
 
-    ensure_parent_dir(out_path)?;
-    DynamicImage::ImageRgba8(out)
-        .save(out_path)
-        .with_context(|| format!("failed to save diff image: {}", out_path.display()))?;
-    Ok(())
+    let mut top_roles: Vec<(String, usize)> = role_counts.into_iter().collect();
+    top_roles.sort_by_key(|item| std::cmp::Reverse(item.1));
+    top_roles.truncate(8);
+
+    json!({
+        "element_count": elements.len(),
+        "named_elements": named_elements,
+        "interactive_guess_count": interactive_guess_count,
+        "top_roles": top_roles.into_iter().map(|(role, count)| json!({"role": role, "count": count})).collect::<Vec<Value>>(),
+    })
 }
 
-fn draw_rect_outline(
-    img: &mut RgbaImage,
-    x: u32,
-    y: u32,
-    w: u32,
-    h: u32,
-    color: Rgba<u8>,
-    thickness: u32,
-) {
-    if w == 0 || h == 0 {
-        return;
-    }
-    let img_w = img.width();
-    let img_h = img.height();
+fn ax_element_is_interactive(obj: &Map<String, Value>) -> bool {
+    let role = obj
+        .get("role_description")
+        .and_then(Value::as_str)
+        .or_else(|| obj.get("class").and_then(Value::as_str))
+        .unwrap_or("unknown")
+        .trim()
+        .to_ascii_lowercase();
+    let haystack = format!(
+        "{} {}",
+        role,
+        obj.get("class")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    );
+    [
+        "button", "checkbox", "menu", "tab", "slider", "text", "field", "link",
+    ]
+    .iter()
+    .any(|token| haystack.contains(token))
+}
 
-    let x0 = x.min(img_w.saturating_sub(1));
-    let y0 = y.min(img_h.saturating_sub(1));
-    let x1 = (x + w.saturating_sub(1)).min(img_w.saturating_sub(1));
-    let y1 = (y + h.saturating_sub(1)).min(img_h.saturating_sub(1));
+fn estimate_prompt_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
 
-    for t in 0..thickness.max(1) {
-        let tx0 = x0.saturating_sub(t);
-        let ty0 = y0.saturating_sub(t);
-        let tx1 = (x1 + t).min(img_w.saturating_sub(1));
-        let ty1 = (y1 + t).min(img_h.saturating_sub(1));
+fn trim_packet_ax_for_prompt(packet: &mut Value) -> Option<(usize, usize)> {
+    let ax = packet.get_mut("ax_tree")?.as_object_mut()?;
+    let elements = ax.get("elements")?.as_array()?.clone();
+    let kept: Vec<Value> = elements
+        .iter()
+        .filter(|element| {
+            element.as_object().is_some_and(|obj| {
+                let named = obj
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .map(|s| !s.trim().is_empty())
+                    .unwrap_or(false);
+                named || ax_element_is_interactive(obj)
+            })
+        })
+        .cloned()
+        .collect();
+    let counts = (elements.len(), kept.len());
+    ax.insert("elements".to_string(), Value::Array(kept));
+    ax.insert("tree".to_string(), Value::Array(Vec::new()));
+    ax.insert("trimmed_for_prompt".to_string(), json!(true));
+    Some(counts)
+}
 
-        for xx in tx0..=tx1 {
-            img.put_pixel(xx, ty0, color);
-            img.put_pixel(xx, ty1, color);
-        }
-        for yy in ty0..=ty1 {
-            img.put_pixel(tx0, yy, color);
-            img.put_pixel(tx1, yy, color);
+fn build_explain_prompt(packet: &Value, extra_prompt: Option<&str>) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "You are analyzing one screenshot of a macOS app and companion accessibility metadata.\n",
+    );
+    out.push_str("Write a detailed UI explanation report in Markdown.\n\n");
+    out.push_str("Required sections:\n");
+    out.push_str("1) Executive summary\n");
+    out.push_str("2) Visual/layout walkthrough (top-to-bottom)\n");
+    out.push_str("3) Interactive elements and likely affordances\n");
+    out.push_str("4) Accessibility and usability observations\n");
+    out.push_str("5) Risks/unknowns\n");
+    out.push_str("6) Recommended next actions\n\n");
+    out.push_str("If something is uncertain, explicitly say so.\n");
+    if let Some(extra) = extra_prompt {
+        if !extra.trim().is_empty() {
+            out.push_str("\nExtra user instructions:\n");
+            out.push_str(extra.trim());
+            out.push('\n');
         }
     }
+    out.push_str("\nContext packet JSON:\n");
+    out.push_str(&serde_json::to_string_pretty(packet).unwrap_or_else(|_| "{}".to_string()));
+    out.push('\n');
+    out
 }
 
-fn capture_internal(
-    out_path: &Path,
-    process: Option<String>,
-    step: Option<&str>,
-    note: Option<&str>,
-    sidecar: Option<&Path>,
-) -> Result<Value> {
-    ensure_parent_dir(out_path)?;
+const DEFAULT_FALLBACK_REPORT_TEMPLATE: &str = "# App Explanation Report (fallback)
 
-    let process_name = process
-        .clone()
-        .or_else(frontmost_app_name)
-        .unwrap_or_else(|| "app".to_string());
-    let app_slug = slugify(&process_name);
+- Generated at: {generated_at}
+- Process: {process}
+- Screenshot: {screenshot}
 
-    let mut x: i64 = 0;
-    let mut y: i64 = 0;
-    let mut w: i64 = 0;
-    let mut h: i64 = 0;
-    let mut window_title: Option<String> = None;
-    let mut selected_window_index: Option<usize> = None;
-    let mut candidate_count: usize = 0;
-    let mut usable_count: usize = 0;
-    let mut selection_mode = "none".to_string();
-    let mut selected_window_usable = false;
-    let mut usable_min_w: i64 = 0;
-    let mut usable_min_h: i64 = 0;
-    let mut usable_min_area: i64 = 0;
+## Why fallback mode was used
+{reason}
 
-    let mut captured = false;
-    let mut capture_mode = "fallback".to_string();
-    let mut warnings: Vec<String> = Vec::new();
-    let (query_window_diag, activation_diag) = if cfg!(target_os = "macos") {
-        let activation_diag = activate_process_window(&process_name);
-        let probe = query_window_probe(&process_name);
-        let query_window_diag = probe.diagnostics.clone();
-        if probe.diagnostics.ok {
-            x = probe.x;
-            y = probe.y;
-            w = probe.w;
-            h = probe.h;
-            window_title = probe.title;
-            selected_window_index = probe.selected_index;
-            candidate_count = probe.candidate_count;
-            usable_count = probe.usable_count;
-            selection_mode = probe.selection_mode.clone();
-            selected_window_usable = probe.usable;
-            usable_min_w = probe.min_width;
-            usable_min_h = probe.min_height;
-            usable_min_area = probe.min_area;
-        }
+## AX summary
+- Element count: {element_count}
+- Named elements: {named_elements}
+- Interactive guess count: {interactive_guess_count}{top_roles_section}{codex_attempt_section}
+";
 
-        if !query_window_diag.ok {
-            if let Some(code) = query_window_diag.error_code.as_deref() {
-                warnings.push(format!("window_query:{code}"));
-            }
-            if let Some(message) = query_window_diag.message.as_deref() {
-                warnings.push(format!("window query unavailable: {message}"));
-            }
+// --attach-logs: the return code, log path and stdout/stderr tails of an attempted codex exec,
+// as already truncated by run_codex_exec_report. None when codex never ran.
+fn codex_log_attachment(codex_meta: &Value) -> Option<Value> {
+    if !codex_meta
+        .get("attempted")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let field = |key: &str| codex_meta.get(key).cloned().unwrap_or(Value::Null);
+    Some(json!({
+        "returncode": field("returncode"),
+        "log_path": field("log_path"),
+        "stdout_tail": field("stdout_tail"),
+        "stderr_tail": field("stderr_tail"),
+    }))
+}
+
+// A backtick fence longer than any backtick run in `content`, so the block cannot end early.
+fn markdown_fence(content: &str) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn build_fallback_explain_report(
+    packet: &Value,
+    reason: &str,
+    codex_meta: &Value,
+    codex_logs: Option<&Value>,
+    template: &str,
+    locale: NumberLocale,
+) -> String {
+    let summary = packet.get("summary").cloned().unwrap_or_else(|| json!({}));
+    let capture = packet.get("capture").cloned().unwrap_or_else(|| json!({}));
+    let text = |value: Option<&Value>| {
+        value
+            .and_then(Value::as_str)
+            .unwrap_or("unknown")
+            .to_string()
+    };
+    let count = |key: &str| {
+        let n = summary.get(key).and_then(Value::as_u64).unwrap_or(0);
+        locale.format(n as f64)
+    };
+
+    let roles: Option<Vec<String>> =
+        summary
+            .get("top_roles")
+            .and_then(Value::as_array)
+            .map(|roles| {
+                roles
+                    .iter()
+                    .map(|role| {
+                        format!(
+                            "  - {}: {}",
+                            role.get("role")
+                                .and_then(Value::as_str)
+                                .unwrap_or("unknown"),
+                            locale.format(
+                                role.get("count").and_then(Value::as_u64).unwrap_or(0) as f64
+                            )
+                        )
+                    })
+                    .collect()
+            });
+    // The *_section forms carry their own leading line breaks and vanish when there is nothing
+    // to show, which is how the built-in template keeps the original report layout.
+    let top_roles_section = roles
+        .as_ref()
+        .map(|roles| {
+            std::iter::once("\n- Top roles:".to_string())
+                .chain(roles.iter().map(|role| format!("\n{role}")))
+                .collect::<String>()
+        })
+        .unwrap_or_default();
+    let roles = roles.unwrap_or_default();
+    let top_roles = if roles.is_empty() {
+        "  - none".to_string()
+    } else {
+        roles.join("\n")
+    };
+    let mut codex_attempt = codex_meta
+        .get("error")
+        .and_then(Value::as_str)
+        .map(|error| format!("## Codex attempt\n- Error: {error}"))
+        .unwrap_or_default();
+    if let (false, Some(logs)) = (codex_attempt.is_empty(), codex_logs) {
+        if let Some(code) = logs.get("returncode").and_then(Value::as_i64) {
+            codex_attempt.push_str(&format!("\n- Return code: {code}"));
         }
-        if !activation_diag.ok {
-            if let Some(code) = activation_diag.error_code.as_deref() {
-                warnings.push(format!("activate:{code}"));
-            }
-            if let Some(message) = activation_diag.message.as_deref() {
-                warnings.push(format!("activation note: {message}"));
-            }
+        if let Some(path) = logs.get("log_path").and_then(Value::as_str) {
+            codex_attempt.push_str(&format!("\n- Log: {path}"));
         }
-
-        if query_window_diag.ok && w > 0 && h > 0 && command_exists("screencapture") {
-            if selected_window_usable {
-                let region = format!("{x},{y},{w},{h}");
-                if Command::new("screencapture")
-                    .arg("-x")
-                    .arg("-R")
-                    .arg(region)
-                    .arg(out_path)
-                    .status()
-                    .map(|s| s.success())
-                    .unwrap_or(false)
-                {
-                    captured = true;
-                    capture_mode = "window".to_string();
-                } else {
-                    warnings
-                        .push("window capture failed; attempting full-screen fallback".to_string());
-                }
-            } else {
-                warnings.push(format!(
-                    "selected window {}x{} is below usable threshold {}x{} / area {}; using full-screen fallback",
-                    w, h, usable_min_w, usable_min_h, usable_min_area
-                ));
+        for (label, key) in [("stdout", "stdout_tail"), ("stderr", "stderr_tail")] {
+            let tail = logs
+                .get(key)
+                .and_then(Value::as_str)
+                .unwrap_or("")
+                .trim_end();
+            if !tail.is_empty() {
+                let fence = markdown_fence(tail);
+                codex_attempt.push_str(&format!("\n\n### {label}\n{fence}text\n{tail}\n{fence}"));
             }
         }
+    }
 
-        if !captured && command_exists("screencapture") {
-            captured = Command::new("screencapture")
-                .arg("-x")
-                .arg(out_path)
-                .status()
-                .map(|s| s.success())
-                .unwrap_or(false);
-            if captured {
-                capture_mode = "screen".to_string();
-                warnings.push(
-                    "window-bounds capture unavailable; used full-screen capture fallback"
-                        .to_string(),
-                );
-            }
+    let section = |body: &str| {
+        if body.is_empty() {
+            String::new()
+        } else {
+            format!("\n\n{body}")
         }
-        (query_window_diag, activation_diag)
-    } else {
-        warnings.push("window capture uses placeholder on non-macOS hosts".to_string());
-        let query_window_diag = QueryDiagnostic {
-            ok: false,
-            attempts: 0,
-            error_code: Some("unsupported_platform".to_string()),
-            message: Some("window queries require macOS System Events".to_string()),
-        };
-        let activation_diag = query_window_diag.clone();
-        (query_window_diag, activation_diag)
     };
+    let vars = [
+        ("generated_at", text(packet.get("generated_at"))),
+        ("process", text(packet.get("process_name"))),
+        ("screenshot", text(capture.get("capture_path"))),
+        ("reason", reason.to_string()),
+        ("element_count", count("element_count")),
+        ("named_elements", count("named_elements")),
+        ("interactive_guess_count", count("interactive_guess_count")),
+        ("top_roles", top_roles),
+        ("top_roles_section", top_roles_section),
+        ("codex_attempt_section", section(&codex_attempt)),
+        ("codex_attempt", codex_attempt),
+    ];
+    let mut report = fill_report_template(template, &vars);
+    if !report.ends_with('\n') {
+        report.push('\n');
+    }
+    report
+}
 
-    if !captured {
-        let fallback = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
-            1280,
-            720,
-            Rgba([255, 255, 255, 255]),
-        ));
-        fallback
-            .save(out_path)
-            .with_context(|| format!("failed to write fallback capture: {}", out_path.display()))?;
-        if w == 0 || h == 0 {
-            w = 1280;
-            h = 720;
-        }
-        if window_title.is_none() {
-            window_title = Some("fallback-window".to_string());
+// Replaces `{name}` for known names only, so literal braces (JSON, code) in a template survive.
+fn fill_report_template(template: &str, vars: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let value = after.find('}').and_then(|close| {
+            let name = &after[..close];
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (value, close))
+        });
+        match value {
+            Some((value, close)) => {
+                out.push_str(value);
+                rest = &after[close + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
         }
-        warnings.push("capture failed; generated placeholder image".to_string());
     }
+    out.push_str(rest);
+    out
+}
 
-    let output_img = image::open(out_path)
-        .with_context(|| format!("failed to read capture image: {}", out_path.display()))?;
-    let (image_w, image_h) = output_img.dimensions();
+fn resolve_codex_executable(override_bin: Option<&str>) -> Option<String> {
+    if let Some(bin) = override_bin {
+        let trimmed = bin.trim();
+        if !trimmed.is_empty() {
+            return Some(trimmed.to_string());
+        }
+    }
+    if command_exists("codex") {
+        return Some("codex".to_string());
+    }
+    if command_exists("codex-auto") {
+        return Some("codex-auto".to_string());
+    }
+    None
+}
 
-    let scale_x = if w > 0 {
-        Some(round_to(image_w as f64 / w as f64, 6))
-    } else {
-        None
-    };
-    let scale_y = if h > 0 {
-        Some(round_to(image_h as f64 / h as f64, 6))
-    } else {
-        None
-    };
-    let scale = match (scale_x, scale_y) {
-        (Some(sx), Some(sy)) => Some(round_to((sx + sy) / 2.0, 6)),
-        _ => None,
+fn run_codex_exec_report(
+    codex_bin: &str,
+    prompt_text: &str,
+    image_path: &Path,
+    report_path: &Path,
+    log_path: &Path,
+    model: Option<&str>,
+    timeout_sec: u64,
+) -> Result<Value> {
+    let mut cmd = Command::new(codex_bin);
+    cmd.arg("exec")
+        .arg("--output-last-message")
+        .arg(report_path)
+        .arg("--image")
+        .arg(image_path);
+    if let Some(model) = model {
+        cmd.arg("--model").arg(model);
+    }
+    cmd.arg("-");
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            return Ok(json!({
+                "attempted": true,
+                "success": false,
+                "command": format!("{codex_bin} exec ..."),
+                "error": err.to_string(),
+            }));
+        }
     };
 
-    let sidecar_abs = sidecar.map(abs_path);
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(prompt_text.as_bytes());
+    }
 
-    let payload = json!({
-        "image_path": abs_path(out_path).display().to_string(),
-        "capture_path": abs_path(out_path).display().to_string(),
-        "sidecar_path": sidecar_abs.as_ref().map(|p| p.display().to_string()),
-        "captured_at": timestamp_iso(),
-        "captured_at_epoch_ms": Utc::now().timestamp_millis(),
-        "app_name": process_name,
-        "app_slug": app_slug,
-        "window_title": window_title,
-        "step": step,
-        "note": note,
-        "bounds": {
-            "x": x,
-            "y": y,
-            "w": w,
-            "h": h,
-            "units": "pt",
-        },
-        "image_size": {
-            "w": image_w,
-            "h": image_h,
-            "units": "px",
-        },
-        "scale": scale,
-        "scale_x": scale_x,
-        "scale_y": scale_y,
-        "window": {
-            "x": x,
-            "y": y,
-            "w": w,
-            "h": h,
-            "x2": x + w,
-            "y2": y + h,
-            "units": "px",
-        },
-        "capture_tool": "codex-visual-loop capture",
-        "capture_sidecar_version": 1,
-        "capture_mode": capture_mode,
-        "fallback_used": !captured,
-        "warnings": warnings,
-        "window_probe": {
-            "selected_index": selected_window_index,
-            "selection_mode": selection_mode,
-            "candidate_count": candidate_count,
-            "usable_count": usable_count,
-            "usable": selected_window_usable,
-            "min_width": usable_min_w,
-            "min_height": usable_min_h,
-            "min_area": usable_min_area,
-        },
-        "query": {
-            "activation": activation_diag,
-            "window": query_window_diag,
-        },
+    let timeout = Duration::from_secs(timeout_sec.max(10));
+    let status_opt = child.wait_timeout(timeout).map_err(anyhow::Error::from)?;
+    if status_opt.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Ok(json!({
+            "attempted": true,
+            "success": false,
+            "command": format!("{codex_bin} exec ..."),
+            "error": format!("codex exec timed out after {}s", timeout.as_secs()),
+        }));
+    }
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| "failed to read codex exec output")?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    let log_text = format!(
+        "command: {codex_bin} exec --output-last-message {} --image {} -\nreturncode: {}\n\nstdout:\n{}\n\nstderr:\n{}\n",
+        report_path.display(),
+        image_path.display(),
+        output.status.code().unwrap_or(1),
+        stdout,
+        stderr
+    );
+    write_text_file(log_path, &log_text)?;
+
+    let report_text = fs::read_to_string(report_path).unwrap_or_default();
+    let success = output.status.success() && !report_text.trim().is_empty();
+    Ok(json!({
+        "attempted": true,
+        "success": success,
+        "returncode": output.status.code().unwrap_or(1),
+        "report_path": abs_path(report_path).display().to_string(),
+        "log_path": abs_path(log_path).display().to_string(),
+        "stdout_tail": truncate_text(&stdout, 2400),
+        "stderr_tail": truncate_text(&stderr, 2400),
+        "error": if success { Value::Null } else { json!("codex exec did not produce a usable report") },
+    }))
+}
+
+fn truncate_text(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    text.chars().take(limit).collect::<String>() + "\n...<truncated>..."
+}
+
+fn write_text_file(path: &Path, content: &str) -> Result<()> {
+    ensure_parent_dir(path)?;
+    fs::write(path, content).with_context(|| format!("failed to write text: {}", path.display()))
+}
+
+fn run_diff_internal(
+    baseline_path: &Path,
+    current_path: &Path,
+    diff_out: Option<&Path>,
+    json_out: Option<&Path>,
+    annotated_out: Option<&Path>,
+    annotate_spec_out: Option<&Path>,
+    options: &DiffOptions,
+) -> Result<DiffRunOutput> {
+    if !baseline_path.exists() {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!("baseline not found: {}", baseline_path.display()),
+        ));
+    }
+    if !current_path.exists() {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!("current not found: {}", current_path.display()),
+        ));
+    }
+
+    let started = Instant::now();
+    let baseline = open_image_within_budget(
+        baseline_path,
+        "baseline",
+        options.max_total_pixels,
+        options.downscale_oversize,
+    )?;
+    let current = open_image_within_budget(
+        current_path,
+        "current",
+        options.max_total_pixels,
+        options.downscale_oversize,
+    )?;
+    run_diff_decoded(
+        started,
+        (baseline_path, baseline),
+        (current_path, current),
+        diff_out,
+        json_out,
+        annotated_out,
+        annotate_spec_out,
+        options,
+    )
+}
+
+// The diff proper over already-opened images, each paired with its path and whether it was
+// downscaled to fit the pixel budget; `started` is when decoding began, for the timings.
+#[allow(clippy::too_many_arguments)]
+fn run_diff_decoded(
+    started: Instant,
+    (baseline_path, (baseline_image, baseline_downscaled)): (&Path, (DynamicImage, bool)),
+    (current_path, (current_image, current_downscaled)): (&Path, (DynamicImage, bool)),
+    diff_out: Option<&Path>,
+    json_out: Option<&Path>,
+    annotated_out: Option<&Path>,
+    annotate_spec_out: Option<&Path>,
+    options: &DiffOptions,
+) -> Result<DiffRunOutput> {
+    let mut stage_ms: Vec<(&str, f64)> = Vec::new();
+    let mut lap = {
+        let mut last = started;
+        move |name: &'static str, stages: &mut Vec<(&str, f64)>| {
+            let now = Instant::now();
+            stages.push((name, round_to((now - last).as_secs_f64() * 1000.0, 3)));
+            last = now;
+        }
+    };
+
+    let (baseline_image, mut current_image) = match options.flatten_bg {
+        Some(bg) => (
+            flatten_onto(baseline_image, bg),
+            flatten_onto(current_image, bg),
+        ),
+        None => (baseline_image, current_image),
+    };
+
+    let mut rotated_degrees: Option<u32> = None;
+    if options.orient_auto {
+        let (rotated_image, degrees) = auto_orient(&baseline_image, current_image);
+        current_image = rotated_image;
+        rotated_degrees = degrees;
+    }
+
+    let original_current_size = current_image.dimensions();
+    let mut resized = false;
+    if baseline_image.dimensions() != current_image.dimensions() {
+        if options.resize {
+            let (w, h) = baseline_image.dimensions();
+            current_image =
+                current_image.resize_exact(w, h, resize_filter_type(&options.resize_filter));
+            resized = true;
+        } else {
+            return Err(cli_failure(
+                EXIT_INPUT,
+                "image sizes differ. Re-run with --resize to match baseline size.",
+            ));
+        }
+    }
+
+    let baseline_rgba = baseline_image.into_rgba8();
+    let current_rgba = current_image.into_rgba8();
+    let (width, height) = baseline_rgba.dimensions();
+    lap("decode", &mut stage_ms);
+
+    let shape_channel = options
+        .shape_mode
+        .then(|| match options.shape_channel.as_str() {
+            "auto" => {
+                let opaque = |img: &RgbaImage| img.pixels().all(|p| p[3] == 255);
+                if opaque(&baseline_rgba) && opaque(&current_rgba) {
+                    "luma"
+                } else {
+                    "alpha"
+                }
+            }
+            channel => channel,
+        });
+    let shape_report = shape_channel.map(|channel| {
+        let count = |img: &RgbaImage| {
+            img.pixels()
+                .filter(|p| shape_ink(p, channel, options.shape_threshold))
+                .count()
+        };
+        json!({
+            "channel": channel,
+            "threshold": options.shape_threshold,
+            "baseline_pixels": count(&baseline_rgba),
+            "current_pixels": count(&current_rgba),
+        })
     });
 
-    if let Some(path) = sidecar {
-        write_json_pretty(path, &payload)?;
+    let total_pixels = (width as u64) * (height as u64);
+    let mut changed_pixels: u64 = 0;
+    let mut diff_sum: u64 = 0;
+    let mut diff_sq_sum: u64 = 0;
+    let mut aa_pixels: u64 = 0;
+    let mut gray = vec![0u8; (width * height) as usize];
+
+    let ignore_mask = match options.mask.as_deref() {
+        Some(path) => Some(load_ignore_mask(path, width, height)?),
+        None => None,
+    };
+    let mut masked_pixels: u64 = 0;
+    let mut histogram = options.histogram.then(|| vec![0u64; 256]);
+    let weight_map = match options.weight_map.as_deref() {
+        Some(path) => Some(load_weight_map(path, width, height)?),
+        None => None,
+    };
+    let mut weighted_changed = 0.0f64;
+    let mut weighted_diff = 0.0f64;
+    let row_bytes = width as usize * 4;
+    let mut identical_rows: u32 = 0;
+    // Tiles can't be thinner than a pixel.
+    let tile_grid = options
+        .tiles
+        .map(|(rows, cols)| (rows.min(height).max(1), cols.min(width).max(1)));
+    let mut tile_changed = tile_grid.map(|(rows, cols)| vec![0u64; (rows * cols) as usize]);
+    let mut exceeded_after_rows: Option<u32> = None;
+
+    for y in 0..height {
+        if options.ignore_identical_rows {
+            // Byte-equal rows diff to 0 everywhere: only the mask and histogram need their counts.
+            let bytes = y as usize * row_bytes..(y as usize + 1) * row_bytes;
+            if baseline_rgba.as_raw()[bytes.clone()] == current_rgba.as_raw()[bytes] {
+                identical_rows += 1;
+                let row = (y * width) as usize..((y + 1) * width) as usize;
+                let masked = ignore_mask.as_ref().map_or(0, |(mask, _)| {
+                    mask[row].iter().filter(|ignored| **ignored).count() as u64
+                });
+                masked_pixels += masked;
+                if let Some(bins) = histogram.as_mut() {
+                    bins[0] += u64::from(width) - masked;
+                }
+                continue;
+            }
+        }
+        for x in 0..width {
+            let idx = (y * width + x) as usize;
+            if ignore_mask.as_ref().is_some_and(|(mask, _)| mask[idx]) {
+                masked_pixels += 1;
+                continue;
+            }
+            let a = baseline_rgba.get_pixel(x, y);
+            let b = current_rgba.get_pixel(x, y);
+
+            let mut diff_v = match shape_channel {
+                Some(channel) => {
+                    let threshold = options.shape_threshold;
+                    if shape_ink(a, channel, threshold) == shape_ink(b, channel, threshold) {
+                        0
+                    } else {
+                        255
+                    }
+                }
+                None => pixel_distance(a, b, &options.colorspace),
+            };
+            if diff_v > 0
+                && options.ignore_aa
+                && (is_antialiased(&baseline_rgba, &current_rgba, x, y)
+                    || is_antialiased(&current_rgba, &baseline_rgba, x, y))
+            {
+                aa_pixels += 1;
+                diff_v = 0;
+            }
+            gray[idx] = diff_v;
+            if let Some(bins) = histogram.as_mut() {
+                bins[usize::from(diff_v)] += 1;
+            }
+            diff_sum += diff_v as u64;
+            diff_sq_sum += (diff_v as u64) * (diff_v as u64);
+            if diff_v > 0 {
+                changed_pixels += 1;
+                if let (Some(counts), Some((rows, cols))) = (tile_changed.as_mut(), tile_grid) {
+                    let row = u64::from(y) * u64::from(rows) / u64::from(height);
+                    let col = u64::from(x) * u64::from(cols) / u64::from(width);
+                    counts[(row * u64::from(cols) + col) as usize] += 1;
+                }
+            }
+            if let Some((weights, _)) = weight_map.as_ref() {
+                let weight = f64::from(weights[idx]);
+                weighted_diff += weight * f64::from(diff_v);
+                if diff_v > 0 {
+                    weighted_changed += weight;
+                }
+            }
+        }
+        if options
+            .fail_fast
+            .is_some_and(|limit| changed_pixels as f64 * 100.0 / total_pixels as f64 > limit)
+        {
+            exceeded_after_rows = Some(y + 1);
+            break;
+        }
+    }
+
+    // --fail-fast: already over budget, so skip regions, overlays and the remaining stats. The
+    // result keeps every diff_schema() key; whatever was never computed or written is null.
+    if let (Some(limit), Some(rows)) = (options.fail_fast, exceeded_after_rows) {
+        let skipped_outputs: Vec<&str> = [
+            ("--diff-out", diff_out.is_some()),
+            ("--annotated-out", annotated_out.is_some()),
+            ("--annotate-spec-out", annotate_spec_out.is_some()),
+            ("--regions-collage", options.regions_collage.is_some()),
+            ("--actions-out", options.actions_out.is_some()),
+            ("--tiles-heatmap", options.tiles_heatmap.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(flag, requested)| requested.then_some(flag))
+        .collect();
+        let mut result: Map<String, Value> = diff_schema()["properties"]
+            .as_object()
+            .map(|props| props.keys().map(|key| (key.clone(), Value::Null)).collect())
+            .unwrap_or_default();
+        let known = json!({
+            "baseline": abs_path(baseline_path).display().to_string(),
+            "current": abs_path(current_path).display().to_string(),
+            "identical": false,
+            "changed_pixels": changed_pixels,
+            "percent_changed": round_to(changed_pixels as f64 * 100.0 / total_pixels as f64, 3),
+            "size": {"width": width, "height": height},
+            "resized": resized,
+            "resize_filter": resized.then_some(&options.resize_filter),
+            "flatten_bg": options.flatten_bg.map(color_hex),
+            "downscaled_oversize": {"baseline": baseline_downscaled, "current": current_downscaled},
+            "colorspace": options.colorspace,
+            "mode": if options.shape_mode { "shape" } else { "color" },
+            "min_region_pixels": options
+                .bbox_min_area
+                .max(relative_min_area(options.min_area_percent, width, height)),
+            "orientation": {
+                "mode": if options.orient_auto { "auto" } else { "none" },
+                "rotated_degrees": rotated_degrees,
+            },
+            "change_regions": [],
+            "regions_only": options.regions_only,
+            "fail_fast": {
+                "percent": limit,
+                "exceeded": true,
+                "rows_checked": rows,
+                "pixels_checked": u64::from(rows) * u64::from(width),
+                "skipped_outputs": skipped_outputs,
+            },
+        });
+        if let Value::Object(known) = known {
+            result.extend(known);
+        }
+        let result = Value::Object(result);
+        if let Some(path) = json_out {
+            write_json_pretty(path, &result)?;
+        }
+        return Ok(DiffRunOutput { json: result });
+    }
+    // Only the diff buffer is needed from here; --regions-only also releases the current frame.
+    drop(baseline_rgba);
+    let current_rgba = (!options.regions_only).then_some(current_rgba);
+    lap("compare", &mut stage_ms);
+
+    // A region has to clear both the absolute and the resolution-relative minimum.
+    let min_region_pixels =
+        options
+            .bbox_min_area
+            .max(relative_min_area(options.min_area_percent, width, height));
+    let regions = extract_change_regions(
+        &gray,
+        width,
+        height,
+        options.bbox_threshold,
+        min_region_pixels,
+        options.bbox_pad,
+        options.max_boxes,
+    );
+    lap("regions", &mut stage_ms);
+
+    let cb_sim = options.cb_sim.as_deref();
+    let mut cb_sim_paths: Map<String, Value> = Map::new();
+    if let (Some(path), Some(current_rgba)) = (diff_out, current_rgba.as_ref()) {
+        let overlay = write_diff_overlay(
+            current_rgba,
+            &gray,
+            width,
+            height,
+            options.overlay_threshold,
+            options.overlay_transparent,
+            &options.png_compression,
+            options.verify_output,
+            path,
+        )?;
+        if let Some(kind) = cb_sim {
+            let sim_path = write_cb_sim(
+                &overlay,
+                path,
+                kind,
+                &options.png_compression,
+                options.verify_output,
+            )?;
+            cb_sim_paths.insert("diff_image".to_string(), json!(sim_path));
+        }
+    }
+
+    if let Some(path) = annotate_spec_out {
+        write_json_pretty(
+            path,
+            &build_annotate_spec(&regions, options, (width, height)),
+        )?;
+    }
+
+    if let Some(path) = options.actions_out.as_deref() {
+        let spec = build_annotate_spec(&regions, options, (width, height));
+        let items: Vec<Value> = spec["annotations"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .filter_map(|(idx, ann)| {
+                Some(annotation_meta_item(idx, ann.as_object()?, width, height))
+            })
+            .collect();
+        write_json_pretty(
+            path,
+            &actions_manifest("diff", current_path, (width, height), &items),
+        )?;
+    }
+
+    if let (Some(path), Some(current_rgba)) = (annotated_out, current_rgba.as_ref()) {
+        let mut annotated = current_rgba.clone();
+        for region in &regions {
+            draw_rect_outline(
+                &mut annotated,
+                region.x,
+                region.y,
+                region.w,
+                region.h,
+                Rgba([255, 69, 58, 255]),
+                3,
+            );
+        }
+        if let Some(kind) = cb_sim {
+            let sim_path = write_cb_sim(
+                &annotated,
+                path,
+                kind,
+                &options.png_compression,
+                options.verify_output,
+            )?;
+            cb_sim_paths.insert("annotated_image".to_string(), json!(sim_path));
+        }
+        ensure_parent_dir(path)?;
+        save_png(
+            &DynamicImage::ImageRgba8(annotated),
+            path,
+            &options.png_compression,
+            options.verify_output,
+        )
+        .with_context(|| format!("failed to save annotated image: {}", path.display()))?;
+    }
+    let regions_collage = match (options.regions_collage.as_deref(), current_rgba.as_ref()) {
+        (Some(path), Some(current_rgba)) if !regions.is_empty() => {
+            let sheet = build_regions_collage(current_rgba, &regions);
+            ensure_parent_dir(path)?;
+            save_png(
+                &DynamicImage::ImageRgba8(sheet),
+                path,
+                &options.png_compression,
+                options.verify_output,
+            )
+            .with_context(|| format!("failed to save regions collage: {}", path.display()))?;
+            Some(abs_path(path).display().to_string())
+        }
+        _ => None,
+    };
+    let tiles = match (tile_grid, tile_changed.as_deref()) {
+        (Some(grid), Some(counts)) => Some(tile_report(grid, counts, (width, height))),
+        _ => None,
+    };
+    let tiles_heatmap = match (
+        options.tiles_heatmap.as_deref(),
+        current_rgba.as_ref(),
+        tiles.as_ref(),
+    ) {
+        (Some(path), Some(current_rgba), Some(report)) => {
+            ensure_parent_dir(path)?;
+            save_png(
+                &DynamicImage::ImageRgba8(render_tile_heatmap(current_rgba, report)),
+                path,
+                &options.png_compression,
+                options.verify_output,
+            )
+            .with_context(|| format!("failed to save tile heatmap: {}", path.display()))?;
+            Some(abs_path(path).display().to_string())
+        }
+        _ => None,
+    };
+    lap("outputs", &mut stage_ms);
+    let timings = options.timings.then(|| {
+        let mut map: Map<String, Value> = stage_ms
+            .iter()
+            .map(|(name, ms)| (name.to_string(), json!(ms)))
+            .collect();
+        map.insert(
+            "total".to_string(),
+            json!(round_to(started.elapsed().as_secs_f64() * 1000.0, 3)),
+        );
+        Value::Object(map)
+    });
+
+    let percent_changed = if total_pixels > 0 {
+        (changed_pixels as f64 / total_pixels as f64) * 100.0
+    } else {
+        0.0
+    };
+    let avg_diff_percent = if total_pixels > 0 {
+        (diff_sum as f64 / (255.0 * total_pixels as f64)) * 100.0
+    } else {
+        0.0
+    };
+    // Weighted figures divide by the total weight, so a uniform map reproduces the plain ones.
+    let weight_report = weight_map.as_ref().map(|(weights, resized)| {
+        let total_weight: f64 = weights.iter().map(|w| f64::from(*w)).sum();
+        let weighted_percent = weighted_changed / total_weight * 100.0;
+        let weighted_avg = weighted_diff / (255.0 * total_weight) * 100.0;
+        (
+            weighted_percent,
+            weighted_avg,
+            json!({
+                "path": options.weight_map.as_deref().map(|p| abs_path(p).display().to_string()),
+                "resized": resized,
+                "mean_weight": round_to(total_weight / weights.len().max(1) as f64, 6),
+                "unweighted": {
+                    "percent_changed": round_to(percent_changed, 3),
+                    "avg_diff_percent": round_to(avg_diff_percent, 3),
+                },
+            }),
+        )
+    });
+    let (percent_changed, avg_diff_percent) = match &weight_report {
+        Some((weighted_percent, weighted_avg, _)) => (*weighted_percent, *weighted_avg),
+        None => (percent_changed, avg_diff_percent),
+    };
+
+    let (mae, psnr) = diff_quality_metrics(diff_sum, diff_sq_sum, total_pixels);
+
+    let result = json!({
+        "baseline": abs_path(baseline_path).display().to_string(),
+        "current": abs_path(current_path).display().to_string(),
+        "diff_image": diff_out.map(|p| abs_path(p).display().to_string()),
+        "annotated_image": annotated_out.map(|p| abs_path(p).display().to_string()),
+        "annotate_spec": annotate_spec_out.map(|p| abs_path(p).display().to_string()),
+        "regions_collage": regions_collage,
+        "actions_path": options.actions_out.as_deref().map(|p| abs_path(p).display().to_string()),
+        "cb_sim": cb_sim.map(|kind| json!({
+            "kind": kind,
+            "diff_image": cb_sim_paths.get("diff_image"),
+            "annotated_image": cb_sim_paths.get("annotated_image"),
+        })),
+        "identical": changed_pixels == 0,
+        "changed_pixels": changed_pixels,
+        "percent_changed": round_to(percent_changed, 3),
+        "avg_diff_percent": round_to(avg_diff_percent, 3),
+        "mae": round_to(mae, 4),
+        "psnr_db": psnr.map(|v| round_to(v, 4)),
+        "size": {"width": width, "height": height},
+        "resized": resized,
+        "resize_filter": resized.then_some(&options.resize_filter),
+        "flatten_bg": options.flatten_bg.map(color_hex),
+        "downscaled_oversize": {"baseline": baseline_downscaled, "current": current_downscaled},
+        "colorspace": options.colorspace,
+        "mode": if options.shape_mode { "shape" } else { "color" },
+        "shape": shape_report,
+        "mask": ignore_mask.as_ref().map(|(_, resized)| json!({
+            "path": options.mask.as_deref().map(|p| abs_path(p).display().to_string()),
+            "resized": resized,
+            "ignored_pixels": masked_pixels,
+        })),
+        "weight_map": weight_report.map(|(_, _, report)| report),
+        "min_region_pixels": min_region_pixels,
+        "aa_ignored_pixels": if options.ignore_aa { Some(aa_pixels) } else { None },
+        "identical_rows_skipped": options.ignore_identical_rows.then_some(identical_rows),
+        "tiles": tiles.map(|mut report| {
+            report["heatmap"] = json!(tiles_heatmap);
+            report
+        }),
+        "diff_histogram": histogram.as_deref().map(|bins| diff_histogram_report(bins, options.bbox_threshold)),
+        "orientation": {
+            "mode": if options.orient_auto { "auto" } else { "none" },
+            "rotated_degrees": rotated_degrees,
+        },
+        "current_space": options.current_coords.then(|| json!({
+            "width": original_current_size.0,
+            "height": original_current_size.1,
+            "scale_x": round_to(f64::from(original_current_size.0) / f64::from(width.max(1)), 6),
+            "scale_y": round_to(f64::from(original_current_size.1) / f64::from(height.max(1)), 6),
+        })),
+        "change_regions_current": options.current_coords.then(|| {
+            regions
+                .iter()
+                .map(|region| remap_region(region, (width, height), original_current_size))
+                .collect::<Vec<_>>()
+        }),
+        "change_regions": regions,
+        "change_region_count": regions.len(),
+        "regions_only": options.regions_only,
+        "fail_fast": options.fail_fast.map(|limit| json!({"percent": limit, "exceeded": false})),
+        "timings_ms": timings,
+    });
+
+    if let Some(path) = json_out {
+        write_json_pretty(path, &result)?;
+    }
+
+    Ok(DiffRunOutput { json: result })
+}
+
+// Reads only the (content-sniffed) header first, so oversized inputs are rejected before any
+// pixel buffer exists; the same decoder then decodes the pixels.
+fn open_image_within_budget(
+    path: &Path,
+    label: &str,
+    max_total_pixels: u64,
+    downscale: bool,
+) -> Result<(DynamicImage, bool)> {
+    let context = || format!("failed to open {label} image: {}", path.display());
+    let decoder = image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .with_context(context)?
+        .into_decoder()
+        .with_context(context)?;
+    let (width, height) = image::ImageDecoder::dimensions(&decoder);
+    let total = u64::from(width) * u64::from(height);
+    let oversized = max_total_pixels > 0 && total > max_total_pixels;
+    if oversized && !downscale {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!(
+                "{label} image {width}x{height} ({total} px) exceeds --max-total-pixels {max_total_pixels}; raise the limit or pass --downscale-oversize: {}",
+                path.display()
+            ),
+        ));
+    }
+    let image = DynamicImage::from_decoder(decoder).with_context(context)?;
+    if !oversized {
+        return Ok((image, false));
+    }
+    let factor = (max_total_pixels as f64 / total as f64).sqrt();
+    let target_w = ((f64::from(width) * factor).floor() as u32).max(1);
+    let target_h = ((f64::from(height) * factor).floor() as u32).max(1);
+    Ok((
+        image.resize_exact(target_w, target_h, FilterType::Triangle),
+        true,
+    ))
+}
+
+// Scale a region from the compared (baseline) size into `to` = the current image's own size.
+// Edges round outward so the box still covers every changed pixel.
+fn remap_region(region: &ChangeRegion, from: (u32, u32), to: (u32, u32)) -> ChangeRegion {
+    let sx = f64::from(to.0) / f64::from(from.0.max(1));
+    let sy = f64::from(to.1) / f64::from(from.1.max(1));
+    let x = (f64::from(region.x) * sx).floor() as u32;
+    let y = (f64::from(region.y) * sy).floor() as u32;
+    let x2 = ((f64::from(region.x2) * sx).ceil() as u32).clamp(x + 1, to.0.max(x + 1));
+    let y2 = ((f64::from(region.y2) * sy).ceil() as u32).clamp(y + 1, to.1.max(y + 1));
+    let (w, h) = (x2 - x, y2 - y);
+    ChangeRegion {
+        x,
+        y,
+        w,
+        h,
+        x2,
+        y2,
+        pixels: (f64::from(region.pixels) * sx * sy).round() as u32,
+        area: w.saturating_mul(h),
+        centroid: [
+            round_to(region.centroid[0] * sx, 2),
+            round_to(region.centroid[1] * sy, 2),
+        ],
+        ..region.clone()
+    }
+}
+
+fn parse_tile_grid(raw: Option<&str>) -> Result<Option<(u32, u32)>> {
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+    raw.trim()
+        .to_ascii_lowercase()
+        .split_once(['x', '*'])
+        .and_then(|(rows, cols)| Some((rows.trim().parse().ok()?, cols.trim().parse().ok()?)))
+        .filter(|&(rows, cols): &(u32, u32)| rows > 0 && cols > 0)
+        .map(Some)
+        .ok_or_else(|| {
+            cli_failure(
+                EXIT_INPUT,
+                format!("invalid --tiles {raw:?}: expected ROWSxCOLS such as 4x6"),
+            )
+        })
+}
+
+// Pixel x of a `size`-wide image lands in tile x * count / size, so tile `index` spans
+// [ceil(index * size / count), ceil((index + 1) * size / count)).
+fn tile_span(index: u32, count: u32, size: u32) -> (u32, u32) {
+    let edge = |i: u32| (u64::from(i) * u64::from(size)).div_ceil(u64::from(count)) as u32;
+    (edge(index), edge(index + 1))
+}
+
+fn tile_report(grid: (u32, u32), changed: &[u64], size: (u32, u32)) -> Value {
+    let (rows, cols) = grid;
+    let mut cells = Vec::new();
+    let mut hottest: Option<(f64, u32, u32)> = None;
+    for row in 0..rows {
+        let (y0, y1) = tile_span(row, rows, size.1);
+        for col in 0..cols {
+            let (x0, x1) = tile_span(col, cols, size.0);
+            let area = u64::from(x1 - x0) * u64::from(y1 - y0);
+            let count = changed[(row * cols + col) as usize];
+            let percent = if area > 0 {
+                count as f64 / area as f64 * 100.0
+            } else {
+                0.0
+            };
+            if count > 0 && hottest.is_none_or(|(best, _, _)| percent > best) {
+                hottest = Some((percent, row, col));
+            }
+            cells.push(json!({
+                "row": row,
+                "col": col,
+                "x": x0,
+                "y": y0,
+                "w": x1 - x0,
+                "h": y1 - y0,
+                "changed_pixels": count,
+                "percent_changed": round_to(percent, 3),
+            }));
+        }
+    }
+    json!({
+        "rows": rows,
+        "cols": cols,
+        "changed_tile_count": changed.iter().filter(|count| **count > 0).count(),
+        "hottest": hottest.map(|(percent, row, col)| json!({
+            "row": row,
+            "col": col,
+            "percent_changed": round_to(percent, 3),
+        })),
+        "cells": cells,
+        "heatmap": null,
+    })
+}
+
+// Tiles tinted red in proportion to their share of the hottest tile, with grid lines and a
+// percentage label per changed tile.
+fn render_tile_heatmap(current: &RgbaImage, report: &Value) -> RgbaImage {
+    let mut out = current.clone();
+    let cells = report["cells"].as_array().cloned().unwrap_or_default();
+    let max = report
+        .pointer("/hottest/percent_changed")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+    let num = |cell: &Value, key: &str| cell[key].as_u64().unwrap_or(0) as i32;
+    for cell in &cells {
+        let (x, y, w, h) = (
+            num(cell, "x"),
+            num(cell, "y"),
+            num(cell, "w"),
+            num(cell, "h"),
+        );
+        let percent = cell["percent_changed"].as_f64().unwrap_or(0.0);
+        if percent > 0.0 && max > 0.0 {
+            let alpha = (40.0 + 160.0 * percent / max).round() as u8;
+            fill_rect_alpha(
+                &mut out,
+                x,
+                y,
+                x + w - 1,
+                y + h - 1,
+                Rgba([255, 45, 45, alpha]),
+                None,
+            );
+        }
+        draw_rect_outline(
+            &mut out,
+            x as u32,
+            y as u32,
+            w as u32,
+            h as u32,
+            Rgba([255, 255, 255, 140]),
+            1,
+        );
+        if percent > 0.0 {
+            let label = format!("{}%", round_to(percent, 1));
+            let (_, _, text_w, text_h) = text_bbox(0, 0, &label, 1);
+            if text_w + 4 <= w && text_h + 4 <= h {
+                fill_rect_alpha(
+                    &mut out,
+                    x + 1,
+                    y + 1,
+                    x + text_w + 3,
+                    y + text_h + 3,
+                    Rgba([0, 0, 0, 170]),
+                    None,
+                );
+                draw_bitmap_text(
+                    &mut out,
+                    x + 2,
+                    y + 2,
+                    &label,
+                    Rgba([255, 255, 255, 255]),
+                    1,
+                    None,
+                );
+            }
+        }
+    }
+    out
+}
+
+fn parse_flatten_bg(raw: Option<&str>) -> Result<Option<Rgba<u8>>> {
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+    let color = parse_color_opt(Some(&Value::String(raw.to_string()))).ok_or_else(|| {
+        cli_failure(
+            EXIT_INPUT,
+            format!("invalid --flatten-bg {raw:?}: expected a color such as #FFFFFF"),
+        )
+    })?;
+    // The backdrop itself is always opaque.
+    Ok(Some(Rgba([color[0], color[1], color[2], 255])))
+}
+
+// Composite over an opaque backdrop so RGB hidden under transparent pixels can't leak into
+// diffs or annotations.
+fn flatten_onto(image: DynamicImage, bg: Rgba<u8>) -> DynamicImage {
+    let mut rgba = image.into_rgba8();
+    for pixel in rgba.pixels_mut() {
+        *pixel = blend_pixel(bg, *pixel);
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+fn relative_min_area(percent: f64, width: u32, height: u32) -> u32 {
+    if percent <= 0.0 {
+        return 0;
+    }
+    let total = f64::from(width) * f64::from(height);
+    (total * percent / 100.0).ceil().min(f64::from(u32::MAX)) as u32
+}
+
+fn resize_filter_type(name: &str) -> FilterType {
+    match name {
+        "nearest" => FilterType::Nearest,
+        "triangle" => FilterType::Triangle,
+        "catmullrom" => FilterType::CatmullRom,
+        _ => FilterType::Lanczos3,
+    }
+}
+
+// Histogram of diff magnitudes (masked pixels excluded) plus nonzero percentiles for threshold tuning.
+fn diff_histogram_report(bins: &[u64], threshold: u8) -> Value {
+    let counted: u64 = bins.iter().sum();
+    let nonzero: u64 = bins.iter().skip(1).sum();
+    let at_or_above: u64 = bins.iter().skip(usize::from(threshold)).sum();
+    let percentile = |p: f64| -> Option<usize> {
+        if nonzero == 0 {
+            return None;
+        }
+        let target = ((nonzero as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0u64;
+        bins.iter().enumerate().skip(1).find_map(|(value, count)| {
+            seen += count;
+            (seen >= target).then_some(value)
+        })
+    };
+    json!({
+        "bins": bins,
+        "counted_pixels": counted,
+        "nonzero_pixels": nonzero,
+        "bbox_threshold": threshold,
+        "at_or_above_threshold": at_or_above,
+        "nonzero_p50": percentile(0.5),
+        "nonzero_p90": percentile(0.9),
+        "nonzero_p99": percentile(0.99),
+    })
+}
+
+fn auto_orient(baseline: &DynamicImage, current: DynamicImage) -> (DynamicImage, Option<u32>) {
+    let (bw, bh) = baseline.dimensions();
+    let (cw, ch) = current.dimensions();
+    if bw == bh || (cw, ch) != (bh, bw) {
+        return (current, None);
+    }
+
+    let baseline_luma = baseline.to_luma8();
+    let luma_distance = |candidate: &DynamicImage| -> u64 {
+        candidate
+            .to_luma8()
+            .pixels()
+            .zip(baseline_luma.pixels())
+            .map(|(a, b)| u64::from(a[0].abs_diff(b[0])))
+            .sum()
+    };
+
+    let cw_turn = current.rotate90();
+    let ccw_turn = current.rotate270();
+    if luma_distance(&ccw_turn) < luma_distance(&cw_turn) {
+        (ccw_turn, Some(270))
+    } else {
+        (cw_turn, Some(90))
+    }
+}
+
+// Returns one ignore flag per pixel (black or mostly transparent) plus whether it was resized.
+fn load_ignore_mask(path: &Path, width: u32, height: u32) -> Result<(Vec<bool>, bool)> {
+    if !path.exists() {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!("mask not found: {}", path.display()),
+        ));
+    }
+    let mut mask = image::open(path)
+        .with_context(|| format!("failed to open mask image: {}", path.display()))?
+        .to_luma_alpha8();
+    let resized = mask.dimensions() != (width, height);
+    if resized {
+        mask = image::imageops::resize(&mask, width, height, FilterType::Nearest);
+    }
+    Ok((
+        mask.pixels().map(|p| p[0] < 128 || p[1] < 128).collect(),
+        resized,
+    ))
+}
+
+// Per-pixel weights in 0..=1 from the map's luma (alpha multiplies in), bilinear-resized to
+// the compared size; plus whether it was resized.
+fn load_weight_map(path: &Path, width: u32, height: u32) -> Result<(Vec<f32>, bool)> {
+    if !path.exists() {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!("weight map not found: {}", path.display()),
+        ));
+    }
+    let mut map = image::open(path)
+        .with_context(|| format!("failed to open weight map: {}", path.display()))?
+        .to_luma_alpha8();
+    let resized = map.dimensions() != (width, height);
+    if resized {
+        map = image::imageops::resize(&map, width, height, FilterType::Triangle);
+    }
+    let weights: Vec<f32> = map
+        .pixels()
+        .map(|p| f32::from(p[0]) / 255.0 * f32::from(p[1]) / 255.0)
+        .collect();
+    if !weights.iter().any(|w| *w > 0.0) {
+        return Err(cli_failure(
+            EXIT_INPUT,
+            format!("weight map is entirely black: {}", path.display()),
+        ));
+    }
+    Ok((weights, resized))
+}
+
+fn pixel_distance(a: &Rgba<u8>, b: &Rgba<u8>, colorspace: &str) -> u8 {
+    match colorspace {
+        "lab" => {
+            let (l1, a1, b1) = rgb_to_lab(a);
+            let (l2, a2, b2) = rgb_to_lab(b);
+            let delta_e = ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt();
+            (delta_e * 2.55).round().min(255.0) as u8
+        }
+        "ycbcr" => {
+            let (y1, cb1, cr1) = rgb_to_ycbcr(a);
+            let (y2, cb2, cr2) = rgb_to_ycbcr(b);
+            (y1 - y2)
+                .abs()
+                .max((cb1 - cb2).abs())
+                .max((cr1 - cr2).abs())
+                .round()
+                .min(255.0) as u8
+        }
+        _ => a[0]
+            .abs_diff(b[0])
+            .max(a[1].abs_diff(b[1]))
+            .max(a[2].abs_diff(b[2])),
+    }
+}
+
+// --mode shape silhouette membership: opaque enough (alpha) or dark enough (luma, for shapes
+// drawn on a light opaque background).
+fn shape_ink(p: &Rgba<u8>, channel: &str, threshold: u8) -> bool {
+    match channel {
+        "alpha" => p[3] >= threshold,
+        _ => rgb_to_ycbcr(p).0 < f64::from(threshold),
+    }
+}
+
+fn rgb_to_ycbcr(p: &Rgba<u8>) -> (f64, f64, f64) {
+    let (r, g, b) = (f64::from(p[0]), f64::from(p[1]), f64::from(p[2]));
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        128.0 - 0.168_736 * r - 0.331_264 * g + 0.5 * b,
+        128.0 + 0.5 * r - 0.418_688 * g - 0.081_312 * b,
+    )
+}
+
+// sRGB (D65) -> CIE L*a*b*.
+fn rgb_to_lab(p: &Rgba<u8>) -> (f64, f64, f64) {
+    let linear = |c: u8| {
+        let v = f64::from(c) / 255.0;
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = (linear(p[0]), linear(p[1]), linear(p[2]));
+    let x = (0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b) / 0.950_47;
+    let y = 0.212_672_9 * r + 0.715_152_2 * g + 0.072_175 * b;
+    let z = (0.019_333_9 * r + 0.119_192 * g + 0.950_304_1 * b) / 1.088_83;
+    let f = |t: f64| {
+        if t > 0.008_856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+fn pixel_luma(p: &Rgba<u8>) -> f64 {
+    0.298_895_31 * f64::from(p[0]) + 0.586_622_47 * f64::from(p[1]) + 0.114_482_23 * f64::from(p[2])
+}
+
+fn neighbor_bounds(img: &RgbaImage, x: u32, y: u32) -> (u32, u32, u32, u32) {
+    (
+        x.saturating_sub(1),
+        y.saturating_sub(1),
+        (x + 1).min(img.width() - 1),
+        (y + 1).min(img.height() - 1),
+    )
+}
+
+fn has_many_siblings(img: &RgbaImage, x: u32, y: u32) -> bool {
+    let (x0, y0, x2, y2) = neighbor_bounds(img, x, y);
+    let center = img.get_pixel(x, y);
+    // Pixels on the image edge count one implicit sibling, as in pixelmatch.
+    let mut zeroes = u32::from(x == x0 || x == x2 || y == y0 || y == y2);
+    for ny in y0..=y2 {
+        for nx in x0..=x2 {
+            if (nx, ny) != (x, y) && img.get_pixel(nx, ny) == center {
+                zeroes += 1;
+                if zeroes > 2 {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+// Lightweight pixelmatch antialias test: the pixel sits on a gradient (has both darker and
+// brighter neighbors) and the extreme neighbor is a flat area in both images.
+fn is_antialiased(img: &RgbaImage, other: &RgbaImage, x: u32, y: u32) -> bool {
+    let (x0, y0, x2, y2) = neighbor_bounds(img, x, y);
+    let center = pixel_luma(img.get_pixel(x, y));
+    let mut zeroes = u32::from(x == x0 || x == x2 || y == y0 || y == y2);
+    let (mut min, mut max) = (0.0f64, 0.0f64);
+    let (mut min_pos, mut max_pos) = ((x, y), (x, y));
+
+    for ny in y0..=y2 {
+        for nx in x0..=x2 {
+            if (nx, ny) == (x, y) {
+                continue;
+            }
+            let delta = pixel_luma(img.get_pixel(nx, ny)) - center;
+            if delta == 0.0 {
+                zeroes += 1;
+                if zeroes > 2 {
+                    return false;
+                }
+            } else if delta < min {
+                min = delta;
+                min_pos = (nx, ny);
+            } else if delta > max {
+                max = delta;
+                max_pos = (nx, ny);
+            }
+        }
+    }
+
+    if min == 0.0 || max == 0.0 {
+        return false;
+    }
+    (has_many_siblings(img, min_pos.0, min_pos.1) && has_many_siblings(other, min_pos.0, min_pos.1))
+        || (has_many_siblings(img, max_pos.0, max_pos.1)
+            && has_many_siblings(other, max_pos.0, max_pos.1))
+}
+
+fn diff_quality_metrics(diff_sum: u64, diff_sq_sum: u64, total_pixels: u64) -> (f64, Option<f64>) {
+    if total_pixels == 0 {
+        return (0.0, None);
+    }
+    let mae = diff_sum as f64 / total_pixels as f64;
+    let mse = diff_sq_sum as f64 / total_pixels as f64;
+    // Identical images have infinite PSNR; report null rather than a sentinel.
+    let psnr = if mse > 0.0 {
+        Some(10.0 * (255.0 * 255.0 / mse).log10())
+    } else {
+        None
+    };
+    (mae, psnr)
+}
+
+fn extract_change_regions(
+    gray: &[u8],
+    width: u32,
+    height: u32,
+    threshold: u8,
+    min_pixels: u32,
+    pad: u32,
+    max_boxes: usize,
+) -> Vec<ChangeRegion> {
+    let total = (width * height) as usize;
+    let mut active = vec![false; total];
+    let mut visited = vec![false; total];
+
+    for (idx, val) in gray.iter().enumerate() {
+        if *val > threshold {
+            active[idx] = true;
+        }
+    }
+
+    let mut raw_regions: Vec<(u32, u32, u32, u32, u32, u64, u64)> = Vec::new();
+
+    for y in 0..height {
+        for x in 0..width {
+            let start = (y * width + x) as usize;
+            if visited[start] || !active[start] {
+                continue;
+            }
+
+            let mut queue = std::collections::VecDeque::new();
+            queue.push_back(start);
+            visited[start] = true;
+
+            let mut minx = x;
+            let mut maxx = x;
+            let mut miny = y;
+            let mut maxy = y;
+            let mut count: u32 = 0;
+            let mut sum_x: u64 = 0;
+            let mut sum_y: u64 = 0;
+
+            while let Some(node) = queue.pop_front() {
+                let cx = (node as u32) % width;
+                let cy = (node as u32) / width;
+                count += 1;
+                sum_x += u64::from(cx);
+                sum_y += u64::from(cy);
+
+                if cx < minx {
+                    minx = cx;
+                }
+                if cx > maxx {
+                    maxx = cx;
+                }
+                if cy < miny {
+                    miny = cy;
+                }
+                if cy > maxy {
+                    maxy = cy;
+                }
+
+                if cx > 0 {
+                    let left = node - 1;
+                    if active[left] && !visited[left] {
+                        visited[left] = true;
+                        queue.push_back(left);
+                    }
+                }
+                if cx + 1 < width {
+                    let right = node + 1;
+                    if active[right] && !visited[right] {
+                        visited[right] = true;
+                        queue.push_back(right);
+                    }
+                }
+                if cy > 0 {
+                    let up = node - width as usize;
+                    if active[up] && !visited[up] {
+                        visited[up] = true;
+                        queue.push_back(up);
+                    }
+                }
+                if cy + 1 < height {
+                    let down = node + width as usize;
+                    if active[down] && !visited[down] {
+                        visited[down] = true;
+                        queue.push_back(down);
+                    }
+                }
+            }
+
+            if count < min_pixels.max(1) {
+                continue;
+            }
+
+            raw_regions.push((minx, miny, maxx, maxy, count, sum_x, sum_y));
+        }
+    }
+
+    raw_regions.sort_by_key(|item| std::cmp::Reverse(item.4));
+    if max_boxes > 0 && raw_regions.len() > max_boxes {
+        raw_regions.truncate(max_boxes);
+    }
+
+    let mut regions = Vec::new();
+
+    for (idx, (minx, miny, maxx, maxy, pixels, sum_x, sum_y)) in raw_regions.into_iter().enumerate()
+    {
+        let x0 = minx.saturating_sub(pad);
+        let y0 = miny.saturating_sub(pad);
+        let x1 = (maxx + pad).min(width.saturating_sub(1));
+        let y1 = (maxy + pad).min(height.saturating_sub(1));
+
+        let box_w = (x1.saturating_sub(x0)) + 1;
+        let box_h = (y1.saturating_sub(y0)) + 1;
+        let area = box_w.saturating_mul(box_h);
+
+        let coverage = if area > 0 {
+            round_to(pixels as f64 / area as f64, 4)
+        } else {
+            0.0
+        };
+
+        regions.push(ChangeRegion {
+            x: x0,
+            y: y0,
+            w: box_w,
+            h: box_h,
+            x2: x0 + box_w,
+            y2: y0 + box_h,
+            pixels,
+            area,
+            coverage,
+            centroid: [
+                round_to(sum_x as f64 / f64::from(pixels), 2),
+                round_to(sum_y as f64 / f64::from(pixels), 2),
+            ],
+            intent: "changed-region".to_string(),
+            action: "inspect".to_string(),
+            id: format!("change-{}", idx + 1),
+            rel: RegionRel {
+                x: if width > 0 {
+                    round_to(x0 as f64 / width as f64, 6)
+                } else {
+                    0.0
+                },
+                y: if height > 0 {
+                    round_to(y0 as f64 / height as f64, 6)
+                } else {
+                    0.0
+                },
+                w: if width > 0 {
+                    round_to(box_w as f64 / width as f64, 6)
+                } else {
+                    0.0
+                },
+                h: if height > 0 {
+                    round_to(box_h as f64 / height as f64, 6)
+                } else {
+                    0.0
+                },
+            },
+        });
+    }
+
+    regions
+}
+
+fn build_annotate_spec(regions: &[ChangeRegion], options: &DiffOptions, size: (u32, u32)) -> Value {
+    let mut annotations = Vec::new();
+
+    for (idx, region) in regions.iter().enumerate() {
+        let label = format!("Δ{}", idx + 1);
+        let (anchor_pos, anchor_offset) =
+            region_label_anchor(region, &label, &options.label_position, size);
+        let [r, g, b] = if options.region_colors == "stable" {
+            stable_region_color(region)
+        } else {
+            [255, 69, 58]
+        };
+        let rect_id = region.id.clone();
+        annotations.push(json!({
+            "type": "rect",
+            "id": rect_id,
+            "x": region.x,
+            "y": region.y,
+            "w": region.w,
+            "h": region.h,
+            "centroid": region.centroid,
+            "color": format!("#{r:02X}{g:02X}{b:02X}"),
+            "width": 3,
+            "intent": "changed-region",
+            "action": "inspect",
+        }));
+        annotations.push(json!({
+            "type": "text",
+            "text": label,
+            "anchor": region.id,
+            "anchor_pos": anchor_pos,
+            "anchor_offset": anchor_offset,
+            "color": "#FFFFFF",
+            "text_bg": format!("rgba({r},{g},{b},0.78)"),
+            "intent": "change-label",
+            "action": "review-diff",
+        }));
+    }
+
+    json!({
+        "defaults": {
+            "auto_scale": true,
+            "outline": true,
+            "text_bg": "rgba(0,0,0,0.6)",
+        },
+        "annotations": annotations,
+    })
+}
+
+const STABLE_REGION_PALETTE: [[u8; 3]; 10] = [
+    [255, 69, 58],
+    [255, 159, 10],
+    [255, 214, 10],
+    [48, 209, 88],
+    [100, 210, 255],
+    [10, 132, 255],
+    [94, 92, 230],
+    [191, 90, 242],
+    [255, 55, 95],
+    [172, 142, 104],
+];
+
+// Palette entry from an FNV-1a hash of the region's box snapped to a 32px grid, so the same
+// logical region keeps its color across runs despite small bbox jitter.
+fn stable_region_color(region: &ChangeRegion) -> [u8; 3] {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for value in [region.x, region.y, region.w, region.h] {
+        for byte in ((value + 16) / 32).to_le_bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    STABLE_REGION_PALETTE[(hash % STABLE_REGION_PALETTE.len() as u64) as usize]
+}
+
+// Anchor pos/offset for a region's Δn label, measured with the exporters' text helpers under the
+// spec's auto_scale; "auto" sits above the box and drops inside it when that would clip.
+fn region_label_anchor(
+    region: &ChangeRegion,
+    label: &str,
+    position: &str,
+    (img_w, img_h): (u32, u32),
+) -> (&'static str, [i64; 2]) {
+    let scale = resolve_scale(&Map::new(), img_w, img_h);
+    let mut label_ann = Map::new();
+    label_ann.insert("type".to_string(), json!("text"));
+    let (_, _, font_size) = export_style(&label_ann, scale);
+    let (text_w, text_h) = text_extent(&label_ann, label, font_size, &FontCache::default());
+    let (text_w, text_h) = (i64::from(text_w), i64::from(text_h));
+    let padding = i64::from(scale_default(4.0, scale, 2));
+    let above = -(text_h + padding * 2);
+    match position {
+        "top_left" => ("top_left", [padding, above]),
+        "top" => ("top", [-text_w / 2, above]),
+        "center" => ("center", [-text_w / 2, -text_h / 2]),
+        "auto" => {
+            let y = if i64::from(region.y) + above - padding < 0 {
+                padding * 2
+            } else {
+                above
+            };
+            // Keep the label's right edge on canvas for boxes hugging the right border.
+            let overflow = i64::from(region.x) + padding + text_w + padding - i64::from(img_w);
+            ("top_left", [padding - overflow.max(0), y])
+        }
+        _ => ("centroid", [-8, -8]),
+    }
+}
+
+const CB_SIM_KINDS: [&str; 3] = ["protanopia", "deuteranopia", "tritanopia"];
+
+// Machado et al. (2009) full-severity dichromacy matrices, applied in linear RGB.
+fn cb_sim_matrix(kind: &str) -> [[f64; 3]; 3] {
+    match kind {
+        "protanopia" => [
+            [0.152286, 1.052583, -0.204868],
+            [0.114503, 0.786281, 0.099216],
+            [-0.003882, -0.048116, 1.051998],
+        ],
+        "tritanopia" => [
+            [1.255528, -0.076749, -0.178779],
+            [-0.078411, 0.930809, 0.147602],
+            [0.004733, 0.691367, 0.303900],
+        ],
+        _ => [
+            [0.367322, 0.860646, -0.227968],
+            [0.280085, 0.672501, 0.047413],
+            [-0.011820, 0.042940, 0.968881],
+        ],
+    }
+}
+
+fn simulate_color_vision(image: &RgbaImage, kind: &str) -> RgbaImage {
+    let matrix = cb_sim_matrix(kind);
+    let to_linear: Vec<f64> = (0..=255u8)
+        .map(|v| {
+            let c = f64::from(v) / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        })
+        .collect();
+    let to_srgb = |c: f64| {
+        let c = c.clamp(0.0, 1.0);
+        let v = if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (v * 255.0).round() as u8
+    };
+    let mut out = image.clone();
+    for pixel in out.pixels_mut() {
+        let rgb = [0, 1, 2].map(|i| to_linear[usize::from(pixel[i])]);
+        for (channel, row) in matrix.iter().enumerate() {
+            pixel[channel] = to_srgb(row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]);
+        }
+    }
+    out
+}
+
+// Writes the simulated copy beside `output` as <stem>.<kind>.png and returns its absolute path.
+fn write_cb_sim(
+    image: &RgbaImage,
+    output: &Path,
+    kind: &str,
+    compression: &str,
+    verify: bool,
+) -> Result<String> {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "output".to_string());
+    let path = output.with_file_name(format!("{stem}.{kind}.png"));
+    ensure_parent_dir(&path)?;
+    save_png(
+        &DynamicImage::ImageRgba8(simulate_color_vision(image, kind)),
+        &path,
+        compression,
+        verify,
+    )
+    .with_context(|| format!("failed to save {kind} simulation: {}", path.display()))?;
+    Ok(abs_path(&path).display().to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_diff_overlay(
+    current: &RgbaImage,
+    gray: &[u8],
+    width: u32,
+    height: u32,
+    overlay_threshold: u8,
+    transparent: bool,
+    png_compression: &str,
+    verify: bool,
+    out_path: &Path,
+) -> Result<RgbaImage> {
+    // Transparent mode starts from an empty canvas: unchanged pixels stay fully transparent and
+    // changed ones are pure red at the diff magnitude, so the PNG composites over any backdrop.
+    let mut out = if transparent {
+        RgbaImage::new(width, height)
+    } else {
+        current.clone()
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            let idx = (y * width + x) as usize;
+            if gray[idx] < overlay_threshold {
+                continue;
+            }
+            let alpha = gray[idx] as f32 / 255.0;
+            if alpha <= 0.0 {
+                continue;
+            }
+            if transparent {
+                out.put_pixel(x, y, Rgba([255, 0, 0, gray[idx]]));
+                continue;
+            }
+            let base = out.get_pixel(x, y).0;
+            let red = [255f32, 0f32, 0f32];
+            let blended = [
+                ((1.0 - alpha) * base[0] as f32 + alpha * red[0]).round() as u8,
+                ((1.0 - alpha) * base[1] as f32 + alpha * red[1]).round() as u8,
+                ((1.0 - alpha) * base[2] as f32 + alpha * red[2]).round() as u8,
+                base[3],
+            ];
+            out.put_pixel(x, y, Rgba(blended));
+        }
+    }
+
+    ensure_parent_dir(out_path)?;
+    let out = DynamicImage::ImageRgba8(out);
+    save_png(&out, out_path, png_compression, verify)
+        .with_context(|| format!("failed to save diff image: {}", out_path.display()))?;
+    Ok(out.into_rgba8())
+}
+
+// draw_rect_outline with an optional (on, off) dash pattern and clip walked clockwise from the top-left
+// corner. Every ring of a thick outline uses the innermost ring's perimeter position, so the
+// dashes line up across the stroke width (and with a wider outline pass beneath them).
+fn draw_rect_outline_dashed(
+    img: &mut RgbaImage,
+    rect: (u32, u32, u32, u32),
+    color: Rgba<u8>,
+    thickness: u32,
+    dash: Option<(f64, f64)>,
+    clip: ClipRect,
+) {
+    let (x, y, w, h) = rect;
+    if w == 0 || h == 0 {
+        return;
+    }
+    let img_w = img.width();
+    let img_h = img.height();
+
+    let x0 = x.min(img_w.saturating_sub(1));
+    let y0 = y.min(img_h.saturating_sub(1));
+    let x1 = (x + w.saturating_sub(1)).min(img_w.saturating_sub(1));
+    let y1 = (y + h.saturating_sub(1)).min(img_h.saturating_sub(1));
+    let (span_x, span_y) = (f64::from(x1 - x0), f64::from(y1 - y0));
+    let mut plot = |px: u32, py: u32, along: f64| {
+        let on_dash = dash.is_none_or(|(on, off)| along.rem_euclid(on + off) < on);
+        if on_dash && clip_contains(clip, px as i32, py as i32) {
+            img.put_pixel(px, py, color);
+        }
+    };
+
+    for t in 0..thickness.max(1) {
+        let tx0 = x0.saturating_sub(t);
+        let ty0 = y0.saturating_sub(t);
+        let tx1 = (x1 + t).min(img_w.saturating_sub(1));
+        let ty1 = (y1 + t).min(img_h.saturating_sub(1));
+
+        for xx in tx0..=tx1 {
+            let dx = f64::from(xx) - f64::from(x0);
+            plot(xx, ty0, dx);
+            plot(xx, ty1, 2.0 * span_x + span_y - dx);
+        }
+        for yy in ty0..=ty1 {
+            let dy = f64::from(yy) - f64::from(y0);
+            plot(tx1, yy, span_x + dy);
+            plot(tx0, yy, 2.0 * (span_x + span_y) - dy);
+        }
+    }
+}
+
+// Rect "radius" in px, clamped to half the shorter side; 0 keeps sharp corners.
+fn rect_corner_radius(ann: &Map<String, Value>, w: f64, h: f64) -> f64 {
+    value_to_f64(ann.get("radius"))
+        .unwrap_or(0.0)
+        .clamp(0.0, w.min(h) / 2.0)
+}
+
+// Rounded counterpart of draw_rect_outline_dashed. The ring lies between the rect grown by
+// `thickness - 1` (its corner radius grown with it) and the rect shrunk by 1 px, matching the
+// sharp outline's footprint along the edges. Dash positions follow the same clockwise walk.
+fn draw_rounded_rect_outline(
+    img: &mut RgbaImage,
+    rect: (u32, u32, u32, u32),
+    color: Rgba<u8>,
+    thickness: u32,
+    radius: f64,
+    dash: Option<(f64, f64)>,
+    clip: ClipRect,
+) {
+    let (x, y, w, h) = rect;
+    if w == 0 || h == 0 || img.width() == 0 || img.height() == 0 {
+        return;
+    }
+    let grow = thickness.max(1) as i32 - 1;
+    let (x0, y0) = (x as i32, y as i32);
+    let (x1, y1) = (x0 + w as i32 - 1, y0 + h as i32 - 1);
+    let radius = radius.min(f64::from(w.min(h)) / 2.0);
+    let (span_x, span_y) = (f64::from(x1 - x0), f64::from(y1 - y0));
+    let outer = (x0 - grow, y0 - grow, x1 + grow + 1, y1 + grow + 1);
+    let max_x = img.width() as i32 - 1;
+    let max_y = img.height() as i32 - 1;
+
+    for py in outer.1.max(0)..=(outer.3 - 1).min(max_y) {
+        for px in outer.0.max(0)..=(outer.2 - 1).min(max_x) {
+            let (ox0, oy0, ox1, oy1) = outer;
+            if !point_in_rounded_rect(px, py, ox0, oy0, ox1, oy1, radius + f64::from(grow)) {
+                continue;
+            }
+            if point_in_rounded_rect(px, py, x0 + 1, y0 + 1, x1, y1, (radius - 1.0).max(0.0)) {
+                continue;
+            }
+            if let Some((on, off)) = dash {
+                let (dx, dy) = (f64::from(px - x0), f64::from(py - y0));
+                let along = if py <= y0 {
+                    dx
+                } else if px >= x1 {
+                    span_x + dy
+                } else if py >= y1 {
+                    2.0 * span_x + span_y - dx
+                } else {
+                    2.0 * (span_x + span_y) - dy
+                };
+                if along.rem_euclid(on + off) >= on {
+                    continue;
+                }
+            }
+            if clip_contains(clip, px, py) {
+                img.put_pixel(px as u32, py as u32, color);
+            }
+        }
+    }
+}
+
+fn draw_rect_outline(
+    img: &mut RgbaImage,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    color: Rgba<u8>,
+    thickness: u32,
+) {
+    if w == 0 || h == 0 {
+        return;
+    }
+    let img_w = img.width();
+    let img_h = img.height();
+
+    let x0 = x.min(img_w.saturating_sub(1));
+    let y0 = y.min(img_h.saturating_sub(1));
+    let x1 = (x + w.saturating_sub(1)).min(img_w.saturating_sub(1));
+    let y1 = (y + h.saturating_sub(1)).min(img_h.saturating_sub(1));
+
+    for t in 0..thickness.max(1) {
+        let tx0 = x0.saturating_sub(t);
+        let ty0 = y0.saturating_sub(t);
+        let tx1 = (x1 + t).min(img_w.saturating_sub(1));
+        let ty1 = (y1 + t).min(img_h.saturating_sub(1));
+
+        for xx in tx0..=tx1 {
+            img.put_pixel(xx, ty0, color);
+            img.put_pixel(xx, ty1, color);
+        }
+        for yy in ty0..=ty1 {
+            img.put_pixel(tx0, yy, color);
+            img.put_pixel(tx1, yy, color);
+        }
+    }
+}
+
+fn capture_internal(
+    out_path: &Path,
+    process: Option<String>,
+    step: Option<&str>,
+    note: Option<&str>,
+    sidecar: Option<&Path>,
+    options: &CaptureOptions,
+) -> Result<Value> {
+    let png_compression = options.png_compression.as_str();
+    ensure_parent_dir(out_path)?;
+
+    let process_name = process
+        .clone()
+        .or_else(env_process_name)
+        .or_else(frontmost_app_name)
+        .unwrap_or_else(|| "app".to_string());
+    let app_slug = slugify(&process_name);
+
+    let mut x: i64 = 0;
+    let mut y: i64 = 0;
+    let mut w: i64 = 0;
+    let mut h: i64 = 0;
+    let mut window_title: Option<String> = None;
+    let mut selected_window_index: Option<usize> = None;
+    let mut candidate_count: usize = 0;
+    let mut usable_count: usize = 0;
+    let mut selection_mode = "none".to_string();
+    let mut selected_window_usable = false;
+    let mut usable_min_w: i64 = 0;
+    let mut usable_min_h: i64 = 0;
+    let mut usable_min_area: i64 = 0;
+
+    let mut captured = false;
+    let mut capture_mode = "fallback".to_string();
+    let mut warnings = Warnings::default();
+    let mut on_other_space: Option<bool> = None;
+    let mut space_switched = false;
+    let mut space_query: Option<QueryDiagnostic> = None;
+    let mut screencapture_diag: Option<QueryDiagnostic> = None;
+    let mut captured_window_id: Option<i64> = None;
+    let (query_window_diag, activation_diag) = if cfg!(target_os = "macos") && options.no_probe {
+        // Transient menus/popovers close on focus change: no activation, no osascript probe.
+        let skipped = QueryDiagnostic {
+            ok: true,
+            attempts: 0,
+            error_code: None,
+            message: Some("skipped by --no-probe".to_string()),
+        };
+        selection_mode = "skipped".to_string();
+        if let Some((rx, ry, rw, rh)) = options.region {
+            (x, y, w, h) = (rx, ry, rw, rh);
+        }
+        if command_exists("screencapture") {
+            let mut args = vec!["-x".to_string()];
+            if options.region.is_some() {
+                args.extend(["-R".to_string(), format!("{x},{y},{w},{h}")]);
+            }
+            captured = screencapture_with_retry(&args, out_path, options, &mut screencapture_diag);
+            if captured {
+                capture_mode = "screen_fast".to_string();
+            } else {
+                warnings.error("fast_capture_failed", "fast screen capture failed");
+            }
+        }
+        (skipped.clone(), skipped)
+    } else if cfg!(target_os = "macos") {
+        let activation_diag = activate_process_window(&process_name);
+        let (thresholds, invalid) = env_window_thresholds(options.min_window);
+        for message in invalid {
+            warnings.warn("invalid_window_threshold_env", message);
+        }
+        let mut probe = query_window_probe(&process_name, thresholds, &options.output.scripts);
+        // System Events only lists windows on the active Space. Ask CoreGraphics only when the
+        // probe came back empty, or when --no-shadow needs the window id for `screencapture -l`.
+        let mut cg_windows = Vec::new();
+        if !(probe.diagnostics.ok && probe.w > 0 && probe.h > 0) || options.exclude_shadow {
+            let (mut windows, mut space_diag) = query_cg_windows(&process_name);
+            if windows_on_other_space(&windows) {
+                // Activation switches Spaces when the system allows it; give the transition time.
+                thread::sleep(Duration::from_millis(600));
+                let _ = activate_process_window(&process_name);
+                thread::sleep(Duration::from_millis(400));
+                (windows, space_diag) = query_cg_windows(&process_name);
+                space_switched = !windows_on_other_space(&windows);
+                if space_switched {
+                    warnings.info(
+                        "space_switched",
+                        format!(
+                            "switched to the Space holding {process_name}'s window before capture"
+                        ),
+                    );
+                    probe = query_window_probe(&process_name, thresholds, &options.output.scripts);
+                } else {
+                    warnings.warn(
+                        "window_on_other_space",
+                        format!(
+                            "{process_name} has {} window(s) but none on the active Space (other Space or minimized); the capture likely shows the wrong content",
+                            windows.len()
+                        ),
+                    );
+                }
+            }
+            on_other_space = space_diag.ok.then(|| windows_on_other_space(&windows));
+            space_query = Some(space_diag);
+            cg_windows = windows;
+        }
+        let query_window_diag = probe.diagnostics.clone();
+        if probe.diagnostics.ok {
+            x = probe.x;
+            y = probe.y;
+            w = probe.w;
+            h = probe.h;
+            window_title = probe.title;
+            selected_window_index = probe.selected_index;
+            candidate_count = probe.candidate_count;
+            usable_count = probe.usable_count;
+            selection_mode = probe.selection_mode.clone();
+            selected_window_usable = probe.usable;
+            usable_min_w = probe.min_width;
+            usable_min_h = probe.min_height;
+            usable_min_area = probe.min_area;
+        }
+
+        warnings.push_diagnostic(
+            "window_query_failed",
+            "window_query",
+            "window query unavailable",
+            &query_window_diag,
+        );
+        warnings.push_diagnostic(
+            "activation_failed",
+            "activate",
+            "activation note",
+            &activation_diag,
+        );
+
+        if options.meta_only {
+            // Probe only: bounds, selection and activation are known; no screencapture.
+        } else if query_window_diag.ok && w > 0 && h > 0 && command_exists("screencapture") {
+            if selected_window_usable {
+                // Only a capture by window id (-l) carries a drop shadow for -o to drop; region
+                // captures never do, so they stay on -R.
+                let window_id = options
+                    .exclude_shadow
+                    .then(|| cg_window_id(&cg_windows, (x, y, w, h)))
+                    .flatten();
+                let args = match window_id {
+                    Some(id) => vec![
+                        "-x".to_string(),
+                        "-o".to_string(),
+                        "-l".to_string(),
+                        id.to_string(),
+                    ],
+                    None => vec![
+                        "-x".to_string(),
+                        "-R".to_string(),
+                        format!("{x},{y},{w},{h}"),
+                    ],
+                };
+                if screencapture_with_retry(&args, out_path, options, &mut screencapture_diag) {
+                    captured = true;
+                    captured_window_id = window_id;
+                    capture_mode = "window".to_string();
+                } else {
+                    warnings.warn(
+                        "window_capture_failed",
+                        "window capture failed; attempting full-screen fallback",
+                    );
+                }
+            } else {
+                warnings.warn("window_below_threshold", format!(
+                    "selected window {}x{} is below usable threshold {}x{} / area {}; using full-screen fallback",
+                    w, h, usable_min_w, usable_min_h, usable_min_area
+                ));
+            }
+        }
+
+        if !captured && !options.meta_only && command_exists("screencapture") {
+            captured = screencapture_with_retry(
+                &["-x".to_string()],
+                out_path,
+                options,
+                &mut screencapture_diag,
+            );
+            if captured {
+                capture_mode = "screen".to_string();
+                warnings.warn(
+                    "screen_fallback",
+                    "window-bounds capture unavailable; used full-screen capture fallback",
+                );
+            }
+        }
+        (query_window_diag, activation_diag)
+    } else {
+        warnings.info(
+            "unsupported_platform",
+            "window capture uses placeholder on non-macOS hosts",
+        );
+        let query_window_diag = QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some("unsupported_platform".to_string()),
+            message: Some("window queries require macOS System Events".to_string()),
+        };
+        let activation_diag = query_window_diag.clone();
+        (query_window_diag, activation_diag)
+    };
+
+    if !captured && !options.meta_only {
+        let ((fallback_w, fallback_h), fallback_color, invalid) = placeholder_settings(
+            env::var("CVLP_PLACEHOLDER_SIZE").ok().as_deref(),
+            env::var("CVLP_PLACEHOLDER_COLOR").ok().as_deref(),
+        );
+        for message in invalid {
+            warnings.warn("invalid_placeholder_env", message);
+        }
+        let fallback = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+            fallback_w,
+            fallback_h,
+            fallback_color,
+        ));
+        save_png(
+            &fallback,
+            out_path,
+            png_compression,
+            options.output.verify_output,
+        )
+        .with_context(|| format!("failed to write fallback capture: {}", out_path.display()))?;
+        if w == 0 || h == 0 {
+            w = i64::from(fallback_w);
+            h = i64::from(fallback_h);
+        }
+        if window_title.is_none() {
+            window_title = Some("fallback-window".to_string());
+        }
+        warnings.error(
+            "placeholder_capture",
+            "capture failed; generated placeholder image",
+        );
+    }
+
+    let mut shadow_trim: Option<Value> = None;
+    let mut redactions: Option<Vec<Value>> = None;
+    let image_size = if options.meta_only {
+        None
+    } else {
+        let mut output_img = image::open(out_path)
+            .with_context(|| format!("failed to read capture image: {}", out_path.display()))?;
+
+        if captured_window_id.is_some() {
+            if let Some((left, top, right, bottom)) = shadow_border(&output_img.to_rgba8()) {
+                let (img_w, img_h) = output_img.dimensions();
+                (x, y, w, h) =
+                    trim_window_bounds((x, y, w, h), (img_w, img_h), (left, top, right, bottom));
+                output_img =
+                    output_img.crop_imm(left, top, img_w - left - right, img_h - top - bottom);
+                shadow_trim = Some(json!({
+                    "left": left, "top": top, "right": right, "bottom": bottom, "units": "px",
+                }));
+            }
+        }
+
+        let output_img = if options.redact_secure && captured && capture_mode == "window" {
+            let ax = query_ax_tree(&process_name, 12, &options.output.scripts);
+            let (image_w, image_h) = output_img.dimensions();
+            let regions =
+                secure_field_pixel_regions(&ax.elements, (x, y, w, h), (image_w, image_h));
+            let mut rgba = output_img.to_rgba8();
+            for &(rx, ry, rw, rh) in &regions {
+                box_blur_region(&mut rgba, rx, ry, rw, rh, (rh / 2).max(4));
+            }
+            redactions = Some(
+                regions
+                    .iter()
+                    .map(|&(rx, ry, rw, rh)| json!({"x": rx, "y": ry, "w": rw, "h": rh, "units": "px"}))
+                    .collect(),
+            );
+            if !ax.diagnostics.ok {
+                warnings.warn(
+                    "redaction_ax_failed",
+                    "secure-field redaction: AX query failed; nothing redacted",
+                );
+            }
+            DynamicImage::ImageRgba8(rgba)
+        } else {
+            if options.redact_secure {
+                warnings.warn(
+                    "redaction_skipped",
+                    format!(
+                        "secure-field redaction skipped: needs a window capture (capture_mode={capture_mode})"
+                    ),
+                );
+            }
+            output_img
+        };
+        // screencapture's PNG is kept as written; the compression level only applies when the
+        // image is re-encoded anyway.
+        if captured && (redactions.is_some() || shadow_trim.is_some()) {
+            save_png(
+                &output_img,
+                out_path,
+                png_compression,
+                options.output.verify_output,
+            )
+            .with_context(|| {
+                format!("failed to re-encode capture image: {}", out_path.display())
+            })?;
+        }
+        Some(output_img.dimensions())
+    };
+    let scale_x = match image_size {
+        Some((image_w, _)) if w > 0 => Some(round_to(image_w as f64 / w as f64, 6)),
+        _ => None,
+    };
+    let scale_y = match image_size {
+        Some((_, image_h)) if h > 0 => Some(round_to(image_h as f64 / h as f64, 6)),
+        _ => None,
+    };
+    let image_path = (!options.meta_only).then(|| abs_path(out_path).display().to_string());
+    if options.meta_only {
+        capture_mode = "meta_only".to_string();
+    }
+    let scale = match (scale_x, scale_y) {
+        (Some(sx), Some(sy)) => Some(round_to((sx + sy) / 2.0, 6)),
+        _ => None,
+    };
+
+    // Backing scale of the display holding the window, independent of the image/window ratio.
+    let display = if cfg!(target_os = "macos") {
+        let (displays, diag) = query_displays();
+        warnings.push_diagnostic(
+            "display_query_failed",
+            "display_query",
+            "display scale unavailable",
+            &diag,
+        );
+        display_for_window(&displays, (x, y, w, h)).cloned()
+    } else {
+        None
+    };
+
+    let sidecar_abs = sidecar.map(abs_path);
+
+    warnings.note_hidden(&options.output);
+    let payload = json!({
+        "image_path": image_path,
+        "capture_path": image_path,
+        "sidecar_path": sidecar_abs.as_ref().map(|p| p.display().to_string()),
+        "captured_at": timestamp_iso(),
+        "captured_at_epoch_ms": Utc::now().timestamp_millis(),
+        "app_name": process_name,
+        "app_slug": app_slug,
+        "window_title": window_title,
+        "step": step,
+        "note": note,
+        "bounds": {
+            "x": x,
+            "y": y,
+            "w": w,
+            "h": h,
+            "units": "pt",
+        },
+        "image_size": {
+            "w": image_size.map(|(image_w, _)| image_w),
+            "h": image_size.map(|(_, image_h)| image_h),
+            "units": "px",
+        },
+        "scale": scale,
+        "scale_x": scale_x,
+        "scale_y": scale_y,
+        "display_scale": display.as_ref().map(|d| d.scale),
+        "display": display,
+        "window": {
+            "x": x,
+            "y": y,
+            "w": w,
+            "h": h,
+            "x2": x + w,
+            "y2": y + h,
+            "units": "px",
+        },
+        "capture_tool": "codex-visual-loop capture",
+        "capture_sidecar_version": 1,
+        "capture_mode": capture_mode,
+        "fallback_used": !captured && !options.meta_only,
+        "redactions": redactions,
+        "shadow_trim": shadow_trim,
+        "warnings": warnings.to_value(&options.output),
+        "space": {
+            "on_other_space": on_other_space,
+            "switched": space_switched,
+        },
+        "window_probe": {
+            "selected_index": selected_window_index,
+            "selection_mode": selection_mode,
+            "candidate_count": candidate_count,
+            "usable_count": usable_count,
+            "usable": selected_window_usable,
+            "min_width": usable_min_w,
+            "min_height": usable_min_h,
+            "min_area": usable_min_area,
+        },
+        "query": {
+            "activation": activation_diag,
+            "window": query_window_diag,
+            "space": space_query,
+            "screencapture": screencapture_diag,
+        },
+    });
+
+    if let Some(path) = sidecar {
+        write_json_pretty(path, &payload)?;
+    }
+
+    Ok(payload)
+}
+
+fn ax_element_is_secure(obj: &Map<String, Value>) -> bool {
+    ["class", "role_description"].iter().any(|key| {
+        obj.get(*key)
+            .and_then(Value::as_str)
+            .map(|v| v.to_ascii_lowercase().contains("secure"))
+            .unwrap_or(false)
+    })
+}
+
+fn secure_field_pixel_regions(
+    elements: &[Value],
+    window: (i64, i64, i64, i64),
+    image_size: (u32, u32),
+) -> Vec<(u32, u32, u32, u32)> {
+    let (wx, wy, ww, wh) = window;
+    let (image_w, image_h) = image_size;
+    if ww <= 0 || wh <= 0 || image_w == 0 || image_h == 0 {
+        return Vec::new();
+    }
+    let sx = f64::from(image_w) / ww as f64;
+    let sy = f64::from(image_h) / wh as f64;
+
+    let mut regions = Vec::new();
+    for obj in elements.iter().filter_map(Value::as_object) {
+        if !ax_element_is_secure(obj) {
+            continue;
+        }
+        let Some(bounds) = obj.get("bounds").and_then(Value::as_object) else {
+            continue;
+        };
+        let (Some(bx), Some(by), Some(bw), Some(bh)) = (
+            value_to_f64(bounds.get("x")),
+            value_to_f64(bounds.get("y")),
+            value_to_f64(bounds.get("w")),
+            value_to_f64(bounds.get("h")),
+        ) else {
+            continue;
+        };
+        let x0 = ((bx - wx as f64) * sx)
+            .floor()
+            .clamp(0.0, f64::from(image_w));
+        let y0 = ((by - wy as f64) * sy)
+            .floor()
+            .clamp(0.0, f64::from(image_h));
+        let x1 = ((bx + bw - wx as f64) * sx)
+            .ceil()
+            .clamp(0.0, f64::from(image_w));
+        let y1 = ((by + bh - wy as f64) * sy)
+            .ceil()
+            .clamp(0.0, f64::from(image_h));
+        if x1 - x0 < 1.0 || y1 - y0 < 1.0 {
+            continue;
+        }
+        regions.push((x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32));
+    }
+    regions
+}
+
+fn box_blur_region(img: &mut RgbaImage, x: u32, y: u32, w: u32, h: u32, radius: u32) {
+    let x1 = (x + w).min(img.width());
+    let y1 = (y + h).min(img.height());
+    if x >= x1 || y >= y1 || radius == 0 {
+        return;
+    }
+    let r = radius as i64;
+    // Two separable passes (horizontal then vertical), sampling only inside the region.
+    for horizontal in [true, false] {
+        let src = image::imageops::crop_imm(img, x, y, x1 - x, y1 - y).to_image();
+        for py in y..y1 {
+            for px in x..x1 {
+                let mut sum = [0u32; 4];
+                let mut count = 0u32;
+                for d in -r..=r {
+                    let (sx, sy) = if horizontal {
+                        (px as i64 + d, py as i64)
+                    } else {
+                        (px as i64, py as i64 + d)
+                    };
+                    if sx < x as i64 || sx >= x1 as i64 || sy < y as i64 || sy >= y1 as i64 {
+                        continue;
+                    }
+                    let p = src.get_pixel(sx as u32 - x, sy as u32 - y).0;
+                    for c in 0..4 {
+                        sum[c] += u32::from(p[c]);
+                    }
+                    count += 1;
+                }
+                let avg = sum.map(|v| (v / count.max(1)) as u8);
+                img.put_pixel(px, py, Rgba(avg));
+            }
+        }
+    }
+}
+
+fn load_spec(path: &str) -> Result<Value> {
+    let raw = if path == "-" {
+        let mut buf = String::new();
+        io::stdin()
+            .read_to_string(&mut buf)
+            .context("failed to read spec from stdin")?;
+        buf
+    } else {
+        fs::read_to_string(path).with_context(|| format!("failed to read spec file: {path}"))?
+    };
+
+    let value: Value = serde_json::from_str(&raw)
+        .map_err(|err| cli_failure(EXIT_INPUT, format!("invalid spec JSON: {err}")))?;
+    match value {
+        Value::Array(arr) => Ok(json!({"annotations": arr, "defaults": {}})),
+        Value::Object(mut obj) => {
+            if obj.get("annotations").is_none() {
+                return Err(cli_failure(
+                    EXIT_INPUT,
+                    "spec must be a list or an object with 'annotations'",
+                ));
+            }
+            if obj.get("defaults").is_none() {
+                obj.insert("defaults".to_string(), Value::Object(Map::new()));
+            }
+            Ok(Value::Object(obj))
+        }
+        _ => Err(cli_failure(
+            EXIT_INPUT,
+            "spec must be a list or an object with 'annotations'",
+        )),
+    }
+}
+
+// Layer specs in order: later `defaults` keys replace earlier ones (shallow, per key),
+// `annotations` concatenate, and other top-level keys take the last value.
+fn merge_specs(specs: Vec<Value>) -> Value {
+    let mut defaults = Map::new();
+    let mut annotations = Vec::new();
+    let mut merged = Map::new();
+    for spec in specs {
+        let Value::Object(obj) = spec else {
+            continue;
+        };
+        for (key, value) in obj {
+            match (key.as_str(), value) {
+                ("defaults", Value::Object(layer)) => defaults.extend(layer),
+                ("annotations", Value::Array(items)) => annotations.extend(items),
+                ("defaults" | "annotations", _) => {}
+                (_, value) => {
+                    merged.insert(key, value);
+                }
+            }
+        }
+    }
+    merged.insert("defaults".to_string(), Value::Object(defaults));
+    merged.insert("annotations".to_string(), Value::Array(annotations));
+    Value::Object(merged)
+}
+
+fn apply_preprocess(
+    image: DynamicImage,
+    defaults: &Map<String, Value>,
+) -> Result<(DynamicImage, Option<Value>)> {
+    let Some(Value::Object(preprocess)) = defaults.get("preprocess") else {
+        return Ok((image, None));
+    };
+
+    let (src_w, src_h) = image.dimensions();
+    let mut image = image;
+    let mut meta = Map::new();
+    meta.insert(
+        "source_size".to_string(),
+        json!({"width": src_w, "height": src_h, "units": "px"}),
+    );
+
+    if let Some(crop) = preprocess.get("crop") {
+        let crop_rel = units_is_rel(preprocess.get("units").or_else(|| defaults.get("units")));
+        let resolved = resolve_region_units(crop, src_w, src_h, crop_rel).ok_or_else(|| {
+            anyhow::anyhow!("invalid defaults.preprocess.crop: expected {{x,y,w,h}} or [x,y,w,h]")
+        })?;
+        let (x0, y0, x1, y1) = parse_fit_region(Some(&resolved), &Map::new(), src_w, src_h);
+        image = image.crop_imm(x0, y0, x1 - x0, y1 - y0);
+        meta.insert(
+            "crop".to_string(),
+            json!({"x": x0, "y": y0, "w": x1 - x0, "h": y1 - y0, "units": "px"}),
+        );
+    }
+
+    if let Some(scale) = value_to_f64(preprocess.get("scale")) {
+        if scale <= 0.0 {
+            bail!("defaults.preprocess.scale must be > 0");
+        }
+        let (w, h) = image.dimensions();
+        let target_w = ((f64::from(w) * scale).round() as u32).max(1);
+        let target_h = ((f64::from(h) * scale).round() as u32).max(1);
+        if (target_w, target_h) != (w, h) {
+            image = image.resize_exact(target_w, target_h, FilterType::Lanczos3);
+        }
+        meta.insert("scale".to_string(), json!(scale));
+    }
+
+    let (out_w, out_h) = image.dimensions();
+    meta.insert(
+        "output_size".to_string(),
+        json!({"width": out_w, "height": out_h, "units": "px"}),
+    );
+    Ok((image, Some(Value::Object(meta))))
+}
+
+fn relative_reference_key(ann: &Map<String, Value>) -> Option<Value> {
+    match ann.get("relative_to")? {
+        Value::Bool(true) => ann.get("reference").cloned(),
+        Value::String(id) if !id.trim().is_empty() => Some(json!(id)),
+        Value::Object(obj) => Some(Value::Object(obj.clone())),
+        _ => None,
+    }
+}
+
+fn resolve_reference_bbox(key: &Value, targets: &[AnchorTarget]) -> Option<(f64, f64, f64, f64)> {
+    match key {
+        Value::String(id) => targets
+            .iter()
+            .find(|target| target.id.as_deref() == Some(id.as_str()))
+            .map(|target| target.bbox),
+        // Literal px box, e.g. AX element bounds copied from ax-tree output.
+        Value::Object(obj) => bbox_from_ann(obj),
+        _ => None,
+    }
+}
+
+// Like resolve_annotation_units, but inside the reference box: units="pt" numbers are point
+// lengths from the box origin (scaled by pt_scale, no pt_origin), other numbers fractions of the
+// box, and coordinate_origin="bottom_left" measures y up from the box's bottom edge.
+fn resolve_relative_units(ann: &mut Map<String, Value>, frame: (f64, f64, f64, f64)) {
+    let (x0, y0, x1, y1) = frame;
+    let (fw, fh) = (x1 - x0, y1 - y0);
+    let pt = units_is_pt(ann.get("units"));
+    let (sx, sy) = point_pair(ann.get("pt_scale"), 1.0);
+
+    let fields = [
+        ("x", x0, fw, sx),
+        ("x1", x0, fw, sx),
+        ("x2", x0, fw, sx),
+        ("cx", x0, fw, sx),
+        ("w", 0.0, fw, sx),
+        ("y", y0, fh, sy),
+        ("y1", y0, fh, sy),
+        ("y2", y0, fh, sy),
+        ("cy", y0, fh, sy),
+        ("h", 0.0, fh, sy),
+    ];
+    for (key, origin, span, scale) in fields {
+        let resolved = match ann.get(key) {
+            Some(Value::Number(n)) if pt => n.as_f64().map(|v| v * scale),
+            Some(value) => resolve_measure(value, span, true),
+            None => None,
+        };
+        if let Some(resolved) = resolved {
+            ann.insert(key.to_string(), json!(origin + resolved));
+        }
+    }
+
+    for key in ["anchor_offset", "from_offset", "to_offset"] {
+        if let Some(offset) = ann.get(key).cloned() {
+            if let Some(resolved) =
+                resolve_offset_units(&offset, fw.max(0.0) as u32, fh.max(0.0) as u32, !pt)
+            {
+                ann.insert(key.to_string(), resolved);
+            }
+        }
+    }
+    if bottom_left_origin(ann, &Map::new()) {
+        flip_y_fields(ann, y0 + y1);
+    }
+    ann.insert("reference_bbox".to_string(), json!([x0, y0, x1, y1]));
+}
+
+fn resolve_annotation_units(
+    ann: &mut Map<String, Value>,
+    img_w: u32,
+    img_h: u32,
+    defaults: &Map<String, Value>,
+) {
+    let units_value = ann.get("units").or_else(|| defaults.get("units")).cloned();
+    let default_rel = units_is_rel(units_value.as_ref());
+    if units_is_pt(units_value.as_ref()) {
+        resolve_point_units(ann);
+    }
+
+    let fields = [
+        ("x", img_w as f64),
+        ("x1", img_w as f64),
+        ("x2", img_w as f64),
+        ("cx", img_w as f64),
+        ("w", img_w as f64),
+        ("y", img_h as f64),
+        ("y1", img_h as f64),
+        ("y2", img_h as f64),
+        ("cy", img_h as f64),
+        ("h", img_h as f64),
+    ];
+
+    for (key, span) in fields {
+        if let Some(value) = ann.get(key).cloned() {
+            if let Some(resolved) = resolve_measure(&value, span, default_rel) {
+                ann.insert(key.to_string(), json!(resolved));
+            }
+        }
+    }
+
+    for key in ["anchor_offset", "from_offset", "to_offset"] {
+        if let Some(offset) = ann.get(key).cloned() {
+            if let Some(resolved) = resolve_offset_units(&offset, img_w, img_h, default_rel) {
+                ann.insert(key.to_string(), resolved);
+            }
+        }
+    }
+
+    for anchor_key in ["anchor", "from", "to"] {
+        let Some(Value::Object(anchor_obj)) = ann.get(anchor_key).cloned() else {
+            continue;
+        };
+        if !anchor_obj.contains_key("offset") {
+            continue;
+        }
+
+        let mut updated = anchor_obj.clone();
+        let anchor_units = updated
+            .get("units")
+            .or_else(|| ann.get("units"))
+            .or_else(|| defaults.get("units"))
+            .cloned();
+        let anchor_rel = units_is_rel(anchor_units.as_ref());
+        if let Some(offset) = updated.get("offset").cloned() {
+            if let Some(resolved) = resolve_offset_units(&offset, img_w, img_h, anchor_rel) {
+                updated.insert("offset".to_string(), resolved);
+            }
+        }
+        ann.insert(anchor_key.to_string(), Value::Object(updated));
+    }
+
+    if let Some(clip) = ann.get("clip").cloned() {
+        if let Some(resolved) = resolve_region_units(&clip, img_w, img_h, default_rel) {
+            ann.insert("clip".to_string(), resolved);
+        }
+    }
+
+    if let Some(Value::Object(fit)) = ann.get("fit").cloned() {
+        let mut updated = fit.clone();
+        let fit_units = updated
+            .get("units")
+            .or_else(|| ann.get("units"))
+            .or_else(|| defaults.get("units"))
+            .cloned();
+        let fit_rel = units_is_rel(fit_units.as_ref());
+
+        if let Some(region) = updated.get("region").cloned() {
+            if let Some(resolved) = resolve_region_units(&region, img_w, img_h, fit_rel) {
+                updated.insert("region".to_string(), resolved);
+            }
+        }
+        if let Some(pad) = updated.get("pad").cloned() {
+            if let Some(resolved) =
+                resolve_measure(&pad, f64::from(img_w.max(img_h).max(1)), fit_rel)
+            {
+                updated.insert("pad".to_string(), json!(resolved));
+            }
+        }
+        ann.insert("fit".to_string(), Value::Object(updated));
+    }
+
+    if bottom_left_origin(ann, defaults) {
+        flip_y_axis(ann, img_h);
+    }
+}
+
+fn bottom_left_origin(ann: &Map<String, Value>, defaults: &Map<String, Value>) -> bool {
+    let origin = value_to_string(ann.get("coordinate_origin"))
+        .or_else(|| value_to_string(defaults.get("coordinate_origin")))
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+        .replace('-', "_");
+    matches!(origin.as_str(), "bottom_left" | "bottomleft" | "bl")
+}
+
+fn flip_y_axis(ann: &mut Map<String, Value>, img_h: u32) {
+    let span = f64::from(img_h);
+    let flip_box = |y: f64, h: Option<f64>| span - y - h.unwrap_or(0.0);
+
+    flip_y_fields(ann, span);
+    match ann.get_mut("clip") {
+        Some(Value::Object(clip)) => {
+            if let Some(y) = value_to_f64(clip.get("y")) {
+                let h = value_to_f64(clip.get("h"));
+                clip.insert("y".to_string(), json!(flip_box(y, h)));
+            }
+        }
+        Some(Value::Array(items)) if items.len() >= 4 => {
+            if let Some(y) = value_to_f64(items.get(1)) {
+                let h = value_to_f64(items.get(3));
+                items[1] = json!(flip_box(y, h));
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(Value::Object(fit)) = ann.get_mut("fit") {
+        match fit.get_mut("region") {
+            Some(Value::Object(region)) => {
+                if let Some(y) = value_to_f64(region.get("y")) {
+                    let h = value_to_f64(region.get("h"));
+                    region.insert("y".to_string(), json!(flip_box(y, h)));
+                }
+            }
+            Some(Value::Array(items)) if items.len() >= 4 => {
+                if let Some(y) = value_to_f64(items.get(1)) {
+                    let h = value_to_f64(items.get(3));
+                    items[1] = json!(flip_box(y, h));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// y/y1/y2/cy and offset dy mirrored within [0, span]; boxes keep their height above the new y.
+fn flip_y_fields(ann: &mut Map<String, Value>, span: f64) {
+    if let Some(y) = value_to_f64(ann.get("y")) {
+        let h = value_to_f64(ann.get("h")).unwrap_or(0.0);
+        ann.insert("y".to_string(), json!(span - y - h));
+    }
+    for key in ["y1", "y2", "cy"] {
+        if let Some(y) = value_to_f64(ann.get(key)) {
+            ann.insert(key.to_string(), json!(span - y));
+        }
+    }
+
+    let negate_dy = |value: &Value| -> Option<Value> {
+        let (dx, dy) = parse_offset_value(Some(value))?;
+        Some(json!([dx, -dy]))
+    };
+    for key in ["anchor_offset", "from_offset", "to_offset"] {
+        if let Some(flipped) = ann.get(key).and_then(negate_dy) {
+            ann.insert(key.to_string(), flipped);
+        }
+    }
+    for key in ["anchor", "from", "to"] {
+        let Some(Value::Object(anchor_obj)) = ann.get_mut(key) else {
+            continue;
+        };
+        if let Some(flipped) = anchor_obj.get("offset").and_then(negate_dy) {
+            anchor_obj.insert("offset".to_string(), flipped);
+        }
+    }
+}
+
+fn resolve_offset_units(value: &Value, img_w: u32, img_h: u32, default_rel: bool) -> Option<Value> {
+    match value {
+        Value::Array(values) if values.len() >= 2 => {
+            let dx = resolve_measure(&values[0], f64::from(img_w), default_rel)?;
+            let dy = resolve_measure(&values[1], f64::from(img_h), default_rel)?;
+            Some(json!([dx, dy]))
+        }
+        Value::String(raw) => {
+            let parts: Vec<&str> = raw.split(',').map(str::trim).collect();
+            if parts.len() < 2 {
+                return None;
+            }
+            let dx = resolve_measure(
+                &Value::String(parts[0].to_string()),
+                f64::from(img_w),
+                default_rel,
+            )?;
+            let dy = resolve_measure(
+                &Value::String(parts[1].to_string()),
+                f64::from(img_h),
+                default_rel,
+            )?;
+            Some(json!([dx, dy]))
+        }
+        _ => None,
+    }
+}
+
+fn resolve_region_units(value: &Value, img_w: u32, img_h: u32, default_rel: bool) -> Option<Value> {
+    match value {
+        Value::Object(obj) => {
+            let x = resolve_measure(obj.get("x")?, f64::from(img_w), default_rel)?;
+            let y = resolve_measure(obj.get("y")?, f64::from(img_h), default_rel)?;
+            let w = resolve_measure(obj.get("w")?, f64::from(img_w), default_rel)?;
+            let h = resolve_measure(obj.get("h")?, f64::from(img_h), default_rel)?;
+            Some(json!({"x": x, "y": y, "w": w, "h": h}))
+        }
+        Value::Array(values) if values.len() >= 4 => {
+            let x = resolve_measure(&values[0], f64::from(img_w), default_rel)?;
+            let y = resolve_measure(&values[1], f64::from(img_h), default_rel)?;
+            let w = resolve_measure(&values[2], f64::from(img_w), default_rel)?;
+            let h = resolve_measure(&values[3], f64::from(img_h), default_rel)?;
+            Some(json!([x, y, w, h]))
+        }
+        _ => None,
+    }
+}
+
+fn units_is_rel(value: Option<&Value>) -> bool {
+    match value {
+        Some(Value::Bool(v)) => *v,
+        Some(Value::String(s)) => {
+            let key = s.trim().to_ascii_lowercase();
+            matches!(
+                key.as_str(),
+                "rel" | "relative" | "ratio" | "fraction" | "normalized"
+            )
+        }
+        _ => false,
+    }
+}
+
+fn units_is_pt(value: Option<&Value>) -> bool {
+    matches!(value, Some(Value::String(s)) if matches!(s.trim().to_ascii_lowercase().as_str(), "pt" | "points"))
+}
+
+// Numeric x/y/w/h-style fields given in screen points (AX/window coordinates) become image px:
+// positions subtract pt_origin then multiply by pt_scale, sizes only scale. Strings ("10%", "4px")
+// keep their usual meaning.
+fn resolve_point_units(ann: &mut Map<String, Value>) {
+    let (sx, sy) = point_pair(ann.get("pt_scale"), 1.0);
+    let (ox, oy) = point_pair(ann.get("pt_origin"), 0.0);
+    let fields = [
+        ("x", ox, sx),
+        ("x1", ox, sx),
+        ("x2", ox, sx),
+        ("cx", ox, sx),
+        ("w", 0.0, sx),
+        ("y", oy, sy),
+        ("y1", oy, sy),
+        ("y2", oy, sy),
+        ("cy", oy, sy),
+        ("h", 0.0, sy),
+    ];
+    for (key, origin, scale) in fields {
+        if let Some(v) = ann.get(key).and_then(Value::as_f64) {
+            ann.insert(key.to_string(), json!(round_to((v - origin) * scale, 3)));
+        }
+    }
+    let clip_fields = [(ox, sx), (oy, sy), (0.0, sx), (0.0, sy)];
+    match ann.get_mut("clip") {
+        Some(Value::Object(clip)) => {
+            for (key, (origin, scale)) in ["x", "y", "w", "h"].into_iter().zip(clip_fields) {
+                if let Some(v) = clip.get(key).and_then(Value::as_f64) {
+                    clip.insert(key.to_string(), json!(round_to((v - origin) * scale, 3)));
+                }
+            }
+        }
+        Some(Value::Array(items)) => {
+            for (item, (origin, scale)) in items.iter_mut().zip(clip_fields) {
+                if let Some(v) = item.as_f64() {
+                    *item = json!(round_to((v - origin) * scale, 3));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// pt_scale/pt_origin as (x, y): a [x, y] pair or one number for both axes.
+fn point_pair(value: Option<&Value>, fallback: f64) -> (f64, f64) {
+    match value {
+        Some(Value::Array(items)) if items.len() == 2 => (
+            value_to_f64(items.first()).unwrap_or(fallback),
+            value_to_f64(items.get(1)).unwrap_or(fallback),
+        ),
+        other => {
+            let v = value_to_f64(other).unwrap_or(fallback);
+            (v, v)
+        }
+    }
+}
+
+// Defaults implied by a capture sidecar: how screen points map onto this image (rescaled when the
+// image was resized since capture) and a stroke scale matching the display density.
+fn capture_sidecar_defaults(sidecar: &Value, image_size: (u32, u32)) -> Map<String, Value> {
+    let scale = value_to_f64(sidecar.get("scale"))
+        .filter(|v| *v > 0.0)
+        .unwrap_or(1.0);
+    let mut sx = value_to_f64(sidecar.get("scale_x"))
+        .filter(|v| *v > 0.0)
+        .unwrap_or(scale);
+    let mut sy = value_to_f64(sidecar.get("scale_y"))
+        .filter(|v| *v > 0.0)
+        .unwrap_or(scale);
+    let captured = sidecar.get("image_size");
+    let captured_w = value_to_f64(captured.and_then(|size| size.get("w"))).unwrap_or(0.0);
+    let captured_h = value_to_f64(captured.and_then(|size| size.get("h"))).unwrap_or(0.0);
+    if captured_w > 0.0 && captured_h > 0.0 {
+        sx *= f64::from(image_size.0) / captured_w;
+        sy *= f64::from(image_size.1) / captured_h;
+    }
+    let bounds = sidecar.get("bounds");
+    let origin_x = value_to_f64(bounds.and_then(|b| b.get("x"))).unwrap_or(0.0);
+    let origin_y = value_to_f64(bounds.and_then(|b| b.get("y"))).unwrap_or(0.0);
+
+    let mut seeded = Map::new();
+    seeded.insert(
+        "pt_scale".to_string(),
+        json!([round_to(sx, 6), round_to(sy, 6)]),
+    );
+    seeded.insert("pt_origin".to_string(), json!([origin_x, origin_y]));
+    seeded.insert(
+        "scale".to_string(),
+        json!(round_to(scale.clamp(1.0, 2.0), 3)),
+    );
+    seeded
+}
+
+fn resolve_measure(value: &Value, span: f64, default_rel: bool) -> Option<f64> {
+    match value {
+        Value::Number(n) => {
+            let v = n.as_f64()?;
+            if default_rel {
+                Some(v * span)
+            } else {
+                Some(v)
+            }
+        }
+        Value::String(s) => {
+            let raw = s.trim().to_ascii_lowercase();
+            if raw.is_empty() {
+                return None;
+            }
+
+            if let Some(percent) = raw.strip_suffix('%') {
+                return percent.parse::<f64>().ok().map(|v| v * span / 100.0);
+            }
+
+            if let Some(rel) = raw.strip_suffix("rel") {
+                if let Ok(mut ratio) = rel.parse::<f64>() {
+                    if ratio.abs() > 1.0 {
+                        ratio /= 100.0;
+                    }
+                    return Some(ratio * span);
+                }
+            }
+
+            if let Some(px) = raw.strip_suffix("px") {
+                return px.parse::<f64>().ok();
+            }
+
+            if let Ok(v) = raw.parse::<f64>() {
+                if default_rel {
+                    return Some(v * span);
+                }
+                return Some(v);
+            }
+
+            None
+        }
+        _ => None,
+    }
+}
+
+fn assign_annotation_id(
+    ann: &mut Map<String, Value>,
+    index: usize,
+    used: &mut std::collections::HashSet<String>,
+) -> Option<String> {
+    let spec_id = value_to_string(ann.get("id")).filter(|id| !id.trim().is_empty());
+    let base = spec_id.clone().unwrap_or_else(|| format!("ann-{index}"));
+    let mut id = base.clone();
+    let mut suffix = 2;
+    while used.contains(&id) {
+        id = format!("{base}-{suffix}");
+        suffix += 1;
+    }
+    used.insert(id.clone());
+    ann.insert("id".to_string(), json!(id));
+
+    // Generated ids are renamed quietly; a clash on a spec id means anchors may have been ambiguous.
+    match spec_id {
+        Some(spec_id) if spec_id != id => Some(format!(
+            "annotation {index}: duplicate id \"{spec_id}\" renamed to \"{id}\""
+        )),
+        _ => None,
+    }
+}
+
+fn annotation_meta_item(index: usize, ann: &Map<String, Value>, img_w: u32, img_h: u32) -> Value {
+    let ann_type = ann
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let mut item = Map::new();
+    item.insert("index".to_string(), json!(index));
+    item.insert("type".to_string(), json!(ann_type));
+
+    for key in [
+        "id",
+        "units",
+        "intent",
+        "action",
+        "severity",
+        "issue",
+        "hypothesis",
+        "next_action",
+        "verify",
+    ] {
+        item.insert(
+            key.to_string(),
+            ann.get(key).cloned().unwrap_or(Value::Null),
+        );
+    }
+
+    let geometry = extract_geometry(ann, &ann_type);
+    if !geometry.is_empty() {
+        let geometry_val = Value::Object(geometry.clone());
+        item.insert("geometry".to_string(), geometry_val);
+        let rel = geometry_rel(&geometry, &ann_type, img_w, img_h);
+        if !rel.is_empty() {
+            item.insert("geometry_rel".to_string(), Value::Object(rel));
+        }
+    }
+
+    if let Some(text) = ann.get("text").and_then(Value::as_str) {
+        if !text.is_empty() {
+            item.insert("text".to_string(), json!(text));
+        }
+    }
+
+    if let Some(frame) = parse_reference_bbox(ann.get("reference_bbox")) {
+        item.insert(
+            "relative_to".to_string(),
+            ann.get("relative_to").cloned().unwrap_or(Value::Null),
+        );
+        let geometry = extract_geometry(ann, &ann_type);
+        item.insert(
+            "geometry_ref".to_string(),
+            Value::Object(geometry_in_frame(&geometry, frame)),
+        );
+    }
+
+    Value::Object(item)
+}
+
+// --actions-out: the annotation meta items an agent can act on (an action or next_action and a
+// locatable geometry), reduced to id, px click target and the action fields.
+fn actions_manifest(source: &str, image: &Path, size: (u32, u32), items: &[Value]) -> Value {
+    let actions: Vec<Value> = items.iter().filter_map(action_item).collect();
+    json!({
+        "source": source,
+        "image": abs_path(image).display().to_string(),
+        "size": {"width": size.0, "height": size.1, "units": "px"},
+        "action_count": actions.len(),
+        "actions": actions,
+    })
+}
+
+fn action_item(item: &Value) -> Option<Value> {
+    let field = |key: &str| item.get(key).cloned().unwrap_or(Value::Null);
+    if field("action").is_null() && field("next_action").is_null() {
+        return None;
+    }
+    let geometry = item.get("geometry")?;
+    let num = |key: &str| value_to_f64(geometry.get(key));
+    // Boxes act on their center, arrows/measures on the head end, points/text on their anchor.
+    let (bbox, center) = match (num("x"), num("y"), num("w"), num("h")) {
+        (Some(x), Some(y), Some(w), Some(h)) => (
+            json!({"x": x, "y": y, "w": w, "h": h}),
+            (x + w / 2.0, y + h / 2.0),
+        ),
+        (Some(x), Some(y), _, _) => (Value::Null, (x, y)),
+        _ => match (num("x2"), num("y2"), num("cx"), num("cy")) {
+            (Some(x2), Some(y2), _, _) => (Value::Null, (x2, y2)),
+            (_, _, Some(cx), Some(cy)) => (Value::Null, (cx, cy)),
+            _ => return None,
+        },
+    };
+    Some(json!({
+        "id": field("id"),
+        "index": field("index"),
+        "type": field("type"),
+        "center": {"x": round_to(center.0, 1), "y": round_to(center.1, 1)},
+        "bbox": bbox,
+        "intent": field("intent"),
+        "action": field("action"),
+        "next_action": field("next_action"),
+        "verify": field("verify"),
+        "severity": field("severity"),
+    }))
+}
+
+fn parse_reference_bbox(value: Option<&Value>) -> Option<(f64, f64, f64, f64)> {
+    let items = value?.as_array()?;
+    Some((
+        value_to_f64(items.first())?,
+        value_to_f64(items.get(1))?,
+        value_to_f64(items.get(2))?,
+        value_to_f64(items.get(3))?,
+    ))
+}
+
+fn geometry_in_frame(
+    geometry: &Map<String, Value>,
+    frame: (f64, f64, f64, f64),
+) -> Map<String, Value> {
+    let (x0, y0, x1, y1) = frame;
+    let (fw, fh) = (x1 - x0, y1 - y0);
+    let mut rel = Map::new();
+    for (key, value) in geometry {
+        let Some(num) = value.as_f64() else {
+            continue;
+        };
+        let ratio = match key.as_str() {
+            "x" | "x1" | "x2" | "cx" if fw > 0.0 => (num - x0) / fw,
+            "w" if fw > 0.0 => num / fw,
+            "y" | "y1" | "y2" | "cy" if fh > 0.0 => (num - y0) / fh,
+            "h" if fh > 0.0 => num / fh,
+            _ => continue,
+        };
+        rel.insert(key.clone(), json!(round_to(ratio, 6)));
+    }
+    rel
+}
+
+// Comma-decimal locales get ';' as the field separator, as spreadsheets in those locales expect.
+fn annotation_meta_csv(items: &[Value], locale: NumberLocale) -> String {
+    let (sep, plain) = if locale.decimal == ',' {
+        (
+            ';',
+            NumberLocale {
+                group: None,
+                ..locale
+            },
+        )
+    } else {
+        (',', NumberLocale::default())
+    };
+    let mut out = "index,type,x,y,w,h,intent,action,severity,text\n".replace(',', &sep.to_string());
+    for item in items {
+        let geometry = item.get("geometry").and_then(Value::as_object);
+        let num = |key: &str| geometry.and_then(|g| g.get(key)).and_then(Value::as_f64);
+        let bbox = match (num("x"), num("y"), num("w"), num("h")) {
+            (Some(x), Some(y), w, h) => Some((x, y, w, h)),
+            _ => match (num("x1"), num("y1"), num("x2"), num("y2")) {
+                (Some(x1), Some(y1), Some(x2), Some(y2)) => Some((
+                    x1.min(x2),
+                    y1.min(y2),
+                    Some((x2 - x1).abs()),
+                    Some((y2 - y1).abs()),
+                )),
+                _ => None,
+            },
+        };
+        let fmt_num = |v: Option<f64>| v.map(|n| plain.format(n)).unwrap_or_default();
+        let text_field = |key: &str| {
+            item.get(key)
+                .and_then(Value::as_str)
+                .map(|raw| csv_escape(raw, sep))
+                .unwrap_or_default()
+        };
+
+        let row = [
+            item.get("index")
+                .and_then(Value::as_u64)
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            text_field("type"),
+            fmt_num(bbox.map(|b| b.0)),
+            fmt_num(bbox.map(|b| b.1)),
+            fmt_num(bbox.and_then(|b| b.2)),
+            fmt_num(bbox.and_then(|b| b.3)),
+            text_field("intent"),
+            text_field("action"),
+            text_field("severity"),
+            text_field("text"),
+        ];
+        out.push_str(&row.join(&sep.to_string()));
+        out.push('\n');
+    }
+    out
+}
+
+// Resolved stroke/text sizes shared by the design-tool exporters (same defaults as rasterizing).
+fn export_style(ann: &Map<String, Value>, base_scale: f64) -> (Rgba<u8>, f64, f64) {
+    let scale = annotation_scale(ann, base_scale);
+    let ann_type = annotation_type(ann);
+    let fallback = match ann_type.as_str() {
+        "arrow" => [10, 132, 255, 255],
+        "text" => [255, 255, 255, 255],
+        _ => [255, 59, 48, 255],
+    };
+    let color = parse_color(ann.get("color"), fallback);
+    let width = value_to_f64(ann.get("width"))
+        .unwrap_or_else(|| f64::from(scale_default(3.0, scale, 2)))
+        .max(1.0);
+    let font_size = value_to_usize(ann.get("size"))
+        .map(|v| v.max(8) as f64)
+        .unwrap_or_else(|| f64::from(scale_default(14.0, scale, 10)));
+    (color, width, font_size)
+}
+
+// Rendered text box size for exporters: TrueType metrics when the annotation's font loaded.
+fn text_extent(
+    ann: &Map<String, Value>,
+    text: &str,
+    font_size: f64,
+    fonts: &FontCache,
+) -> (i32, i32) {
+    match text_ttf_mask(ann, text, font_size, fonts) {
+        Some(mask) => (mask.width as i32, mask.height as i32),
+        None => {
+            let (_, _, w, h) = text_bbox_directed(
+                0,
+                0,
+                text,
+                (font_size / 8.0).round().max(1.0) as u32,
+                text_direction(ann),
+                text_line_spacing(ann),
+            );
+            (w, h)
+        }
+    }
+}
+
+fn color_hex(color: Rgba<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn export_arrow_points(ann: &Map<String, Value>) -> Option<Vec<(f64, f64)>> {
+    let mut points = vec![(value_to_f64(ann.get("x1"))?, value_to_f64(ann.get("y1"))?)];
+    if let (Some(cx), Some(cy)) = (value_to_f64(ann.get("cx")), value_to_f64(ann.get("cy"))) {
+        points.push((cx, cy));
+    }
+    points.push((value_to_f64(ann.get("x2"))?, value_to_f64(ann.get("y2"))?));
+    Some(points)
+}
+
+fn figma_scene(
+    items: &[(usize, Map<String, Value>)],
+    img_w: u32,
+    img_h: u32,
+    base_scale: f64,
+    fonts: &FontCache,
+) -> Value {
+    let paint = |color: Rgba<u8>| {
+        json!([{
+            "type": "SOLID",
+            "color": {
+                "r": round_to(f64::from(color[0]) / 255.0, 4),
+                "g": round_to(f64::from(color[1]) / 255.0, 4),
+                "b": round_to(f64::from(color[2]) / 255.0, 4),
+            },
+            "opacity": round_to(f64::from(color[3]) / 255.0, 4),
+        }])
+    };
+
+    let mut children = Vec::new();
+    for (idx, ann) in items {
+        let ann_type = annotation_type(ann);
+        let (color, width, font_size) = export_style(ann, base_scale);
+        let name = value_to_string(ann.get("id")).unwrap_or_else(|| format!("ann-{idx}"));
+        let node = if ann_type == "arrow" {
+            let Some(points) = export_arrow_points(ann) else {
+                continue;
+            };
+            let (x0, y0) = points[0];
+            let data = match points[..] {
+                [_, (cx, cy), (x2, y2)] => format!(
+                    "M 0 0 Q {} {} {} {}",
+                    round_to(cx - x0, 2),
+                    round_to(cy - y0, 2),
+                    round_to(x2 - x0, 2),
+                    round_to(y2 - y0, 2)
+                ),
+                [_, (x2, y2)] => {
+                    format!("M 0 0 L {} {}", round_to(x2 - x0, 2), round_to(y2 - y0, 2))
+                }
+                _ => continue,
+            };
+            json!({
+                "type": "VECTOR",
+                "name": name,
+                "x": x0,
+                "y": y0,
+                "vectorPaths": [{"windingRule": "NONE", "data": data}],
+                "strokes": paint(color),
+                "strokeWeight": width,
+                "strokeCap": "ARROW_EQUILATERAL",
+                "dashPattern": dash_pattern(ann).map(|(on, off)| vec![on, off]).unwrap_or_default(),
+            })
+        } else if ann_type == "text" {
+            let text = value_to_string(ann.get("text")).unwrap_or_default();
+            if text.is_empty() {
+                continue;
+            }
+            let (text_w, text_h) = text_extent(ann, &text, font_size, fonts);
+            let (dx, dy) = text_align_shift(ann, 0, 0, (0, 0, text_w, text_h));
+            json!({
+                "type": "TEXT",
+                "name": name,
+                "x": value_to_f64(ann.get("x")).unwrap_or(0.0) + f64::from(dx),
+                "y": value_to_f64(ann.get("y")).unwrap_or(0.0) + f64::from(dy),
+                "characters": text,
+                "fontSize": font_size,
+                "fills": paint(color),
+            })
+        } else {
+            let Some((x, y, x2, y2)) = bbox_from_ann(ann) else {
+                continue;
+            };
+            let (w, h) = (x2 - x, y2 - y);
+            let spotlight = is_spotlight_type(&ann_type);
+            let fills = match parse_color_opt(ann.get("fill")) {
+                Some(fill) if !spotlight => paint(fill),
+                _ => json!([]),
+            };
+            json!({
+                "type": if is_ellipse_type(&ann_type) { "ELLIPSE" } else { "RECTANGLE" },
+                "name": name,
+                "x": x,
+                "y": y,
+                "width": w,
+                "height": h,
+                "fills": fills,
+                "strokes": paint(color),
+                "strokeWeight": width,
+                "dashPattern": match dash_pattern(ann) {
+                    Some((on, off)) => json!([on, off]),
+                    None if spotlight => json!([8, 6]),
+                    None => json!([]),
+                },
+                "cornerRadius": if ann_type == "rect" { rect_corner_radius(ann, w, h) } else { 0.0 },
+            })
+        };
+        children.push(node);
+    }
+
+    json!({
+        "type": "FRAME",
+        "name": "codex-visual-loop annotations",
+        "x": 0,
+        "y": 0,
+        "width": img_w,
+        "height": img_h,
+        "fills": [],
+        "children": children,
+    })
+}
+
+fn excalidraw_scene(
+    items: &[(usize, Map<String, Value>)],
+    base_scale: f64,
+    fonts: &FontCache,
+) -> Value {
+    let mut elements = Vec::new();
+    for (idx, ann) in items {
+        let ann_type = annotation_type(ann);
+        let (color, width, font_size) = export_style(ann, base_scale);
+        let mut element = json!({
+            "id": value_to_string(ann.get("id")).unwrap_or_else(|| format!("ann-{idx}")),
+            "angle": 0,
+            "strokeColor": color_hex(color),
+            "backgroundColor": "transparent",
+            "fillStyle": "solid",
+            "strokeWidth": width,
+            "strokeStyle": if dash_pattern(ann).is_some() { "dashed" } else { "solid" },
+            "roughness": 0,
+            "opacity": (f64::from(color[3]) / 255.0 * 100.0).round(),
+            "groupIds": [],
+            "frameId": null,
+            "roundness": null,
+            "seed": idx + 1,
+            "version": 1,
+            "versionNonce": idx + 1,
+            "isDeleted": false,
+            "boundElements": null,
+            "link": null,
+            "locked": false,
+        });
+        let Some(obj) = element.as_object_mut() else {
+            continue;
+        };
+        if ann_type == "arrow" {
+            let Some(mut points) = export_arrow_points(ann) else {
+                continue;
+            };
+            // Excalidraw's rounded arrows pass through every point, so a curve's quadratic
+            // control point becomes the curve's own midpoint.
+            if let [(x1, y1), (cx, cy), (x2, y2)] = points[..] {
+                points[1] = (
+                    0.25 * x1 + 0.5 * cx + 0.25 * x2,
+                    0.25 * y1 + 0.5 * cy + 0.25 * y2,
+                );
+                obj.insert("roundness".to_string(), json!({"type": 2}));
+            }
+            let (x0, y0) = points[0];
+            let (max_dx, max_dy) = points.iter().fold((0.0f64, 0.0f64), |acc, (x, y)| {
+                (acc.0.max((x - x0).abs()), acc.1.max((y - y0).abs()))
+            });
+            obj.insert("type".to_string(), json!("arrow"));
+            obj.insert("x".to_string(), json!(x0));
+            obj.insert("y".to_string(), json!(y0));
+            obj.insert("width".to_string(), json!(max_dx));
+            obj.insert("height".to_string(), json!(max_dy));
+            obj.insert(
+                "points".to_string(),
+                json!(points
+                    .iter()
+                    .map(|(x, y)| [round_to(x - x0, 2), round_to(y - y0, 2)])
+                    .collect::<Vec<_>>()),
+            );
+            obj.insert("startArrowhead".to_string(), Value::Null);
+            obj.insert("endArrowhead".to_string(), json!("arrow"));
+        } else if ann_type == "text" {
+            let text = value_to_string(ann.get("text")).unwrap_or_default();
+            if text.is_empty() {
+                continue;
+            }
+            let (x, y) = (
+                value_to_f64(ann.get("x")).unwrap_or(0.0),
+                value_to_f64(ann.get("y")).unwrap_or(0.0),
+            );
+            let (text_w, text_h) = text_extent(ann, &text, font_size, fonts);
+            let (dx, dy) = text_align_shift(ann, 0, 0, (0, 0, text_w, text_h));
+            obj.insert("type".to_string(), json!("text"));
+            obj.insert("x".to_string(), json!(x + f64::from(dx)));
+            obj.insert("y".to_string(), json!(y + f64::from(dy)));
+            obj.insert("width".to_string(), json!(text_w));
+            obj.insert("height".to_string(), json!(text_h));
+            obj.insert("text".to_string(), json!(text));
+            obj.insert("originalText".to_string(), json!(text));
+            obj.insert("fontSize".to_string(), json!(font_size));
+            obj.insert("fontFamily".to_string(), json!(1));
+            obj.insert("textAlign".to_string(), json!("left"));
+            obj.insert("verticalAlign".to_string(), json!("top"));
+            obj.insert("containerId".to_string(), Value::Null);
+            obj.insert("lineHeight".to_string(), json!(1.25));
+        } else {
+            let Some((x, y, x2, y2)) = bbox_from_ann(ann) else {
+                continue;
+            };
+            let (w, h) = (x2 - x, y2 - y);
+            let spotlight = is_spotlight_type(&ann_type);
+            let shape = if is_ellipse_type(&ann_type) {
+                "ellipse"
+            } else {
+                "rectangle"
+            };
+            obj.insert("type".to_string(), json!(shape));
+            obj.insert("x".to_string(), json!(x));
+            obj.insert("y".to_string(), json!(y));
+            obj.insert("width".to_string(), json!(w));
+            obj.insert("height".to_string(), json!(h));
+            if ann_type == "rect" && rect_corner_radius(ann, w, h) > 0.0 {
+                obj.insert("roundness".to_string(), json!({"type": 3}));
+            }
+            if spotlight {
+                obj.insert("strokeStyle".to_string(), json!("dashed"));
+            } else if let Some(fill) = parse_color_opt(ann.get("fill")) {
+                obj.insert("backgroundColor".to_string(), json!(color_hex(fill)));
+            }
+        }
+        elements.push(element);
+    }
+
+    json!({
+        "type": "excalidraw",
+        "version": 2,
+        "source": "codex-visual-loop",
+        "elements": elements,
+        "appState": {"viewBackgroundColor": "#ffffff", "gridSize": null},
+        "files": {},
+    })
+}
+
+fn csv_escape(raw: &str, sep: char) -> String {
+    if raw.contains([sep, '"', '\n', '\r']) {
+        format!("\"{}\"", raw.replace('"', "\"\""))
+    } else {
+        raw.to_string()
+    }
+}
+
+// Decimal and digit-group separators for --locale. The default is the canonical JSON form
+// (`1234.5`); grouping applies to the integer part only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NumberLocale {
+    decimal: char,
+    group: Option<char>,
+}
+
+impl Default for NumberLocale {
+    fn default() -> Self {
+        NumberLocale {
+            decimal: '.',
+            group: None,
+        }
+    }
+}
+
+impl NumberLocale {
+    // Rounded to 4 decimals like the CSV always was, then re-punctuated.
+    fn format(&self, n: f64) -> String {
+        let canonical = round_to(n, 4).to_string();
+        let (sign, digits) = match canonical.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", canonical.as_str()),
+        };
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        let mut out = sign.to_string();
+        for (i, ch) in int.chars().enumerate() {
+            if let Some(group) = self.group {
+                if i > 0 && (int.len() - i) % 3 == 0 {
+                    out.push(group);
+                }
+            }
+            out.push(ch);
+        }
+        if !frac.is_empty() {
+            out.push(self.decimal);
+            out.push_str(frac);
+        }
+        out
+    }
+}
+
+// Keyed on the language subtag only (`de-AT` and `de_CH.UTF-8` both read as `de`).
+fn parse_number_locale(tag: &str) -> std::result::Result<NumberLocale, String> {
+    let lang = tag
+        .split(['-', '_', '.'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let (decimal, group) = match lang.as_str() {
+        "c" | "posix" => return Ok(NumberLocale::default()),
+        "en" | "ja" | "zh" | "ko" | "he" | "th" | "hi" => ('.', ','),
+        "de" | "nl" | "it" | "es" | "pt" | "da" | "id" | "tr" | "el" | "ro" | "hr" | "sl" => {
+            (',', '.')
+        }
+        "fr" | "ru" | "pl" | "sv" | "nb" | "nn" | "no" | "fi" | "cs" | "sk" | "uk" | "hu"
+        | "bg" | "et" | "lt" | "lv" => (',', '\u{a0}'),
+        _ => return Err(format!("unsupported locale {tag:?} (try en, de, fr, ...)")),
+    };
+    Ok(NumberLocale {
+        decimal,
+        group: Some(group),
+    })
+}
+
+fn extract_geometry(ann: &Map<String, Value>, ann_type: &str) -> Map<String, Value> {
+    let keys: &[&str] = match ann_type {
+        "rect" | "ellipse" | "circle" | "spotlight" | "focus" | "dim" => &["x", "y", "w", "h"],
+        "arrow" => &["x1", "y1", "x2", "y2", "cx", "cy"],
+        "measure" => &["x1", "y1", "x2", "y2"],
+        "text" | "point" | "crosshair" => &["x", "y"],
+        _ => &[],
+    };
+
+    let mut geometry = Map::new();
+    for key in keys {
+        if let Some(value) = ann.get(*key) {
+            if let Some(n) = normalize_number(value) {
+                geometry.insert((*key).to_string(), n);
+            }
+        }
+    }
+    geometry
+}
+
+fn geometry_rel(
+    geometry: &Map<String, Value>,
+    ann_type: &str,
+    img_w: u32,
+    img_h: u32,
+) -> Map<String, Value> {
+    let mut rel = Map::new();
+
+    for (key, value) in geometry {
+        let num = match value.as_f64() {
+            Some(v) => v,
+            None => continue,
+        };
+
+        if matches!(key.as_str(), "x" | "x1" | "x2" | "cx" | "w") && img_w > 0 {
+            rel.insert(key.clone(), json!(round_to(num / img_w as f64, 6)));
+        } else if matches!(key.as_str(), "y" | "y1" | "y2" | "cy" | "h") && img_h > 0 {
+            rel.insert(key.clone(), json!(round_to(num / img_h as f64, 6)));
+        }
+    }
+
+    if matches!(
+        ann_type,
+        "rect" | "ellipse" | "circle" | "spotlight" | "focus" | "dim"
+    ) {
+        let x = geometry.get("x").and_then(Value::as_f64);
+        let y = geometry.get("y").and_then(Value::as_f64);
+        let w = geometry.get("w").and_then(Value::as_f64);
+        let h = geometry.get("h").and_then(Value::as_f64);
+
+        if let (Some(x), Some(y), Some(w), Some(h)) = (x, y, w, h) {
+            rel.insert(
+                "bbox".to_string(),
+                json!({
+                    "x": if img_w > 0 { round_to(x / img_w as f64, 6) } else { 0.0 },
+                    "y": if img_h > 0 { round_to(y / img_h as f64, 6) } else { 0.0 },
+                    "w": if img_w > 0 { round_to(w / img_w as f64, 6) } else { 0.0 },
+                    "h": if img_h > 0 { round_to(h / img_h as f64, 6) } else { 0.0 },
+                }),
+            );
+        }
+    }
+
+    rel
+}
+
+fn normalize_number(value: &Value) -> Option<Value> {
+    if value.is_boolean() {
+        return Some(value.clone());
+    }
+    if let Some(i) = value.as_i64() {
+        return Some(json!(i));
+    }
+    if let Some(f) = value.as_f64() {
+        let rounded = round_to(f, 4);
+        if (rounded - rounded.round()).abs() < 1e-6 {
+            return Some(json!(rounded.round() as i64));
+        }
+        return Some(json!(rounded));
+    }
+    None
+}
+
+// Aliases collapse onto the name they draw as, so --only-types spotlight also covers focus/dim.
+fn canonical_annotation_type(ann_type: &str) -> String {
+    match ann_type {
+        "focus" | "dim" => "spotlight".to_string(),
+        "crosshair" => "point".to_string(),
+        "circle" => "ellipse".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn canonical_type_filter(raw: &[String], flag: &str) -> Result<Vec<String>> {
+    let mut types = Vec::new();
+    for item in raw {
+        let ann_type = item.trim().to_ascii_lowercase();
+        if ann_type.is_empty() {
+            continue;
+        }
+        if !matches!(
+            ann_type.as_str(),
+            "rect"
+                | "ellipse"
+                | "circle"
+                | "arrow"
+                | "text"
+                | "spotlight"
+                | "focus"
+                | "dim"
+                | "point"
+                | "crosshair"
+                | "measure"
+        ) {
+            return Err(cli_failure(
+                EXIT_INPUT,
+                format!("unknown annotation type {ann_type:?} in {flag}: expected rect|ellipse|arrow|text|spotlight|point|measure"),
+            ));
+        }
+        types.push(canonical_annotation_type(&ann_type));
+    }
+    Ok(types)
+}
+
+fn annotation_type(ann: &Map<String, Value>) -> String {
+    ann.get("type")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// Built-in color presets for defaults.theme; None for unknown themes.
+fn theme_palette(theme: &str, ann_type: &str) -> Option<Map<String, Value>> {
+    let theme = theme.trim().to_ascii_lowercase().replace('-', "_");
+    let (rect, arrow, text, text_bg, outline) = match theme.as_str() {
+        // Matches the built-in draw fallbacks; outlines stay automatic.
+        "" | "default" | "light" => return Some(Map::new()),
+        "dark" => (
+            "#FF6961",
+            "#64D2FF",
+            "#FFFFFF",
+            "rgba(28,28,30,0.82)",
+            "#F2F2F7",
+        ),
+        "high_contrast" => ("#FFD60A", "#FFD60A", "#000000", "#FFD60A", "#000000"),
+        _ => return None,
+    };
+    let mut palette = Map::new();
+    match ann_type {
+        "rect" | "ellipse" | "circle" => {
+            palette.insert("color".to_string(), json!(rect));
+            palette.insert("outline_color".to_string(), json!(outline));
+        }
+        "arrow" => {
+            palette.insert("color".to_string(), json!(arrow));
+            palette.insert("outline_color".to_string(), json!(outline));
+        }
+        "text" => {
+            palette.insert("color".to_string(), json!(text));
+            palette.insert("text_bg".to_string(), json!(text_bg));
+            palette.insert("outline_color".to_string(), json!(outline));
+        }
+        _ => {}
+    }
+    Some(palette)
+}
+
+fn is_spotlight_type(kind: &str) -> bool {
+    matches!(kind, "spotlight" | "focus" | "dim")
+}
+
+fn is_ellipse_type(kind: &str) -> bool {
+    matches!(kind, "ellipse" | "circle")
+}
+
+fn resolve_scale(defaults: &Map<String, Value>, img_w: u32, img_h: u32) -> f64 {
+    if let Some(scale) = defaults.get("scale").and_then(Value::as_f64) {
+        return scale.max(0.1);
+    }
+
+    let mut auto_scale = true;
+    if let Some(value) = defaults.get("auto_scale") {
+        auto_scale = value_to_bool(value, true);
+    }
+    if !auto_scale {
+        return 1.0;
+    }
+    let max_dim = f64::from(img_w.max(img_h).max(1));
+    let raw = max_dim / 1200.0;
+    raw.clamp(1.0, 2.0)
+}
+
+fn value_to_bool(value: &Value, default_value: bool) -> bool {
+    match value {
+        Value::Bool(v) => *v,
+        Value::Number(n) => n.as_i64().map(|v| v != 0).unwrap_or(default_value),
+        Value::String(s) => {
+            let key = s.trim().to_ascii_lowercase();
+            if matches!(key.as_str(), "1" | "true" | "yes" | "on") {
+                true
+            } else if matches!(key.as_str(), "0" | "false" | "no" | "off") {
+                false
+            } else {
+                default_value
+            }
+        }
+        _ => default_value,
+    }
+}
+
+fn value_to_f64(value: Option<&Value>) -> Option<f64> {
+    match value {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+fn value_to_i32(value: Option<&Value>, fallback: i32) -> i32 {
+    value_to_f64(value)
+        .map(|v| v.round() as i32)
+        .unwrap_or(fallback)
+}
+
+fn value_to_usize(value: Option<&Value>) -> Option<usize> {
+    match value {
+        Some(Value::Number(n)) => n.as_u64().map(|v| v as usize),
+        Some(Value::String(s)) => s.trim().parse::<usize>().ok(),
+        _ => None,
+    }
+}
+
+fn value_to_string(value: Option<&Value>) -> Option<String> {
+    match value {
+        Some(Value::String(s)) => {
+            let v = s.trim();
+            if v.is_empty() {
+                None
+            } else {
+                Some(v.to_string())
+            }
+        }
+        Some(v) if !v.is_null() => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_offset_value(value: Option<&Value>) -> Option<(f64, f64)> {
+    let value = value?;
+    match value {
+        Value::Array(items) if items.len() >= 2 => {
+            Some((value_to_f64(items.first())?, value_to_f64(items.get(1))?))
+        }
+        Value::String(raw) => {
+            let parts: Vec<&str> = raw.split(',').map(str::trim).collect();
+            if parts.len() < 2 {
+                return None;
+            }
+            let x = parts[0].parse::<f64>().ok()?;
+            let y = parts[1].parse::<f64>().ok()?;
+            Some((x, y))
+        }
+        _ => None,
+    }
+}
+
+fn parse_color(value: Option<&Value>, fallback: [u8; 4]) -> Rgba<u8> {
+    parse_color_opt(value).unwrap_or(Rgba(fallback))
+}
+
+fn parse_color_opt(value: Option<&Value>) -> Option<Rgba<u8>> {
+    let raw = value_to_string(value)?;
+    let s = raw.trim();
+    if let Some(hex) = s.strip_prefix('#') {
+        if hex.len() == 6 {
+            let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
+            let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
+            let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
+            return Some(Rgba([r, g, b, 255]));
+        }
+        if hex.len() == 8 {
+            let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
+            let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
+            let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
+            let a = u8::from_str_radix(&hex[6..8], 16).ok()?;
+            return Some(Rgba([r, g, b, a]));
+        }
+    }
+
+    let lower = s.to_ascii_lowercase();
+    if lower.starts_with("rgba(") && lower.ends_with(')') {
+        let body = &lower[5..lower.len() - 1];
+        let parts: Vec<&str> = body.split(',').map(str::trim).collect();
+        if parts.len() != 4 {
+            return None;
+        }
+        let r = parts[0].parse::<f64>().ok()?.round().clamp(0.0, 255.0) as u8;
+        let g = parts[1].parse::<f64>().ok()?.round().clamp(0.0, 255.0) as u8;
+        let b = parts[2].parse::<f64>().ok()?.round().clamp(0.0, 255.0) as u8;
+        let alpha_value = parts[3].parse::<f64>().ok()?;
+        let a = if alpha_value <= 1.0 {
+            (alpha_value * 255.0).round().clamp(0.0, 255.0) as u8
+        } else {
+            alpha_value.round().clamp(0.0, 255.0) as u8
+        };
+        return Some(Rgba([r, g, b, a]));
+    }
+
+    None
+}
+
+fn color_luma(color: Rgba<u8>) -> f64 {
+    let [r, g, b, _] = color.0;
+    (0.2126 * f64::from(r) + 0.7152 * f64::from(g) + 0.0722 * f64::from(b)) / 255.0
+}
+
+fn auto_outline_color(color: Rgba<u8>) -> Rgba<u8> {
+    if color_luma(color) > 0.6 {
+        Rgba([0, 0, 0, 220])
+    } else {
+        Rgba([255, 255, 255, 220])
+    }
+}
+
+// defaults.stroke_units="rel" reads stroke sizes as fractions of the image diagonal.
+fn rel_stroke_px(ann: &Map<String, Value>, key: &str, img: &RgbaImage) -> Option<f64> {
+    let rel = value_to_string(ann.get("stroke_units"))
+        .map(|units| units.eq_ignore_ascii_case("rel"))
+        .unwrap_or(false);
+    if !rel {
+        return None;
+    }
+    let diagonal = f64::from(img.width()).hypot(f64::from(img.height()));
+    value_to_f64(ann.get(key)).map(|v| v * diagonal)
+}
+
+fn scale_default(value: f64, scale: f64, min_value: u32) -> u32 {
+    ((value * scale).round() as i64).max(i64::from(min_value)) as u32
+}
+
+fn clamp_i32(value: i32, min_value: i32, max_value: i32) -> i32 {
+    value.max(min_value).min(max_value)
+}
+
+fn blend_pixel(dst: Rgba<u8>, src: Rgba<u8>) -> Rgba<u8> {
+    let a = f64::from(src[3]) / 255.0;
+    if a <= 0.0 {
+        return dst;
+    }
+    if dst[3] < 255 {
+        return blend_pixel_over(dst, src, a);
+    }
+    let inv = 1.0 - a;
+    let r = (f64::from(dst[0]) * inv + f64::from(src[0]) * a)
+        .round()
+        .clamp(0.0, 255.0) as u8;
+    let g = (f64::from(dst[1]) * inv + f64::from(src[1]) * a)
+        .round()
+        .clamp(0.0, 255.0) as u8;
+    let b = (f64::from(dst[2]) * inv + f64::from(src[2]) * a)
+        .round()
+        .clamp(0.0, 255.0) as u8;
+    let out_a = (f64::from(dst[3]) + f64::from(src[3]) * inv)
+        .round()
+        .clamp(0.0, 255.0) as u8;
+    Rgba([r, g, b, out_a])
+}
+
+// Straight-alpha "over" for destinations that are not opaque, such as the --overlay-only and
+// --layered-out canvases, so half-transparent marks keep their color instead of darkening.
+fn blend_pixel_over(dst: Rgba<u8>, src: Rgba<u8>, a: f64) -> Rgba<u8> {
+    let dst_a = f64::from(dst[3]) / 255.0 * (1.0 - a);
+    let out_a = a + dst_a;
+    let channel = |i: usize| {
+        ((f64::from(dst[i]) * dst_a + f64::from(src[i]) * a) / out_a)
+            .round()
+            .clamp(0.0, 255.0) as u8
+    };
+    Rgba([
+        channel(0),
+        channel(1),
+        channel(2),
+        (out_a * 255.0).round().clamp(0.0, 255.0) as u8,
+    ])
+}
+
+// Pixel window (x0, y0, x1, y1), max exclusive, that a drawing call may write to: an
+// annotation's "clip". None leaves the whole image writable.
+type ClipRect = Option<(i32, i32, i32, i32)>;
+
+fn clip_contains(clip: ClipRect, x: i32, y: i32) -> bool {
+    clip.is_none_or(|(x0, y0, x1, y1)| x >= x0 && y >= y0 && x < x1 && y < y1)
+}
+
+// Blends `color` over (x, y) when the pixel is on the image and inside `clip`.
+fn blend_at(img: &mut RgbaImage, x: i32, y: i32, color: Rgba<u8>, clip: ClipRect) {
+    if x < 0 || y < 0 || x >= img.width() as i32 || y >= img.height() as i32 {
+        return;
+    }
+    if clip_contains(clip, x, y) {
+        let dst = *img.get_pixel(x as u32, y as u32);
+        img.put_pixel(x as u32, y as u32, blend_pixel(dst, color));
+    }
+}
+
+fn draw_disc(img: &mut RgbaImage, cx: f64, cy: f64, radius: f64, color: Rgba<u8>, clip: ClipRect) {
+    if radius <= 0.1 {
+        blend_at(img, cx.round() as i32, cy.round() as i32, color, clip);
+        return;
+    }
+    let min_x = clamp_i32((cx - radius).floor() as i32, 0, img.width() as i32 - 1);
+    let max_x = clamp_i32((cx + radius).ceil() as i32, 0, img.width() as i32 - 1);
+    let min_y = clamp_i32((cy - radius).floor() as i32, 0, img.height() as i32 - 1);
+    let max_y = clamp_i32((cy + radius).ceil() as i32, 0, img.height() as i32 - 1);
+    let r2 = radius * radius;
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let dx = f64::from(x) - cx;
+            let dy = f64::from(y) - cy;
+            if dx * dx + dy * dy <= r2 {
+                blend_at(img, x, y, color, clip);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_thick_line(
+    img: &mut RgbaImage,
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+    color: Rgba<u8>,
+    width: f64,
+    clip: ClipRect,
+) {
+    draw_thick_line_dashed(img, (x1, y1), (x2, y2), color, width, None, 0.0, clip);
+}
+
+// With a `dash` of (on, off) px, discs are skipped while the distance travelled (starting at
+// `offset`) is in an off run. Returns the distance at the end of the segment so consecutive
+// segments of one stroke keep a continuous pattern.
+#[allow(clippy::too_many_arguments)]
+fn draw_thick_line_dashed(
+    img: &mut RgbaImage,
+    from: (f64, f64),
+    to: (f64, f64),
+    color: Rgba<u8>,
+    width: f64,
+    dash: Option<(f64, f64)>,
+    offset: f64,
+    clip: ClipRect,
+) -> f64 {
+    let dx = to.0 - from.0;
+    let dy = to.1 - from.1;
+    let distance = (dx * dx + dy * dy).sqrt();
+    let steps = distance.max(1.0).ceil() as i32;
+    let radius = (width.max(1.0) / 2.0).max(0.6);
+    for step in 0..=steps {
+        let t = f64::from(step) / f64::from(steps.max(1));
+        if let Some((on, off)) = dash {
+            if (offset + t * distance).rem_euclid(on + off) >= on {
+                continue;
+            }
+        }
+        let x = from.0 + dx * t;
+        let y = from.1 + dy * t;
+        draw_disc(img, x, y, radius, color, clip);
+    }
+    offset + distance
+}
+
+// "dash": [on, off] in px, or one number for equal on/off runs. Anything else (or nothing)
+// keeps the stroke solid.
+fn dash_pattern(ann: &Map<String, Value>) -> Option<(f64, f64)> {
+    let (on, off) = match ann.get("dash")? {
+        Value::Array(items) => match items.as_slice() {
+            [on, off] => (value_to_f64(Some(on))?, value_to_f64(Some(off))?),
+            _ => return None,
+        },
+        value => {
+            let run = value_to_f64(Some(value))?;
+            (run, run)
+        }
+    };
+    (on > 0.0 && off > 0.0).then_some((on, off))
+}
+
+fn triangle_area(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+    ((a.0 * (b.1 - c.1) + b.0 * (c.1 - a.1) + c.0 * (a.1 - b.1)).abs()) / 2.0
+}
+
+fn point_in_triangle(p: (f64, f64), a: (f64, f64), b: (f64, f64), c: (f64, f64), eps: f64) -> bool {
+    let total = triangle_area(a, b, c);
+    if total <= eps {
+        return false;
+    }
+    let a1 = triangle_area(p, b, c);
+    let a2 = triangle_area(a, p, c);
+    let a3 = triangle_area(a, b, p);
+    (a1 + a2 + a3 - total).abs() <= eps
+}
+
+fn fill_triangle(
+    img: &mut RgbaImage,
+    a: (f64, f64),
+    b: (f64, f64),
+    c: (f64, f64),
+    color: Rgba<u8>,
+    clip: ClipRect,
+) {
+    let min_x = clamp_i32(
+        a.0.min(b.0).min(c.0).floor() as i32,
+        0,
+        img.width() as i32 - 1,
+    );
+    let max_x = clamp_i32(
+        a.0.max(b.0).max(c.0).ceil() as i32,
+        0,
+        img.width() as i32 - 1,
+    );
+    let min_y = clamp_i32(
+        a.1.min(b.1).min(c.1).floor() as i32,
+        0,
+        img.height() as i32 - 1,
+    );
+    let max_y = clamp_i32(
+        a.1.max(b.1).max(c.1).ceil() as i32,
+        0,
+        img.height() as i32 - 1,
+    );
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let p = (f64::from(x) + 0.5, f64::from(y) + 0.5);
+            if point_in_triangle(p, a, b, c, 0.8) {
+                blend_at(img, x, y, color, clip);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_arrow_primitive(
+    img: &mut RgbaImage,
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+    color: Rgba<u8>,
+    width: f64,
+    head_len: f64,
+    head_width: f64,
+    head_shape: &str,
+    dash: Option<(f64, f64)>,
+    clip: ClipRect,
+) {
+    let angle = (y2 - y1).atan2(x2 - x1);
+    let (cos, sin) = (angle.cos(), angle.sin());
+    // Point `along` px back from the tip, offset `side` px perpendicular to the shaft.
+    let at = |along: f64, side: f64| (x2 - along * cos - side * sin, y2 - along * sin + side * cos);
+    let half = head_width / 2.0;
+    let shaft = |img: &mut RgbaImage, end: (f64, f64)| {
+        draw_thick_line_dashed(img, (x1, y1), end, color, width, dash, 0.0, clip);
+    };
+    match head_shape {
+        "open" => {
+            shaft(img, (x2, y2));
+            for side in [half, -half] {
+                let (bx, by) = at(head_len, side);
+                draw_thick_line(img, x2, y2, bx, by, color, width, clip);
+            }
+        }
+        "diamond" => {
+            let back = at(head_len, 0.0);
+            shaft(img, back);
+            let (left, right) = (at(head_len / 2.0, half), at(head_len / 2.0, -half));
+            fill_triangle(img, (x2, y2), left, right, color, clip);
+            fill_triangle(img, back, left, right, color, clip);
+        }
+        "dot" => {
+            let radius = head_len.max(head_width) / 2.0;
+            let center = at(radius, 0.0);
+            shaft(img, center);
+            draw_disc(img, center.0, center.1, radius, color, clip);
+        }
+        _ => {
+            let back = at(head_len, 0.0);
+            shaft(img, back);
+            fill_triangle(
+                img,
+                (x2, y2),
+                at(head_len, half),
+                at(head_len, -half),
+                color,
+                clip,
+            );
+        }
+    }
+}
+
+fn quad_bezier_point(p0: (f64, f64), ctrl: (f64, f64), p1: (f64, f64), t: f64) -> (f64, f64) {
+    let u = 1.0 - t;
+    (
+        u * u * p0.0 + 2.0 * u * t * ctrl.0 + t * t * p1.0,
+        u * u * p0.1 + 2.0 * u * t * ctrl.1 + t * t * p1.1,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_curved_arrow_primitive(
+    img: &mut RgbaImage,
+    from: (f64, f64),
+    ctrl: (f64, f64),
+    to: (f64, f64),
+    color: Rgba<u8>,
+    width: f64,
+    head_len: f64,
+    head_width: f64,
+    head_shape: &str,
+    dash: Option<(f64, f64)>,
+    clip: ClipRect,
+) {
+    // Head follows the end tangent (ctrl -> to); the body stops where the head begins.
+    let angle = (to.1 - ctrl.1).atan2(to.0 - ctrl.0);
+    let back = (to.0 - head_len * angle.cos(), to.1 - head_len * angle.sin());
+
+    let steps = 32;
+    let mut prev = from;
+    let mut travelled = 0.0;
+    for step in 1..=steps {
+        let point = quad_bezier_point(from, ctrl, to, step as f64 / steps as f64);
+        if (point.0 - to.0).hypot(point.1 - to.1) < head_len {
+            break;
+        }
+        travelled = draw_thick_line_dashed(img, prev, point, color, width, dash, travelled, clip);
+        prev = point;
+    }
+    draw_thick_line_dashed(img, prev, back, color, width, dash, travelled, clip);
+    draw_arrow_primitive(
+        img, back.0, back.1, to.0, to.1, color, width, head_len, head_width, head_shape, None, clip,
+    );
+}
+
+fn draw_bitmap_text(
+    img: &mut RgbaImage,
+    x: i32,
+    y: i32,
+    text: &str,
+    color: Rgba<u8>,
+    scale: u32,
+    clip: ClipRect,
+) {
+    draw_bitmap_text_directed(img, x, y, text, color, scale, "ltr", 0, clip);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_bitmap_text_directed(
+    img: &mut RgbaImage,
+    x: i32,
+    y: i32,
+    text: &str,
+    color: Rgba<u8>,
+    scale: u32,
+    direction: &str,
+    line_spacing: i32,
+    clip: ClipRect,
+) {
+    let scale_i = scale.max(1) as i32;
+    for (ch, cursor_x, cursor_y) in text_glyph_origins(x, y, text, scale, direction, line_spacing) {
+        let Some(glyph) = BASIC_FONTS.get(ch).or_else(|| BASIC_FONTS.get('?')) else {
+            continue;
+        };
+        for (row_idx, row) in glyph.iter().enumerate() {
+            let row_bits = *row;
+            for col_idx in 0..8 {
+                if (row_bits >> col_idx) & 1 == 0 {
+                    continue;
+                }
+                let px = cursor_x + col_idx * scale_i;
+                let py = cursor_y + row_idx as i32 * scale_i;
+                for sy in 0..scale_i {
+                    for sx in 0..scale_i {
+                        blend_at(img, px + sx, py + sy, color, clip);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Top-left of each 8x8 glyph cell; (x, y) is always the top-left of text_bbox_directed.
+// ltr advances right and starts each line below the previous one, rtl right-aligns each line
+// with its first char rightmost, vertical stacks chars downward and starts each line as a column
+// to the left. `line_spacing` px are added between lines (columns for vertical).
+fn text_glyph_origins(
+    x: i32,
+    y: i32,
+    text: &str,
+    scale: u32,
+    direction: &str,
+    line_spacing: i32,
+) -> Vec<(char, i32, i32)> {
+    let step = 8 * scale.max(1) as i32;
+    let line_step = step + line_spacing;
+    let lines: Vec<Vec<char>> = text
+        .split('\n')
+        .map(|line| line.chars().collect())
+        .collect();
+    let longest = lines.iter().map(Vec::len).max().unwrap_or(0) as i32;
+    let columns = lines.len() as i32;
+    let mut origins = Vec::new();
+    for (line_idx, line) in lines.iter().enumerate() {
+        let line_idx = line_idx as i32;
+        for (idx, ch) in line.iter().enumerate() {
+            let idx = idx as i32;
+            let origin = match direction {
+                "rtl" => (x + (longest - 1 - idx) * step, y + line_idx * line_step),
+                "vertical" => (x + (columns - 1 - line_idx) * line_step, y + idx * step),
+                _ => (x + idx * step, y + line_idx * line_step),
+            };
+            origins.push((*ch, origin.0, origin.1));
+        }
+    }
+    origins
+}
+
+fn text_bbox(x: i32, y: i32, text: &str, scale: u32) -> (i32, i32, i32, i32) {
+    text_bbox_directed(x, y, text, scale, "ltr", 0)
+}
+
+fn text_bbox_directed(
+    x: i32,
+    y: i32,
+    text: &str,
+    scale: u32,
+    direction: &str,
+    line_spacing: i32,
+) -> (i32, i32, i32, i32) {
+    let scale_i = scale.max(1) as i32;
+    let lines: Vec<&str> = text.split('\n').collect();
+    let width_chars = lines
+        .iter()
+        .map(|line| line.chars().count() as i32)
+        .max()
+        .unwrap_or(0);
+    let line_count = lines.len().max(1) as i32;
+    let spacing = (line_count - 1) * line_spacing;
+    if direction == "vertical" {
+        (
+            x,
+            y,
+            x + line_count * 8 * scale_i + spacing,
+            y + width_chars * 8 * scale_i,
+        )
+    } else {
+        (
+            x,
+            y,
+            x + width_chars * 8 * scale_i,
+            y + line_count * 8 * scale_i + spacing,
+        )
+    }
+}
+
+// "line_spacing": extra px between text lines (default 0, negative values are ignored).
+fn text_line_spacing(ann: &Map<String, Value>) -> i32 {
+    value_to_f64(ann.get("line_spacing"))
+        .unwrap_or(0.0)
+        .max(0.0)
+        .round() as i32
+}
+
+// fill_rect_alpha (corners inclusive) gated by point_in_rounded_rect; radius 0 is a plain fill.
+fn fill_rounded_rect_alpha(
+    img: &mut RgbaImage,
+    rect: (i32, i32, i32, i32),
+    radius: f64,
+    color: Rgba<u8>,
+    clip: ClipRect,
+) {
+    let (x0, y0, x1, y1) = rect;
+    if radius <= 0.0 {
+        fill_rect_alpha(img, x0, y0, x1, y1, color, clip);
+        return;
+    }
+    if img.width() == 0 || img.height() == 0 {
+        return;
+    }
+    let (left, right) = (x0.min(x1), x0.max(x1));
+    let (top, bottom) = (y0.min(y1), y0.max(y1));
+    for y in
+        clamp_i32(top, 0, img.height() as i32 - 1)..=clamp_i32(bottom, 0, img.height() as i32 - 1)
+    {
+        for x in
+            clamp_i32(left, 0, img.width() as i32 - 1)..=clamp_i32(right, 0, img.width() as i32 - 1)
+        {
+            if point_in_rounded_rect(x, y, left, top, right + 1, bottom + 1, radius) {
+                blend_at(img, x, y, color, clip);
+            }
+        }
+    }
+}
+
+fn fill_rect_alpha(
+    img: &mut RgbaImage,
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    color: Rgba<u8>,
+    clip: ClipRect,
+) {
+    if img.width() == 0 || img.height() == 0 {
+        return;
+    }
+    let min_x = clamp_i32(x0.min(x1), 0, img.width() as i32 - 1);
+    let max_x = clamp_i32(x0.max(x1), 0, img.width() as i32 - 1);
+    let min_y = clamp_i32(y0.min(y1), 0, img.height() as i32 - 1);
+    let max_y = clamp_i32(y0.max(y1), 0, img.height() as i32 - 1);
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            blend_at(img, x, y, color, clip);
+        }
+    }
+}
+
+fn bbox_from_ann(ann: &Map<String, Value>) -> Option<(f64, f64, f64, f64)> {
+    let x = value_to_f64(ann.get("x"))?;
+    let y = value_to_f64(ann.get("y"))?;
+    let w = value_to_f64(ann.get("w"))?;
+    let h = value_to_f64(ann.get("h"))?;
+    if w <= 0.0 || h <= 0.0 {
+        return None;
+    }
+    Some((x, y, x + w, y + h))
+}
+
+fn anchor_point(bbox: (f64, f64, f64, f64), pos: &str) -> (f64, f64) {
+    let (x0, y0, x1, y1) = bbox;
+    let cx = (x0 + x1) / 2.0;
+    let cy = (y0 + y1) / 2.0;
+    match pos.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+        "top" => (cx, y0),
+        "bottom" => (cx, y1),
+        "left" => (x0, cy),
+        "right" => (x1, cy),
+        "top_left" => (x0, y0),
+        "top_right" => (x1, y0),
+        "bottom_left" => (x0, y1),
+        "bottom_right" => (x1, y1),
+        _ => (cx, cy),
+    }
+}
+
+fn anchor_target_point(target: &AnchorTarget, pos: &str) -> (f64, f64) {
+    if pos.trim().eq_ignore_ascii_case("centroid") {
+        return target
+            .centroid
+            .unwrap_or_else(|| anchor_target_center(target));
+    }
+    anchor_point(target.bbox, pos)
+}
+
+fn anchor_target_center(target: &AnchorTarget) -> (f64, f64) {
+    (
+        (target.bbox.0 + target.bbox.2) / 2.0,
+        (target.bbox.1 + target.bbox.3) / 2.0,
+    )
+}
+
+fn normalize_anchor_spec(value: Option<&Value>) -> Option<AnchorSpec> {
+    let value = value?;
+    match value {
+        Value::Bool(v) => {
+            if *v {
+                Some(AnchorSpec {
+                    id: None,
+                    index: None,
+                    nearest: true,
+                    target_type: None,
+                    pos: None,
+                    offset: None,
+                })
+            } else {
+                None
+            }
+        }
+        Value::Number(n) => Some(AnchorSpec {
+            id: None,
+            index: n.as_u64().map(|v| v as usize),
+            nearest: false,
+            target_type: None,
+            pos: None,
+            offset: None,
+        }),
+        Value::String(s) => {
+            let raw = s.trim();
+            if raw.is_empty() {
+                return None;
+            }
+            if raw.eq_ignore_ascii_case("nearest") {
+                Some(AnchorSpec {
+                    id: None,
+                    index: None,
+                    nearest: true,
+                    target_type: None,
+                    pos: None,
+                    offset: None,
+                })
+            } else {
+                Some(AnchorSpec {
+                    id: Some(raw.to_string()),
+                    index: None,
+                    nearest: false,
+                    target_type: None,
+                    pos: None,
+                    offset: None,
+                })
+            }
+        }
+        Value::Object(obj) => Some(AnchorSpec {
+            id: value_to_string(obj.get("id")),
+            index: value_to_usize(obj.get("index")),
+            nearest: obj
+                .get("nearest")
+                .map(|v| value_to_bool(v, false))
+                .unwrap_or(false),
+            target_type: value_to_string(obj.get("type")),
+            pos: value_to_string(obj.get("pos")),
+            offset: parse_offset_value(obj.get("offset")),
+        }),
+        _ => None,
+    }
+}
+
+fn resolve_target<'a>(
+    spec: &AnchorSpec,
+    targets: &'a [AnchorTarget],
+    fallback_point: (f64, f64),
+) -> Option<&'a AnchorTarget> {
+    if targets.is_empty() {
+        return None;
+    }
+
+    let mut candidates: Vec<&AnchorTarget> = targets.iter().collect();
+    if let Some(kind) = &spec.target_type {
+        candidates.retain(|target| target.ann_type.eq_ignore_ascii_case(kind));
+    }
+    if candidates.is_empty() {
+        return None;
+    }
+
+    if let Some(id) = &spec.id {
+        if let Some(found) = candidates
+            .iter()
+            .copied()
+            .find(|target| target.id.as_deref() == Some(id.as_str()))
+        {
+            return Some(found);
+        }
+    }
+
+    if let Some(index) = spec.index {
+        if let Some(found) = candidates
+            .iter()
+            .copied()
+            .find(|target| target.index == index)
+        {
+            return Some(found);
+        }
+    }
+
+    if spec.nearest || (spec.id.is_none() && spec.index.is_none() && spec.target_type.is_none()) {
+        let mut best: Option<&AnchorTarget> = None;
+        let mut best_dist = f64::MAX;
+        for target in candidates {
+            let center = anchor_target_center(target);
+            let dx = center.0 - fallback_point.0;
+            let dy = center.1 - fallback_point.1;
+            let dist = dx * dx + dy * dy;
+            if dist < best_dist {
+                best = Some(target);
+                best_dist = dist;
+            }
+        }
+        return best;
+    }
+
+    None
+}
+
+fn resolve_anchor_pos(
+    spec_pos: Option<String>,
+    ann_pos: Option<String>,
+    defaults: &Map<String, Value>,
+    fallback: &str,
+) -> String {
+    spec_pos
+        .or(ann_pos)
+        .or_else(|| value_to_string(defaults.get("anchor_pos")))
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+fn resolve_anchor_offset(
+    spec_offset: Option<(f64, f64)>,
+    ann_offset: Option<(f64, f64)>,
+    defaults: &Map<String, Value>,
+    fallback: (f64, f64),
+) -> (f64, f64) {
+    spec_offset
+        .or(ann_offset)
+        .or_else(|| parse_offset_value(defaults.get("anchor_offset")))
+        .unwrap_or(fallback)
+}
+
+fn apply_text_anchor(
+    ann: &Map<String, Value>,
+    targets: &[AnchorTarget],
+    defaults: &Map<String, Value>,
+    img_w: u32,
+    img_h: u32,
+) -> Map<String, Value> {
+    let mut updated = ann.clone();
+    let spec = normalize_anchor_spec(ann.get("anchor"));
+    let Some(spec) = spec else {
+        return updated;
+    };
+    let x = value_to_f64(ann.get("x")).unwrap_or(f64::from(img_w) / 2.0);
+    let y = value_to_f64(ann.get("y")).unwrap_or(f64::from(img_h) / 2.0);
+    let Some(target) = resolve_target(&spec, targets, (x, y)) else {
+        record_anchor(&mut updated, "anchor", Value::Null);
+        return updated;
+    };
+
+    let pos = resolve_anchor_pos(
+        spec.pos.clone(),
+        value_to_string(ann.get("anchor_pos")),
+        defaults,
+        "top",
+    );
+    let offset = resolve_anchor_offset(
+        spec.offset,
+        parse_offset_value(ann.get("anchor_offset")),
+        defaults,
+        (0.0, 0.0),
+    );
+    let anchor = anchor_target_point(target, &pos);
+    updated.insert("x".to_string(), json!(anchor.0 + offset.0));
+    updated.insert("y".to_string(), json!(anchor.1 + offset.1));
+    record_anchor(
+        &mut updated,
+        "anchor",
+        anchor_decision(&spec, target, &pos, anchor),
+    );
+    updated
+}
+
+fn apply_arrow_anchor(
+    ann: &Map<String, Value>,
+    targets: &[AnchorTarget],
+    defaults: &Map<String, Value>,
+    img_w: u32,
+    img_h: u32,
+) -> Map<String, Value> {
+    let from_spec = normalize_anchor_spec(ann.get("from"));
+    let to_spec = normalize_anchor_spec(ann.get("to"));
+    if from_spec.is_none() && to_spec.is_none() {
+        return ann.clone();
+    }
+    let mut updated = ann.clone();
+
+    if let Some(spec) = from_spec {
+        let x1 = value_to_f64(ann.get("x1")).unwrap_or(f64::from(img_w) / 2.0);
+        let y1 = value_to_f64(ann.get("y1")).unwrap_or(f64::from(img_h) / 2.0);
+        let target = resolve_target(&spec, targets, (x1, y1));
+        if target.is_none() {
+            record_anchor(&mut updated, "from", Value::Null);
+        }
+        if let Some(target) = target {
+            let pos = resolve_anchor_pos(
+                spec.pos.clone(),
+                value_to_string(ann.get("from_pos")),
+                defaults,
+                "center",
+            );
+            let offset = resolve_anchor_offset(
+                spec.offset,
+                parse_offset_value(ann.get("from_offset")),
+                defaults,
+                (0.0, 0.0),
+            );
+            let anchor = anchor_target_point(target, &pos);
+            updated.insert("x1".to_string(), json!(anchor.0 + offset.0));
+            updated.insert("y1".to_string(), json!(anchor.1 + offset.1));
+            record_anchor(
+                &mut updated,
+                "from",
+                anchor_decision(&spec, target, &pos, anchor),
+            );
+        }
+    }
+
+    if let Some(spec) = to_spec {
+        let x2 = value_to_f64(ann.get("x2")).unwrap_or(f64::from(img_w) / 2.0);
+        let y2 = value_to_f64(ann.get("y2")).unwrap_or(f64::from(img_h) / 2.0);
+        let target = resolve_target(&spec, targets, (x2, y2));
+        if target.is_none() {
+            record_anchor(&mut updated, "to", Value::Null);
+        }
+        if let Some(target) = target {
+            let pos = resolve_anchor_pos(
+                spec.pos.clone(),
+                value_to_string(ann.get("to_pos")),
+                defaults,
+                "center",
+            );
+            let offset = resolve_anchor_offset(
+                spec.offset,
+                parse_offset_value(ann.get("to_offset")),
+                defaults,
+                (0.0, 0.0),
+            );
+            let anchor = anchor_target_point(target, &pos);
+            updated.insert("x2".to_string(), json!(anchor.0 + offset.0));
+            updated.insert("y2".to_string(), json!(anchor.1 + offset.1));
+            record_anchor(
+                &mut updated,
+                "to",
+                anchor_decision(&spec, target, &pos, anchor),
+            );
+        }
+    }
+
+    updated
+}
+
+// "anchored_to" keeps each anchor role's resolution (null when nothing matched) for --probe;
+// it never reaches the sidecar.
+fn record_anchor(ann: &mut Map<String, Value>, role: &str, decision: Value) {
+    if let Some(roles) = ann
+        .entry("anchored_to")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+    {
+        roles.insert(role.to_string(), decision);
+    }
+}
+
+// Mirrors resolve_target's precedence: an id match wins, then index, else the nearest target.
+fn anchor_decision(
+    spec: &AnchorSpec,
+    target: &AnchorTarget,
+    pos: &str,
+    point: (f64, f64),
+) -> Value {
+    let matched_by = if spec.id.is_some() && spec.id == target.id {
+        "id"
+    } else if spec.index == Some(target.index) {
+        "index"
+    } else {
+        "nearest"
+    };
+    json!({
+        "index": target.index,
+        "id": target.id,
+        "type": target.ann_type,
+        "matched_by": matched_by,
+        "pos": pos,
+        "point": [round_to(point.0, 2), round_to(point.1, 2)],
+    })
+}
+
+fn probe_item(item: &Value, anchors: &std::collections::BTreeMap<usize, &Value>) -> Value {
+    let index = item.get("index").and_then(Value::as_u64).unwrap_or(0) as usize;
+    json!({
+        "index": index,
+        "id": item.get("id").cloned().unwrap_or(Value::Null),
+        "type": item.get("type").cloned().unwrap_or(Value::Null),
+        "rendered": item.get("rendered").and_then(Value::as_bool).unwrap_or(true),
+        "geometry": item.get("geometry").cloned().unwrap_or(Value::Null),
+        "anchored_to": anchors.get(&index).copied().cloned().unwrap_or(Value::Null),
+    })
+}
+
+// One line per annotation: `#2 arrow id=save-arrow x1=.. y1=.. | to -> rect #0 "save" (id, center)`.
+fn probe_table(items: &[Value]) -> String {
+    let mut out = String::new();
+    for item in items {
+        let mut line = format!(
+            "#{} {}",
+            item["index"],
+            item["type"].as_str().unwrap_or("?")
+        );
+        if let Some(id) = item["id"].as_str() {
+            line.push_str(&format!(" id={id}"));
+        }
+        if let Some(geometry) = item["geometry"].as_object() {
+            for (key, value) in geometry {
+                line.push_str(&format!(" {key}={value}"));
+            }
+        }
+        if item["rendered"] == json!(false) {
+            line.push_str(" (not rendered)");
+        }
+        if let Some(roles) = item["anchored_to"].as_object() {
+            for (role, target) in roles {
+                if target.is_null() {
+                    line.push_str(&format!(" | {role} -> unresolved"));
+                    continue;
+                }
+                line.push_str(&format!(
+                    " | {role} -> {} #{}",
+                    target["type"].as_str().unwrap_or("?"),
+                    target["index"]
+                ));
+                if let Some(id) = target["id"].as_str() {
+                    line.push_str(&format!(" {id:?}"));
+                }
+                line.push_str(&format!(
+                    " ({}, {})",
+                    target["matched_by"].as_str().unwrap_or("?"),
+                    target["pos"].as_str().unwrap_or("?")
+                ));
+            }
+        }
+        out.push_str(&format!("probe: {line}\n"));
+    }
+    out
+}
+
+fn segment_hits_box(from: (f64, f64), to: (f64, f64), bbox: (f64, f64, f64, f64)) -> bool {
+    let steps = 32;
+    (0..=steps).any(|step| {
+        let t = step as f64 / steps as f64;
+        let x = from.0 + (to.0 - from.0) * t;
+        let y = from.1 + (to.1 - from.1) * t;
+        x > bbox.0 && x < bbox.2 && y > bbox.1 && y < bbox.3
+    })
+}
+
+fn arrow_avoid_control(
+    from: (f64, f64),
+    to: (f64, f64),
+    boxes: &[(f64, f64, f64, f64)],
+    margin: f64,
+) -> Option<(f64, f64)> {
+    let contains = |bbox: &(f64, f64, f64, f64), p: (f64, f64)| {
+        p.0 >= bbox.0 && p.0 <= bbox.2 && p.1 >= bbox.1 && p.1 <= bbox.3
+    };
+    let mid = ((from.0 + to.0) / 2.0, (from.1 + to.1) / 2.0);
+    let len = (to.0 - from.0).hypot(to.1 - from.1);
+    if len < 1.0 {
+        return None;
+    }
+    let normal = (-(to.1 - from.1) / len, (to.0 - from.0) / len);
+
+    // Boxes holding either endpoint are the arrow's own source/target, not obstacles.
+    let obstacle = boxes
+        .iter()
+        .filter(|bbox| !contains(bbox, from) && !contains(bbox, to))
+        .filter(|bbox| segment_hits_box(from, to, **bbox))
+        .min_by(|a, b| {
+            let da = ((a.0 + a.2) / 2.0 - mid.0).hypot((a.1 + a.3) / 2.0 - mid.1);
+            let db = ((b.0 + b.2) / 2.0 - mid.0).hypot((b.1 + b.3) / 2.0 - mid.1);
+            da.total_cmp(&db)
+        })?;
+
+    let center = (
+        (obstacle.0 + obstacle.2) / 2.0,
+        (obstacle.1 + obstacle.3) / 2.0,
+    );
+    let side = if (center.0 - mid.0) * normal.0 + (center.1 - mid.1) * normal.1 >= 0.0 {
+        -1.0
+    } else {
+        1.0
+    };
+    let away = (normal.0 * side, normal.1 * side);
+    let clearance = [
+        (obstacle.0, obstacle.1),
+        (obstacle.2, obstacle.1),
+        (obstacle.0, obstacle.3),
+        (obstacle.2, obstacle.3),
+    ]
+    .iter()
+    .map(|corner| (corner.0 - mid.0) * away.0 + (corner.1 - mid.1) * away.1)
+    .fold(0.0f64, f64::max)
+        + margin;
+    // A quadratic curve peaks halfway to its control point, so push the control twice as far.
+    Some((
+        mid.0 + away.0 * clearance * 2.0,
+        mid.1 + away.1 * clearance * 2.0,
+    ))
+}
+
+fn apply_arrow_avoid(ann: &Map<String, Value>, targets: &[AnchorTarget]) -> Map<String, Value> {
+    let avoid = ann
+        .get("avoid")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    if !avoid || ann.contains_key("cx") || ann.contains_key("cy") {
+        return ann.clone();
+    }
+    let (Some(x1), Some(y1), Some(x2), Some(y2)) = (
+        value_to_f64(ann.get("x1")),
+        value_to_f64(ann.get("y1")),
+        value_to_f64(ann.get("x2")),
+        value_to_f64(ann.get("y2")),
+    ) else {
+        return ann.clone();
+    };
+    let boxes: Vec<(f64, f64, f64, f64)> = targets
+        .iter()
+        .filter(|target| target.ann_type == "rect")
+        .map(|target| target.bbox)
+        .collect();
+    let margin = value_to_f64(ann.get("avoid_margin")).unwrap_or(12.0);
+
+    let mut updated = ann.clone();
+    if let Some((cx, cy)) = arrow_avoid_control((x1, y1), (x2, y2), &boxes, margin) {
+        updated.insert("cx".to_string(), json!(round_to(cx, 2)));
+        updated.insert("cy".to_string(), json!(round_to(cy, 2)));
+    }
+    updated
+}
+
+fn point_in_rounded_rect(
+    px: i32,
+    py: i32,
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    radius: f64,
+) -> bool {
+    if px < x0 || px >= x1 || py < y0 || py >= y1 {
+        return false;
+    }
+    if radius <= 0.1 {
+        return true;
+    }
+    let r = radius
+        .min(f64::from((x1 - x0).abs()) / 2.0)
+        .min(f64::from((y1 - y0).abs()) / 2.0);
+    let fx = f64::from(px);
+    let fy = f64::from(py);
+    let left = f64::from(x0);
+    let right = f64::from(x1);
+    let top = f64::from(y0);
+    let bottom = f64::from(y1);
+
+    if (fx >= left + r && fx <= right - r) || (fy >= top + r && fy <= bottom - r) {
+        return true;
+    }
+
+    let corners = [
+        (left + r, top + r),
+        (right - r, top + r),
+        (left + r, bottom - r),
+        (right - r, bottom - r),
+    ];
+    corners.iter().any(|(cx, cy)| {
+        let dx = fx - cx;
+        let dy = fy - cy;
+        dx * dx + dy * dy <= r * r
+    })
+}
+
+fn annotation_scale(ann: &Map<String, Value>, base_scale: f64) -> f64 {
+    ann.get("scale")
+        .and_then(Value::as_f64)
+        .unwrap_or(base_scale)
+}
+
+fn draw_unfocused_dim(
+    img: &mut RgbaImage,
+    items: &[(usize, Map<String, Value>)],
+    base_scale: f64,
+    defaults: &Map<String, Value>,
+) {
+    let holes: Vec<DimHole> = items
+        .iter()
+        .filter(|(_, ann)| {
+            let ann_type = annotation_type(ann);
+            ann_type == "rect" || is_spotlight_type(&ann_type)
+        })
+        .map(|(_, ann)| spotlight_hole(ann, annotation_scale(ann, base_scale), defaults))
+        .collect();
+    if holes.is_empty() {
+        return;
+    }
+    draw_dim_layer(
+        img,
+        &holes,
+        spotlight_dim_color(&Map::new(), defaults),
+        None,
+    );
+}
+
+// Content box (x, y, w, h) inside border rows/columns that match the top-left pixel within
+// `tolerance`, grown by `pad`; None when nothing would be removed or the image is uniform.
+fn trim_bounds(img: &RgbaImage, tolerance: u8, pad: u32) -> Option<(u32, u32, u32, u32)> {
+    let (w, h) = img.dimensions();
+    if w == 0 || h == 0 {
+        return None;
+    }
+    let border = *img.get_pixel(0, 0);
+    let is_border = |x: u32, y: u32| {
+        let px = img.get_pixel(x, y);
+        (0..4).all(|c| px[c].abs_diff(border[c]) <= tolerance)
+    };
+    let top = (0..h).find(|&y| (0..w).any(|x| !is_border(x, y)))?;
+    let bottom = (top..h).rev().find(|&y| (0..w).any(|x| !is_border(x, y)))?;
+    let left = (0..w).find(|&x| (top..=bottom).any(|y| !is_border(x, y)))?;
+    let right = (left..w)
+        .rev()
+        .find(|&x| (top..=bottom).any(|y| !is_border(x, y)))?;
+
+    let x0 = left.saturating_sub(pad);
+    let y0 = top.saturating_sub(pad);
+    let x1 = right.saturating_add(pad).min(w - 1);
+    let y1 = bottom.saturating_add(pad).min(h - 1);
+    let bounds = (x0, y0, x1 - x0 + 1, y1 - y0 + 1);
+    (bounds != (0, 0, w, h)).then_some(bounds)
+}
+
+// Shift an annotation's resolved px geometry (and reference frame) by (dx, dy).
+fn offset_annotation(ann: &Map<String, Value>, dx: f64, dy: f64) -> Map<String, Value> {
+    let mut shifted = ann.clone();
+    for (key, delta) in [
+        ("x", dx),
+        ("x1", dx),
+        ("x2", dx),
+        ("cx", dx),
+        ("y", dy),
+        ("y1", dy),
+        ("y2", dy),
+        ("cy", dy),
+    ] {
+        if let Some(v) = value_to_f64(ann.get(key)) {
+            shifted.insert(key.to_string(), json!(v + delta));
+        }
+    }
+    if let Some((x0, y0, x1, y1)) = parse_reference_bbox(ann.get("reference_bbox")) {
+        shifted.insert(
+            "reference_bbox".to_string(),
+            json!([x0 + dx, y0 + dy, x1 + dx, y1 + dy]),
+        );
+    }
+    if let Some((cx, cy)) = parse_offset_value(ann.get("centroid")) {
+        shifted.insert("centroid".to_string(), json!([cx + dx, cy + dy]));
+    }
+    if let Some(Value::Object(roles)) = shifted.get_mut("anchored_to") {
+        for target in roles.values_mut() {
+            if let Some([x, y]) = target["point"].as_array().map(Vec::as_slice) {
+                let point = json!([
+                    round_to(x.as_f64().unwrap_or(0.0) + dx, 2),
+                    round_to(y.as_f64().unwrap_or(0.0) + dy, 2)
+                ]);
+                target["point"] = point;
+            }
+        }
+    }
+    shifted
+}
+
+fn render_annotations(
+    img: &mut RgbaImage,
+    queue: &[(usize, Map<String, Value>)],
+    base_scale: f64,
+    defaults: &Map<String, Value>,
+    fonts: &FontCache,
+) {
+    let dim_unfocused = defaults
+        .get("dim_unfocused")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    if dim_unfocused {
+        draw_unfocused_dim(img, queue, base_scale, defaults);
+    }
+    for (_, ann) in queue {
+        // Spotlights already punched their holes into the shared dim layer.
+        if !(dim_unfocused && is_spotlight_type(&annotation_type(ann))) {
+            draw_prepared_annotation(img, ann, base_scale, defaults, fonts);
+        }
+    }
+}
+
+// Transparent overlay layers for --layered-out: one "annotations" layer, or with `by_type` one
+// layer per annotation type in first-use order (a shared defaults.dim_unfocused dim gets its own
+// "dim" layer underneath).
+fn annotation_layers(
+    backdrop: &RgbaImage,
+    queue: &[(usize, Map<String, Value>)],
+    base_scale: f64,
+    defaults: &Map<String, Value>,
+    fonts: &FontCache,
+    by_type: bool,
+) -> Vec<(String, RgbaImage)> {
+    let (w, h) = backdrop.dimensions();
+    if !by_type {
+        return vec![(
+            "annotations".to_string(),
+            render_layer(backdrop, queue, base_scale, defaults, fonts),
+        )];
+    }
+
+    let mut layers = Vec::new();
+    let mut layer_defaults = defaults.clone();
+    let dim_unfocused = defaults
+        .get("dim_unfocused")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    if dim_unfocused {
+        let mut dim = RgbaImage::new(w, h);
+        draw_unfocused_dim(&mut dim, queue, base_scale, defaults);
+        layers.push(("dim".to_string(), dim));
+        layer_defaults.remove("dim_unfocused");
+    }
+    let layer_type = |ann: &Map<String, Value>| canonical_annotation_type(&annotation_type(ann));
+    let mut names: Vec<String> = Vec::new();
+    for (_, ann) in queue {
+        let name = layer_type(ann);
+        if !(names.contains(&name) || dim_unfocused && name == "spotlight") {
+            names.push(name);
+        }
+    }
+    for name in names {
+        let group: Vec<(usize, Map<String, Value>)> = queue
+            .iter()
+            .filter(|(_, ann)| layer_type(ann) == name)
+            .cloned()
+            .collect();
+        let layer = render_layer(backdrop, &group, base_scale, &layer_defaults, fonts);
+        layers.push((name, layer));
+    }
+    layers
+}
+
+fn render_layer(
+    backdrop: &RgbaImage,
+    queue: &[(usize, Map<String, Value>)],
+    base_scale: f64,
+    defaults: &Map<String, Value>,
+    fonts: &FontCache,
+) -> RgbaImage {
+    let (w, h) = backdrop.dimensions();
+    let mut layer = RgbaImage::new(w, h);
+    render_annotations(&mut layer, queue, base_scale, defaults, fonts);
+    layer
+}
+
+// Multi-page TIFF, one uncompressed RGBA page per layer (bottom first) named via PageName,
+// which GIMP and other editors can open as layers.
+fn write_layered_tiff(path: &Path, layers: &[(String, RgbaImage)], verify: bool) -> Result<()> {
+    const PAGE_NAME: u16 = 285;
+    const PAGE_NUMBER: u16 = 297;
+    const UNASSOCIATED_ALPHA: u16 = 2;
+    const PAGE_SUBFILE: u32 = 2;
+
+    let page_count = u16::try_from(layers.len())
+        .map_err(|_| anyhow::anyhow!("too many layers for a TIFF: {}", layers.len()))?;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut buf)?;
+    for (page, (name, layer)) in layers.iter().enumerate() {
+        let (w, h) = layer.dimensions();
+        let mut image = encoder.new_image::<tiff::encoder::colortype::RGBA8>(w, h)?;
+        let tags = image.encoder();
+        tags.write_tag(tiff::tags::Tag::NewSubfileType, PAGE_SUBFILE)?;
+        tags.write_tag(tiff::tags::Tag::ExtraSamples, UNASSOCIATED_ALPHA)?;
+        let ascii: String = name.chars().filter(char::is_ascii).collect();
+        tags.write_tag(tiff::tags::Tag::Unknown(PAGE_NAME), ascii.as_str())?;
+        tags.write_tag(
+            tiff::tags::Tag::Unknown(PAGE_NUMBER),
+            &[page as u16, page_count][..],
+        )?;
+        image.write_data(layer.as_raw())?;
+    }
+
+    ensure_parent_dir(path)?;
+    fs::write(path, buf.into_inner())
+        .with_context(|| format!("failed to write layered TIFF: {}", path.display()))?;
+    if verify {
+        let sizes: Vec<(u32, u32)> = layers.iter().map(|(_, layer)| layer.dimensions()).collect();
+        verify_layered_tiff(path, &sizes)?;
+    }
+    Ok(())
+}
+
+// --verify-output for layered TIFFs: every page must decode, at its layer's size.
+fn verify_layered_tiff(path: &Path, expected: &[(u32, u32)]) -> Result<()> {
+    let failure = |detail: String| anyhow::anyhow!("--verify-output: {} {detail}", path.display());
+    let file = File::open(path).map_err(|err| failure(format!("does not open: {err}")))?;
+    let mut decoder = tiff::decoder::Decoder::new(io::BufReader::new(file))
+        .map_err(|err| failure(format!("does not decode: {err}")))?;
+    for (page, &(w, h)) in expected.iter().enumerate() {
+        if page > 0 {
+            if !decoder.more_images() {
+                return Err(failure(format!(
+                    "has {page} pages, expected {}",
+                    expected.len()
+                )));
+            }
+            decoder
+                .next_image()
+                .map_err(|err| failure(format!("page {page} does not decode: {err}")))?;
+        }
+        let size = decoder
+            .dimensions()
+            .map_err(|err| failure(format!("page {page} does not decode: {err}")))?;
+        if size != (w, h) {
+            return Err(failure(format!(
+                "page {page} reads back as {}x{}, expected {w}x{h}",
+                size.0, size.1
+            )));
+        }
+        decoder
+            .read_image()
+            .map_err(|err| failure(format!("page {page} does not decode: {err}")))?;
+    }
+    Ok(())
+}
+
+fn draw_prepared_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    base_scale: f64,
+    defaults: &Map<String, Value>,
+    fonts: &FontCache,
+) {
+    let clip = annotation_clip(ann, img.width(), img.height());
+    let scale = annotation_scale(ann, base_scale);
+    let ann_type = annotation_type(ann);
+    if is_spotlight_type(&ann_type) {
+        draw_spotlight_annotation(img, ann, scale, defaults, clip);
+        return;
+    }
+    match ann_type.as_str() {
+        "rect" => draw_rect_annotation(img, ann, scale, clip),
+        "ellipse" | "circle" => draw_ellipse_annotation(img, ann, scale, clip),
+        "arrow" => draw_arrow_annotation(img, ann, scale, clip),
+        "text" => draw_text_annotation(img, ann, scale, fonts, clip),
+        "point" | "crosshair" => draw_point_annotation(img, ann, scale, clip),
+        "measure" => draw_measure_annotation(img, ann, scale, clip),
+        _ => {}
+    }
+}
+
+// "clip": {x, y, w, h} (or [x, y, w, h]) in px after unit resolution, intersected with the
+// image. None when absent or when it covers the whole image; an empty intersection clips all.
+fn annotation_clip(ann: &Map<String, Value>, img_w: u32, img_h: u32) -> ClipRect {
+    let (x, y, w, h) = match ann.get("clip")? {
+        Value::Object(obj) => (
+            value_to_f64(obj.get("x"))?,
+            value_to_f64(obj.get("y"))?,
+            value_to_f64(obj.get("w"))?,
+            value_to_f64(obj.get("h"))?,
+        ),
+        Value::Array(items) if items.len() >= 4 => (
+            value_to_f64(items.first())?,
+            value_to_f64(items.get(1))?,
+            value_to_f64(items.get(2))?,
+            value_to_f64(items.get(3))?,
+        ),
+        _ => return None,
+    };
+    let clamp_x = |v: f64| v.round().clamp(0.0, f64::from(img_w)) as i32;
+    let clamp_y = |v: f64| v.round().clamp(0.0, f64::from(img_h)) as i32;
+    let (x0, x1) = (clamp_x(x), clamp_x(x + w.max(0.0)));
+    let (y0, y1) = (clamp_y(y), clamp_y(y + h.max(0.0)));
+    if (x0, y0, x1, y1) == (0, 0, img_w as i32, img_h as i32) {
+        return None;
+    }
+    Some((x0, y0, x1, y1))
+}
+
+fn build_preview_grid(
+    base: &RgbaImage,
+    items: &[(usize, Map<String, Value>)],
+    base_scale: f64,
+    defaults: &Map<String, Value>,
+    fonts: &FontCache,
+) -> RgbaImage {
+    const TILE_MAX_W: u32 = 480;
+    const LABEL_H: u32 = 20;
+    const GAP: u32 = 8;
+
+    let count = (items.len() as u32).max(1);
+    let cols = (f64::from(count).sqrt().ceil() as u32).max(1);
+    let rows = count.div_ceil(cols);
+    let (base_w, base_h) = base.dimensions();
+    let tile_w = base_w.clamp(1, TILE_MAX_W);
+    let tile_h =
+        ((f64::from(base_h) * f64::from(tile_w) / f64::from(base_w.max(1))).round() as u32).max(1);
+    let cell_w = tile_w + GAP;
+    let cell_h = tile_h + LABEL_H + GAP;
+
+    let mut sheet = RgbaImage::from_pixel(
+        cols * cell_w + GAP,
+        rows * cell_h + GAP,
+        Rgba([32, 32, 32, 255]),
+    );
+    let mut ordered: Vec<&(usize, Map<String, Value>)> = items.iter().collect();
+    ordered.sort_by_key(|(idx, _)| *idx);
+    for (slot, (idx, ann)) in ordered.into_iter().enumerate() {
+        let mut variant = base.clone();
+        draw_prepared_annotation(&mut variant, ann, base_scale, defaults, fonts);
+        let tile = image::imageops::resize(&variant, tile_w, tile_h, FilterType::Triangle);
+
+        let x0 = GAP + (slot as u32 % cols) * cell_w;
+        let y0 = GAP + (slot as u32 / cols) * cell_h;
+        let label = match ann.get("id").and_then(Value::as_str) {
+            Some(id) => format!("#{idx} {} {id}", annotation_type(ann)),
+            None => format!("#{idx} {}", annotation_type(ann)),
+        };
+        draw_bitmap_text(
+            &mut sheet,
+            x0 as i32,
+            y0 as i32 + 6,
+            &label,
+            Rgba([255, 255, 255, 255]),
+            1,
+            None,
+        );
+        image::imageops::overlay(&mut sheet, &tile, i64::from(x0), i64::from(y0 + LABEL_H));
+    }
+    sheet
+}
+
+// Crops of each change region (plus a little context) tiled in reading order under an id label,
+// each tile capped at TILE_MAX_W wide; cells take the largest tile's size.
+fn build_regions_collage(current: &RgbaImage, regions: &[ChangeRegion]) -> RgbaImage {
+    const TILE_MAX_W: u32 = 480;
+    const CONTEXT: u32 = 8;
+    const LABEL_H: u32 = 20;
+    const GAP: u32 = 8;
+
+    let (img_w, img_h) = current.dimensions();
+    let tiles: Vec<(String, RgbaImage)> = regions
+        .iter()
+        .map(|region| {
+            let x0 = region.x.saturating_sub(CONTEXT);
+            let y0 = region.y.saturating_sub(CONTEXT);
+            let x1 = (region.x2 + CONTEXT).min(img_w);
+            let y1 = (region.y2 + CONTEXT).min(img_h);
+            let mut crop = image::imageops::crop_imm(
+                current,
+                x0,
+                y0,
+                x1.saturating_sub(x0).max(1),
+                y1.saturating_sub(y0).max(1),
+            )
+            .to_image();
+            if crop.width() > TILE_MAX_W {
+                let h = (f64::from(crop.height()) * f64::from(TILE_MAX_W) / f64::from(crop.width()))
+                    .round() as u32;
+                crop = image::imageops::resize(&crop, TILE_MAX_W, h.max(1), FilterType::Triangle);
+            }
+            let label = format!(
+                "{} {}x{}@{},{}",
+                region.id, region.w, region.h, region.x, region.y
+            );
+            (label, crop)
+        })
+        .collect();
+
+    let count = (tiles.len() as u32).max(1);
+    let cols = (f64::from(count).sqrt().ceil() as u32).max(1);
+    let rows = count.div_ceil(cols);
+    let label_w = tiles
+        .iter()
+        .map(|(label, _)| text_bbox(0, 0, label, 1).2 as u32)
+        .max()
+        .unwrap_or(0);
+    let tile_w = tiles
+        .iter()
+        .map(|(_, t)| t.width())
+        .max()
+        .unwrap_or(1)
+        .max(label_w);
+    let tile_h = tiles.iter().map(|(_, t)| t.height()).max().unwrap_or(1);
+    let cell_w = tile_w + GAP;
+    let cell_h = tile_h + LABEL_H + GAP;
+
+    let mut sheet = RgbaImage::from_pixel(
+        cols * cell_w + GAP,
+        rows * cell_h + GAP,
+        Rgba([32, 32, 32, 255]),
+    );
+    for (slot, (label, tile)) in tiles.iter().enumerate() {
+        let x0 = GAP + (slot as u32 % cols) * cell_w;
+        let y0 = GAP + (slot as u32 / cols) * cell_h;
+        draw_bitmap_text(
+            &mut sheet,
+            x0 as i32,
+            y0 as i32 + 6,
+            label,
+            Rgba([255, 255, 255, 255]),
+            1,
+            None,
+        );
+        image::imageops::overlay(&mut sheet, tile, i64::from(x0), i64::from(y0 + LABEL_H));
+    }
+    sheet
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DimHole {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    radius: f64,
+}
+
+fn spotlight_dim_color(ann: &Map<String, Value>, defaults: &Map<String, Value>) -> Rgba<u8> {
+    let dim_color = parse_color_opt(ann.get("color"))
+        .or_else(|| parse_color_opt(ann.get("dim_color")))
+        .or_else(|| parse_color_opt(defaults.get("dim_color")))
+        .unwrap_or(Rgba([0, 0, 0, 115]));
+
+    let opacity =
+        value_to_f64(ann.get("opacity")).or_else(|| value_to_f64(defaults.get("dim_opacity")));
+    if let Some(alpha_raw) = opacity {
+        let alpha = if alpha_raw <= 1.0 {
+            (alpha_raw * 255.0).round().clamp(0.0, 255.0) as u8
+        } else {
+            alpha_raw.round().clamp(0.0, 255.0) as u8
+        };
+        Rgba([dim_color[0], dim_color[1], dim_color[2], alpha])
+    } else {
+        dim_color
+    }
+}
+
+fn spotlight_hole(ann: &Map<String, Value>, scale: f64, defaults: &Map<String, Value>) -> DimHole {
+    let padding = value_to_f64(ann.get("padding"))
+        .or_else(|| value_to_f64(defaults.get("dim_padding")))
+        .unwrap_or(0.0)
+        * scale;
+    let radius = value_to_f64(ann.get("radius"))
+        .or_else(|| value_to_f64(defaults.get("dim_radius")))
+        .unwrap_or(0.0)
+        * scale;
+
+    let x = value_to_f64(ann.get("x")).unwrap_or(0.0) - padding;
+    let y = value_to_f64(ann.get("y")).unwrap_or(0.0) - padding;
+    let w = value_to_f64(ann.get("w")).unwrap_or(0.0) + padding * 2.0;
+    let h = value_to_f64(ann.get("h")).unwrap_or(0.0) + padding * 2.0;
+
+    DimHole {
+        x0: x.floor() as i32,
+        y0: y.floor() as i32,
+        x1: (x + w).ceil() as i32,
+        y1: (y + h).ceil() as i32,
+        radius,
+    }
+}
+
+fn draw_dim_layer(img: &mut RgbaImage, holes: &[DimHole], color: Rgba<u8>, clip: ClipRect) {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    let (x0, y0, x1, y1) = clip.unwrap_or((0, 0, w, h));
+    for py in y0.max(0)..y1.min(h) {
+        for px in x0.max(0)..x1.min(w) {
+            if holes.iter().any(|hole| {
+                point_in_rounded_rect(px, py, hole.x0, hole.y0, hole.x1, hole.y1, hole.radius)
+            }) {
+                continue;
+            }
+            blend_at(img, px, py, color, None);
+        }
+    }
+}
+
+// defaults.ruler=true: measuring strips along the top and left edges, part of the backdrop so
+// annotations draw over them. Ticks sit at image px positions (after preprocess): labeled major
+// ticks every ruler_step px and minor ticks at fifths. Left-edge labels stack their digits
+// downward to fit the strip.
+fn draw_ruler(img: &mut RgbaImage, defaults: &Map<String, Value>) {
+    let enabled = defaults
+        .get("ruler")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    let (w, h) = img.dimensions();
+    if !enabled || w == 0 || h == 0 {
+        return;
+    }
+    let step = value_to_usize(defaults.get("ruler_step"))
+        .unwrap_or(50)
+        .max(10) as i32;
+    let size = value_to_usize(defaults.get("ruler_size"))
+        .unwrap_or(14)
+        .clamp(12, 64) as i32;
+    let bg = parse_color(defaults.get("ruler_bg"), [255, 255, 255, 220]);
+    let ink = parse_color(defaults.get("ruler_color"), [28, 28, 30, 255]);
+    let (w, h) = (w as i32, h as i32);
+    fill_rect_alpha(img, 0, 0, w - 1, size - 1, bg, None);
+    fill_rect_alpha(img, 0, size, size - 1, h - 1, bg, None);
+
+    // Minors sit at the rounded fifths of each step, so a step that is not a multiple of 5
+    // still gets every major.
+    for (along, vertical) in [(w, false), (h, true)] {
+        let mut label_end = i32::MIN;
+        for major_pos in (0..along).step_by(step as usize) {
+            for fifth in 0..5 {
+                let pos = major_pos + (step * fifth + 2) / 5;
+                if pos < size || pos >= along {
+                    continue;
+                }
+                let major = fifth == 0;
+                let len = if major { size / 2 } else { size / 4 };
+                if vertical {
+                    fill_rect_alpha(img, size - len, pos, size - 1, pos, ink, None);
+                } else {
+                    fill_rect_alpha(img, pos, size - len, pos, size - 1, ink, None);
+                }
+                let label = pos.to_string();
+                let label_len = 8 * label.len() as i32;
+                if major && pos + 2 > label_end && pos + 2 + label_len <= along {
+                    if vertical {
+                        draw_bitmap_text_directed(
+                            img,
+                            2,
+                            pos + 2,
+                            &label,
+                            ink,
+                            1,
+                            "vertical",
+                            0,
+                            None,
+                        );
+                    } else {
+                        draw_bitmap_text(img, pos + 2, 2, &label, ink, 1, None);
+                    }
+                    label_end = pos + 2 + label_len;
+                }
+            }
+        }
+    }
+}
+
+fn draw_spotlight_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    scale: f64,
+    defaults: &Map<String, Value>,
+    clip: ClipRect,
+) {
+    let color = spotlight_dim_color(ann, defaults);
+    let hole = spotlight_hole(ann, scale, defaults);
+    draw_dim_layer(img, &[hole], color, clip);
+}
+
+fn draw_rect_annotation(img: &mut RgbaImage, ann: &Map<String, Value>, scale: f64, clip: ClipRect) {
+    let x = value_to_f64(ann.get("x")).unwrap_or(0.0);
+    let y = value_to_f64(ann.get("y")).unwrap_or(0.0);
+    let w = value_to_f64(ann.get("w")).unwrap_or(0.0);
+    let h = value_to_f64(ann.get("h")).unwrap_or(0.0);
+    if w <= 0.0 || h <= 0.0 {
+        return;
+    }
+
+    let radius = rect_corner_radius(ann, w, h);
+    if let Some(fill) = parse_color_opt(ann.get("fill")) {
+        fill_rounded_rect_alpha(
+            img,
+            (
+                x.round() as i32,
+                y.round() as i32,
+                (x + w).round() as i32,
+                (y + h).round() as i32,
+            ),
+            radius,
+            fill,
+            clip,
+        );
+    }
+
+    let stroke = parse_color(ann.get("color"), [255, 59, 48, 255]);
+    let width = rel_stroke_px(ann, "width", img)
+        .map(|v| (v.round() as u32).max(1))
+        .or_else(|| value_to_usize(ann.get("width")).map(|v| v.max(1) as u32))
+        .unwrap_or_else(|| scale_default(3.0, scale, 2));
+    let outline_enabled = ann
+        .get("outline")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    let outline_width = rel_stroke_px(ann, "outline_width", img)
+        .map(|v| (v.round() as u32).max(1))
+        .or_else(|| value_to_usize(ann.get("outline_width")).map(|v| v.max(1) as u32))
+        .unwrap_or_else(|| ((f64::from(width) * 0.6).round() as u32).max(2));
+    let outline_color =
+        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(stroke));
+
+    let x_u = x.max(0.0).round() as u32;
+    let y_u = y.max(0.0).round() as u32;
+    let w_u = w.max(1.0).round() as u32;
+    let h_u = h.max(1.0).round() as u32;
+    let dash = dash_pattern(ann);
+    let rect = (x_u, y_u, w_u, h_u);
+
+    if outline_enabled {
+        let thickness = width + outline_width * 2;
+        if radius > 0.0 {
+            draw_rounded_rect_outline(img, rect, outline_color, thickness, radius, dash, clip);
+        } else {
+            draw_rect_outline_dashed(img, rect, outline_color, thickness, dash, clip);
+        }
+    }
+    if radius > 0.0 {
+        draw_rounded_rect_outline(img, rect, stroke, width, radius, dash, clip);
+    } else {
+        draw_rect_outline_dashed(img, rect, stroke, width, dash, clip);
+    }
+
+    let show_severity = ann
+        .get("show_severity")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    if show_severity {
+        draw_severity_badge(img, ann, (x, y, w), width, scale, clip);
+    }
+}
+
+fn draw_ellipse_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    scale: f64,
+    clip: ClipRect,
+) {
+    let x = value_to_f64(ann.get("x")).unwrap_or(0.0);
+    let y = value_to_f64(ann.get("y")).unwrap_or(0.0);
+    let w = value_to_f64(ann.get("w")).unwrap_or(0.0);
+    let h = value_to_f64(ann.get("h")).unwrap_or(0.0);
+    if w <= 0.0 || h <= 0.0 {
+        return;
+    }
+    let bounds = (x, y, w, h);
+
+    if let Some(fill) = parse_color_opt(ann.get("fill")) {
+        draw_ellipse_band(img, bounds, None, fill, clip);
+    }
+
+    let stroke = parse_color(ann.get("color"), [255, 59, 48, 255]);
+    let width = rel_stroke_px(ann, "width", img)
+        .map(|v| (v.round() as u32).max(1))
+        .or_else(|| value_to_usize(ann.get("width")).map(|v| v.max(1) as u32))
+        .unwrap_or_else(|| scale_default(3.0, scale, 2));
+    let outline_enabled = ann
+        .get("outline")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    let outline_width = rel_stroke_px(ann, "outline_width", img)
+        .map(|v| (v.round() as u32).max(1))
+        .or_else(|| value_to_usize(ann.get("outline_width")).map(|v| v.max(1) as u32))
+        .unwrap_or_else(|| ((f64::from(width) * 0.6).round() as u32).max(2));
+    let outline_color =
+        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(stroke));
+
+    if outline_enabled {
+        draw_ellipse_band(
+            img,
+            bounds,
+            Some(f64::from(width + outline_width * 2)),
+            outline_color,
+            clip,
+        );
+    }
+    draw_ellipse_band(img, bounds, Some(f64::from(width)), stroke, clip);
+}
+
+// Pixels (tested at their centers) inside the ellipse inscribed in `bounds` but outside the one
+// inset by `thickness`, so strokes grow inward like draw_rect_outline; None fills it whole.
+fn draw_ellipse_band(
+    img: &mut RgbaImage,
+    bounds: (f64, f64, f64, f64),
+    thickness: Option<f64>,
+    color: Rgba<u8>,
+    clip: ClipRect,
+) {
+    let (x, y, w, h) = bounds;
+    let (rx, ry) = (w / 2.0, h / 2.0);
+    let (cx, cy) = (x + rx, y + ry);
+    let inside = |dx: f64, dy: f64, rx: f64, ry: f64| (dx / rx).powi(2) + (dy / ry).powi(2) <= 1.0;
+    let hole = thickness
+        .map(|t| (rx - t, ry - t))
+        .filter(|(ix, iy)| *ix > 0.0 && *iy > 0.0);
+    let x0 = x.floor().max(0.0) as u32;
+    let y0 = y.floor().max(0.0) as u32;
+    let x1 = ((x + w).ceil().max(0.0) as u32).min(img.width());
+    let y1 = ((y + h).ceil().max(0.0) as u32).min(img.height());
+    for py in y0..y1 {
+        for px in x0..x1 {
+            let dx = f64::from(px) + 0.5 - cx;
+            let dy = f64::from(py) + 0.5 - cy;
+            if !inside(dx, dy, rx, ry) {
+                continue;
+            }
+            if hole.is_some_and(|(ix, iy)| inside(dx, dy, ix, iy)) {
+                continue;
+            }
+            blend_at(img, px as i32, py as i32, color, clip);
+        }
+    }
+}
+
+fn severity_badge_color(level: &str) -> Rgba<u8> {
+    match level.trim().to_ascii_lowercase().as_str() {
+        "critical" | "blocker" | "p0" => Rgba([191, 0, 0, 235]),
+        "high" | "major" | "error" | "p1" => Rgba([255, 59, 48, 235]),
+        "medium" | "moderate" | "warning" | "p2" => Rgba([255, 159, 10, 235]),
+        "low" | "minor" | "p3" => Rgba([255, 214, 10, 235]),
+        "info" | "note" => Rgba([10, 132, 255, 235]),
+        _ => Rgba([142, 142, 147, 235]),
+    }
+}
+
+// Severity label drawn inside the rect's top-right corner (defaults.show_severity).
+fn draw_severity_badge(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    (x, y, w): (f64, f64, f64),
+    stroke_width: u32,
+    scale: f64,
+    clip: ClipRect,
+) {
+    let Some(level) = value_to_string(ann.get("severity")).filter(|v| !v.trim().is_empty()) else {
+        return;
+    };
+    let label = level.trim().to_ascii_uppercase();
+    let glyph_scale = scale_default(1.0, scale, 1);
+    let padding = scale_default(3.0, scale, 2) as i32;
+    let (_, _, text_w, text_h) = text_bbox(0, 0, &label, glyph_scale);
+    let right = (x + w).round() as i32 - stroke_width as i32;
+    let top = y.round() as i32 + stroke_width as i32;
+    let left = right - text_w - padding * 2;
+    let bg = severity_badge_color(&level);
+    fill_rect_alpha(
+        img,
+        left,
+        top,
+        right - 1,
+        top + text_h + padding * 2 - 1,
+        bg,
+        clip,
+    );
+    draw_bitmap_text(
+        img,
+        left + padding,
+        top + padding,
+        &label,
+        auto_outline_color(bg),
+        glyph_scale,
+        clip,
+    );
+}
+
+fn draw_arrow_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    scale: f64,
+    clip: ClipRect,
+) {
+    let x1 = value_to_f64(ann.get("x1")).unwrap_or(0.0);
+    let y1 = value_to_f64(ann.get("y1")).unwrap_or(0.0);
+    let x2 = value_to_f64(ann.get("x2")).unwrap_or(0.0);
+    let y2 = value_to_f64(ann.get("y2")).unwrap_or(0.0);
+    let color = parse_color(ann.get("color"), [10, 132, 255, 255]);
+    let width = rel_stroke_px(ann, "width", img)
+        .or_else(|| value_to_f64(ann.get("width")))
+        .unwrap_or_else(|| f64::from(scale_default(3.0, scale, 2)))
+        .max(1.0);
+    let head_len = rel_stroke_px(ann, "head_len", img)
+        .or_else(|| value_to_f64(ann.get("head_len")))
+        .unwrap_or_else(|| f64::from(scale_default(12.0, scale, 6)))
+        .max(2.0);
+    let head_width = rel_stroke_px(ann, "head_width", img)
+        .or_else(|| value_to_f64(ann.get("head_width")))
+        .unwrap_or_else(|| f64::from(scale_default(8.0, scale, 5)))
+        .max(2.0);
+
+    let outline_enabled = ann
+        .get("outline")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    let outline_width = rel_stroke_px(ann, "outline_width", img)
+        .or_else(|| value_to_f64(ann.get("outline_width")))
+        .unwrap_or_else(|| (width * 0.6).round().max(2.0))
+        .max(1.0);
+    let outline_color =
+        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(color));
+
+    let head_shape = ann
+        .get("head_shape")
+        .and_then(Value::as_str)
+        .unwrap_or("triangle");
+    let dash = dash_pattern(ann);
+
+    let control = match (value_to_f64(ann.get("cx")), value_to_f64(ann.get("cy"))) {
+        (Some(cx), Some(cy)) => Some((cx, cy)),
+        _ => None,
+    };
+    if let Some(ctrl) = control {
+        if outline_enabled {
+            draw_curved_arrow_primitive(
+                img,
+                (x1, y1),
+                ctrl,
+                (x2, y2),
+                outline_color,
+                width + outline_width * 2.0,
+                head_len + outline_width * 2.0,
+                head_width + outline_width * 2.0,
+                head_shape,
+                dash,
+                clip,
+            );
+        }
+        draw_curved_arrow_primitive(
+            img,
+            (x1, y1),
+            ctrl,
+            (x2, y2),
+            color,
+            width,
+            head_len,
+            head_width,
+            head_shape,
+            dash,
+            clip,
+        );
+        return;
+    }
+
+    if outline_enabled {
+        draw_arrow_primitive(
+            img,
+            x1,
+            y1,
+            x2,
+            y2,
+            outline_color,
+            width + outline_width * 2.0,
+            head_len + outline_width * 2.0,
+            head_width + outline_width * 2.0,
+            head_shape,
+            dash,
+            clip,
+        );
+    }
+    draw_arrow_primitive(
+        img, x1, y1, x2, y2, color, width, head_len, head_width, head_shape, dash, clip,
+    );
+}
+
+fn draw_point_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    scale: f64,
+    clip: ClipRect,
+) {
+    let (Some(x), Some(y)) = (value_to_f64(ann.get("x")), value_to_f64(ann.get("y"))) else {
+        return;
+    };
+    let color = parse_color(ann.get("color"), [255, 59, 48, 255]);
+    let arm = value_to_f64(ann.get("size"))
+        .unwrap_or_else(|| f64::from(scale_default(10.0, scale, 6)))
+        .max(2.0);
+    let width = value_to_f64(ann.get("width"))
+        .unwrap_or_else(|| f64::from(scale_default(2.0, scale, 1)))
+        .max(1.0);
+    let outline_enabled = ann
+        .get("outline")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    if outline_enabled {
+        let outline_color =
+            parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(color));
+        let outline_width = width + f64::from(scale_default(2.0, scale, 2));
+        draw_thick_line(
+            img,
+            x - arm,
+            y,
+            x + arm,
+            y,
+            outline_color,
+            outline_width,
+            clip,
+        );
+        draw_thick_line(
+            img,
+            x,
+            y - arm,
+            x,
+            y + arm,
+            outline_color,
+            outline_width,
+            clip,
+        );
+    }
+    draw_thick_line(img, x - arm, y, x + arm, y, color, width, clip);
+    draw_thick_line(img, x, y - arm, x, y + arm, color, width, clip);
+
+    let show_coords = ann
+        .get("show_coords")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    if show_coords {
+        let label = point_coords_label(x, y, img.width(), img.height(), ann);
+        let glyph_scale = scale_default(1.0, scale, 1);
+        let padding = scale_default(3.0, scale, 2) as i32;
+        let (_, _, text_w, text_h) = text_bbox(0, 0, &label, glyph_scale);
+        let gap = arm.round() as i32 + padding;
+        // Up and to the right of the marker, flipped inward when it would leave the image.
+        let mut label_x = x.round() as i32 + gap;
+        if label_x + text_w + padding > img.width() as i32 {
+            label_x = (x.round() as i32 - gap - text_w).max(padding);
+        }
+        let mut label_y = y.round() as i32 - gap - text_h;
+        if label_y - padding < 0 {
+            label_y = y.round() as i32 + gap;
+        }
+        let bg = parse_color(ann.get("coords_bg"), [0, 0, 0, 170]);
+        fill_rect_alpha(
+            img,
+            label_x - padding,
+            label_y - padding,
+            label_x + text_w + padding - 1,
+            label_y + text_h + padding - 1,
+            bg,
+            clip,
+        );
+        let text_color = parse_color(ann.get("coords_color"), [255, 255, 255, 255]);
+        draw_bitmap_text(img, label_x, label_y, &label, text_color, glyph_scale, clip);
+    }
+}
+
+// Dimension line from (x1, y1) to (x2, y2) with perpendicular end ticks, labeled with its px
+// length on the side away from the image edge.
+fn draw_measure_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    scale: f64,
+    clip: ClipRect,
+) {
+    let coords = ["x1", "y1", "x2", "y2"].map(|key| value_to_f64(ann.get(key)));
+    let [Some(x1), Some(y1), Some(x2), Some(y2)] = coords else {
+        return;
+    };
+    let length = (x2 - x1).hypot(y2 - y1);
+    if length < 0.5 {
+        return;
+    }
+    let color = parse_color(ann.get("color"), [255, 45, 85, 255]);
+    let width = value_to_f64(ann.get("width"))
+        .unwrap_or_else(|| f64::from(scale_default(1.0, scale, 1)))
+        .max(1.0);
+    let tick = value_to_f64(ann.get("tick"))
+        .unwrap_or_else(|| f64::from(scale_default(5.0, scale, 3)))
+        .max(1.0);
+    // Unit normal, pointing up (or right for vertical lines) so labels sit above/beside the line.
+    let (mut nx, mut ny) = (-(y2 - y1) / length, (x2 - x1) / length);
+    if ny > 0.0 || (ny == 0.0 && nx < 0.0) {
+        (nx, ny) = (-nx, -ny);
+    }
+    let strokes = [
+        (x1, y1, x2, y2),
+        (
+            x1 - nx * tick,
+            y1 - ny * tick,
+            x1 + nx * tick,
+            y1 + ny * tick,
+        ),
+        (
+            x2 - nx * tick,
+            y2 - ny * tick,
+            x2 + nx * tick,
+            y2 + ny * tick,
+        ),
+    ];
+    let outline_enabled = ann
+        .get("outline")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    if outline_enabled {
+        let outline_color =
+            parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(color));
+        let outline_width = width + f64::from(scale_default(2.0, scale, 2));
+        for (ax, ay, bx, by) in strokes {
+            draw_thick_line(img, ax, ay, bx, by, outline_color, outline_width, clip);
+        }
+    }
+    for (ax, ay, bx, by) in strokes {
+        draw_thick_line(img, ax, ay, bx, by, color, width, clip);
+    }
+
+    let label = match ann.get("label") {
+        Some(Value::Bool(false)) => return,
+        Some(Value::String(text)) => text.clone(),
+        _ => format!("{}px", length.round() as i64),
+    };
+    let glyph_scale = scale_default(1.0, scale, 1);
+    let padding = scale_default(2.0, scale, 2) as i32;
+    let (_, _, text_w, text_h) = text_bbox(0, 0, &label, glyph_scale);
+    let offset = tick + f64::from(padding) + f64::from(text_w.max(text_h)) / 2.0;
+    let mut center = ((x1 + x2) / 2.0 + nx * offset, (y1 + y2) / 2.0 + ny * offset);
+    if center.1 - f64::from(text_h) / 2.0 < 0.0
+        || center.0 + f64::from(text_w) / 2.0 > f64::from(img.width())
+    {
+        center = ((x1 + x2) / 2.0 - nx * offset, (y1 + y2) / 2.0 - ny * offset);
+    }
+    let label_x = (center.0.round() as i32 - text_w / 2).max(padding);
+    let label_y = (center.1.round() as i32 - text_h / 2).max(padding);
+    fill_rect_alpha(
+        img,
+        label_x - padding,
+        label_y - padding,
+        label_x + text_w + padding - 1,
+        label_y + text_h + padding - 1,
+        parse_color(ann.get("label_bg"), [0, 0, 0, 170]),
+        clip,
+    );
+    let text_color = parse_color(ann.get("label_color"), [255, 255, 255, 255]);
+    draw_bitmap_text(img, label_x, label_y, &label, text_color, glyph_scale, clip);
+}
+
+// Measure annotations (in pt) for the gap from each AX element to its nearest sibling on the
+// right (overlapping vertically) and below (overlapping horizontally). Reads the `tree` of an
+// ax-tree payload; window-space payloads (--ax-origin window) are already window-relative.
+fn ax_spacing_measures(ax: &Value) -> Vec<Value> {
+    type Bounds = (f64, f64, f64, f64);
+
+    fn bounds(node: &Value) -> Option<Bounds> {
+        let b = node.get("bounds")?;
+        let (x, y) = (value_to_f64(b.get("x"))?, value_to_f64(b.get("y"))?);
+        let (w, h) = (value_to_f64(b.get("w"))?, value_to_f64(b.get("h"))?);
+        (w > 0.0 && h > 0.0).then_some((x, y, w, h))
+    }
+
+    fn walk(siblings: &[Value], window_space: bool, out: &mut Vec<Value>) {
+        let boxed: Vec<(&Value, Bounds)> = siblings
+            .iter()
+            .filter_map(|node| Some((node, bounds(node)?)))
+            .collect();
+        for (node, (x, y, w, h)) in &boxed {
+            let mut right: Option<(f64, &Value, f64)> = None;
+            let mut below: Option<(f64, &Value, f64)> = None;
+            for (other, (ox, oy, ow, oh)) in &boxed {
+                let (top, bottom) = (y.max(*oy), (y + h).min(oy + oh));
+                let gap = ox - (x + w);
+                if bottom > top && gap > 0.0 && right.is_none_or(|(best, _, _)| gap < best) {
+                    right = Some((gap, *other, (top + bottom) / 2.0));
+                }
+                let (left, right_edge) = (x.max(*ox), (x + w).min(ox + ow));
+                let gap = oy - (y + h);
+                if right_edge > left && gap > 0.0 && below.is_none_or(|(best, _, _)| gap < best) {
+                    below = Some((gap, *other, (left + right_edge) / 2.0));
+                }
+            }
+            if let Some((gap, other, mid_y)) = right {
+                out.push(ax_gap_measure(
+                    node,
+                    other,
+                    (x + w, mid_y),
+                    (x + w + gap, mid_y),
+                    gap,
+                    window_space,
+                ));
+            }
+            if let Some((gap, other, mid_x)) = below {
+                out.push(ax_gap_measure(
+                    node,
+                    other,
+                    (mid_x, y + h),
+                    (mid_x, y + h + gap),
+                    gap,
+                    window_space,
+                ));
+            }
+        }
+        for node in siblings {
+            if let Some(children) = node.get("children").and_then(Value::as_array) {
+                walk(children, window_space, out);
+            }
+        }
+    }
+
+    let window_space = ax.pointer("/coordinate_space/space") == Some(&json!("window"));
+    let roots = ax
+        .get("tree")
+        .or(Some(ax))
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let mut out = Vec::new();
+    walk(&roots, window_space, &mut out);
+    for (n, measure) in out.iter_mut().enumerate() {
+        measure["id"] = json!(format!("ax-gap-{}", n + 1));
+    }
+    out
+}
+
+fn ax_gap_measure(
+    from: &Value,
+    to: &Value,
+    start: (f64, f64),
+    end: (f64, f64),
+    gap: f64,
+    window_space: bool,
+) -> Value {
+    let mut measure = json!({
+        "type": "measure",
+        "x1": start.0, "y1": start.1, "x2": end.0, "y2": end.1,
+        "units": "pt",
+        "intent": "spacing",
+        "issue": format!("{}pt gap", round_to(gap, 2)),
+        "ax_from": from.get("index").cloned().unwrap_or(Value::Null),
+        "ax_to": to.get("index").cloned().unwrap_or(Value::Null),
+    });
+    if window_space {
+        measure["pt_origin"] = json!([0, 0]);
+    }
+    measure
+}
+
+// "(x, y)" in px, plus the rel fraction when "coords_rel" is set.
+fn point_coords_label(x: f64, y: f64, img_w: u32, img_h: u32, ann: &Map<String, Value>) -> String {
+    let mut label = format!("({}, {})", x.round() as i64, y.round() as i64);
+    let with_rel = ann
+        .get("coords_rel")
+        .map(|v| value_to_bool(v, false))
+        .unwrap_or(false);
+    if with_rel && img_w > 0 && img_h > 0 {
+        label.push_str(&format!(
+            " rel({:.3}, {:.3})",
+            x / f64::from(img_w),
+            y / f64::from(img_h)
+        ));
+    }
+    label
+}
+
+fn draw_text_annotation(
+    img: &mut RgbaImage,
+    ann: &Map<String, Value>,
+    scale: f64,
+    fonts: &FontCache,
+    clip: ClipRect,
+) {
+    let text = ann
+        .get("text")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    if text.is_empty() {
+        return;
+    }
+
+    let x = value_to_i32(ann.get("x"), 0);
+    let y = value_to_i32(ann.get("y"), 0);
+    let color = parse_color(ann.get("color"), [255, 255, 255, 255]);
+    let size = value_to_usize(ann.get("size"))
+        .map(|v| v.max(8) as u32)
+        .unwrap_or_else(|| scale_default(14.0, scale, 10));
+    let glyph_scale = (size as f64 / 8.0).round().max(1.0) as u32;
+    let padding = value_to_usize(ann.get("padding"))
+        .map(|v| v as i32)
+        .unwrap_or_else(|| scale_default(4.0, scale, 2) as i32);
+    let direction = text_direction(ann);
+    let line_spacing = text_line_spacing(ann);
+    let ttf_mask = text_ttf_mask(ann, &text, f64::from(size), fonts);
+    let bbox = match &ttf_mask {
+        Some(mask) => (x, y, x + mask.width as i32, y + mask.height as i32),
+        None => text_bbox_directed(x, y, &text, glyph_scale, direction, line_spacing),
+    };
+    let (dx, dy) = text_align_shift(ann, x, y, bbox);
+    let (x, y) = (x + dx, y + dy);
+    let draw = |img: &mut RgbaImage, x: i32, y: i32, color: Rgba<u8>| match &ttf_mask {
+        Some(mask) => stamp_coverage_mask(img, x, y, mask, color, clip),
+        None => draw_bitmap_text_directed(
+            img,
+            x,
+            y,
+            &text,
+            color,
+            glyph_scale,
+            direction,
+            line_spacing,
+            clip,
+        ),
+    };
+
+    let bg_value = ann.get("bg").or_else(|| ann.get("text_bg"));
+    if let Some(bg_color) = parse_color_opt(bg_value) {
+        fill_rect_alpha(
+            img,
+            bbox.0 + dx - padding,
+            bbox.1 + dy - padding,
+            bbox.2 + dx + padding,
+            bbox.3 + dy + padding,
+            bg_color,
+            clip,
+        );
+    }
+
+    let outline_enabled = ann
+        .get("outline")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    let outline_width = value_to_usize(ann.get("outline_width"))
+        .map(|v| v.max(1) as i32)
+        .unwrap_or_else(|| ((size as f64 * 0.12).round() as i32).max(1));
+    let outline_color =
+        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(color));
+
+    if outline_enabled {
+        for dx in -outline_width..=outline_width {
+            for dy in -outline_width..=outline_width {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                if dx * dx + dy * dy > outline_width * outline_width {
+                    continue;
+                }
+                draw(img, x + dx, y + dy, outline_color);
+            }
+        }
+    }
+
+    draw(img, x, y, color);
+}
+
+// "align": left|center|right and "valign": top|middle|bottom put that edge (or the middle) of
+// the measured text box on x/y, so anchored text can center over its anchor point. Without the
+// keys x/y stay the top-left of the text box, as before.
+fn text_align_shift(
+    ann: &Map<String, Value>,
+    x: i32,
+    y: i32,
+    bbox: (i32, i32, i32, i32),
+) -> (i32, i32) {
+    let key = |name: &str| {
+        ann.get(name)
+            .and_then(Value::as_str)
+            .map(|v| v.trim().to_ascii_lowercase())
+    };
+    let dx = match key("align").as_deref() {
+        Some("left") => x - bbox.0,
+        Some("center") => x - (bbox.0 + bbox.2).div_euclid(2),
+        Some("right") => x - bbox.2,
+        _ => 0,
+    };
+    let dy = match key("valign").as_deref() {
+        Some("top") => y - bbox.1,
+        Some("middle") => y - (bbox.1 + bbox.3).div_euclid(2),
+        Some("bottom") => y - bbox.3,
+        _ => 0,
+    };
+    (dx, dy)
+}
+
+// "direction": "rtl" | "vertical"; anything else (or absent) is left-to-right.
+fn text_direction(ann: &Map<String, Value>) -> &'static str {
+    match ann
+        .get("direction")
+        .and_then(Value::as_str)
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("rtl") => "rtl",
+        Some("vertical") => "vertical",
+        _ => "ltr",
+    }
+}
+
+// TrueType coverage for a text annotation's "font" (already merged from --font/defaults.font).
+// TrueType rendering is left-to-right only; rtl/vertical keep the bitmap layout.
+fn text_ttf_mask(
+    ann: &Map<String, Value>,
+    text: &str,
+    size: f64,
+    fonts: &FontCache,
+) -> Option<CoverageMask> {
+    let font = value_to_string(ann.get("font"))
+        .filter(|_| text_direction(ann) == "ltr")
+        .and_then(|path| fonts.get(&path))?;
+    font_text_mask(&font, text, size, f64::from(text_line_spacing(ann)))
+}
+
+// Parsed with fontdue under the `ttf` feature; otherwise only the file bytes, and text keeps the
+// built-in bitmap font.
+#[cfg(feature = "ttf")]
+type TextFont = fontdue::Font;
+#[cfg(not(feature = "ttf"))]
+type TextFont = Vec<u8>;
+
+// Font files by path, loaded once per annotate run so a "font" repeated across annotations is
+// neither reloaded nor reported twice.
+#[derive(Default)]
+struct FontCache {
+    files:
+        std::collections::BTreeMap<String, std::result::Result<std::sync::Arc<TextFont>, String>>,
+}
+
+impl FontCache {
+    fn load(&mut self, path: &str) -> std::result::Result<std::sync::Arc<TextFont>, String> {
+        self.files
+            .entry(path.to_string())
+            .or_insert_with(|| {
+                let data = fs::read(path).map_err(|err| err.to_string())?;
+                parse_text_font(data).map(std::sync::Arc::new)
+            })
+            .clone()
+    }
+
+    // Fonts `load` already accepted; rendering never reads files itself.
+    fn get(&self, path: &str) -> Option<std::sync::Arc<TextFont>> {
+        self.files.get(path)?.as_ref().ok().cloned()
+    }
+}
+
+#[cfg(feature = "ttf")]
+fn parse_text_font(data: Vec<u8>) -> std::result::Result<TextFont, String> {
+    fontdue::Font::from_bytes(data, fontdue::FontSettings::default()).map_err(str::to_string)
+}
+
+#[cfg(not(feature = "ttf"))]
+fn parse_text_font(data: Vec<u8>) -> std::result::Result<TextFont, String> {
+    Ok(data)
+}
+
+#[cfg_attr(not(feature = "ttf"), allow(dead_code))]
+struct CoverageMask {
+    width: u32,
+    height: u32,
+    coverage: Vec<f32>,
+}
+
+// Lines of `text` laid out on the font's ascent/descent/line gap (plus `line_spacing`), with
+// the mask's top-left at the top of the first line's ascent. No kerning or shaping.
+#[cfg(feature = "ttf")]
+fn font_text_mask(
+    font: &TextFont,
+    text: &str,
+    size: f64,
+    line_spacing: f64,
+) -> Option<CoverageMask> {
+    let px = size as f32;
+    let (ascent, descent, line_gap) = font
+        .horizontal_line_metrics(px)
+        .map(|m| (m.ascent, m.descent, m.line_gap))
+        .unwrap_or((px * 0.8, -px * 0.2, 0.0));
+    let line_height = f64::from(ascent - descent + line_gap) + line_spacing;
+
+    let mut glyphs = Vec::new();
+    let mut width = 0.0f64;
+    let mut lines = 0usize;
+    for (idx, line) in text.split('\n').enumerate() {
+        lines = idx + 1;
+        let baseline = f64::from(ascent) + idx as f64 * line_height;
+        let mut pen = 0.0f64;
+        for ch in line.chars() {
+            let (metrics, bitmap) = font.rasterize(ch, px);
+            let left = (pen + f64::from(metrics.xmin)).round() as i64;
+            let top = (baseline - f64::from(metrics.ymin) - metrics.height as f64).round() as i64;
+            glyphs.push((left, top, metrics.width, bitmap));
+            pen += f64::from(metrics.advance_width);
+        }
+        width = width.max(pen);
+    }
+
+    let width = width.ceil().max(0.0) as u32;
+    let height = (line_height * lines as f64 - line_spacing).ceil().max(0.0) as u32;
+    let mut coverage = vec![0.0f32; width as usize * height as usize];
+    for (left, top, glyph_w, bitmap) in glyphs {
+        for (idx, value) in bitmap.iter().enumerate() {
+            let x = left + (idx % glyph_w.max(1)) as i64;
+            let y = top + (idx / glyph_w.max(1)) as i64;
+            if *value == 0 || x < 0 || y < 0 || x >= i64::from(width) || y >= i64::from(height) {
+                continue;
+            }
+            let cell = &mut coverage[y as usize * width as usize + x as usize];
+            *cell = (*cell + f32::from(*value) / 255.0).min(1.0);
+        }
+    }
+    Some(CoverageMask {
+        width,
+        height,
+        coverage,
+    })
+}
+
+#[cfg(not(feature = "ttf"))]
+fn font_text_mask(
+    _font: &TextFont,
+    _text: &str,
+    _size: f64,
+    _line_spacing: f64,
+) -> Option<CoverageMask> {
+    None
+}
+
+fn stamp_coverage_mask(
+    img: &mut RgbaImage,
+    x: i32,
+    y: i32,
+    mask: &CoverageMask,
+    color: Rgba<u8>,
+    clip: ClipRect,
+) {
+    for (idx, cov) in mask.coverage.iter().enumerate() {
+        if *cov <= 0.0 {
+            continue;
+        }
+        let tx = x + (idx as u32 % mask.width.max(1)) as i32;
+        let ty = y + (idx as u32 / mask.width.max(1)) as i32;
+        let mut src = color;
+        src[3] = (f32::from(color[3]) * cov.min(1.0)).round() as u8;
+        blend_at(img, tx, ty, src, clip);
+    }
+}
+
+fn fit_bbox_luma(
+    image_rgb: &image::RgbImage,
+    region: (u32, u32, u32, u32),
+    threshold: f64,
+    target: &str,
+    min_pixels: u32,
+) -> Option<(u32, u32, u32, u32)> {
+    let (x0, y0, x1, y1) = region;
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    let mut minx = u32::MAX;
+    let mut miny = u32::MAX;
+    let mut maxx = 0u32;
+    let mut maxy = 0u32;
+    let mut count = 0u32;
+    let dark = !target.eq_ignore_ascii_case("light");
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let pixel = image_rgb.get_pixel(x, y).0;
+            let luma = 0.2126 * f64::from(pixel[0])
+                + 0.7152 * f64::from(pixel[1])
+                + 0.0722 * f64::from(pixel[2]);
+            let matched = if dark {
+                luma <= threshold
+            } else {
+                luma >= threshold
+            };
+            if matched {
+                count += 1;
+                minx = minx.min(x);
+                miny = miny.min(y);
+                maxx = maxx.max(x);
+                maxy = maxy.max(y);
+            }
+        }
+    }
+
+    if count < min_pixels.max(1) || minx == u32::MAX {
+        return None;
+    }
+    Some((minx, miny, maxx, maxy))
+}
+
+fn fit_bbox_color(
+    image_rgb: &image::RgbImage,
+    region: (u32, u32, u32, u32),
+    color: Rgba<u8>,
+    tolerance: f64,
+    min_pixels: u32,
+) -> Option<(u32, u32, u32, u32)> {
+    let (x0, y0, x1, y1) = region;
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    let mut minx = u32::MAX;
+    let mut miny = u32::MAX;
+    let mut maxx = 0u32;
+    let mut maxy = 0u32;
+    let mut count = 0u32;
+    let tol = tolerance.max(0.0);
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let pixel = image_rgb.get_pixel(x, y).0;
+            let delta = (i16::from(pixel[0]) - i16::from(color[0]))
+                .unsigned_abs()
+                .max((i16::from(pixel[1]) - i16::from(color[1])).unsigned_abs())
+                .max((i16::from(pixel[2]) - i16::from(color[2])).unsigned_abs())
+                as f64;
+            if delta <= tol {
+                count += 1;
+                minx = minx.min(x);
+                miny = miny.min(y);
+                maxx = maxx.max(x);
+                maxy = maxy.max(y);
+            }
+        }
+    }
+
+    if count < min_pixels.max(1) || minx == u32::MAX {
+        return None;
+    }
+    Some((minx, miny, maxx, maxy))
+}
+
+fn expand_bbox(
+    bbox: Option<(u32, u32, u32, u32)>,
+    pad: f64,
+    img_w: u32,
+    img_h: u32,
+) -> Option<(u32, u32, u32, u32)> {
+    let (x0, y0, x1, y1) = bbox?;
+    let p = pad.max(0.0).round() as i64;
+    let min_x = (i64::from(x0) - p).clamp(0, i64::from(img_w)) as u32;
+    let min_y = (i64::from(y0) - p).clamp(0, i64::from(img_h)) as u32;
+    let max_x = (i64::from(x1) + p).clamp(0, i64::from(img_w)) as u32;
+    let max_y = (i64::from(y1) + p).clamp(0, i64::from(img_h)) as u32;
+    if max_x <= min_x || max_y <= min_y {
+        return None;
+    }
+    Some((min_x, min_y, max_x, max_y))
+}
+
+fn parse_fit_region(
+    fit_region: Option<&Value>,
+    ann: &Map<String, Value>,
+    img_w: u32,
+    img_h: u32,
+) -> (u32, u32, u32, u32) {
+    let (mut x, mut y, mut w, mut h) = (
+        value_to_f64(ann.get("x")),
+        value_to_f64(ann.get("y")),
+        value_to_f64(ann.get("w")),
+        value_to_f64(ann.get("h")),
+    );
+
+    if let Some(value) = fit_region {
+        match value {
+            Value::Object(obj) => {
+                x = value_to_f64(obj.get("x"));
+                y = value_to_f64(obj.get("y"));
+                w = value_to_f64(obj.get("w"));
+                h = value_to_f64(obj.get("h"));
+            }
+            Value::Array(items) if items.len() >= 4 => {
+                x = value_to_f64(items.first());
+                y = value_to_f64(items.get(1));
+                w = value_to_f64(items.get(2));
+                h = value_to_f64(items.get(3));
+            }
+            _ => {}
+        }
+    }
+
+    let x = x.unwrap_or(0.0);
+    let y = y.unwrap_or(0.0);
+    let w = w.unwrap_or(f64::from(img_w));
+    let h = h.unwrap_or(f64::from(img_h));
+    let x0 = clamp_i32(x.round() as i32, 0, img_w as i32) as u32;
+    let y0 = clamp_i32(y.round() as i32, 0, img_h as i32) as u32;
+    let x1 = clamp_i32((x + w).round() as i32, 0, img_w as i32) as u32;
+    let y1 = clamp_i32((y + h).round() as i32, 0, img_h as i32) as u32;
+    if x1 <= x0 || y1 <= y0 {
+        (0, 0, img_w, img_h)
+    } else {
+        (x0, y0, x1, y1)
+    }
+}
+
+fn target_center_from_bbox(bbox: (u32, u32, u32, u32)) -> (f64, f64) {
+    (
+        f64::from(bbox.0 + bbox.2) / 2.0,
+        f64::from(bbox.1 + bbox.3) / 2.0,
+    )
+}
+
+fn snap_bbox_to_region(
+    region: (u32, u32, u32, u32),
+    bbox: (u32, u32, u32, u32),
+    img_w: u32,
+    img_h: u32,
+) -> (u32, u32, u32, u32) {
+    let region_w = region.2.saturating_sub(region.0);
+    let region_h = region.3.saturating_sub(region.1);
+    let bbox_w = bbox.2.saturating_sub(bbox.0);
+    let bbox_h = bbox.3.saturating_sub(bbox.1);
+    if region_w == 0 || region_h == 0 {
+        return bbox;
+    }
+    if bbox_w <= region_w && bbox_h <= region_h {
+        let center = target_center_from_bbox(bbox);
+        let x0 = clamp_i32(
+            (center.0 - f64::from(region_w) / 2.0).round() as i32,
+            0,
+            img_w as i32,
+        ) as u32;
+        let y0 = clamp_i32(
+            (center.1 - f64::from(region_h) / 2.0).round() as i32,
+            0,
+            img_h as i32,
+        ) as u32;
+        let x1 = (x0 + region_w).min(img_w);
+        let y1 = (y0 + region_h).min(img_h);
+        if x1 > x0 && y1 > y0 {
+            return (x0, y0, x1, y1);
+        }
+    }
+    bbox
+}
+
+fn resolve_fit_config(ann: &Map<String, Value>, defaults: &Map<String, Value>) -> Option<Value> {
+    if let Some(fit) = ann.get("fit") {
+        return match fit {
+            Value::Bool(v) => {
+                if *v {
+                    Some(json!({}))
+                } else {
+                    None
+                }
+            }
+            Value::String(mode) => Some(json!({"mode": mode})),
+            Value::Object(obj) => Some(Value::Object(obj.clone())),
+            _ => None,
+        };
+    }
+
+    let auto_fit = defaults
+        .get("auto_fit")
+        .map(|v| value_to_bool(v, true))
+        .unwrap_or(true);
+    if !auto_fit {
+        return None;
+    }
+
+    let mut fit = Map::new();
+    fit.insert(
+        "mode".to_string(),
+        defaults
+            .get("fit_mode")
+            .cloned()
+            .unwrap_or_else(|| json!("luma")),
+    );
+    for key in [
+        "fit_threshold",
+        "fit_target",
+        "fit_tolerance",
+        "fit_color",
+        "fit_pad",
+        "fit_min_pixels",
+        "fit_min_coverage",
+    ] {
+        if let Some(value) = defaults.get(key).cloned() {
+            let out_key = key.trim_start_matches("fit_").to_string();
+            fit.insert(out_key, value);
+        }
+    }
+    Some(Value::Object(fit))
+}
+
+fn apply_fit(
+    ann: &Map<String, Value>,
+    image_rgb: &image::RgbImage,
+    img_w: u32,
+    img_h: u32,
+    defaults: &Map<String, Value>,
+) -> Map<String, Value> {
+    let fit_value = resolve_fit_config(ann, defaults);
+    let Some(fit_value) = fit_value else {
+        return ann.clone();
+    };
+    let Value::Object(fit) = fit_value else {
+        return ann.clone();
+    };
+
+    let mode = value_to_string(fit.get("mode"))
+        .unwrap_or_else(|| "luma".to_string())
+        .to_ascii_lowercase();
+    let region = parse_fit_region(fit.get("region"), ann, img_w, img_h);
+    let min_pixels = value_to_f64(fit.get("min_pixels")).unwrap_or(30.0).max(1.0) as u32;
+
+    let mut bbox = if mode == "luma" {
+        let threshold = value_to_f64(fit.get("threshold")).unwrap_or(160.0);
+        let target = value_to_string(fit.get("target")).unwrap_or_else(|| "dark".to_string());
+        fit_bbox_luma(image_rgb, region, threshold, &target, min_pixels)
+    } else if mode == "color" {
+        let target_color = parse_color_opt(fit.get("color").or_else(|| fit.get("target_color")));
+        let Some(color) = target_color else {
+            return ann.clone();
+        };
+        let tolerance = value_to_f64(fit.get("tolerance")).unwrap_or(18.0);
+        fit_bbox_color(image_rgb, region, color, tolerance, min_pixels)
+    } else {
+        return ann.clone();
+    };
+
+    let pad = value_to_f64(fit.get("pad")).unwrap_or(0.0);
+    bbox = expand_bbox(bbox, pad, img_w, img_h);
+    let Some(mut bbox) = bbox else {
+        return ann.clone();
+    };
+
+    bbox = snap_bbox_to_region(region, bbox, img_w, img_h);
+
+    let mut updated = ann.clone();
+    updated.insert("x".to_string(), json!(bbox.0));
+    updated.insert("y".to_string(), json!(bbox.1));
+    updated.insert("w".to_string(), json!(bbox.2.saturating_sub(bbox.0)));
+    updated.insert("h".to_string(), json!(bbox.3.saturating_sub(bbox.1)));
+    updated
+}
+
+fn activate_process_window(process: &str) -> QueryDiagnostic {
+    if !cfg!(target_os = "macos") {
+        return QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some("unsupported_platform".to_string()),
+            message: Some("activation requires macOS".to_string()),
+        };
+    }
+
+    let escaped = process.replace('"', "\\\"");
+    let script = format!("try\ntell application \"{escaped}\" to activate\nreturn \"ok\"\non error errMsg number errNum\nreturn \"err:\" & errNum & \":\" & errMsg\nend try");
+    let (stdout, mut diag) = run_osascript_with_retry(&script, &[], 1, 40);
+    if let Some(text) = stdout {
+        if text.starts_with("err:") {
+            let code = text
+                .split(':')
+                .nth(1)
+                .map(|v| format!("osascript_{v}"))
+                .unwrap_or_else(|| "activation_failed".to_string());
+            diag.ok = false;
+            diag.error_code = Some(code);
+            diag.message = Some(text);
+        } else {
+            diag.ok = true;
+            diag.error_code = None;
+            diag.message = Some("activated".to_string());
+        }
+    }
+    diag
+}
+
+fn query_displays() -> (Vec<DisplayInfo>, QueryDiagnostic) {
+    let script = r#"
+use framework "AppKit"
+use scripting additions
+set linesOut to {}
+set screenList to current application's NSScreen's screens() as list
+repeat with screenRef in screenList
+  set frameVal to screenRef's frame()
+  set originVal to item 1 of frameVal
+  set sizeVal to item 2 of frameVal
+  set scaleVal to screenRef's backingScaleFactor()
+  set end of linesOut to ((item 1 of originVal) as text) & tab & ((item 2 of originVal) as text) & tab & ((item 1 of sizeVal) as text) & tab & ((item 2 of sizeVal) as text) & tab & (scaleVal as text)
+end repeat
+set AppleScript's text item delimiters to linefeed
+set joined to linesOut as text
+set AppleScript's text item delimiters to ""
+return joined
+"#;
+    let (raw, diagnostics) = run_osascript_with_retry(script, &[], 2, 80);
+    let displays = raw.as_deref().map(parse_display_lines).unwrap_or_default();
+    (displays, diagnostics)
+}
+
+// NSScreen frames are bottom-left based on the main (first) screen; flip them into the
+// top-left global space that System Events window bounds use.
+fn parse_display_lines(raw: &str) -> Vec<DisplayInfo> {
+    let rows: Vec<[f64; 5]> = raw
+        .lines()
+        .filter_map(|line| {
+            let values = line
+                .split('\t')
+                .map(|part| part.trim().replace(',', ".").parse::<f64>().ok())
+                .collect::<Option<Vec<f64>>>()?;
+            <[f64; 5]>::try_from(values).ok()
+        })
+        .collect();
+    let Some(main_h) = rows.first().map(|row| row[3]) else {
+        return Vec::new();
+    };
+    rows.iter()
+        .enumerate()
+        .map(|(index, &[x, y, w, h, scale])| DisplayInfo {
+            index,
+            x: x.round() as i64,
+            y: (main_h - (y + h)).round() as i64,
+            w: w.round() as i64,
+            h: h.round() as i64,
+            scale,
+        })
+        .collect()
+}
+
+// Display with the largest overlap with the window; the main display when there is no window.
+fn display_for_window(
+    displays: &[DisplayInfo],
+    window: (i64, i64, i64, i64),
+) -> Option<&DisplayInfo> {
+    let (wx, wy, ww, wh) = window;
+    if ww <= 0 || wh <= 0 {
+        return displays.first();
+    }
+    displays
+        .iter()
+        .map(|d| {
+            let overlap_w = ((wx + ww).min(d.x + d.w) - wx.max(d.x)).max(0);
+            let overlap_h = ((wy + wh).min(d.y + d.h) - wy.max(d.y)).max(0);
+            (overlap_w * overlap_h, d)
+        })
+        .filter(|(area, _)| *area > 0)
+        .max_by_key(|(area, _)| *area)
+        .map(|(_, d)| d)
+        .or_else(|| displays.first())
+}
+
+// System Events only sees windows on the active Space, so ask CoreGraphics instead: a
+// window on another Space (or minimized) is listed but not flagged kCGWindowIsOnscreen.
+fn query_cg_windows(process: &str) -> (Vec<CgWindow>, QueryDiagnostic) {
+    let script = r#"
+ObjC.import('CoreGraphics');
+function run(argv) {
+  const info = ObjC.castRefToObject($.CGWindowListCopyWindowInfo($.kCGWindowListOptionAll, 0));
+  const rows = [];
+  for (const w of ObjC.deepUnwrap(info) || []) {
+    if (w.kCGWindowOwnerName !== argv[0] || w.kCGWindowLayer !== 0) continue;
+    const b = w.kCGWindowBounds || {};
+    if (!(b.Width > 0 && b.Height > 0)) continue;
+    rows.push([w.kCGWindowNumber, w.kCGWindowIsOnscreen ? 1 : 0, b.X, b.Y, b.Width, b.Height].join('\t'));
+  }
+  return rows.length ? rows.join('\n') : 'none';
+}
+"#;
+    let (raw, diagnostics) =
+        run_osascript_lang_with_retry(Some("JavaScript"), script, &[process.to_string()], 2, 80);
+    let windows = raw
+        .as_deref()
+        .map(parse_cg_window_lines)
+        .unwrap_or_default();
+    (windows, diagnostics)
+}
+
+fn parse_cg_window_lines(raw: &str) -> Vec<CgWindow> {
+    raw.lines()
+        .filter_map(|line| {
+            let values = line
+                .split('\t')
+                .map(|part| part.trim().parse::<f64>().ok())
+                .collect::<Option<Vec<f64>>>()?;
+            let [number, onscreen, x, y, w, h] = <[f64; 6]>::try_from(values).ok()?;
+            Some(CgWindow {
+                number: number as i64,
+                onscreen: onscreen != 0.0,
+                x: x.round() as i64,
+                y: y.round() as i64,
+                w: w.round() as i64,
+                h: h.round() as i64,
+            })
+        })
+        .collect()
+}
+
+// True when the app has normal windows but none of them is on screen: the window lives on
+// another Space (or is minimized), and screencapture would grab the wrong content.
+fn windows_on_other_space(windows: &[CgWindow]) -> bool {
+    !windows.is_empty() && windows.iter().all(|w| !w.onscreen)
+}
+
+const MIN_USABLE_WINDOW_WIDTH: i64 = 220;
+const MIN_USABLE_WINDOW_HEIGHT: i64 = 140;
+const MIN_USABLE_WINDOW_AREA: i64 = 40_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WindowThresholds {
+    min_width: i64,
+    min_height: i64,
+    min_area: i64,
+}
+
+impl Default for WindowThresholds {
+    fn default() -> Self {
+        Self {
+            min_width: MIN_USABLE_WINDOW_WIDTH,
+            min_height: MIN_USABLE_WINDOW_HEIGHT,
+            min_area: MIN_USABLE_WINDOW_AREA,
+        }
+    }
+}
+
+// --min-window-* flags win over CVLP_MIN_WINDOW_WIDTH/HEIGHT/AREA, which win over the defaults;
+// invalid env values are reported and skipped.
+fn window_thresholds(
+    flags: (Option<i64>, Option<i64>, Option<i64>),
+    env: (Option<&str>, Option<&str>, Option<&str>),
+) -> (WindowThresholds, Vec<String>) {
+    let mut invalid = Vec::new();
+    let mut pick = |flag: Option<i64>, raw: Option<&str>, name: &str, default: i64| {
+        if let Some(value) = flag {
+            return value;
+        }
+        match raw.map(str::trim).filter(|raw| !raw.is_empty()) {
+            Some(raw) => match raw.parse::<i64>() {
+                Ok(value) if value >= 0 => value,
+                _ => {
+                    invalid.push(format!(
+                        "ignoring {name}={raw:?}; expected a non-negative integer"
+                    ));
+                    default
+                }
+            },
+            None => default,
+        }
+    };
+    let thresholds = WindowThresholds {
+        min_width: pick(
+            flags.0,
+            env.0,
+            "CVLP_MIN_WINDOW_WIDTH",
+            MIN_USABLE_WINDOW_WIDTH,
+        ),
+        min_height: pick(
+            flags.1,
+            env.1,
+            "CVLP_MIN_WINDOW_HEIGHT",
+            MIN_USABLE_WINDOW_HEIGHT,
+        ),
+        min_area: pick(
+            flags.2,
+            env.2,
+            "CVLP_MIN_WINDOW_AREA",
+            MIN_USABLE_WINDOW_AREA,
+        ),
+    };
+    (thresholds, invalid)
+}
+
+fn env_window_thresholds(
+    flags: (Option<i64>, Option<i64>, Option<i64>),
+) -> (WindowThresholds, Vec<String>) {
+    let width = env::var("CVLP_MIN_WINDOW_WIDTH").ok();
+    let height = env::var("CVLP_MIN_WINDOW_HEIGHT").ok();
+    let area = env::var("CVLP_MIN_WINDOW_AREA").ok();
+    window_thresholds(
+        flags,
+        (width.as_deref(), height.as_deref(), area.as_deref()),
+    )
+}
+
+// For window probes outside `capture`, which only honor the env overrides.
+fn default_window_thresholds() -> WindowThresholds {
+    let (thresholds, invalid) = env_window_thresholds((None, None, None));
+    for message in invalid {
+        eprintln!("warning: {message}");
+    }
+    thresholds
+}
+
+fn query_window_probe(
+    process: &str,
+    thresholds: WindowThresholds,
+    scripts: &ScriptOverrides,
+) -> WindowProbe {
+    let mut probe = WindowProbe {
+        x: 0,
+        y: 0,
+        w: 0,
+        h: 0,
+        title: None,
+        selected_index: None,
+        candidate_count: 0,
+        usable_count: 0,
+        selection_mode: "none".to_string(),
+        usable: false,
+        min_width: thresholds.min_width,
+        min_height: thresholds.min_height,
+        min_area: thresholds.min_area,
+        diagnostics: QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some("window_query_not_started".to_string()),
+            message: Some("window query not executed".to_string()),
+        },
+    };
+
+    if !cfg!(target_os = "macos") {
+        probe.diagnostics = QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some("unsupported_platform".to_string()),
+            message: Some("window queries require macOS".to_string()),
+        };
+        return probe;
+    }
+
+    let custom_script = match read_script_override(scripts.window.as_deref(), "window_script") {
+        Ok(custom) => custom,
+        Err(diag) => {
+            probe.diagnostics = diag;
+            return probe;
+        }
+    };
+
+    let script = r#"
+on cleanText(v)
+  try
+    set t to v as text
+  on error
+    set t to ""
+  end try
+  set AppleScript's text item delimiters to {return, linefeed, tab}
+  set parts to text items of t
+  set AppleScript's text item delimiters to " "
+  set clean to parts as text
+  set AppleScript's text item delimiters to ""
+  return clean
+end cleanText
+
+on emitLine(idxVal, xVal, yVal, wVal, hVal, titleVal)
+  return (idxVal as text) & tab & (xVal as text) & tab & (yVal as text) & tab & (wVal as text) & tab & (hVal as text) & tab & my cleanText(titleVal)
+end emitLine
+
+on run argv
+  set procName to item 1 of argv
+  tell application "System Events"
+    if not (exists process procName) then
+      error "process_not_found"
+    end if
+    tell process procName
+      set winRefs to windows
+      if (count of winRefs) is 0 then
+        error "no_windows"
+      end if
+      set linesOut to {}
+      set idx to 0
+      repeat with winRef in winRefs
+        set idx to idx + 1
+        try
+          set p to position of winRef
+          set s to size of winRef
+          set wx to item 1 of p
+          set wy to item 2 of p
+          set ww to item 1 of s
+          set wh to item 2 of s
+          if ww > 0 and wh > 0 then
+            set wt to ""
+            try
+              set wt to name of winRef
+            end try
+            set end of linesOut to my emitLine(idx, wx, wy, ww, wh, wt)
+          end if
+        end try
+      end repeat
+      if (count of linesOut) is 0 then
+        error "no_window_bounds"
+      end if
+      set AppleScript's text item delimiters to linefeed
+      set outText to linesOut as text
+      set AppleScript's text item delimiters to ""
+      return outText
+    end tell
+  end tell
+end run
+"#;
+    let script = custom_script.as_deref().unwrap_or(script);
+
+    let args = vec![process.to_string()];
+    let (raw_lines, raw_diag) = run_osascript_with_retry(script, &args, 3, 120);
+    let attempts = raw_diag.attempts.max(1);
+
+    let Some(lines) = raw_lines else {
+        probe.diagnostics = QueryDiagnostic {
+            ok: false,
+            attempts,
+            error_code: raw_diag
+                .error_code
+                .clone()
+                .or(Some("window_query_empty".to_string())),
+            message: raw_diag
+                .message
+                .clone()
+                .or(Some("window bounds missing".to_string())),
+        };
+        return probe;
+    };
+
+    let candidates = parse_window_candidates(&lines);
+    if candidates.is_empty() && custom_script.is_some() {
+        probe.diagnostics = QueryDiagnostic {
+            ok: false,
+            attempts,
+            error_code: Some("window_script_invalid".to_string()),
+            message: Some(format!(
+                "--window-script output has no index\\tx\\ty\\tw\\th\\ttitle rows with a positive size (first line: {:?})",
+                lines.lines().next().unwrap_or_default()
+            )),
+        };
+        return probe;
+    }
+    if candidates.is_empty() {
+        probe.diagnostics = QueryDiagnostic {
+            ok: false,
+            attempts,
+            error_code: Some("window_bounds_parse_failed".to_string()),
+            message: Some("window bounds output was present but parse failed".to_string()),
+        };
+        return probe;
+    }
+
+    probe.candidate_count = candidates.len();
+    let (selected, selection_mode, usable_count) = select_window_candidate(
+        &candidates,
+        thresholds.min_width,
+        thresholds.min_height,
+        thresholds.min_area,
+    );
+    probe.x = selected.x;
+    probe.y = selected.y;
+    probe.w = selected.w;
+    probe.h = selected.h;
+    probe.title = selected.title.clone();
+    probe.selected_index = Some(selected.index);
+    probe.selection_mode = selection_mode.to_string();
+    probe.usable_count = usable_count;
+    probe.usable = selected.w >= thresholds.min_width
+        && selected.h >= thresholds.min_height
+        && selected.w.saturating_mul(selected.h) >= thresholds.min_area;
+
+    let selection_note = format!(
+        "selected window {} ({}) {}x{}, candidates={} usable={}",
+        selected.index,
+        selection_mode,
+        selected.w,
+        selected.h,
+        probe.candidate_count,
+        probe.usable_count
+    );
+
+    probe.diagnostics = QueryDiagnostic {
+        ok: true,
+        attempts,
+        error_code: None,
+        message: Some(selection_note),
+    };
+    probe
+}
+
+fn run_osascript_with_retry(
+    script: &str,
+    args: &[String],
+    attempts: u32,
+    delay_ms: u64,
+) -> (Option<String>, QueryDiagnostic) {
+    run_osascript_lang_with_retry(None, script, args, attempts, delay_ms)
+}
+
+// `language` is passed to `osascript -l` (e.g. JavaScript for JXA); None means AppleScript.
+fn run_osascript_lang_with_retry(
+    language: Option<&str>,
+    script: &str,
+    args: &[String],
+    attempts: u32,
+    delay_ms: u64,
+) -> (Option<String>, QueryDiagnostic) {
+    if !cfg!(target_os = "macos") {
+        return (
+            None,
+            QueryDiagnostic {
+                ok: false,
+                attempts: 0,
+                error_code: Some("unsupported_platform".to_string()),
+                message: Some("osascript requires macOS".to_string()),
+            },
+        );
+    }
+
+    let max_attempts = attempts.max(1);
+    let timeout_ms = 450u64;
+    let mut last_code = Some("osascript_no_output".to_string());
+    let mut last_message = Some("osascript returned empty output".to_string());
+
+    for attempt in 1..=max_attempts {
+        let mut cmd = Command::new("osascript");
+        if let Some(language) = language {
+            cmd.arg("-l").arg(language);
+        }
+        cmd.arg("-e").arg(script);
+        if !args.is_empty() {
+            cmd.arg("--");
+            for arg in args {
+                cmd.arg(arg);
+            }
+        }
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+
+        match cmd.spawn() {
+            Ok(mut child) => match child.wait_timeout(Duration::from_millis(timeout_ms)) {
+                Ok(Some(_)) => match child.wait_with_output() {
+                    Ok(output) => {
+                        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                        if output.status.success() && !stdout.is_empty() {
+                            return (
+                                Some(stdout),
+                                QueryDiagnostic {
+                                    ok: true,
+                                    attempts: attempt,
+                                    error_code: None,
+                                    message: None,
+                                },
+                            );
+                        }
+
+                        if output.status.success() {
+                            last_code = Some("osascript_empty_stdout".to_string());
+                            last_message =
+                                Some("osascript succeeded but returned empty output".to_string());
+                        } else {
+                            let code = output.status.code().unwrap_or(1);
+                            last_code = Some(format!("osascript_exit_{code}"));
+                            last_message = Some(if stderr.is_empty() {
+                                format!("osascript failed with status {code}")
+                            } else {
+                                stderr
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        last_code = Some("osascript_wait_output_failed".to_string());
+                        last_message = Some(err.to_string());
+                    }
+                },
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    last_code = Some("osascript_timeout".to_string());
+                    last_message = Some(format!(
+                        "osascript timed out after {}ms (attempt {attempt}/{max_attempts})",
+                        timeout_ms
+                    ));
+                }
+                Err(err) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    last_code = Some("osascript_wait_timeout_failed".to_string());
+                    last_message = Some(err.to_string());
+                }
+            },
+            Err(err) => {
+                last_code = Some("osascript_spawn_failed".to_string());
+                last_message = Some(err.to_string());
+            }
+        }
+
+        if attempt < max_attempts {
+            let backoff = delay_ms.saturating_mul(u64::from(attempt));
+            thread::sleep(Duration::from_millis(backoff.max(10)));
+        }
     }
 
-    Ok(payload)
+    (
+        None,
+        QueryDiagnostic {
+            ok: false,
+            attempts: max_attempts,
+            error_code: last_code,
+            message: last_message,
+        },
+    )
 }
 
-fn load_spec(path: &str) -> Result<Value> {
-    let raw = if path == "-" {
-        let mut buf = String::new();
-        io::stdin()
-            .read_to_string(&mut buf)
-            .context("failed to read spec from stdin")?;
-        buf
-    } else {
-        fs::read_to_string(path).with_context(|| format!("failed to read spec file: {path}"))?
-    };
+// Edge rows/columns of a window capture with no fully opaque pixel are drop shadow (window
+// content is opaque; rounded corners still leave opaque pixels mid-edge). Returns the
+// (left, top, right, bottom) widths to trim, or None when there is nothing to trim or nothing
+// opaque at all.
+fn shadow_border(img: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    let (w, h) = img.dimensions();
+    let opaque_row = |y: u32| (0..w).any(|x| img.get_pixel(x, y)[3] == 255);
+    let top = (0..h).find(|&y| opaque_row(y))?;
+    let bottom = h - 1 - (0..h).rev().find(|&y| opaque_row(y))?;
+    let opaque_col = |x: u32| (top..h - bottom).any(|y| img.get_pixel(x, y)[3] == 255);
+    let left = (0..w).find(|&x| opaque_col(x))?;
+    let right = w - 1 - (0..w).rev().find(|&x| opaque_col(x))?;
+    (left + top + right + bottom > 0).then_some((left, top, right, bottom))
+}
 
-    let value: Value = serde_json::from_str(&raw).context("invalid spec JSON")?;
-    match value {
-        Value::Array(arr) => Ok(json!({"annotations": arr, "defaults": {}})),
-        Value::Object(mut obj) => {
-            if obj.get("annotations").is_none() {
-                bail!("spec must be a list or an object with 'annotations'");
-            }
-            if obj.get("defaults").is_none() {
-                obj.insert("defaults".to_string(), Value::Object(Map::new()));
-            }
-            Ok(Value::Object(obj))
-        }
-        _ => bail!("spec must be a list or an object with 'annotations'"),
+// A shadow trim crops the window capture, so move the pt bounds in by the same margins (at the
+// capture's px-per-pt scale); redaction and scale then line up with the trimmed pixels.
+fn trim_window_bounds(
+    bounds: (i64, i64, i64, i64),
+    image_size: (u32, u32),
+    trim: (u32, u32, u32, u32),
+) -> (i64, i64, i64, i64) {
+    let (x, y, w, h) = bounds;
+    let (image_w, image_h) = image_size;
+    if w <= 0 || h <= 0 || image_w == 0 || image_h == 0 {
+        return bounds;
     }
+    let (left, top, right, bottom) = trim;
+    let to_pt_x = |px: u32| (f64::from(px) * w as f64 / f64::from(image_w)).round() as i64;
+    let to_pt_y = |px: u32| (f64::from(px) * h as f64 / f64::from(image_h)).round() as i64;
+    (
+        x + to_pt_x(left),
+        y + to_pt_y(top),
+        w - to_pt_x(left) - to_pt_x(right),
+        h - to_pt_y(top) - to_pt_y(bottom),
+    )
 }
 
-fn resolve_annotation_units(
-    ann: &mut Map<String, Value>,
-    img_w: u32,
-    img_h: u32,
-    defaults: &Map<String, Value>,
-) {
-    let units_value = ann.get("units").or_else(|| defaults.get("units")).cloned();
-    let default_rel = units_is_rel(units_value.as_ref());
+// The CoreGraphics window number of the probed window, for `screencapture -l`.
+fn cg_window_id(windows: &[CgWindow], bounds: (i64, i64, i64, i64)) -> Option<i64> {
+    windows
+        .iter()
+        .find(|win| win.onscreen && (win.x, win.y, win.w, win.h) == bounds)
+        .map(|win| win.number)
+}
 
-    let fields = [
-        ("x", img_w as f64),
-        ("x1", img_w as f64),
-        ("x2", img_w as f64),
-        ("w", img_w as f64),
-        ("y", img_h as f64),
-        ("y1", img_h as f64),
-        ("y2", img_h as f64),
-        ("h", img_h as f64),
-    ];
+const SCREENCAPTURE_RETRY_BASE_MS: u64 = 250;
+const SCREENCAPTURE_RETRY_MAX_MS: u64 = 4000;
 
-    for (key, span) in fields {
-        if let Some(value) = ann.get(key).cloned() {
-            if let Some(resolved) = resolve_measure(&value, span, default_rel) {
-                ann.insert(key.to_string(), json!(resolved));
-            }
-        }
-    }
+// Runs screencapture with --capture-retry; `diag` accumulates attempts across the window and
+// full-screen invocations so the sidecar shows the total and the last failure.
+fn screencapture_with_retry(
+    args: &[String],
+    out_path: &Path,
+    options: &CaptureOptions,
+    diag: &mut Option<QueryDiagnostic>,
+) -> bool {
+    let result = run_capture_command_with_retry(
+        "screencapture",
+        args,
+        out_path,
+        options.capture_retry,
+        SCREENCAPTURE_RETRY_BASE_MS,
+    );
+    let prior = diag.as_ref().map(|d| d.attempts).unwrap_or(0);
+    let ok = result.ok;
+    *diag = Some(QueryDiagnostic {
+        attempts: prior + result.attempts,
+        ..result
+    });
+    ok
+}
 
-    for key in ["anchor_offset", "from_offset", "to_offset"] {
-        if let Some(offset) = ann.get(key).cloned() {
-            if let Some(resolved) = resolve_offset_units(&offset, img_w, img_h, default_rel) {
-                ann.insert(key.to_string(), resolved);
+// Transient failures (display sleep, fast user switch) exit non-zero or leave no file behind.
+fn run_capture_command_with_retry(
+    program: &str,
+    args: &[String],
+    out_path: &Path,
+    retries: u32,
+    base_delay_ms: u64,
+) -> QueryDiagnostic {
+    let max_attempts = retries.saturating_add(1);
+    let mut last_code = None;
+    let mut last_message = None;
+    for attempt in 1..=max_attempts {
+        match Command::new(program).args(args).arg(out_path).status() {
+            Ok(status) if status.success() && out_path.is_file() => {
+                return QueryDiagnostic {
+                    ok: true,
+                    attempts: attempt,
+                    error_code: None,
+                    message: None,
+                };
             }
-        }
-    }
-
-    for anchor_key in ["anchor", "from", "to"] {
-        let Some(Value::Object(anchor_obj)) = ann.get(anchor_key).cloned() else {
-            continue;
-        };
-        if !anchor_obj.contains_key("offset") {
-            continue;
-        }
-
-        let mut updated = anchor_obj.clone();
-        let anchor_units = updated
-            .get("units")
-            .or_else(|| ann.get("units"))
-            .or_else(|| defaults.get("units"))
-            .cloned();
-        let anchor_rel = units_is_rel(anchor_units.as_ref());
-        if let Some(offset) = updated.get("offset").cloned() {
-            if let Some(resolved) = resolve_offset_units(&offset, img_w, img_h, anchor_rel) {
-                updated.insert("offset".to_string(), resolved);
+            Ok(status) if status.success() => {
+                last_code = Some(format!("{program}_no_output"));
+                last_message = Some(format!("{program} succeeded but wrote no image"));
             }
-        }
-        ann.insert(anchor_key.to_string(), Value::Object(updated));
-    }
-
-    if let Some(Value::Object(fit)) = ann.get("fit").cloned() {
-        let mut updated = fit.clone();
-        let fit_units = updated
-            .get("units")
-            .or_else(|| ann.get("units"))
-            .or_else(|| defaults.get("units"))
-            .cloned();
-        let fit_rel = units_is_rel(fit_units.as_ref());
-
-        if let Some(region) = updated.get("region").cloned() {
-            if let Some(resolved) = resolve_region_units(&region, img_w, img_h, fit_rel) {
-                updated.insert("region".to_string(), resolved);
+            Ok(status) => {
+                let code = status.code().unwrap_or(1);
+                last_code = Some(format!("{program}_exit_{code}"));
+                last_message = Some(format!("{program} failed with status {code}"));
             }
-        }
-        if let Some(pad) = updated.get("pad").cloned() {
-            if let Some(resolved) =
-                resolve_measure(&pad, f64::from(img_w.max(img_h).max(1)), fit_rel)
-            {
-                updated.insert("pad".to_string(), json!(resolved));
+            Err(err) => {
+                last_code = Some(format!("{program}_spawn_failed"));
+                last_message = Some(err.to_string());
             }
         }
-        ann.insert("fit".to_string(), Value::Object(updated));
-    }
-}
-
-fn resolve_offset_units(value: &Value, img_w: u32, img_h: u32, default_rel: bool) -> Option<Value> {
-    match value {
-        Value::Array(values) if values.len() >= 2 => {
-            let dx = resolve_measure(&values[0], f64::from(img_w), default_rel)?;
-            let dy = resolve_measure(&values[1], f64::from(img_h), default_rel)?;
-            Some(json!([dx, dy]))
-        }
-        Value::String(raw) => {
-            let parts: Vec<&str> = raw.split(',').map(str::trim).collect();
-            if parts.len() < 2 {
-                return None;
-            }
-            let dx = resolve_measure(
-                &Value::String(parts[0].to_string()),
-                f64::from(img_w),
-                default_rel,
-            )?;
-            let dy = resolve_measure(
-                &Value::String(parts[1].to_string()),
-                f64::from(img_h),
-                default_rel,
-            )?;
-            Some(json!([dx, dy]))
+        if attempt < max_attempts {
+            thread::sleep(Duration::from_millis(capture_retry_delay_ms(
+                base_delay_ms,
+                attempt,
+            )));
         }
-        _ => None,
+    }
+    QueryDiagnostic {
+        ok: false,
+        attempts: max_attempts,
+        error_code: last_code,
+        message: last_message,
     }
 }
 
-fn resolve_region_units(value: &Value, img_w: u32, img_h: u32, default_rel: bool) -> Option<Value> {
-    match value {
-        Value::Object(obj) => {
-            let x = resolve_measure(obj.get("x")?, f64::from(img_w), default_rel)?;
-            let y = resolve_measure(obj.get("y")?, f64::from(img_h), default_rel)?;
-            let w = resolve_measure(obj.get("w")?, f64::from(img_w), default_rel)?;
-            let h = resolve_measure(obj.get("h")?, f64::from(img_h), default_rel)?;
-            Some(json!({"x": x, "y": y, "w": w, "h": h}))
-        }
-        Value::Array(values) if values.len() >= 4 => {
-            let x = resolve_measure(&values[0], f64::from(img_w), default_rel)?;
-            let y = resolve_measure(&values[1], f64::from(img_h), default_rel)?;
-            let w = resolve_measure(&values[2], f64::from(img_w), default_rel)?;
-            let h = resolve_measure(&values[3], f64::from(img_h), default_rel)?;
-            Some(json!([x, y, w, h]))
-        }
-        _ => None,
-    }
+// Exponential backoff: base, 2x base, 4x base, ... capped at SCREENCAPTURE_RETRY_MAX_MS.
+fn capture_retry_delay_ms(base_ms: u64, attempt: u32) -> u64 {
+    base_ms
+        .saturating_mul(1u64 << attempt.saturating_sub(1).min(16))
+        .min(SCREENCAPTURE_RETRY_MAX_MS)
 }
 
-fn units_is_rel(value: Option<&Value>) -> bool {
-    match value {
-        Some(Value::Bool(v)) => *v,
-        Some(Value::String(s)) => {
-            let key = s.trim().to_ascii_lowercase();
-            matches!(
-                key.as_str(),
-                "rel" | "relative" | "ratio" | "fraction" | "normalized"
-            )
-        }
-        _ => false,
+fn run_osascript_exec_with_retry(
+    script: &str,
+    args: &[String],
+    attempts: u32,
+    delay_ms: u64,
+) -> QueryDiagnostic {
+    if !cfg!(target_os = "macos") {
+        return QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some("unsupported_platform".to_string()),
+            message: Some("osascript requires macOS".to_string()),
+        };
     }
-}
 
-fn resolve_measure(value: &Value, span: f64, default_rel: bool) -> Option<f64> {
-    match value {
-        Value::Number(n) => {
-            let v = n.as_f64()?;
-            if default_rel {
-                Some(v * span)
-            } else {
-                Some(v)
-            }
-        }
-        Value::String(s) => {
-            let raw = s.trim().to_ascii_lowercase();
-            if raw.is_empty() {
-                return None;
-            }
+    let max_attempts = attempts.max(1);
+    let timeout_ms = 1500u64;
+    let mut last_code = Some("osascript_exec_failed".to_string());
+    let mut last_message = Some("osascript execution failed".to_string());
 
-            if let Some(percent) = raw.strip_suffix('%') {
-                return percent.parse::<f64>().ok().map(|v| v * span / 100.0);
+    for attempt in 1..=max_attempts {
+        let mut cmd = Command::new("osascript");
+        cmd.arg("-e").arg(script);
+        if !args.is_empty() {
+            cmd.arg("--");
+            for arg in args {
+                cmd.arg(arg);
             }
+        }
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
 
-            if let Some(rel) = raw.strip_suffix("rel") {
-                if let Ok(mut ratio) = rel.parse::<f64>() {
-                    if ratio.abs() > 1.0 {
-                        ratio /= 100.0;
+        match cmd.spawn() {
+            Ok(mut child) => match child.wait_timeout(Duration::from_millis(timeout_ms)) {
+                Ok(Some(_)) => match child.wait_with_output() {
+                    Ok(output) => {
+                        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                        if output.status.success() {
+                            return QueryDiagnostic {
+                                ok: true,
+                                attempts: attempt,
+                                error_code: None,
+                                message: Some("executed".to_string()),
+                            };
+                        }
+                        let code = output.status.code().unwrap_or(1);
+                        last_code = Some(format!("osascript_exit_{code}"));
+                        last_message = Some(if stderr.is_empty() {
+                            format!("osascript failed with status {code}")
+                        } else {
+                            stderr
+                        });
                     }
-                    return Some(ratio * span);
+                    Err(err) => {
+                        last_code = Some("osascript_wait_output_failed".to_string());
+                        last_message = Some(err.to_string());
+                    }
+                },
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    last_code = Some("osascript_timeout".to_string());
+                    last_message = Some(format!(
+                        "osascript timed out after {}ms (attempt {attempt}/{max_attempts})",
+                        timeout_ms
+                    ));
                 }
-            }
-
-            if let Some(px) = raw.strip_suffix("px") {
-                return px.parse::<f64>().ok();
-            }
-
-            if let Ok(v) = raw.parse::<f64>() {
-                if default_rel {
-                    return Some(v * span);
+                Err(err) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    last_code = Some("osascript_wait_timeout_failed".to_string());
+                    last_message = Some(err.to_string());
                 }
-                return Some(v);
+            },
+            Err(err) => {
+                last_code = Some("osascript_spawn_failed".to_string());
+                last_message = Some(err.to_string());
             }
+        }
 
-            None
+        if attempt < max_attempts {
+            let backoff = delay_ms.saturating_mul(u64::from(attempt));
+            thread::sleep(Duration::from_millis(backoff.max(10)));
         }
-        _ => None,
     }
-}
-
-fn annotation_meta_item(index: usize, ann: &Map<String, Value>, img_w: u32, img_h: u32) -> Value {
-    let ann_type = ann
-        .get("type")
-        .and_then(Value::as_str)
-        .unwrap_or_default()
-        .to_ascii_lowercase();
 
-    let mut item = Map::new();
-    item.insert("index".to_string(), json!(index));
-    item.insert("type".to_string(), json!(ann_type));
+    QueryDiagnostic {
+        ok: false,
+        attempts: max_attempts,
+        error_code: last_code,
+        message: last_message,
+    }
+}
 
-    for key in [
-        "id",
-        "units",
-        "intent",
-        "action",
-        "severity",
-        "issue",
-        "hypothesis",
-        "next_action",
-        "verify",
-    ] {
-        item.insert(
-            key.to_string(),
-            ann.get(key).cloned().unwrap_or(Value::Null),
-        );
+fn parse_coord_pair(raw: &str) -> Option<(i64, i64)> {
+    let mut parts = raw.split(',');
+    let x = parts.next()?.trim().parse::<f64>().ok()?.round() as i64;
+    let y = parts.next()?.trim().parse::<f64>().ok()?.round() as i64;
+    if parts.next().is_some() {
+        return None;
     }
+    Some((x, y))
+}
 
-    let geometry = extract_geometry(ann, &ann_type);
-    if !geometry.is_empty() {
-        let geometry_val = Value::Object(geometry.clone());
-        item.insert("geometry".to_string(), geometry_val);
-        let rel = geometry_rel(&geometry, &ann_type, img_w, img_h);
-        if !rel.is_empty() {
-            item.insert("geometry_rel".to_string(), Value::Object(rel));
+// Placeholder geometry/fill from CVLP_PLACEHOLDER_SIZE (WxH) and CVLP_PLACEHOLDER_COLOR;
+// unset or invalid values keep the 1280x720 white default (invalid ones are reported).
+fn placeholder_settings(
+    size: Option<&str>,
+    color: Option<&str>,
+) -> ((u32, u32), Rgba<u8>, Vec<String>) {
+    let mut invalid = Vec::new();
+    let mut dims = (1280, 720);
+    if let Some(raw) = size.map(str::trim).filter(|raw| !raw.is_empty()) {
+        let parsed = raw
+            .split_once(['x', 'X'])
+            .and_then(|(w, h)| Some((w.trim().parse::<u32>().ok()?, h.trim().parse::<u32>().ok()?)))
+            .filter(|&(w, h)| {
+                w > 0 && h > 0 && u64::from(w) * u64::from(h) <= DEFAULT_MAX_TOTAL_PIXELS
+            });
+        match parsed {
+            Some(parsed) => dims = parsed,
+            None => invalid.push(format!(
+                "ignoring CVLP_PLACEHOLDER_SIZE={raw:?}; expected WxH (e.g. 1440x900)"
+            )),
         }
     }
-
-    if let Some(text) = ann.get("text").and_then(Value::as_str) {
-        if !text.is_empty() {
-            item.insert("text".to_string(), json!(text));
+    let mut fill = Rgba([255, 255, 255, 255]);
+    if let Some(raw) = color.map(str::trim).filter(|raw| !raw.is_empty()) {
+        match parse_color_opt(Some(&Value::String(raw.to_string()))) {
+            Some(parsed) => fill = parsed,
+            None => invalid.push(format!(
+                "ignoring CVLP_PLACEHOLDER_COLOR={raw:?}; expected a color such as #RRGGBB"
+            )),
         }
     }
+    (dims, fill, invalid)
+}
 
-    Value::Object(item)
+fn parse_capture_region(raw: &str) -> Option<(i64, i64, i64, i64)> {
+    let values = raw
+        .split(',')
+        .map(|part| part.trim().parse::<f64>().ok().map(|v| v.round() as i64))
+        .collect::<Option<Vec<i64>>>()?;
+    match values[..] {
+        [x, y, w, h] if w > 0 && h > 0 => Some((x, y, w, h)),
+        _ => None,
+    }
 }
 
-fn extract_geometry(ann: &Map<String, Value>, ann_type: &str) -> Map<String, Value> {
-    let keys: &[&str] = match ann_type {
-        "rect" | "spotlight" | "focus" | "dim" => &["x", "y", "w", "h"],
-        "arrow" => &["x1", "y1", "x2", "y2"],
-        "text" => &["x", "y"],
-        _ => &[],
-    };
+fn escape_osascript_string(input: &str) -> String {
+    input.replace('\\', "\\\\").replace('"', "\\\"")
+}
 
-    let mut geometry = Map::new();
-    for key in keys {
-        if let Some(value) = ann.get(*key) {
-            if let Some(n) = normalize_number(value) {
-                geometry.insert((*key).to_string(), n);
-            }
-        }
+fn parse_hotkey_combo(raw: &str) -> Result<HotkeySpec> {
+    let combo = raw.trim().to_string();
+    if combo.is_empty() {
+        bail!("hotkey combo is empty");
+    }
+    let tokens: Vec<String> = combo
+        .split('+')
+        .map(|item| item.trim().to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect();
+    if tokens.is_empty() {
+        bail!("hotkey combo is empty");
     }
-    geometry
-}
 
-fn geometry_rel(
-    geometry: &Map<String, Value>,
-    ann_type: &str,
-    img_w: u32,
-    img_h: u32,
-) -> Map<String, Value> {
-    let mut rel = Map::new();
+    let mut modifiers = Vec::<String>::new();
+    let mut key_token: Option<String> = None;
 
-    for (key, value) in geometry {
-        let num = match value.as_f64() {
-            Some(v) => v,
-            None => continue,
+    for token in tokens {
+        let mapped = match token.as_str() {
+            "cmd" | "command" => Some("command down"),
+            "ctrl" | "control" => Some("control down"),
+            "alt" | "option" | "opt" => Some("option down"),
+            "shift" => Some("shift down"),
+            "fn" | "function" => Some("fn down"),
+            _ => None,
         };
 
-        if matches!(key.as_str(), "x" | "x1" | "x2" | "w") && img_w > 0 {
-            rel.insert(key.clone(), json!(round_to(num / img_w as f64, 6)));
-        } else if matches!(key.as_str(), "y" | "y1" | "y2" | "h") && img_h > 0 {
-            rel.insert(key.clone(), json!(round_to(num / img_h as f64, 6)));
+        if let Some(modifier) = mapped {
+            if !modifiers.iter().any(|m| m == modifier) {
+                modifiers.push(modifier.to_string());
+            }
+        } else {
+            key_token = Some(token);
         }
     }
 
-    if matches!(ann_type, "rect" | "spotlight" | "focus" | "dim") {
-        let x = geometry.get("x").and_then(Value::as_f64);
-        let y = geometry.get("y").and_then(Value::as_f64);
-        let w = geometry.get("w").and_then(Value::as_f64);
-        let h = geometry.get("h").and_then(Value::as_f64);
+    let key = key_token.ok_or_else(|| anyhow::anyhow!("hotkey requires a key token"))?;
+    let key_code = match key.as_str() {
+        "enter" | "return" => Some(36),
+        "tab" => Some(48),
+        "space" => Some(49),
+        "esc" | "escape" => Some(53),
+        "delete" | "backspace" => Some(51),
+        "left" => Some(123),
+        "right" => Some(124),
+        "down" => Some(125),
+        "up" => Some(126),
+        _ => None,
+    };
 
-        if let (Some(x), Some(y), Some(w), Some(h)) = (x, y, w, h) {
-            rel.insert(
-                "bbox".to_string(),
-                json!({
-                    "x": if img_w > 0 { round_to(x / img_w as f64, 6) } else { 0.0 },
-                    "y": if img_h > 0 { round_to(y / img_h as f64, 6) } else { 0.0 },
-                    "w": if img_w > 0 { round_to(w / img_w as f64, 6) } else { 0.0 },
-                    "h": if img_h > 0 { round_to(h / img_h as f64, 6) } else { 0.0 },
-                }),
-            );
+    Ok(HotkeySpec {
+        combo,
+        key,
+        modifiers,
+        key_code,
+    })
+}
+
+fn hotkey_script(spec: &HotkeySpec) -> String {
+    let modifiers = if spec.modifiers.is_empty() {
+        None
+    } else {
+        Some(format!("{{{}}}", spec.modifiers.join(", ")))
+    };
+
+    if let Some(code) = spec.key_code {
+        if let Some(using) = modifiers {
+            format!("tell application \"System Events\" to key code {code} using {using}")
+        } else {
+            format!("tell application \"System Events\" to key code {code}")
+        }
+    } else {
+        let key_text = if spec.key == "space" {
+            " ".to_string()
+        } else {
+            escape_osascript_string(&spec.key)
+        };
+        if let Some(using) = modifiers {
+            format!("tell application \"System Events\" to keystroke \"{key_text}\" using {using}")
+        } else {
+            format!("tell application \"System Events\" to keystroke \"{key_text}\"")
         }
     }
-
-    rel
 }
 
-fn normalize_number(value: &Value) -> Option<Value> {
-    if value.is_boolean() {
-        return Some(value.clone());
-    }
-    if let Some(i) = value.as_i64() {
-        return Some(json!(i));
-    }
-    if let Some(f) = value.as_f64() {
-        let rounded = round_to(f, 4);
-        if (rounded - rounded.round()).abs() < 1e-6 {
-            return Some(json!(rounded.round() as i64));
+fn parse_window_candidates(raw: &str) -> Vec<WindowCandidate> {
+    let mut items = Vec::new();
+    for line in raw.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = trimmed.split('\t').collect();
+        if parts.len() < 5 {
+            continue;
+        }
+        let index = match parts[0].trim().parse::<usize>() {
+            Ok(v) if v > 0 => v,
+            _ => continue,
+        };
+        let x = match parts[1].trim().parse::<f64>() {
+            Ok(v) => v.round() as i64,
+            _ => continue,
+        };
+        let y = match parts[2].trim().parse::<f64>() {
+            Ok(v) => v.round() as i64,
+            _ => continue,
+        };
+        let w = match parts[3].trim().parse::<f64>() {
+            Ok(v) => v.round() as i64,
+            _ => continue,
+        };
+        let h = match parts[4].trim().parse::<f64>() {
+            Ok(v) => v.round() as i64,
+            _ => continue,
+        };
+        if w <= 0 || h <= 0 {
+            continue;
         }
-        return Some(json!(rounded));
+        let title = parts
+            .get(5)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(ToString::to_string);
+        items.push(WindowCandidate {
+            index,
+            x,
+            y,
+            w,
+            h,
+            title,
+        });
     }
-    None
-}
-
-fn annotation_type(ann: &Map<String, Value>) -> String {
-    ann.get("type")
-        .and_then(Value::as_str)
-        .unwrap_or_default()
-        .trim()
-        .to_ascii_lowercase()
+    items
 }
 
-fn is_spotlight_type(kind: &str) -> bool {
-    matches!(kind, "spotlight" | "focus" | "dim")
-}
+fn select_window_candidate(
+    candidates: &[WindowCandidate],
+    min_width: i64,
+    min_height: i64,
+    min_area: i64,
+) -> (&WindowCandidate, &'static str, usize) {
+    const MIN_REASONABLE_XY: i64 = -5_000;
+    const MAX_REASONABLE_XY: i64 = 50_000;
 
-fn resolve_scale(defaults: &Map<String, Value>, img_w: u32, img_h: u32) -> f64 {
-    if let Some(scale) = defaults.get("scale").and_then(Value::as_f64) {
-        return scale.max(0.1);
+    fn area(candidate: &WindowCandidate) -> i64 {
+        candidate.w.saturating_mul(candidate.h)
     }
 
-    let mut auto_scale = true;
-    if let Some(value) = defaults.get("auto_scale") {
-        auto_scale = value_to_bool(value, true);
-    }
-    if !auto_scale {
-        return 1.0;
+    fn cmp_window(a: &WindowCandidate, b: &WindowCandidate) -> std::cmp::Ordering {
+        area(b)
+            .cmp(&area(a))
+            .then_with(|| b.w.cmp(&a.w))
+            .then_with(|| b.h.cmp(&a.h))
+            .then_with(|| a.index.cmp(&b.index))
     }
-    let max_dim = f64::from(img_w.max(img_h).max(1));
-    let raw = max_dim / 1200.0;
-    raw.clamp(1.0, 2.0)
-}
 
-fn value_to_bool(value: &Value, default_value: bool) -> bool {
-    match value {
-        Value::Bool(v) => *v,
-        Value::Number(n) => n.as_i64().map(|v| v != 0).unwrap_or(default_value),
-        Value::String(s) => {
-            let key = s.trim().to_ascii_lowercase();
-            if matches!(key.as_str(), "1" | "true" | "yes" | "on") {
-                true
-            } else if matches!(key.as_str(), "0" | "false" | "no" | "off") {
-                false
-            } else {
-                default_value
-            }
-        }
-        _ => default_value,
+    let is_usable = |candidate: &WindowCandidate| {
+        candidate.w >= min_width
+            && candidate.h >= min_height
+            && area(candidate) >= min_area
+            && candidate.x >= MIN_REASONABLE_XY
+            && candidate.y >= MIN_REASONABLE_XY
+            && candidate.x <= MAX_REASONABLE_XY
+            && candidate.y <= MAX_REASONABLE_XY
+    };
+
+    let mut usable: Vec<&WindowCandidate> = candidates.iter().filter(|c| is_usable(c)).collect();
+    usable.sort_by(|a, b| cmp_window(a, b));
+    let usable_count = usable.len();
+    if let Some(candidate) = usable.first().copied() {
+        return (candidate, "largest_usable", usable_count);
     }
-}
 
-fn value_to_f64(value: Option<&Value>) -> Option<f64> {
-    match value {
-        Some(Value::Number(n)) => n.as_f64(),
-        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
-        _ => None,
+    let mut all: Vec<&WindowCandidate> = candidates.iter().collect();
+    all.sort_by(|a, b| cmp_window(a, b));
+    if let Some(candidate) = all.first().copied() {
+        return (candidate, "largest_any", usable_count);
     }
+
+    // parse_window_candidates guarantees non-empty when this is called.
+    (&candidates[0], "window_1", usable_count)
 }
 
-fn value_to_i32(value: Option<&Value>, fallback: i32) -> i32 {
-    value_to_f64(value)
-        .map(|v| v.round() as i32)
-        .unwrap_or(fallback)
+// Session-wide default target from CVLP_PROCESS; explicit --process still wins.
+fn env_process_name() -> Option<String> {
+    env::var("CVLP_PROCESS")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
 }
 
-fn value_to_usize(value: Option<&Value>) -> Option<usize> {
-    match value {
-        Some(Value::Number(n)) => n.as_u64().map(|v| v as usize),
-        Some(Value::String(s)) => s.trim().parse::<usize>().ok(),
-        _ => None,
+fn frontmost_app_name() -> Option<String> {
+    if !cfg!(target_os = "macos") {
+        return None;
     }
+
+    run_osascript_with_retry(
+        "tell application \"System Events\" to get name of first process whose frontmost is true",
+        &[],
+        1,
+        20,
+    )
+    .0
 }
 
-fn value_to_string(value: Option<&Value>) -> Option<String> {
-    match value {
-        Some(Value::String(s)) => {
-            let v = s.trim();
-            if v.is_empty() {
-                None
-            } else {
-                Some(v.to_string())
-            }
-        }
-        Some(v) if !v.is_null() => Some(v.to_string()),
-        _ => None,
+fn query_ax_tree(process: &str, depth: u32, scripts: &ScriptOverrides) -> AxQueryResult {
+    if !cfg!(target_os = "macos") {
+        return AxQueryResult {
+            elements: Vec::new(),
+            tree: Vec::new(),
+            diagnostics: QueryDiagnostic {
+                ok: false,
+                attempts: 0,
+                error_code: Some("unsupported_platform".to_string()),
+                message: Some("AX tree extraction requires macOS".to_string()),
+            },
+            warnings: {
+                let mut warnings = Warnings::default();
+                warnings.info(
+                    "unsupported_platform",
+                    "ax-tree is only available on macOS; emitted empty payload",
+                );
+                warnings
+            },
+        };
     }
-}
 
-fn parse_offset_value(value: Option<&Value>) -> Option<(f64, f64)> {
-    let value = value?;
-    match value {
-        Value::Array(items) if items.len() >= 2 => {
-            Some((value_to_f64(items.first())?, value_to_f64(items.get(1))?))
-        }
-        Value::String(raw) => {
-            let parts: Vec<&str> = raw.split(',').map(str::trim).collect();
-            if parts.len() < 2 {
-                return None;
-            }
-            let x = parts[0].parse::<f64>().ok()?;
-            let y = parts[1].parse::<f64>().ok()?;
-            Some((x, y))
+    let custom_script = match read_script_override(scripts.ax.as_deref(), "ax_script") {
+        Ok(custom) => custom,
+        Err(diagnostics) => {
+            let mut warnings = Warnings::default();
+            warnings.push_diagnostic(
+                "ax_query_failed",
+                "ax_tree_query",
+                "AX tree unavailable",
+                &diagnostics,
+            );
+            return AxQueryResult {
+                elements: Vec::new(),
+                tree: Vec::new(),
+                diagnostics,
+                warnings,
+            };
         }
-        _ => None,
-    }
-}
+    };
 
-fn parse_color(value: Option<&Value>, fallback: [u8; 4]) -> Rgba<u8> {
-    parse_color_opt(value).unwrap_or(Rgba(fallback))
-}
+    let script = r#"
+on sanitize(v)
+  try
+    set t to v as text
+  on error
+    set t to ""
+  end try
+  set AppleScript's text item delimiters to {return, linefeed, tab}
+  set parts to text items of t
+  set AppleScript's text item delimiters to " "
+  set clean to parts as text
+  set AppleScript's text item delimiters to ""
+  return clean
+end sanitize
 
-fn parse_color_opt(value: Option<&Value>) -> Option<Rgba<u8>> {
-    let raw = value_to_string(value)?;
-    let s = raw.trim();
-    if let Some(hex) = s.strip_prefix('#') {
-        if hex.len() == 6 {
-            let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
-            let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
-            let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
-            return Some(Rgba([r, g, b, 255]));
-        }
-        if hex.len() == 8 {
-            let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
-            let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
-            let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
-            let a = u8::from_str_radix(&hex[6..8], 16).ok()?;
-            return Some(Rgba([r, g, b, a]));
-        }
-    }
+on emitLine(depthVal, clsVal, nameVal, roleVal, enabledVal, xVal, yVal, wVal, hVal)
+  return (depthVal as text) & tab & my sanitize(clsVal) & tab & my sanitize(nameVal) & tab & my sanitize(roleVal) & tab & my sanitize(enabledVal) & tab & (xVal as text) & tab & (yVal as text) & tab & (wVal as text) & tab & (hVal as text)
+end emitLine
 
-    let lower = s.to_ascii_lowercase();
-    if lower.starts_with("rgba(") && lower.ends_with(')') {
-        let body = &lower[5..lower.len() - 1];
-        let parts: Vec<&str> = body.split(',').map(str::trim).collect();
-        if parts.len() != 4 {
-            return None;
-        }
-        let r = parts[0].parse::<f64>().ok()?.round().clamp(0.0, 255.0) as u8;
-        let g = parts[1].parse::<f64>().ok()?.round().clamp(0.0, 255.0) as u8;
-        let b = parts[2].parse::<f64>().ok()?.round().clamp(0.0, 255.0) as u8;
-        let alpha_value = parts[3].parse::<f64>().ok()?;
-        let a = if alpha_value <= 1.0 {
-            (alpha_value * 255.0).round().clamp(0.0, 255.0) as u8
-        } else {
-            alpha_value.round().clamp(0.0, 255.0) as u8
-        };
-        return Some(Rgba([r, g, b, a]));
-    }
+on walkNode(nodeRef, depthVal, maxDepth)
+  set linesOut to {}
+  try
+    set clsVal to class of nodeRef as text
+  on error
+    set clsVal to "unknown"
+  end try
+  try
+    set nameVal to name of nodeRef
+  on error
+    set nameVal to ""
+  end try
+  try
+    set roleVal to role description of nodeRef
+  on error
+    set roleVal to ""
+  end try
+  try
+    set enabledVal to enabled of nodeRef
+  on error
+    set enabledVal to ""
+  end try
 
-    None
-}
+  set xVal to ""
+  set yVal to ""
+  set wVal to ""
+  set hVal to ""
+  try
+    set posVal to position of nodeRef
+    set xVal to item 1 of posVal
+    set yVal to item 2 of posVal
+  end try
+  try
+    set sizeVal to size of nodeRef
+    set wVal to item 1 of sizeVal
+    set hVal to item 2 of sizeVal
+  end try
 
-fn color_luma(color: Rgba<u8>) -> f64 {
-    let [r, g, b, _] = color.0;
-    (0.2126 * f64::from(r) + 0.7152 * f64::from(g) + 0.0722 * f64::from(b)) / 255.0
-}
+  set end of linesOut to my emitLine(depthVal, clsVal, nameVal, roleVal, enabledVal, xVal, yVal, wVal, hVal)
+  if depthVal < maxDepth then
+    try
+      set childrenRefs to UI elements of nodeRef
+      repeat with childRef in childrenRefs
+        set childLines to my walkNode(childRef, depthVal + 1, maxDepth)
+        set linesOut to linesOut & childLines
+      end repeat
+    end try
+  end if
+  return linesOut
+end walkNode
 
-fn auto_outline_color(color: Rgba<u8>) -> Rgba<u8> {
-    if color_luma(color) > 0.6 {
-        Rgba([0, 0, 0, 220])
-    } else {
-        Rgba([255, 255, 255, 220])
-    }
-}
+on run argv
+  set procName to item 1 of argv
+  set depthLimit to item 2 of argv as integer
+  tell application "System Events"
+    tell process procName
+      set targetWindow to window 1
+      set linesOut to my walkNode(targetWindow, 0, depthLimit)
+    end tell
+  end tell
+  set AppleScript's text item delimiters to linefeed
+  set joined to linesOut as text
+  set AppleScript's text item delimiters to ""
+  return joined
+end run
+"#;
 
-fn scale_default(value: f64, scale: f64, min_value: u32) -> u32 {
-    ((value * scale).round() as i64).max(i64::from(min_value)) as u32
-}
+    let script = custom_script.as_deref().unwrap_or(script);
 
-fn clamp_i32(value: i32, min_value: i32, max_value: i32) -> i32 {
-    value.max(min_value).min(max_value)
-}
+    let args = vec![process.to_string(), depth.to_string()];
+    let (raw_lines, mut diagnostics) = run_osascript_with_retry(script, &args, 2, 80);
+    let mut warnings = Warnings::default();
 
-fn blend_pixel(dst: Rgba<u8>, src: Rgba<u8>) -> Rgba<u8> {
-    let a = f64::from(src[3]) / 255.0;
-    if a <= 0.0 {
-        return dst;
+    let Some(lines) = raw_lines else {
+        warnings.push_diagnostic(
+            "ax_query_failed",
+            "ax_tree_query",
+            "AX tree unavailable",
+            &diagnostics,
+        );
+        return AxQueryResult {
+            elements: Vec::new(),
+            tree: Vec::new(),
+            diagnostics,
+            warnings,
+        };
+    };
+    if let Some(problem) = custom_script.as_ref().and(ax_output_problem(&lines)) {
+        diagnostics = QueryDiagnostic {
+            ok: false,
+            attempts: diagnostics.attempts,
+            error_code: Some("ax_script_invalid".to_string()),
+            message: Some(format!("--ax-script output {problem}")),
+        };
+        warnings.push_diagnostic(
+            "ax_query_failed",
+            "ax_tree_query",
+            "AX tree unavailable",
+            &diagnostics,
+        );
+        return AxQueryResult {
+            elements: Vec::new(),
+            tree: Vec::new(),
+            diagnostics,
+            warnings,
+        };
     }
-    let inv = 1.0 - a;
-    let r = (f64::from(dst[0]) * inv + f64::from(src[0]) * a)
-        .round()
-        .clamp(0.0, 255.0) as u8;
-    let g = (f64::from(dst[1]) * inv + f64::from(src[1]) * a)
-        .round()
-        .clamp(0.0, 255.0) as u8;
-    let b = (f64::from(dst[2]) * inv + f64::from(src[2]) * a)
-        .round()
-        .clamp(0.0, 255.0) as u8;
-    let out_a = (f64::from(dst[3]) + f64::from(src[3]) * inv)
-        .round()
-        .clamp(0.0, 255.0) as u8;
-    Rgba([r, g, b, out_a])
-}
 
-fn draw_disc(img: &mut RgbaImage, cx: f64, cy: f64, radius: f64, color: Rgba<u8>) {
-    if radius <= 0.1 {
-        let x = cx.round() as i32;
-        let y = cy.round() as i32;
-        if x >= 0 && y >= 0 && x < img.width() as i32 && y < img.height() as i32 {
-            let dst = *img.get_pixel(x as u32, y as u32);
-            img.put_pixel(x as u32, y as u32, blend_pixel(dst, color));
-        }
-        return;
-    }
-    let min_x = clamp_i32((cx - radius).floor() as i32, 0, img.width() as i32 - 1);
-    let max_x = clamp_i32((cx + radius).ceil() as i32, 0, img.width() as i32 - 1);
-    let min_y = clamp_i32((cy - radius).floor() as i32, 0, img.height() as i32 - 1);
-    let max_y = clamp_i32((cy + radius).ceil() as i32, 0, img.height() as i32 - 1);
-    let r2 = radius * radius;
-    for y in min_y..=max_y {
-        for x in min_x..=max_x {
-            let dx = f64::from(x) - cx;
-            let dy = f64::from(y) - cy;
-            if dx * dx + dy * dy <= r2 {
-                let dst = *img.get_pixel(x as u32, y as u32);
-                img.put_pixel(x as u32, y as u32, blend_pixel(dst, color));
-            }
-        }
-    }
-}
+    let flat_nodes = parse_ax_lines(&lines);
+    let elements: Vec<Value> = flat_nodes.iter().map(ax_element_value).collect();
+    let tree = ax_tree_values(&flat_nodes);
 
-fn draw_thick_line(
-    img: &mut RgbaImage,
-    x1: f64,
-    y1: f64,
-    x2: f64,
-    y2: f64,
-    color: Rgba<u8>,
-    width: f64,
-) {
-    let dx = x2 - x1;
-    let dy = y2 - y1;
-    let distance = (dx * dx + dy * dy).sqrt();
-    let steps = distance.max(1.0).ceil() as i32;
-    let radius = (width.max(1.0) / 2.0).max(0.6);
-    for step in 0..=steps {
-        let t = f64::from(step) / f64::from(steps.max(1));
-        let x = x1 + dx * t;
-        let y = y1 + dy * t;
-        draw_disc(img, x, y, radius, color);
+    if flat_nodes.is_empty() {
+        warnings.warn("ax_empty", "AX tree query returned no elements");
     }
-}
 
-fn triangle_area(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
-    ((a.0 * (b.1 - c.1) + b.0 * (c.1 - a.1) + c.0 * (a.1 - b.1)).abs()) / 2.0
+    AxQueryResult {
+        elements,
+        tree,
+        diagnostics,
+        warnings,
+    }
 }
 
-fn point_in_triangle(p: (f64, f64), a: (f64, f64), b: (f64, f64), c: (f64, f64), eps: f64) -> bool {
-    let total = triangle_area(a, b, c);
-    if total <= eps {
-        return false;
+// Source of a user-supplied replacement script; Err carries a diagnostic ("<code>_unreadable").
+fn read_script_override(
+    path: Option<&Path>,
+    code: &str,
+) -> std::result::Result<Option<String>, QueryDiagnostic> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    match fs::read_to_string(path) {
+        Ok(source) if !source.trim().is_empty() => Ok(Some(source)),
+        Ok(_) => Err(QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some(format!("{code}_unreadable")),
+            message: Some(format!(
+                "--{} {} is empty",
+                code.replace('_', "-"),
+                path.display()
+            )),
+        }),
+        Err(err) => Err(QueryDiagnostic {
+            ok: false,
+            attempts: 0,
+            error_code: Some(format!("{code}_unreadable")),
+            message: Some(format!(
+                "failed to read --{} {}: {err}",
+                code.replace('_', "-"),
+                path.display()
+            )),
+        }),
     }
-    let a1 = triangle_area(p, b, c);
-    let a2 = triangle_area(a, p, c);
-    let a3 = triangle_area(a, b, p);
-    (a1 + a2 + a3 - total).abs() <= eps
 }
 
-fn fill_triangle(
-    img: &mut RgbaImage,
-    a: (f64, f64),
-    b: (f64, f64),
-    c: (f64, f64),
-    color: Rgba<u8>,
-) {
-    let min_x = clamp_i32(
-        a.0.min(b.0).min(c.0).floor() as i32,
-        0,
-        img.width() as i32 - 1,
-    );
-    let max_x = clamp_i32(
-        a.0.max(b.0).max(c.0).ceil() as i32,
-        0,
-        img.width() as i32 - 1,
-    );
-    let min_y = clamp_i32(
-        a.1.min(b.1).min(c.1).floor() as i32,
-        0,
-        img.height() as i32 - 1,
-    );
-    let max_y = clamp_i32(
-        a.1.max(b.1).max(c.1).ceil() as i32,
-        0,
-        img.height() as i32 - 1,
-    );
-    for y in min_y..=max_y {
-        for x in min_x..=max_x {
-            let p = (f64::from(x) + 0.5, f64::from(y) + 0.5);
-            if point_in_triangle(p, a, b, c, 0.8) {
-                let dst = *img.get_pixel(x as u32, y as u32);
-                img.put_pixel(x as u32, y as u32, blend_pixel(dst, color));
-            }
+// Shape check for custom AX output: every row needs a numeric depth and a class column.
+fn ax_output_problem(raw: &str) -> Option<String> {
+    let mut rows = 0;
+    for (line_no, line) in raw.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        rows += 1;
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() < 2 || parts[0].trim().parse::<usize>().is_err() {
+            return Some(format!(
+                "line {} is not depth\\tclass\\tname\\trole\\tenabled\\tx\\ty\\tw\\th: {line:?}",
+                line_no + 1
+            ));
         }
     }
+    (rows == 0).then(|| "is empty".to_string())
 }
 
-#[allow(clippy::too_many_arguments)]
-fn draw_arrow_primitive(
-    img: &mut RgbaImage,
-    x1: f64,
-    y1: f64,
-    x2: f64,
-    y2: f64,
-    color: Rgba<u8>,
-    width: f64,
-    head_len: f64,
-    head_width: f64,
-) {
-    let angle = (y2 - y1).atan2(x2 - x1);
-    let back_x = x2 - head_len * angle.cos();
-    let back_y = y2 - head_len * angle.sin();
-    draw_thick_line(img, x1, y1, back_x, back_y, color, width);
-
-    let left_angle = angle + PI / 2.0;
-    let right_angle = angle - PI / 2.0;
-    let left = (
-        back_x + (head_width / 2.0) * left_angle.cos(),
-        back_y + (head_width / 2.0) * left_angle.sin(),
-    );
-    let right = (
-        back_x + (head_width / 2.0) * right_angle.cos(),
-        back_y + (head_width / 2.0) * right_angle.sin(),
-    );
-    fill_triangle(img, (x2, y2), left, right, color);
-}
-
-fn draw_bitmap_text(img: &mut RgbaImage, x: i32, y: i32, text: &str, color: Rgba<u8>, scale: u32) {
-    let scale_i = scale.max(1) as i32;
-    let mut cursor_x = x;
-    for ch in text.chars() {
-        if ch == '\n' {
-            cursor_x = x;
+fn parse_ax_lines(raw: &str) -> Vec<AxFlatNode> {
+    let mut rows = Vec::new();
+    for (index, line) in raw.lines().enumerate() {
+        if line.trim().is_empty() {
             continue;
         }
-        let glyph = BASIC_FONTS.get(ch).or_else(|| BASIC_FONTS.get('?'));
-        let Some(glyph) = glyph else {
-            cursor_x += 8 * scale_i;
-            continue;
-        };
-        for (row_idx, row) in glyph.iter().enumerate() {
-            let row_bits = *row;
-            for col_idx in 0..8 {
-                if (row_bits >> col_idx) & 1 == 0 {
-                    continue;
-                }
-                let px = cursor_x + col_idx * scale_i;
-                let py = y + row_idx as i32 * scale_i;
-                for sy in 0..scale_i {
-                    for sx in 0..scale_i {
-                        let tx = px + sx;
-                        let ty = py + sy;
-                        if tx >= 0 && ty >= 0 && tx < img.width() as i32 && ty < img.height() as i32
-                        {
-                            let dst = *img.get_pixel(tx as u32, ty as u32);
-                            img.put_pixel(tx as u32, ty as u32, blend_pixel(dst, color));
-                        }
-                    }
-                }
-            }
+        let mut parts: Vec<&str> = line.split('\t').collect();
+        while parts.len() < 9 {
+            parts.push("");
         }
-        cursor_x += 8 * scale_i;
-    }
-}
 
-fn text_bbox(x: i32, y: i32, text: &str, scale: u32) -> (i32, i32, i32, i32) {
-    let scale_i = scale.max(1) as i32;
-    let lines: Vec<&str> = text.split('\n').collect();
-    let width_chars = lines
-        .iter()
-        .map(|line| line.chars().count() as i32)
-        .max()
-        .unwrap_or(0);
-    let line_count = lines.len().max(1) as i32;
-    (
-        x,
-        y,
-        x + width_chars * 8 * scale_i,
-        y + line_count * 8 * scale_i,
-    )
-}
+        let depth = parts[0].trim().parse::<usize>().unwrap_or(0);
+        let class_name = parts[1].trim().to_string();
+        let name = (!parts[2].trim().is_empty()).then(|| parts[2].trim().to_string());
+        let role_description = (!parts[3].trim().is_empty()).then(|| parts[3].trim().to_string());
+        let enabled = (!parts[4].trim().is_empty()).then(|| parts[4].trim().to_string());
+
+        let x = parts[5]
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|v| v.round() as i64);
+        let y = parts[6]
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|v| v.round() as i64);
+        let w = parts[7]
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|v| v.round() as i64);
+        let h = parts[8]
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|v| v.round() as i64);
+        let bounds = match (x, y, w, h) {
+            (Some(x), Some(y), Some(w), Some(h)) => Some((x, y, w, h)),
+            _ => None,
+        };
 
-fn fill_rect_alpha(img: &mut RgbaImage, x0: i32, y0: i32, x1: i32, y1: i32, color: Rgba<u8>) {
-    if img.width() == 0 || img.height() == 0 {
-        return;
-    }
-    let min_x = clamp_i32(x0.min(x1), 0, img.width() as i32 - 1);
-    let max_x = clamp_i32(x0.max(x1), 0, img.width() as i32 - 1);
-    let min_y = clamp_i32(y0.min(y1), 0, img.height() as i32 - 1);
-    let max_y = clamp_i32(y0.max(y1), 0, img.height() as i32 - 1);
-    for y in min_y..=max_y {
-        for x in min_x..=max_x {
-            let dst = *img.get_pixel(x as u32, y as u32);
-            img.put_pixel(x as u32, y as u32, blend_pixel(dst, color));
-        }
+        rows.push(AxFlatNode {
+            index,
+            depth,
+            class_name,
+            name,
+            role_description,
+            enabled,
+            bounds,
+        });
     }
+    rows
 }
 
-fn bbox_from_ann(ann: &Map<String, Value>) -> Option<(f64, f64, f64, f64)> {
-    let x = value_to_f64(ann.get("x"))?;
-    let y = value_to_f64(ann.get("y"))?;
-    let w = value_to_f64(ann.get("w"))?;
-    let h = value_to_f64(ann.get("h"))?;
-    if w <= 0.0 || h <= 0.0 {
-        return None;
+fn ax_bounds_value(bounds: Option<(i64, i64, i64, i64)>) -> Value {
+    match bounds {
+        Some((x, y, w, h)) => json!({
+            "x": x,
+            "y": y,
+            "w": w,
+            "h": h,
+            "units": "pt",
+        }),
+        None => Value::Null,
     }
-    Some((x, y, x + w, y + h))
 }
 
-fn anchor_point(bbox: (f64, f64, f64, f64), pos: &str) -> (f64, f64) {
-    let (x0, y0, x1, y1) = bbox;
-    let cx = (x0 + x1) / 2.0;
-    let cy = (y0 + y1) / 2.0;
-    match pos.trim().to_ascii_lowercase().replace('-', "_").as_str() {
-        "top" => (cx, y0),
-        "bottom" => (cx, y1),
-        "left" => (x0, cy),
-        "right" => (x1, cy),
-        "top_left" => (x0, y0),
-        "top_right" => (x1, y0),
-        "bottom_left" => (x0, y1),
-        "bottom_right" => (x1, y1),
-        _ => (cx, cy),
+// Window origin in screen points: the capture sidecar's bounds when given, else a live probe.
+fn ax_window_origin(
+    process: &str,
+    sidecar: Option<&Path>,
+    scripts: &ScriptOverrides,
+) -> Result<((i64, i64), &'static str), String> {
+    if let Some(path) = sidecar {
+        let raw = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read sidecar {}: {err}", path.display()))?;
+        let value: Value = serde_json::from_str(&raw)
+            .map_err(|err| format!("invalid sidecar {}: {err}", path.display()))?;
+        let bounds = value.get("bounds");
+        let x = bounds.and_then(|b| b.get("x")).and_then(Value::as_f64);
+        let y = bounds.and_then(|b| b.get("y")).and_then(Value::as_f64);
+        return match (x, y) {
+            (Some(x), Some(y)) => Ok(((x.round() as i64, y.round() as i64), "sidecar")),
+            _ => Err(format!("sidecar {} has no bounds", path.display())),
+        };
+    }
+    let probe = query_window_probe(process, default_window_thresholds(), scripts);
+    if probe.diagnostics.ok {
+        Ok(((probe.x, probe.y), "window_probe"))
+    } else {
+        Err(probe
+            .diagnostics
+            .message
+            .unwrap_or_else(|| "window query failed".to_string()))
     }
 }
 
-fn anchor_target_center(target: &AnchorTarget) -> (f64, f64) {
-    (
-        (target.bbox.0 + target.bbox.2) / 2.0,
-        (target.bbox.1 + target.bbox.3) / 2.0,
-    )
-}
-
-fn normalize_anchor_spec(value: Option<&Value>) -> Option<AnchorSpec> {
-    let value = value?;
-    match value {
-        Value::Bool(v) => {
-            if *v {
-                Some(AnchorSpec {
-                    id: None,
-                    index: None,
-                    nearest: true,
-                    target_type: None,
-                    pos: None,
-                    offset: None,
-                })
-            } else {
-                None
+// Shift every `bounds` in AX element/tree values (recursing into `children`).
+fn offset_ax_bounds(values: &mut [Value], dx: i64, dy: i64) {
+    for value in values {
+        if let Some(bounds) = value.get_mut("bounds").and_then(Value::as_object_mut) {
+            for (key, delta) in [("x", dx), ("y", dy)] {
+                if let Some(v) = bounds.get(key).and_then(Value::as_i64) {
+                    bounds.insert(key.to_string(), json!(v + delta));
+                }
             }
         }
-        Value::Number(n) => Some(AnchorSpec {
-            id: None,
-            index: n.as_u64().map(|v| v as usize),
-            nearest: false,
-            target_type: None,
-            pos: None,
-            offset: None,
-        }),
-        Value::String(s) => {
-            let raw = s.trim();
-            if raw.is_empty() {
-                return None;
-            }
-            if raw.eq_ignore_ascii_case("nearest") {
-                Some(AnchorSpec {
-                    id: None,
-                    index: None,
-                    nearest: true,
-                    target_type: None,
-                    pos: None,
-                    offset: None,
-                })
-            } else {
-                Some(AnchorSpec {
-                    id: Some(raw.to_string()),
-                    index: None,
-                    nearest: false,
-                    target_type: None,
-                    pos: None,
-                    offset: None,
-                })
-            }
+        if let Some(children) = value.get_mut("children").and_then(Value::as_array_mut) {
+            offset_ax_bounds(children, dx, dy);
         }
-        Value::Object(obj) => Some(AnchorSpec {
-            id: value_to_string(obj.get("id")),
-            index: value_to_usize(obj.get("index")),
-            nearest: obj
-                .get("nearest")
-                .map(|v| value_to_bool(v, false))
-                .unwrap_or(false),
-            target_type: value_to_string(obj.get("type")),
-            pos: value_to_string(obj.get("pos")),
-            offset: parse_offset_value(obj.get("offset")),
-        }),
-        _ => None,
     }
 }
 
-fn resolve_target<'a>(
-    spec: &AnchorSpec,
-    targets: &'a [AnchorTarget],
-    fallback_point: (f64, f64),
-) -> Option<&'a AnchorTarget> {
-    if targets.is_empty() {
-        return None;
-    }
+fn ax_element_value(node: &AxFlatNode) -> Value {
+    json!({
+        "index": node.index,
+        "depth": node.depth,
+        "class": node.class_name,
+        "name": node.name,
+        "role_description": node.role_description,
+        "enabled": node.enabled,
+        "bounds": ax_bounds_value(node.bounds),
+    })
+}
 
-    let mut candidates: Vec<&AnchorTarget> = targets.iter().collect();
-    if let Some(kind) = &spec.target_type {
-        candidates.retain(|target| target.ann_type.eq_ignore_ascii_case(kind));
-    }
-    if candidates.is_empty() {
-        return None;
-    }
+fn ax_tree_node_value(node: &AxTreeNode) -> Value {
+    let children: Vec<Value> = node.children.iter().map(ax_tree_node_value).collect();
+    json!({
+        "index": node.index,
+        "class": node.class_name,
+        "name": node.name,
+        "role_description": node.role_description,
+        "enabled": node.enabled,
+        "bounds": ax_bounds_value(node.bounds),
+        "children": children,
+    })
+}
 
-    if let Some(id) = &spec.id {
-        if let Some(found) = candidates
-            .iter()
-            .copied()
-            .find(|target| target.id.as_deref() == Some(id.as_str()))
-        {
-            return Some(found);
-        }
-    }
+fn ax_tree_values(rows: &[AxFlatNode]) -> Vec<Value> {
+    let mut roots: Vec<AxTreeNode> = Vec::new();
+    let mut stack: Vec<(usize, Vec<usize>)> = Vec::new();
 
-    if let Some(index) = spec.index {
-        if let Some(found) = candidates
-            .iter()
-            .copied()
-            .find(|target| target.index == index)
+    for row in rows {
+        let node = AxTreeNode {
+            index: row.index,
+            class_name: row.class_name.clone(),
+            name: row.name.clone(),
+            role_description: row.role_description.clone(),
+            enabled: row.enabled.clone(),
+            bounds: row.bounds,
+            children: Vec::new(),
+        };
+
+        while stack
+            .last()
+            .map(|(depth, _)| *depth >= row.depth)
+            .unwrap_or(false)
         {
-            return Some(found);
+            stack.pop();
         }
-    }
 
-    if spec.nearest || (spec.id.is_none() && spec.index.is_none() && spec.target_type.is_none()) {
-        let mut best: Option<&AnchorTarget> = None;
-        let mut best_dist = f64::MAX;
-        for target in candidates {
-            let center = anchor_target_center(target);
-            let dx = center.0 - fallback_point.0;
-            let dy = center.1 - fallback_point.1;
-            let dist = dx * dx + dy * dy;
-            if dist < best_dist {
-                best = Some(target);
-                best_dist = dist;
+        if let Some((_, parent_path)) = stack.last().cloned() {
+            if let Some(parent) = ax_tree_node_mut(&mut roots, &parent_path) {
+                parent.children.push(node);
+                let mut node_path = parent_path;
+                node_path.push(parent.children.len() - 1);
+                stack.push((row.depth, node_path));
+            } else {
+                roots.push(node);
+                stack.push((row.depth, vec![roots.len() - 1]));
             }
+        } else {
+            roots.push(node);
+            stack.push((row.depth, vec![roots.len() - 1]));
         }
-        return best;
     }
 
-    None
+    roots.iter().map(ax_tree_node_value).collect()
+}
+
+fn ax_tree_node_mut<'a>(nodes: &'a mut [AxTreeNode], path: &[usize]) -> Option<&'a mut AxTreeNode> {
+    let (head, tail) = path.split_first()?;
+    let node = nodes.get_mut(*head)?;
+    if tail.is_empty() {
+        Some(node)
+    } else {
+        ax_tree_node_mut(node.children.as_mut_slice(), tail)
+    }
+}
+
+fn copy_file(src: &Path, dst: &Path) -> Result<()> {
+    ensure_parent_dir(dst)?;
+    fs::copy(src, dst)
+        .with_context(|| format!("failed to copy {} -> {}", src.display(), dst.display()))?;
+    Ok(())
+}
+
+fn write_json_pretty(path: &Path, value: &Value) -> Result<()> {
+    ensure_parent_dir(path)?;
+    let raw = serde_json::to_string_pretty(value)?;
+    fs::write(path, raw).with_context(|| format!("failed to write JSON: {}", path.display()))?;
+    Ok(())
 }
 
-fn resolve_anchor_pos(
-    spec_pos: Option<String>,
-    ann_pos: Option<String>,
-    defaults: &Map<String, Value>,
-    fallback: &str,
-) -> String {
-    spec_pos
-        .or(ann_pos)
-        .or_else(|| value_to_string(defaults.get("anchor_pos")))
-        .unwrap_or_else(|| fallback.to_string())
+fn append_json_line(path: &Path, value: &Value) -> Result<()> {
+    ensure_parent_dir(path)?;
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open JSON lines log: {}", path.display()))?;
+    // Concurrent agents share one log: one O_APPEND write per whole line keeps records from
+    // interleaving.
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("failed to append JSON line: {}", path.display()))?;
+    Ok(())
 }
 
-fn resolve_anchor_offset(
-    spec_offset: Option<(f64, f64)>,
-    ann_offset: Option<(f64, f64)>,
-    defaults: &Map<String, Value>,
-    fallback: (f64, f64),
-) -> (f64, f64) {
-    spec_offset
-        .or(ann_offset)
-        .or_else(|| parse_offset_value(defaults.get("anchor_offset")))
-        .unwrap_or(fallback)
+fn save_png(
+    image: &DynamicImage,
+    path: &Path,
+    compression: &str,
+    verify: bool,
+) -> image::ImageResult<()> {
+    encode_png(image, path, compression)?;
+    if verify {
+        verify_written_image(path, image.dimensions())?;
+    }
+    Ok(())
 }
 
-fn apply_text_anchor(
-    ann: &Map<String, Value>,
-    targets: &[AnchorTarget],
-    defaults: &Map<String, Value>,
-    img_w: u32,
-    img_h: u32,
-) -> Map<String, Value> {
-    let mut updated = ann.clone();
-    let spec = normalize_anchor_spec(ann.get("anchor"));
-    let Some(spec) = spec else {
-        return updated;
-    };
-    let x = value_to_f64(ann.get("x")).unwrap_or(f64::from(img_w) / 2.0);
-    let y = value_to_f64(ann.get("y")).unwrap_or(f64::from(img_h) / 2.0);
-    let Some(target) = resolve_target(&spec, targets, (x, y)) else {
-        return updated;
+// A truncated or short write (e.g. disk full) either fails to decode or decodes at another size.
+fn verify_written_image(path: &Path, expected: (u32, u32)) -> image::ImageResult<()> {
+    let failure = |detail: String| {
+        image::ImageError::IoError(io::Error::other(format!(
+            "--verify-output: {} {detail}",
+            path.display()
+        )))
     };
-
-    let pos = resolve_anchor_pos(
-        spec.pos.clone(),
-        value_to_string(ann.get("anchor_pos")),
-        defaults,
-        "top",
-    );
-    let offset = resolve_anchor_offset(
-        spec.offset,
-        parse_offset_value(ann.get("anchor_offset")),
-        defaults,
-        (0.0, 0.0),
-    );
-    let anchor = anchor_point(target.bbox, &pos);
-    updated.insert("x".to_string(), json!(anchor.0 + offset.0));
-    updated.insert("y".to_string(), json!(anchor.1 + offset.1));
-    updated
+    let written = image::open(path).map_err(|err| failure(format!("does not decode: {err}")))?;
+    if written.dimensions() != expected {
+        let (w, h) = written.dimensions();
+        return Err(failure(format!(
+            "reads back as {w}x{h}, expected {}x{}",
+            expected.0, expected.1
+        )));
+    }
+    Ok(())
 }
 
-fn apply_arrow_anchor(
-    ann: &Map<String, Value>,
-    targets: &[AnchorTarget],
-    defaults: &Map<String, Value>,
-    img_w: u32,
-    img_h: u32,
-) -> Map<String, Value> {
-    let from_spec = normalize_anchor_spec(ann.get("from"));
-    let to_spec = normalize_anchor_spec(ann.get("to"));
-    if from_spec.is_none() && to_spec.is_none() {
-        return ann.clone();
+fn encode_png(image: &DynamicImage, path: &Path, compression: &str) -> image::ImageResult<()> {
+    let is_png = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("png"))
+        .unwrap_or(false);
+    let compression = match compression {
+        "fast" => CompressionType::Fast,
+        "best" => CompressionType::Best,
+        _ => return image.save(path),
+    };
+    if !is_png {
+        return image.save(path);
     }
-    let mut updated = ann.clone();
+    let file = File::create(path)?;
+    let encoder = PngEncoder::new_with_quality(
+        io::BufWriter::new(file),
+        compression,
+        PngFilterType::Adaptive,
+    );
+    image.write_with_encoder(encoder)
+}
 
-    if let Some(spec) = from_spec {
-        let x1 = value_to_f64(ann.get("x1")).unwrap_or(f64::from(img_w) / 2.0);
-        let y1 = value_to_f64(ann.get("y1")).unwrap_or(f64::from(img_h) / 2.0);
-        if let Some(target) = resolve_target(&spec, targets, (x1, y1)) {
-            let pos = resolve_anchor_pos(
-                spec.pos.clone(),
-                value_to_string(ann.get("from_pos")),
-                defaults,
-                "center",
-            );
-            let offset = resolve_anchor_offset(
-                spec.offset,
-                parse_offset_value(ann.get("from_offset")),
-                defaults,
-                (0.0, 0.0),
-            );
-            let anchor = anchor_point(target.bbox, &pos);
-            updated.insert("x1".to_string(), json!(anchor.0 + offset.0));
-            updated.insert("y1".to_string(), json!(anchor.1 + offset.1));
+fn ensure_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("failed to create parent directory: {}", parent.display())
+            })?;
         }
     }
+    Ok(())
+}
 
-    if let Some(spec) = to_spec {
-        let x2 = value_to_f64(ann.get("x2")).unwrap_or(f64::from(img_w) / 2.0);
-        let y2 = value_to_f64(ann.get("y2")).unwrap_or(f64::from(img_h) / 2.0);
-        if let Some(target) = resolve_target(&spec, targets, (x2, y2)) {
-            let pos = resolve_anchor_pos(
-                spec.pos.clone(),
-                value_to_string(ann.get("to_pos")),
-                defaults,
-                "center",
-            );
-            let offset = resolve_anchor_offset(
-                spec.offset,
-                parse_offset_value(ann.get("to_offset")),
-                defaults,
-                (0.0, 0.0),
-            );
-            let anchor = anchor_point(target.bbox, &pos);
-            updated.insert("x2".to_string(), json!(anchor.0 + offset.0));
-            updated.insert("y2".to_string(), json!(anchor.1 + offset.1));
-        }
-    }
+fn default_sidecar_for(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output")
+        .to_string();
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    parent.join(format!("{stem}.json"))
+}
 
-    updated
+fn out_root() -> PathBuf {
+    env::var("CVLP_OUT_DIR")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var("CVLP_TMP_DIR")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(PathBuf::from)
+        })
+        .unwrap_or_else(|| PathBuf::from(".codex-visual-loop"))
 }
 
-fn point_in_rounded_rect(
-    px: i32,
-    py: i32,
-    x0: i32,
-    y0: i32,
-    x1: i32,
-    y1: i32,
-    radius: f64,
-) -> bool {
-    if px < x0 || px >= x1 || py < y0 || py >= y1 {
-        return false;
-    }
-    if radius <= 0.1 {
-        return true;
+fn abs_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
     }
-    let r = radius
-        .min(f64::from((x1 - x0).abs()) / 2.0)
-        .min(f64::from((y1 - y0).abs()) / 2.0);
-    let fx = f64::from(px);
-    let fy = f64::from(py);
-    let left = f64::from(x0);
-    let right = f64::from(x1);
-    let top = f64::from(y0);
-    let bottom = f64::from(y1);
+    env::current_dir()
+        .unwrap_or_else(|_| PathBuf::from("."))
+        .join(path)
+}
 
-    if (fx >= left + r && fx <= right - r) || (fy >= top + r && fy <= bottom - r) {
-        return true;
+fn sanitize_baseline_name(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for ch in input.chars() {
+        if ch.is_ascii_alphanumeric() || matches!(ch, '.' | '_' | '-') {
+            out.push(ch);
+        } else if matches!(ch, ' ' | '/' | ':') {
+            out.push('_');
+        }
+    }
+    if out.is_empty() {
+        "baseline".to_string()
+    } else {
+        out
     }
-
-    let corners = [
-        (left + r, top + r),
-        (right - r, top + r),
-        (left + r, bottom - r),
-        (right - r, bottom - r),
-    ];
-    corners.iter().any(|(cx, cy)| {
-        let dx = fx - cx;
-        let dy = fy - cy;
-        dx * dx + dy * dy <= r * r
-    })
 }
 
-fn draw_spotlight_annotation(
-    img: &mut RgbaImage,
-    ann: &Map<String, Value>,
-    scale: f64,
-    defaults: &Map<String, Value>,
-) {
-    let dim_color = parse_color_opt(ann.get("color"))
-        .or_else(|| parse_color_opt(ann.get("dim_color")))
-        .or_else(|| parse_color_opt(defaults.get("dim_color")))
-        .unwrap_or(Rgba([0, 0, 0, 115]));
-
-    let opacity =
-        value_to_f64(ann.get("opacity")).or_else(|| value_to_f64(defaults.get("dim_opacity")));
-    let final_color = if let Some(alpha_raw) = opacity {
-        let alpha = if alpha_raw <= 1.0 {
-            (alpha_raw * 255.0).round().clamp(0.0, 255.0) as u8
-        } else {
-            alpha_raw.round().clamp(0.0, 255.0) as u8
-        };
-        Rgba([dim_color[0], dim_color[1], dim_color[2], alpha])
+fn slugify(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for ch in input.chars() {
+        let lower = ch.to_ascii_lowercase();
+        if lower.is_ascii_alphanumeric() || matches!(lower, '.' | '_' | '-') {
+            out.push(lower);
+        } else if lower.is_ascii_whitespace() {
+            out.push('-');
+        }
+    }
+    if out.is_empty() {
+        "app".to_string()
     } else {
-        dim_color
-    };
+        out
+    }
+}
 
-    let padding = value_to_f64(ann.get("padding"))
-        .or_else(|| value_to_f64(defaults.get("dim_padding")))
-        .unwrap_or(0.0)
-        * scale;
-    let radius = value_to_f64(ann.get("radius"))
-        .or_else(|| value_to_f64(defaults.get("dim_radius")))
-        .unwrap_or(0.0)
-        * scale;
+fn timestamp_compact() -> String {
+    Utc::now().format("%Y%m%d-%H%M%S").to_string()
+}
 
-    let x = value_to_f64(ann.get("x")).unwrap_or(0.0) - padding;
-    let y = value_to_f64(ann.get("y")).unwrap_or(0.0) - padding;
-    let w = value_to_f64(ann.get("w")).unwrap_or(0.0) + padding * 2.0;
-    let h = value_to_f64(ann.get("h")).unwrap_or(0.0) + padding * 2.0;
+fn timestamp_iso() -> String {
+    Utc::now().to_rfc3339()
+}
 
-    let hole_x0 = x.floor() as i32;
-    let hole_y0 = y.floor() as i32;
-    let hole_x1 = (x + w).ceil() as i32;
-    let hole_y1 = (y + h).ceil() as i32;
+fn round_to(v: f64, digits: u32) -> f64 {
+    let factor = 10f64.powi(digits as i32);
+    (v * factor).round() / factor
+}
 
-    for py in 0..img.height() as i32 {
-        for px in 0..img.width() as i32 {
-            if point_in_rounded_rect(px, py, hole_x0, hole_y0, hole_x1, hole_y1, radius) {
-                continue;
-            }
-            let dst = *img.get_pixel(px as u32, py as u32);
-            img.put_pixel(px as u32, py as u32, blend_pixel(dst, final_color));
-        }
-    }
+fn command_exists(name: &str) -> bool {
+    Command::new("bash")
+        .arg("-lc")
+        .arg(format!("command -v {name} >/dev/null 2>&1"))
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
 }
 
-fn draw_rect_annotation(img: &mut RgbaImage, ann: &Map<String, Value>, scale: f64) {
-    let x = value_to_f64(ann.get("x")).unwrap_or(0.0);
-    let y = value_to_f64(ann.get("y")).unwrap_or(0.0);
-    let w = value_to_f64(ann.get("w")).unwrap_or(0.0);
-    let h = value_to_f64(ann.get("h")).unwrap_or(0.0);
-    if w <= 0.0 || h <= 0.0 {
-        return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn rel_units_convert_to_pixels() {
+        let mut ann = Map::new();
+        ann.insert("units".to_string(), json!("rel"));
+        ann.insert("x".to_string(), json!(0.1));
+        ann.insert("y".to_string(), json!(0.2));
+        ann.insert("w".to_string(), json!(0.5));
+        ann.insert("h".to_string(), json!(0.4));
+        let defaults = Map::new();
+        resolve_annotation_units(&mut ann, 200, 100, &defaults);
+
+        assert_eq!(ann.get("x").and_then(Value::as_f64), Some(20.0));
+        assert_eq!(ann.get("y").and_then(Value::as_f64), Some(20.0));
+        assert_eq!(ann.get("w").and_then(Value::as_f64), Some(100.0));
+        assert_eq!(ann.get("h").and_then(Value::as_f64), Some(40.0));
     }
 
-    if let Some(fill) = parse_color_opt(ann.get("fill")) {
-        fill_rect_alpha(
-            img,
-            x.round() as i32,
-            y.round() as i32,
-            (x + w).round() as i32,
-            (y + h).round() as i32,
-            fill,
+    #[test]
+    fn preprocess_crops_then_scales_input() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(200, 100));
+        let mut defaults = Map::new();
+        defaults.insert(
+            "preprocess".to_string(),
+            json!({"crop": {"x": 0, "y": 20, "w": "100%", "h": 80}, "scale": 0.5}),
         );
+        let (out, meta) = apply_preprocess(image, &defaults).unwrap();
+        assert_eq!(out.dimensions(), (100, 40));
+        let meta = meta.unwrap();
+        assert_eq!(meta["crop"]["y"], json!(20));
+        assert_eq!(meta["output_size"]["height"], json!(40));
     }
 
-    let stroke = parse_color(ann.get("color"), [255, 59, 48, 255]);
-    let width = value_to_usize(ann.get("width"))
-        .map(|v| v.max(1) as u32)
-        .unwrap_or_else(|| scale_default(3.0, scale, 2));
-    let outline_enabled = ann
-        .get("outline")
-        .map(|v| value_to_bool(v, true))
-        .unwrap_or(true);
-    let outline_width = value_to_usize(ann.get("outline_width"))
-        .map(|v| v.max(1) as u32)
-        .unwrap_or_else(|| ((f64::from(width) * 0.6).round() as u32).max(2));
-    let outline_color =
-        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(stroke));
+    #[test]
+    fn annotation_meta_csv_flattens_geometry_and_escapes_text() {
+        let items = vec![
+            json!({"index": 0, "type": "rect", "geometry": {"x": 10, "y": 20, "w": 30, "h": 40}, "severity": "high"}),
+            json!({"index": 1, "type": "arrow", "geometry": {"x1": 50, "y1": 5, "x2": 10, "y2": 25}}),
+            json!({"index": 2, "type": "text", "geometry": {"x": 1, "y": 2}, "text": "a, \"b\""}),
+        ];
+        let csv = annotation_meta_csv(&items, NumberLocale::default());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "index,type,x,y,w,h,intent,action,severity,text");
+        assert_eq!(lines[1], "0,rect,10,20,30,40,,,high,");
+        assert_eq!(lines[2], "1,arrow,10,5,40,20,,,,");
+        assert_eq!(lines[3], "2,text,1,2,,,,,,\"a, \"\"b\"\"\"");
+    }
 
-    let x_u = x.max(0.0).round() as u32;
-    let y_u = y.max(0.0).round() as u32;
-    let w_u = w.max(1.0).round() as u32;
-    let h_u = h.max(1.0).round() as u32;
+    #[test]
+    fn locale_formats_report_numbers_but_not_json() {
+        let de = parse_number_locale("de_DE.UTF-8").unwrap();
+        assert_eq!(de.format(1234.5), "1.234,5");
+        assert_eq!(de.format(-0.25), "-0,25");
+        assert_eq!(
+            parse_number_locale("en-US").unwrap().format(1234567.0),
+            "1,234,567"
+        );
+        assert_eq!(
+            parse_number_locale("fr").unwrap().format(1234.5),
+            "1\u{a0}234,5"
+        );
+        assert_eq!(NumberLocale::default().format(1234.5), "1234.5");
+        assert!(parse_number_locale("xx").is_err());
 
-    if outline_enabled {
-        draw_rect_outline(
-            img,
-            x_u,
-            y_u,
-            w_u,
-            h_u,
-            outline_color,
-            width + outline_width * 2,
+        let items = vec![
+            json!({"index": 0, "type": "rect", "geometry": {"x": 1200.5, "y": 2, "w": 0.125, "h": 4}}),
+            json!({"index": 1, "type": "text", "geometry": {"x": 1, "y": 2}, "text": "a; b, c"}),
+        ];
+        let csv = annotation_meta_csv(&items, de);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "index;type;x;y;w;h;intent;action;severity;text");
+        assert_eq!(lines[1], "0;rect;1200,5;2;0,125;4;;;;");
+        assert_eq!(lines[2], "1;text;1;2;;;;;;\"a; b, c\"");
+
+        let packet = json!({"summary": {"element_count": 1500,
+                                         "top_roles": [{"role": "AXButton", "count": 1200}]}});
+        let report = build_fallback_explain_report(
+            &packet,
+            "no codex",
+            &json!({}),
+            None,
+            "{element_count} {top_roles}",
+            de,
         );
+        assert_eq!(report, "1.500   - AXButton: 1.200\n");
+
+        let cli = Cli::try_parse_from(["codex-visual-loop", "schema", "diff", "--locale", "de"]);
+        assert_eq!(OutputOptions::from_cli(&cli.unwrap()).locale, de);
+        assert!(Cli::try_parse_from(["codex-visual-loop", "--locale", "xx", "commands"]).is_err());
     }
-    draw_rect_outline(img, x_u, y_u, w_u, h_u, stroke, width);
-}
 
-fn draw_arrow_annotation(img: &mut RgbaImage, ann: &Map<String, Value>, scale: f64) {
-    let x1 = value_to_f64(ann.get("x1")).unwrap_or(0.0);
-    let y1 = value_to_f64(ann.get("y1")).unwrap_or(0.0);
-    let x2 = value_to_f64(ann.get("x2")).unwrap_or(0.0);
-    let y2 = value_to_f64(ann.get("y2")).unwrap_or(0.0);
-    let color = parse_color(ann.get("color"), [10, 132, 255, 255]);
-    let width = value_to_f64(ann.get("width"))
-        .unwrap_or_else(|| f64::from(scale_default(3.0, scale, 2)))
-        .max(1.0);
-    let head_len = value_to_f64(ann.get("head_len"))
-        .unwrap_or_else(|| f64::from(scale_default(12.0, scale, 6)))
-        .max(2.0);
-    let head_width = value_to_f64(ann.get("head_width"))
-        .unwrap_or_else(|| f64::from(scale_default(8.0, scale, 5)))
-        .max(2.0);
+    #[test]
+    fn bottom_left_origin_flips_y_fields() {
+        let mut defaults = Map::new();
+        defaults.insert("coordinate_origin".to_string(), json!("bottom_left"));
+        let mut rect = defaults.clone();
+        rect.insert("y".to_string(), json!(10));
+        rect.insert("h".to_string(), json!(20));
+        rect.insert("anchor_offset".to_string(), json!([4, 6]));
+        resolve_annotation_units(&mut rect, 200, 100, &defaults);
+        assert_eq!(rect.get("y").and_then(Value::as_f64), Some(70.0));
+        assert_eq!(rect.get("anchor_offset"), Some(&json!([4.0, -6.0])));
+
+        let mut arrow = defaults.clone();
+        arrow.insert("y1".to_string(), json!(0));
+        arrow.insert("y2".to_string(), json!("25%"));
+        resolve_annotation_units(&mut arrow, 200, 100, &defaults);
+        assert_eq!(arrow.get("y1").and_then(Value::as_f64), Some(100.0));
+        assert_eq!(arrow.get("y2").and_then(Value::as_f64), Some(75.0));
+    }
 
-    let outline_enabled = ann
-        .get("outline")
-        .map(|v| value_to_bool(v, true))
-        .unwrap_or(true);
-    let outline_width = value_to_f64(ann.get("outline_width"))
-        .unwrap_or_else(|| (width * 0.6).round().max(2.0))
-        .max(1.0);
-    let outline_color =
-        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(color));
+    #[test]
+    fn diff_detects_change_regions() {
+        let mut gray = vec![0u8; 100 * 60];
+        for y in 10..30 {
+            for x in 20..40 {
+                gray[y * 100 + x] = 255;
+            }
+        }
+        let regions = extract_change_regions(&gray, 100, 60, 1, 10, 2, 8);
+        assert!(!regions.is_empty());
+        let first = &regions[0];
+        assert!(first.x <= 20);
+        assert!(first.y <= 10);
+        assert!(first.w >= 20);
+        assert!(first.h >= 20);
+    }
 
-    if outline_enabled {
-        draw_arrow_primitive(
-            img,
-            x1,
-            y1,
-            x2,
-            y2,
-            outline_color,
-            width + outline_width * 2.0,
-            head_len + outline_width * 2.0,
-            head_width + outline_width * 2.0,
-        );
+    #[test]
+    fn diff_quality_metrics_report_mae_and_psnr() {
+        assert_eq!(diff_quality_metrics(0, 0, 100), (0.0, None));
+        let (mae, psnr) = diff_quality_metrics(400, 40_000, 4);
+        assert_eq!(mae, 100.0);
+        let psnr = psnr.unwrap();
+        assert!((psnr - 20.0 * (255.0f64 / 100.0).log10()).abs() < 1e-9);
     }
-    draw_arrow_primitive(img, x1, y1, x2, y2, color, width, head_len, head_width);
-}
 
-fn draw_text_annotation(img: &mut RgbaImage, ann: &Map<String, Value>, scale: f64) {
-    let text = ann
-        .get("text")
-        .and_then(Value::as_str)
-        .unwrap_or_default()
-        .to_string();
-    if text.is_empty() {
-        return;
+    #[test]
+    fn auto_orient_rotates_swapped_dimensions_to_best_match() {
+        let mut baseline = RgbaImage::from_pixel(4, 2, Rgba([0, 0, 0, 255]));
+        baseline.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        let baseline = DynamicImage::ImageRgba8(baseline);
+        let current = baseline.rotate90();
+        let (oriented, degrees) = auto_orient(&baseline, current);
+        assert_eq!(degrees, Some(270));
+        assert_eq!(oriented.to_rgba8(), baseline.to_rgba8());
+
+        let square = DynamicImage::ImageRgba8(RgbaImage::new(3, 3));
+        assert_eq!(auto_orient(&square, square.clone()).1, None);
     }
 
-    let x = value_to_i32(ann.get("x"), 0);
-    let y = value_to_i32(ann.get("y"), 0);
-    let color = parse_color(ann.get("color"), [255, 255, 255, 255]);
-    let size = value_to_usize(ann.get("size"))
-        .map(|v| v.max(8) as u32)
-        .unwrap_or_else(|| scale_default(14.0, scale, 10));
-    let glyph_scale = (size as f64 / 8.0).round().max(1.0) as u32;
-    let padding = value_to_usize(ann.get("padding"))
-        .map(|v| v as i32)
-        .unwrap_or_else(|| scale_default(4.0, scale, 2) as i32);
+    #[test]
+    fn trim_packet_ax_keeps_named_and_interactive_elements() {
+        let mut packet = json!({
+            "ax_tree": {
+                "elements": [
+                    {"class": "group", "name": null, "role_description": "group"},
+                    {"class": "button", "name": null, "role_description": "button"},
+                    {"class": "static text", "name": "Title", "role_description": null},
+                ],
+                "tree": [{"index": 0}],
+            }
+        });
+        assert_eq!(trim_packet_ax_for_prompt(&mut packet), Some((3, 2)));
+        assert_eq!(packet["ax_tree"]["tree"], json!([]));
+        assert_eq!(packet["ax_tree"]["trimmed_for_prompt"], json!(true));
+        assert_eq!(estimate_prompt_tokens("abcdefghi"), 3);
+    }
 
-    let bg_value = ann.get("bg").or_else(|| ann.get("text_bg"));
-    if let Some(bg_color) = parse_color_opt(bg_value) {
-        let bbox = text_bbox(x, y, &text, glyph_scale);
-        fill_rect_alpha(
-            img,
-            bbox.0 - padding,
-            bbox.1 - padding,
-            bbox.2 + padding,
-            bbox.3 + padding,
-            bg_color,
-        );
+    #[test]
+    fn writes_json_pretty() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("a").join("b.json");
+        write_json_pretty(&target, &json!({"ok": true})).unwrap();
+        assert!(target.exists());
     }
 
-    let outline_enabled = ann
-        .get("outline")
-        .map(|v| value_to_bool(v, true))
-        .unwrap_or(true);
-    let outline_width = value_to_usize(ann.get("outline_width"))
-        .map(|v| v.max(1) as i32)
-        .unwrap_or_else(|| ((size as f64 * 0.12).round() as i32).max(1));
-    let outline_color =
-        parse_color_opt(ann.get("outline_color")).unwrap_or_else(|| auto_outline_color(color));
+    #[test]
+    fn custom_query_scripts_are_read_and_validated() {
+        let dir = tempdir().unwrap();
+        assert!(matches!(read_script_override(None, "ax_script"), Ok(None)));
+        let script = dir.path().join("windows.applescript");
+        fs::write(&script, "return \"1\t0\t0\t800\t600\tMain\"").unwrap();
+        assert!(read_script_override(Some(&script), "window_script")
+            .unwrap()
+            .is_some());
+        let missing = read_script_override(Some(&dir.path().join("nope")), "window_script");
+        assert_eq!(
+            missing.unwrap_err().error_code.as_deref(),
+            Some("window_script_unreadable")
+        );
 
-    if outline_enabled {
-        for dx in -outline_width..=outline_width {
-            for dy in -outline_width..=outline_width {
-                if dx == 0 && dy == 0 {
-                    continue;
-                }
-                if dx * dx + dy * dy > outline_width * outline_width {
-                    continue;
-                }
-                draw_bitmap_text(img, x + dx, y + dy, &text, outline_color, glyph_scale);
-            }
-        }
+        assert_eq!(
+            ax_output_problem("0\tAXWindow\tMain\n1\tAXButton\tOK\n"),
+            None
+        );
+        assert!(ax_output_problem("AXWindow Main")
+            .unwrap()
+            .contains("line 1"));
+        assert_eq!(ax_output_problem("\n").as_deref(), Some("is empty"));
     }
 
-    draw_bitmap_text(img, x, y, &text, color, glyph_scale);
-}
-
-fn fit_bbox_luma(
-    image_rgb: &image::RgbImage,
-    region: (u32, u32, u32, u32),
-    threshold: f64,
-    target: &str,
-    min_pixels: u32,
-) -> Option<(u32, u32, u32, u32)> {
-    let (x0, y0, x1, y1) = region;
-    if x1 <= x0 || y1 <= y0 {
-        return None;
+    #[test]
+    fn script_overrides_come_from_each_parsed_cli() {
+        let options = |argv: &[&str]| {
+            let cli = Cli::try_parse_from(["codex-visual-loop"].iter().chain(argv).chain(&[
+                "ax-tree",
+                "--process",
+                "Finder",
+            ]))
+            .unwrap();
+            OutputOptions::from_cli(&cli).scripts
+        };
+        assert_eq!(options(&[]), ScriptOverrides::default());
+        let first = options(&["--window-script", "a.applescript", "--ax-script", "ax.scpt"]);
+        assert_eq!(first.window, Some(PathBuf::from("a.applescript")));
+        assert_eq!(first.ax, Some(PathBuf::from("ax.scpt")));
+        // Not write-once: a second parse gets its own overrides.
+        let second = options(&["--window-script", "b.applescript"]);
+        assert_eq!(second.window, Some(PathBuf::from("b.applescript")));
+        assert_eq!(second.ax, None);
     }
-    let mut minx = u32::MAX;
-    let mut miny = u32::MAX;
-    let mut maxx = 0u32;
-    let mut maxy = 0u32;
-    let mut count = 0u32;
-    let dark = !target.eq_ignore_ascii_case("light");
 
-    for y in y0..y1 {
-        for x in x0..x1 {
-            let pixel = image_rgb.get_pixel(x, y).0;
-            let luma = 0.2126 * f64::from(pixel[0])
-                + 0.7152 * f64::from(pixel[1])
-                + 0.0722 * f64::from(pixel[2]);
-            let matched = if dark {
-                luma <= threshold
-            } else {
-                luma >= threshold
-            };
-            if matched {
-                count += 1;
-                minx = minx.min(x);
-                miny = miny.min(y);
-                maxx = maxx.max(x);
-                maxy = maxy.max(y);
-            }
-        }
+    #[test]
+    fn parse_window_candidates_reads_tsv_rows() {
+        let raw = "1\t0\t38\t902\t1079\tMain\n2\t8\t8\t30\t23\tTiny";
+        let parsed = parse_window_candidates(raw);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].index, 1);
+        assert_eq!(parsed[0].w, 902);
+        assert_eq!(parsed[1].h, 23);
     }
 
-    if count < min_pixels.max(1) || minx == u32::MAX {
-        return None;
+    #[test]
+    fn select_window_candidate_prefers_largest_usable() {
+        let windows = vec![
+            WindowCandidate {
+                index: 1,
+                x: 0,
+                y: 0,
+                w: 30,
+                h: 23,
+                title: Some("tiny".to_string()),
+            },
+            WindowCandidate {
+                index: 2,
+                x: 10,
+                y: 10,
+                w: 640,
+                h: 480,
+                title: Some("main".to_string()),
+            },
+            WindowCandidate {
+                index: 3,
+                x: 20,
+                y: 20,
+                w: 800,
+                h: 600,
+                title: Some("largest".to_string()),
+            },
+        ];
+        let (selected, mode, usable_count) = select_window_candidate(&windows, 220, 140, 40_000);
+        assert_eq!(selected.index, 3);
+        assert_eq!(mode, "largest_usable");
+        assert_eq!(usable_count, 2);
     }
-    Some((minx, miny, maxx, maxy))
-}
 
-fn fit_bbox_color(
-    image_rgb: &image::RgbImage,
-    region: (u32, u32, u32, u32),
-    color: Rgba<u8>,
-    tolerance: f64,
-    min_pixels: u32,
-) -> Option<(u32, u32, u32, u32)> {
-    let (x0, y0, x1, y1) = region;
-    if x1 <= x0 || y1 <= y0 {
-        return None;
+    #[test]
+    fn select_window_candidate_uses_largest_any_when_all_tiny() {
+        let windows = vec![
+            WindowCandidate {
+                index: 1,
+                x: 0,
+                y: 0,
+                w: 30,
+                h: 23,
+                title: None,
+            },
+            WindowCandidate {
+                index: 2,
+                x: 0,
+                y: 0,
+                w: 120,
+                h: 90,
+                title: None,
+            },
+        ];
+        let (selected, mode, usable_count) = select_window_candidate(&windows, 220, 140, 40_000);
+        assert_eq!(selected.index, 2);
+        assert_eq!(mode, "largest_any");
+        assert_eq!(usable_count, 0);
     }
-    let mut minx = u32::MAX;
-    let mut miny = u32::MAX;
-    let mut maxx = 0u32;
-    let mut maxy = 0u32;
-    let mut count = 0u32;
-    let tol = tolerance.max(0.0);
 
-    for y in y0..y1 {
-        for x in x0..x1 {
-            let pixel = image_rgb.get_pixel(x, y).0;
-            let delta = (i16::from(pixel[0]) - i16::from(color[0]))
-                .unsigned_abs()
-                .max((i16::from(pixel[1]) - i16::from(color[1])).unsigned_abs())
-                .max((i16::from(pixel[2]) - i16::from(color[2])).unsigned_abs())
-                as f64;
-            if delta <= tol {
-                count += 1;
-                minx = minx.min(x);
-                miny = miny.min(y);
-                maxx = maxx.max(x);
-                maxy = maxy.max(y);
-            }
-        }
+    #[test]
+    fn parse_hotkey_combo_parses_modifiers_and_key() {
+        let spec = parse_hotkey_combo("cmd+shift+p").unwrap();
+        assert_eq!(spec.key, "p");
+        assert_eq!(spec.key_code, None);
+        assert_eq!(spec.modifiers, vec!["command down", "shift down"]);
     }
 
-    if count < min_pixels.max(1) || minx == u32::MAX {
-        return None;
+    #[test]
+    fn parse_hotkey_combo_maps_special_keys_to_keycode() {
+        let spec = parse_hotkey_combo("ctrl+enter").unwrap();
+        assert_eq!(spec.key, "enter");
+        assert_eq!(spec.key_code, Some(36));
+        assert_eq!(spec.modifiers, vec!["control down"]);
     }
-    Some((minx, miny, maxx, maxy))
-}
 
-fn expand_bbox(
-    bbox: Option<(u32, u32, u32, u32)>,
-    pad: f64,
-    img_w: u32,
-    img_h: u32,
-) -> Option<(u32, u32, u32, u32)> {
-    let (x0, y0, x1, y1) = bbox?;
-    let p = pad.max(0.0).round() as i64;
-    let min_x = (i64::from(x0) - p).clamp(0, i64::from(img_w)) as u32;
-    let min_y = (i64::from(y0) - p).clamp(0, i64::from(img_h)) as u32;
-    let max_x = (i64::from(x1) + p).clamp(0, i64::from(img_w)) as u32;
-    let max_y = (i64::from(y1) + p).clamp(0, i64::from(img_h)) as u32;
-    if max_x <= min_x || max_y <= min_y {
-        return None;
+    #[test]
+    fn parse_coord_pair_requires_two_values() {
+        assert_eq!(parse_coord_pair("120,80"), Some((120, 80)));
+        assert_eq!(parse_coord_pair("120"), None);
+        assert_eq!(parse_coord_pair("120,80,1"), None);
     }
-    Some((min_x, min_y, max_x, max_y))
-}
-
-fn parse_fit_region(
-    fit_region: Option<&Value>,
-    ann: &Map<String, Value>,
-    img_w: u32,
-    img_h: u32,
-) -> (u32, u32, u32, u32) {
-    let (mut x, mut y, mut w, mut h) = (
-        value_to_f64(ann.get("x")),
-        value_to_f64(ann.get("y")),
-        value_to_f64(ann.get("w")),
-        value_to_f64(ann.get("h")),
-    );
 
-    if let Some(value) = fit_region {
-        match value {
-            Value::Object(obj) => {
-                x = value_to_f64(obj.get("x"));
-                y = value_to_f64(obj.get("y"));
-                w = value_to_f64(obj.get("w"));
-                h = value_to_f64(obj.get("h"));
+    #[test]
+    fn auto_region_label_stays_on_canvas() {
+        let mut gray = vec![0u8; 200 * 100];
+        for y in 0..20 {
+            for x in 175..200 {
+                gray[y * 200 + x] = 255;
             }
-            Value::Array(items) if items.len() >= 4 => {
-                x = value_to_f64(items.first());
-                y = value_to_f64(items.get(1));
-                w = value_to_f64(items.get(2));
-                h = value_to_f64(items.get(3));
+            for x in 20..60 {
+                gray[(y + 60) * 200 + x] = 255;
             }
-            _ => {}
         }
-    }
-
-    let x = x.unwrap_or(0.0);
-    let y = y.unwrap_or(0.0);
-    let w = w.unwrap_or(f64::from(img_w));
-    let h = h.unwrap_or(f64::from(img_h));
-    let x0 = clamp_i32(x.round() as i32, 0, img_w as i32) as u32;
-    let y0 = clamp_i32(y.round() as i32, 0, img_h as i32) as u32;
-    let x1 = clamp_i32((x + w).round() as i32, 0, img_w as i32) as u32;
-    let y1 = clamp_i32((y + h).round() as i32, 0, img_h as i32) as u32;
-    if x1 <= x0 || y1 <= y0 {
-        (0, 0, img_w, img_h)
-    } else {
-        (x0, y0, x1, y1)
-    }
-}
-
-fn target_center_from_bbox(bbox: (u32, u32, u32, u32)) -> (f64, f64) {
-    (
-        f64::from(bbox.0 + bbox.2) / 2.0,
-        f64::from(bbox.1 + bbox.3) / 2.0,
-    )
-}
-
-fn snap_bbox_to_region(
-    region: (u32, u32, u32, u32),
-    bbox: (u32, u32, u32, u32),
-    img_w: u32,
-    img_h: u32,
-) -> (u32, u32, u32, u32) {
-    let region_w = region.2.saturating_sub(region.0);
-    let region_h = region.3.saturating_sub(region.1);
-    let bbox_w = bbox.2.saturating_sub(bbox.0);
-    let bbox_h = bbox.3.saturating_sub(bbox.1);
-    if region_w == 0 || region_h == 0 {
-        return bbox;
-    }
-    if bbox_w <= region_w && bbox_h <= region_h {
-        let center = target_center_from_bbox(bbox);
-        let x0 = clamp_i32(
-            (center.0 - f64::from(region_w) / 2.0).round() as i32,
-            0,
-            img_w as i32,
-        ) as u32;
-        let y0 = clamp_i32(
-            (center.1 - f64::from(region_h) / 2.0).round() as i32,
-            0,
-            img_h as i32,
-        ) as u32;
-        let x1 = (x0 + region_w).min(img_w);
-        let y1 = (y0 + region_h).min(img_h);
-        if x1 > x0 && y1 > y0 {
-            return (x0, y0, x1, y1);
+        let regions = extract_change_regions(&gray, 200, 100, 24, 1, 0, 4);
+        assert_eq!(regions.len(), 2);
+        let options = DiffOptions {
+            label_position: "auto".to_string(),
+            ..DiffOptions::default()
+        };
+        let spec = build_annotate_spec(&regions, &options, (200, 100));
+        let labels: Vec<&Value> = spec["annotations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|ann| ann["type"] == "text")
+            .collect();
+        for (label, region) in labels.iter().zip(&regions) {
+            let (pos, [dx, dy]) = region_label_anchor(region, "Δ1", "auto", (200, 100));
+            assert_eq!(label["anchor_pos"], json!(pos));
+            let y = i64::from(region.y) + dy;
+            // "Δ1" renders 32px wide at this size (glyph scale 2).
+            let x_end = i64::from(region.x) + dx + 32;
+            assert!(y >= 0 && x_end <= 200, "{region:?} -> ({dx}, {dy})");
         }
+        // The region touching y=0 puts its label inside; the lower one keeps it above.
+        let top = regions.iter().find(|r| r.y == 0).unwrap();
+        let low = regions.iter().find(|r| r.y == 60).unwrap();
+        assert!(region_label_anchor(top, "Δ1", "auto", (200, 100)).1[1] > 0);
+        assert!(region_label_anchor(low, "Δ1", "auto", (200, 100)).1[1] < 0);
     }
-    bbox
-}
 
-fn resolve_fit_config(ann: &Map<String, Value>, defaults: &Map<String, Value>) -> Option<Value> {
-    if let Some(fit) = ann.get("fit") {
-        return match fit {
-            Value::Bool(v) => {
-                if *v {
-                    Some(json!({}))
-                } else {
-                    None
-                }
+    #[test]
+    fn stable_region_colors_survive_jitter() {
+        let mut gray = vec![0u8; 240 * 120];
+        for y in 10..40 {
+            for x in 20..120 {
+                gray[y * 240 + x] = 255;
             }
-            Value::String(mode) => Some(json!({"mode": mode})),
-            Value::Object(obj) => Some(Value::Object(obj.clone())),
-            _ => None,
+        }
+        let regions = extract_change_regions(&gray, 240, 120, 24, 1, 0, 4);
+        let mut jittered = regions[0].clone();
+        jittered.x += 3;
+        jittered.w -= 2;
+        assert_eq!(
+            stable_region_color(&regions[0]),
+            stable_region_color(&jittered)
+        );
+
+        let stable = DiffOptions {
+            region_colors: "stable".to_string(),
+            ..DiffOptions::default()
         };
+        let [r, g, b] = stable_region_color(&regions[0]);
+        let spec = build_annotate_spec(&regions, &stable, (240, 120));
+        assert_eq!(
+            spec["annotations"][0]["color"],
+            json!(format!("#{r:02X}{g:02X}{b:02X}"))
+        );
+        let fixed = build_annotate_spec(&regions, &DiffOptions::default(), (240, 120));
+        assert_eq!(fixed["annotations"][0]["color"], json!("#FF453A"));
+        assert_eq!(
+            fixed["annotations"][1]["text_bg"],
+            json!("rgba(255,69,58,0.78)")
+        );
     }
 
-    let auto_fit = defaults
-        .get("auto_fit")
-        .map(|v| value_to_bool(v, true))
-        .unwrap_or(true);
-    if !auto_fit {
-        return None;
-    }
+    #[test]
+    fn annotate_output_defaults_next_to_input_without_clobbering() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("shot.png");
+        let first = annotate_output_path(&input, None);
+        assert_eq!(first, dir.path().join("shot.annotated.png"));
+        fs::write(&first, b"png").unwrap();
+        assert_eq!(
+            annotate_output_path(&input, None),
+            dir.path().join("shot.annotated-2.png")
+        );
 
-    let mut fit = Map::new();
-    fit.insert(
-        "mode".to_string(),
-        defaults
-            .get("fit_mode")
-            .cloned()
-            .unwrap_or_else(|| json!("luma")),
-    );
-    for key in [
-        "fit_threshold",
-        "fit_target",
-        "fit_tolerance",
-        "fit_color",
-        "fit_pad",
-        "fit_min_pixels",
-        "fit_min_coverage",
-    ] {
-        if let Some(value) = defaults.get(key).cloned() {
-            let out_key = key.trim_start_matches("fit_").to_string();
-            fit.insert(out_key, value);
-        }
+        let out_dir = dir.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        assert_eq!(
+            annotate_output_path(&input, Some(&out_dir)),
+            out_dir.join("shot.annotated.png")
+        );
+        let explicit = dir.path().join("custom.png");
+        assert_eq!(annotate_output_path(&input, Some(&explicit)), explicit);
     }
-    Some(Value::Object(fit))
-}
 
-fn apply_fit(
-    ann: &Map<String, Value>,
-    image_rgb: &image::RgbImage,
-    img_w: u32,
-    img_h: u32,
-    defaults: &Map<String, Value>,
-) -> Map<String, Value> {
-    let fit_value = resolve_fit_config(ann, defaults);
-    let Some(fit_value) = fit_value else {
-        return ann.clone();
-    };
-    let Value::Object(fit) = fit_value else {
-        return ann.clone();
-    };
+    #[test]
+    fn diff_fail_fast_stops_once_over_budget() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("baseline.png");
+        let current = dir.path().join("current.png");
+        RgbaImage::from_pixel(50, 40, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        // The top 10 rows change: 25% of the image.
+        RgbaImage::from_fn(50, 40, |_, y| {
+            if y < 10 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        })
+        .save(&current)
+        .unwrap();
+        let diff_out = dir.path().join("diff.png");
+        let run = |limit: f64, json_out: Option<&Path>| {
+            let options = DiffOptions {
+                fail_fast: Some(limit),
+                ..DiffOptions::default()
+            };
+            run_diff_internal(
+                &baseline,
+                &current,
+                Some(&diff_out),
+                json_out,
+                None,
+                None,
+                &options,
+            )
+            .unwrap()
+            .json
+        };
 
-    let mode = value_to_string(fit.get("mode"))
-        .unwrap_or_else(|| "luma".to_string())
-        .to_ascii_lowercase();
-    let region = parse_fit_region(fit.get("region"), ann, img_w, img_h);
-    let min_pixels = value_to_f64(fit.get("min_pixels")).unwrap_or(30.0).max(1.0) as u32;
-    let min_coverage = value_to_f64(fit.get("min_coverage"))
-        .unwrap_or(0.6)
-        .max(0.0);
+        let report = dir.path().join("report.json");
+        let exceeded = run(5.0, Some(&report));
+        assert_eq!(exceeded["fail_fast"]["exceeded"], true);
+        // 3 rows = 150 px = 7.5% is the first row boundary past 5%.
+        assert_eq!(exceeded["fail_fast"]["rows_checked"], 3);
+        assert_eq!(
+            exceeded["fail_fast"]["skipped_outputs"],
+            json!(["--diff-out"])
+        );
+        assert!(!diff_out.exists());
+        assert_eq!(exceeded["diff_image"], Value::Null);
+        assert_eq!(exceeded["changed_pixels"], 150);
+        assert_eq!(exceeded["change_region_count"], Value::Null);
+        let written: Value = serde_json::from_str(&fs::read_to_string(&report).unwrap()).unwrap();
+        assert_eq!(written, exceeded);
+        let mut violations = Vec::new();
+        schema_violations(&diff_schema(), &exceeded, "diff", &mut violations);
+        assert!(violations.is_empty(), "{violations:?}");
+
+        let full = run(30.0, None);
+        assert_eq!(
+            full["fail_fast"],
+            json!({"percent": 30.0, "exceeded": false})
+        );
+        assert_eq!(full["changed_pixels"], 500);
+        assert_eq!(full["change_region_count"], 1);
+        assert!(diff_out.exists());
+        let mut violations = Vec::new();
+        schema_violations(&diff_schema(), &full, "diff", &mut violations);
+        assert!(violations.is_empty(), "{violations:?}");
+    }
 
-    let mut bbox = if mode == "luma" {
-        let threshold = value_to_f64(fit.get("threshold")).unwrap_or(160.0);
-        let target = value_to_string(fit.get("target")).unwrap_or_else(|| "dark".to_string());
-        fit_bbox_luma(image_rgb, region, threshold, &target, min_pixels)
-    } else if mode == "color" {
-        let target_color = parse_color_opt(fit.get("color").or_else(|| fit.get("target_color")));
-        let Some(color) = target_color else {
-            return ann.clone();
+    #[test]
+    fn regions_only_diff_reports_regions_and_timings() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        let mut img = RgbaImage::from_pixel(48, 32, Rgba([255, 255, 255, 255]));
+        img.save(&baseline).unwrap();
+        fill_rect_alpha(&mut img, 8, 8, 23, 19, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+
+        let options = DiffOptions {
+            regions_only: true,
+            timings: true,
+            ..DiffOptions::default()
         };
-        let tolerance = value_to_f64(fit.get("tolerance")).unwrap_or(18.0);
-        fit_bbox_color(image_rgb, region, color, tolerance, min_pixels)
-    } else {
-        return ann.clone();
-    };
+        let output = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        assert_eq!(output["change_region_count"], json!(1));
+        assert_eq!(output["regions_only"], json!(true));
+        for stage in ["decode", "compare", "regions", "outputs", "total"] {
+            assert!(output["timings_ms"][stage].is_number(), "{stage}");
+        }
+        let plain = run_diff_internal(
+            &baseline,
+            &current,
+            None,
+            None,
+            None,
+            None,
+            &DiffOptions::default(),
+        )
+        .unwrap()
+        .json;
+        assert_eq!(plain["timings_ms"], Value::Null);
+    }
 
-    let pad = value_to_f64(fit.get("pad")).unwrap_or(0.0);
-    bbox = expand_bbox(bbox, pad, img_w, img_h);
-    let Some(mut bbox) = bbox else {
-        return ann.clone();
-    };
+    #[test]
+    fn artifact_gitignore_block_is_idempotent_and_keeps_user_lines() {
+        let first = gitignore_with_artifact_block("*.log\n");
+        assert!(first.starts_with("*.log\n\n# >>> codex-visual-loop"));
+        assert!(first.contains("/observe/\n"));
+        assert!(!first.contains("/loop/baselines/"));
+        assert_eq!(gitignore_with_artifact_block(&first), first);
 
-    let region_area = f64::from(region.2.saturating_sub(region.0).max(1))
-        * f64::from(region.3.saturating_sub(region.1).max(1));
-    let bbox_area = f64::from(bbox.2.saturating_sub(bbox.0).max(1))
-        * f64::from(bbox.3.saturating_sub(bbox.1).max(1));
-    if bbox_area / region_area < min_coverage {}
-    bbox = snap_bbox_to_region(region, bbox, img_w, img_h);
+        let dir = tempdir().unwrap();
+        let path = ensure_artifact_gitignore(dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            gitignore_with_artifact_block("")
+        );
 
-    let mut updated = ann.clone();
-    updated.insert("x".to_string(), json!(bbox.0));
-    updated.insert("y".to_string(), json!(bbox.1));
-    updated.insert("w".to_string(), json!(bbox.2.saturating_sub(bbox.0)));
-    updated.insert("h".to_string(), json!(bbox.3.saturating_sub(bbox.1)));
-    updated
-}
+        // Only a missing file starts empty; an unreadable one is an error, not an overwrite.
+        let unreadable = tempdir().unwrap();
+        fs::create_dir(unreadable.path().join(".gitignore")).unwrap();
+        assert!(ensure_artifact_gitignore(unreadable.path()).is_err());
+    }
 
-fn activate_process_window(process: &str) -> QueryDiagnostic {
-    if !cfg!(target_os = "macos") {
-        return QueryDiagnostic {
-            ok: false,
-            attempts: 0,
-            error_code: Some("unsupported_platform".to_string()),
-            message: Some("activation requires macOS".to_string()),
+    #[test]
+    fn nearest_resize_filter_avoids_ringing_diffs() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        // A 2x-upscaled checkerboard: nearest downsampling reproduces the baseline exactly.
+        let small = RgbaImage::from_fn(16, 16, |x, y| {
+            let v = if (x / 4 + y / 4) % 2 == 0 { 0 } else { 255 };
+            Rgba([v, v, v, 255])
+        });
+        small.save(&baseline).unwrap();
+        image::imageops::resize(&small, 32, 32, FilterType::Nearest)
+            .save(&current)
+            .unwrap();
+
+        let diff_with = |filter: &str| {
+            let options = DiffOptions {
+                resize: true,
+                resize_filter: filter.to_string(),
+                ..DiffOptions::default()
+            };
+            run_diff_internal(&baseline, &current, None, None, None, None, &options)
+                .unwrap()
+                .json
         };
+        let nearest = diff_with("nearest");
+        assert_eq!(nearest["percent_changed"], json!(0.0));
+        assert_eq!(nearest["resize_filter"], json!("nearest"));
+        assert!(diff_with("lanczos3")["percent_changed"].as_f64().unwrap() > 0.0);
     }
 
-    let escaped = process.replace('"', "\\\"");
-    let script = format!("try\ntell application \"{escaped}\" to activate\nreturn \"ok\"\non error errMsg number errNum\nreturn \"err:\" & errNum & \":\" & errMsg\nend try");
-    let (stdout, mut diag) = run_osascript_with_retry(&script, &[], 1, 40);
-    if let Some(text) = stdout {
-        if text.starts_with("err:") {
-            let code = text
-                .split(':')
-                .nth(1)
-                .map(|v| format!("osascript_{v}"))
-                .unwrap_or_else(|| "activation_failed".to_string());
-            diag.ok = false;
-            diag.error_code = Some(code);
-            diag.message = Some(text);
-        } else {
-            diag.ok = true;
-            diag.error_code = None;
-            diag.message = Some("activated".to_string());
-        }
+    #[test]
+    fn workflow_steps_chain_outputs_by_reference() {
+        let mut outputs = Map::new();
+        outputs.insert(
+            "cmp".to_string(),
+            json!({"annotate_spec": "/tmp/spec.json", "change_regions": [{"x": 4}], "resized": false}),
+        );
+        assert_eq!(
+            resolve_step_refs("--spec=${cmp.annotate_spec}", &outputs).unwrap(),
+            "--spec=/tmp/spec.json"
+        );
+        assert_eq!(
+            resolve_step_refs("${cmp.change_regions.0.x},${cmp.resized}", &outputs).unwrap(),
+            "4,false"
+        );
+        assert!(resolve_step_refs("${shot.image_path}", &outputs).is_err());
+        assert!(resolve_step_refs("${cmp.missing}", &outputs).is_err());
+
+        let dir = tempdir().unwrap();
+        let base = dir.path().join("a.png");
+        let cur = dir.path().join("b.png");
+        let spec = dir.path().join("spec.json");
+        let mut img = RgbaImage::from_pixel(40, 30, Rgba([255, 255, 255, 255]));
+        img.save(&base).unwrap();
+        fill_rect_alpha(&mut img, 5, 5, 20, 15, Rgba([0, 0, 0, 255]), None);
+        img.save(&cur).unwrap();
+        let argv = |items: &[&str]| -> Vec<String> {
+            std::iter::once("codex-visual-loop")
+                .chain(items.iter().copied())
+                .map(String::from)
+                .collect()
+        };
+        let diff = run_workflow_step(
+            &argv(&[
+                "diff",
+                base.to_str().unwrap(),
+                cur.to_str().unwrap(),
+                "--annotate-spec-out",
+                spec.to_str().unwrap(),
+            ]),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        outputs.insert("cmp".to_string(), diff);
+        let out = dir.path().join("out.png");
+        let spec_arg = resolve_step_refs("${cmp.annotate_spec}", &outputs).unwrap();
+        let annotated = run_workflow_step(
+            &argv(&[
+                "annotate",
+                cur.to_str().unwrap(),
+                out.to_str().unwrap(),
+                "--spec",
+                &spec_arg,
+                "--no-meta",
+            ]),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(annotated["annotation_count"], json!(2));
+        assert!(out.exists());
+        assert!(run_workflow_step(&argv(&["loop", "x.png"]), &OutputOptions::default()).is_err());
     }
-    diag
-}
 
-fn query_window_probe(process: &str) -> WindowProbe {
-    const MIN_USABLE_WINDOW_WIDTH: i64 = 220;
-    const MIN_USABLE_WINDOW_HEIGHT: i64 = 140;
-    const MIN_USABLE_WINDOW_AREA: i64 = 40_000;
+    #[test]
+    fn workflow_manifest_parses_yaml_and_json_by_extension() {
+        let yaml =
+            "steps:\n  - id: shot\n    command: capture\n    args: [\"--process\", \"Finder\"]\n";
+        let parsed = parse_workflow_manifest("flow.YML", yaml).unwrap();
+        assert_eq!(
+            parsed,
+            json!({"steps": [{"id": "shot", "command": "capture", "args": ["--process", "Finder"]}]})
+        );
+        let json_raw = r#"[{"command": "diff", "args": ["a.png", "b.png"]}]"#;
+        assert!(parse_workflow_manifest("flow.json", json_raw)
+            .unwrap()
+            .is_array());
+        assert!(parse_workflow_manifest("-", yaml).is_err());
+        assert!(parse_workflow_manifest("flow.yaml", "steps: [unclosed").is_err());
+    }
 
-    let mut probe = WindowProbe {
-        x: 0,
-        y: 0,
-        w: 0,
-        h: 0,
-        title: None,
-        selected_index: None,
-        candidate_count: 0,
-        usable_count: 0,
-        selection_mode: "none".to_string(),
-        usable: false,
-        min_width: MIN_USABLE_WINDOW_WIDTH,
-        min_height: MIN_USABLE_WINDOW_HEIGHT,
-        min_area: MIN_USABLE_WINDOW_AREA,
-        diagnostics: QueryDiagnostic {
-            ok: false,
-            attempts: 0,
-            error_code: Some("window_query_not_started".to_string()),
-            message: Some("window query not executed".to_string()),
-        },
-    };
+    #[test]
+    fn current_coords_remap_regions_to_unscaled_current() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        RgbaImage::from_pixel(50, 40, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        // 2x current with a block at (40..60, 20..40) => (20..30, 10..20) in baseline space.
+        let mut img = RgbaImage::from_pixel(100, 80, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 40, 20, 59, 39, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+
+        let options = DiffOptions {
+            resize: true,
+            resize_filter: "nearest".to_string(),
+            bbox_pad: 0,
+            bbox_min_area: 1,
+            current_coords: true,
+            ..DiffOptions::default()
+        };
+        let out = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        let base = &out["change_regions"][0];
+        let mapped = &out["change_regions_current"][0];
+        assert_eq!(
+            (base["x"].clone(), base["w"].clone()),
+            (json!(20), json!(10))
+        );
+        assert_eq!(
+            [&mapped["x"], &mapped["y"], &mapped["w"], &mapped["h"]],
+            [&json!(40), &json!(20), &json!(20), &json!(20)]
+        );
+        assert_eq!(out["current_space"]["scale_x"], json!(2.0));
+        assert_eq!(mapped["rel"], base["rel"]);
+    }
 
-    if !cfg!(target_os = "macos") {
-        probe.diagnostics = QueryDiagnostic {
-            ok: false,
-            attempts: 0,
-            error_code: Some("unsupported_platform".to_string()),
-            message: Some("window queries require macOS".to_string()),
+    #[test]
+    fn flatten_bg_hides_rgb_under_transparency() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        // Same visible content; only the RGB under fully transparent pixels differs.
+        RgbaImage::from_pixel(20, 20, Rgba([255, 0, 0, 0]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(20, 20, Rgba([0, 0, 255, 0]));
+        img.put_pixel(3, 3, Rgba([0, 0, 0, 128]));
+        img.save(&current).unwrap();
+
+        let diff_with = |flatten_bg: Option<Rgba<u8>>| {
+            let options = DiffOptions {
+                flatten_bg,
+                ..DiffOptions::default()
+            };
+            run_diff_internal(&baseline, &current, None, None, None, None, &options)
+                .unwrap()
+                .json
         };
-        return probe;
+        assert!(diff_with(None)["percent_changed"].as_f64().unwrap() > 99.0);
+        let flat = diff_with(parse_flatten_bg(Some("#ffffff")).unwrap());
+        assert_eq!(flat["percent_changed"], json!(0.25));
+        assert_eq!(flat["flatten_bg"], json!("#ffffff"));
+
+        let blended = flatten_onto(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 128]))),
+            Rgba([255, 255, 255, 255]),
+        );
+        assert_eq!(
+            blended.to_rgba8().get_pixel(0, 0),
+            &Rgba([127, 127, 127, 255])
+        );
+        assert!(parse_flatten_bg(Some("nope")).is_err());
     }
 
-    let script = r#"
-on cleanText(v)
-  try
-    set t to v as text
-  on error
-    set t to ""
-  end try
-  set AppleScript's text item delimiters to {return, linefeed, tab}
-  set parts to text items of t
-  set AppleScript's text item delimiters to " "
-  set clean to parts as text
-  set AppleScript's text item delimiters to ""
-  return clean
-end cleanText
+    #[test]
+    fn preview_gif_loops_downscaled_frames() {
+        use image::AnimationDecoder;
 
-on emitLine(idxVal, xVal, yVal, wVal, hVal, titleVal)
-  return (idxVal as text) & tab & (xVal as text) & tab & (yVal as text) & tab & (wVal as text) & tab & (hVal as text) & tab & my cleanText(titleVal)
-end emitLine
+        let dir = tempdir().unwrap();
+        let frames: Vec<PathBuf> = [[255, 0, 0, 255], [0, 0, 255, 255]]
+            .iter()
+            .enumerate()
+            .map(|(idx, color)| {
+                let path = dir.path().join(format!("frame-{idx}.png"));
+                RgbaImage::from_pixel(200, 100, Rgba(*color))
+                    .save(&path)
+                    .unwrap();
+                path
+            })
+            .collect();
+        let out = dir.path().join("preview.gif");
+        let delay = preview_gif_delay_ms(2, frames.len());
+        assert_eq!(delay, 1000);
+        write_preview_gif(&frames, &out, delay, 100).unwrap();
+
+        let decoder =
+            image::codecs::gif::GifDecoder::new(io::BufReader::new(File::open(&out).unwrap()))
+                .unwrap();
+        let decoded = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].buffer().dimensions(), (100, 50));
+        assert_eq!(decoded[1].delay().numer_denom_ms(), (1000, 1));
+        assert_eq!(preview_gif_delay_ms(0, 2), 500);
+    }
 
-on run argv
-  set procName to item 1 of argv
-  tell application "System Events"
-    if not (exists process procName) then
-      error "process_not_found"
-    end if
-    tell process procName
-      set winRefs to windows
-      if (count of winRefs) is 0 then
-        error "no_windows"
-      end if
-      set linesOut to {}
-      set idx to 0
-      repeat with winRef in winRefs
-        set idx to idx + 1
-        try
-          set p to position of winRef
-          set s to size of winRef
-          set wx to item 1 of p
-          set wy to item 2 of p
-          set ww to item 1 of s
-          set wh to item 2 of s
-          if ww > 0 and wh > 0 then
-            set wt to ""
-            try
-              set wt to name of winRef
-            end try
-            set end of linesOut to my emitLine(idx, wx, wy, ww, wh, wt)
-          end if
-        end try
-      end repeat
-      if (count of linesOut) is 0 then
-        error "no_window_bounds"
-      end if
-      set AppleScript's text item delimiters to linefeed
-      set outText to linesOut as text
-      set AppleScript's text item delimiters to ""
-      return outText
-    end tell
-  end tell
-end run
-"#;
+    #[test]
+    fn capture_sidecar_seeds_point_units() {
+        let sidecar = json!({
+            "bounds": {"x": 100, "y": 50, "w": 200, "h": 100, "units": "pt"},
+            "image_size": {"w": 400, "h": 200, "units": "px"},
+            "scale": 2.0,
+        });
+        // The annotated image was downscaled to half the captured size since capture.
+        let defaults = capture_sidecar_defaults(&sidecar, (200, 100));
+        assert_eq!(defaults["pt_scale"], json!([1.0, 1.0]));
+        assert_eq!(defaults["pt_origin"], json!([100.0, 50.0]));
+        assert_eq!(defaults["scale"], json!(2.0));
+
+        let full = capture_sidecar_defaults(&sidecar, (400, 200));
+        let mut ann =
+            json!({"type": "rect", "x": 110, "y": 60, "w": 20, "h": "50%", "units": "pt"})
+                .as_object()
+                .unwrap()
+                .clone();
+        ann.extend(full.clone());
+        resolve_annotation_units(&mut ann, 400, 200, &full);
+        assert_eq!(
+            [&ann["x"], &ann["y"], &ann["w"], &ann["h"]],
+            [&json!(20.0), &json!(20.0), &json!(40.0), &json!(100.0)]
+        );
+    }
 
-    let args = vec![process.to_string()];
-    let (raw_lines, raw_diag) = run_osascript_with_retry(script, &args, 3, 120);
-    let attempts = raw_diag.attempts.max(1);
+    #[test]
+    fn type_filters_canonicalize_aliases() {
+        let only =
+            canonical_type_filter(&["Arrow".to_string(), " focus".to_string()], "--only-types")
+                .unwrap();
+        assert_eq!(only, vec!["arrow".to_string(), "spotlight".to_string()]);
+        assert_eq!(canonical_annotation_type("crosshair"), "point");
+        assert!(canonical_type_filter(&[String::new()], "--skip-types")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            canonical_type_filter(&["circle".to_string()], "--only-types").unwrap(),
+            vec!["ellipse".to_string()]
+        );
+        assert!(canonical_type_filter(&["polygon".to_string()], "--only-types").is_err());
+    }
 
-    let Some(lines) = raw_lines else {
-        probe.diagnostics = QueryDiagnostic {
-            ok: false,
-            attempts,
-            error_code: raw_diag
-                .error_code
-                .clone()
-                .or(Some("window_query_empty".to_string())),
-            message: raw_diag
-                .message
-                .clone()
-                .or(Some("window bounds missing".to_string())),
+    #[test]
+    fn offscreen_only_windows_mean_another_space() {
+        let windows =
+            parse_cg_window_lines("412\t0\t0\t25\t1280\t775\n413\t0\t40\t60.4\t300\t200\nbad");
+        assert_eq!(windows.len(), 2);
+        assert_eq!(
+            windows[1],
+            CgWindow {
+                number: 413,
+                onscreen: false,
+                x: 40,
+                y: 60,
+                w: 300,
+                h: 200
+            }
+        );
+        assert!(windows_on_other_space(&windows));
+        assert!(!windows_on_other_space(&parse_cg_window_lines(
+            "412\t1\t0\t25\t1280\t775"
+        )));
+        // "none" (no windows at all) is a different problem, reported by the window probe.
+        assert!(!windows_on_other_space(&parse_cg_window_lines("none")));
+    }
+
+    #[test]
+    fn weight_map_scales_percent_changed() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        let weights = dir.path().join("weights.png");
+        RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        // Left half changes; the map weights the right half 3x as heavily (255 vs 85).
+        let mut img = RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 0, 0, 9, 9, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+        image::GrayImage::from_fn(20, 10, |x, _| image::Luma([if x < 10 { 85 } else { 255 }]))
+            .save(&weights)
+            .unwrap();
+
+        let options = DiffOptions {
+            weight_map: Some(weights.clone()),
+            ..DiffOptions::default()
         };
-        return probe;
-    };
+        let out = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        assert_eq!(
+            out["weight_map"]["unweighted"]["percent_changed"],
+            json!(50.0)
+        );
+        assert_eq!(out["weight_map"]["resized"], json!(false));
+        assert_eq!(out["percent_changed"], json!(25.0));
 
-    let candidates = parse_window_candidates(&lines);
-    if candidates.is_empty() {
-        probe.diagnostics = QueryDiagnostic {
-            ok: false,
-            attempts,
-            error_code: Some("window_bounds_parse_failed".to_string()),
-            message: Some("window bounds output was present but parse failed".to_string()),
+        image::GrayImage::new(4, 4).save(&weights).unwrap();
+        assert!(run_diff_internal(&baseline, &current, None, None, None, None, &options).is_err());
+    }
+
+    #[test]
+    fn identical_flag_catches_changes_below_rounding() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        let img = RgbaImage::from_pixel(1000, 1000, Rgba([255, 255, 255, 255]));
+        img.save(&baseline).unwrap();
+        img.save(&current).unwrap();
+        let diff = || {
+            run_diff_internal(
+                &baseline,
+                &current,
+                None,
+                None,
+                None,
+                None,
+                &DiffOptions::default(),
+            )
+            .unwrap()
+            .json
         };
-        return probe;
+        assert_eq!(diff()["identical"], json!(true));
+
+        // One pixel in a million rounds percent_changed to 0.0 but is not identical.
+        let mut changed = img;
+        changed.put_pixel(5, 5, Rgba([0, 0, 0, 255]));
+        changed.save(&current).unwrap();
+        let out = diff();
+        assert_eq!(out["percent_changed"], json!(0.0));
+        assert_eq!(out["identical"], json!(false));
     }
 
-    probe.candidate_count = candidates.len();
-    let (selected, selection_mode, usable_count) = select_window_candidate(
-        &candidates,
-        MIN_USABLE_WINDOW_WIDTH,
-        MIN_USABLE_WINDOW_HEIGHT,
-        MIN_USABLE_WINDOW_AREA,
-    );
-    probe.x = selected.x;
-    probe.y = selected.y;
-    probe.w = selected.w;
-    probe.h = selected.h;
-    probe.title = selected.title.clone();
-    probe.selected_index = Some(selected.index);
-    probe.selection_mode = selection_mode.to_string();
-    probe.usable_count = usable_count;
-    probe.usable = selected.w >= MIN_USABLE_WINDOW_WIDTH
-        && selected.h >= MIN_USABLE_WINDOW_HEIGHT
-        && selected.w.saturating_mul(selected.h) >= MIN_USABLE_WINDOW_AREA;
+    #[test]
+    fn relative_min_area_scales_with_resolution() {
+        assert_eq!(relative_min_area(0.0, 1920, 1080), 0);
+        // 0.05% of 1080p vs. the same screen at 2x.
+        assert_eq!(relative_min_area(0.05, 1920, 1080), 1037);
+        assert_eq!(relative_min_area(0.05, 3840, 2160), 4148);
+
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        RgbaImage::from_pixel(100, 100, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(100, 100, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 10, 10, 19, 19, Rgba([0, 0, 0, 255]), None);
+        fill_rect_alpha(&mut img, 60, 60, 62, 62, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+        let options = DiffOptions {
+            bbox_min_area: 1,
+            min_area_percent: 0.5,
+            ..DiffOptions::default()
+        };
+        let out = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        // 0.5% of 10_000 = 50px: the 100px block stays, the 9px speck is dropped.
+        assert_eq!(out["min_region_pixels"], json!(50));
+        assert_eq!(out["change_region_count"], json!(1));
+    }
 
-    let selection_note = format!(
-        "selected window {} ({}) {}x{}, candidates={} usable={}",
-        selected.index,
-        selection_mode,
-        selected.w,
-        selected.h,
-        probe.candidate_count,
-        probe.usable_count
-    );
+    #[test]
+    fn fallback_report_default_template_matches_original_layout() {
+        let report = |packet: &Value, codex_meta: &Value| {
+            build_fallback_explain_report(
+                packet,
+                "codex disabled",
+                codex_meta,
+                None,
+                DEFAULT_FALLBACK_REPORT_TEMPLATE,
+                NumberLocale::default(),
+            )
+        };
+        let header = "# App Explanation Report (fallback)\n\n- Generated at: unknown\n- Process: Finder\n- Screenshot: /tmp/shot.png\n\n## Why fallback mode was used\ncodex disabled\n\n## AX summary\n- Element count: 3\n- Named elements: 2\n- Interactive guess count: 1\n";
+        let summary =
+            json!({"element_count": 3, "named_elements": 2, "interactive_guess_count": 1});
+        let mut packet = json!({
+            "process_name": "Finder",
+            "capture": {"capture_path": "/tmp/shot.png"},
+            "summary": summary,
+        });
+        assert_eq!(report(&packet, &json!({})), header);
 
-    probe.diagnostics = QueryDiagnostic {
-        ok: true,
-        attempts,
-        error_code: None,
-        message: Some(selection_note),
-    };
-    probe
-}
+        packet["summary"]["top_roles"] = json!([]);
+        assert_eq!(
+            report(&packet, &json!({})),
+            format!("{header}- Top roles:\n")
+        );
 
-fn run_osascript_with_retry(
-    script: &str,
-    args: &[String],
-    attempts: u32,
-    delay_ms: u64,
-) -> (Option<String>, QueryDiagnostic) {
-    if !cfg!(target_os = "macos") {
-        return (
-            None,
-            QueryDiagnostic {
-                ok: false,
-                attempts: 0,
-                error_code: Some("unsupported_platform".to_string()),
-                message: Some("osascript requires macOS".to_string()),
-            },
+        packet["summary"]["top_roles"] =
+            json!([{"role": "AXButton", "count": 2}, {"role": "AXRow", "count": 1}]);
+        assert_eq!(
+            report(&packet, &json!({"error": "codex exited 1"})),
+            format!(
+                "{header}- Top roles:\n  - AXButton: 2\n  - AXRow: 1\n\n## Codex attempt\n- Error: codex exited 1\n"
+            )
         );
     }
 
-    let max_attempts = attempts.max(1);
-    let timeout_ms = 450u64;
-    let mut last_code = Some("osascript_no_output".to_string());
-    let mut last_message = Some("osascript returned empty output".to_string());
+    #[test]
+    fn fallback_report_fills_custom_templates() {
+        let packet = json!({
+            "process_name": "Slack",
+            "summary": {"element_count": 42, "top_roles": [{"role": "AXButton", "count": 7}]},
+        });
+        let builtin = build_fallback_explain_report(
+            &packet,
+            "no codex",
+            &json!({}),
+            None,
+            DEFAULT_FALLBACK_REPORT_TEMPLATE,
+            NumberLocale::default(),
+        );
+        assert!(builtin.contains("- Process: Slack\n"));
+        assert!(builtin.contains("- Top roles:\n  - AXButton: 7\n"));
+        assert!(builtin.ends_with("AXButton: 7\n"));
 
-    for attempt in 1..=max_attempts {
-        let mut cmd = Command::new("osascript");
-        cmd.arg("-e").arg(script);
-        if !args.is_empty() {
-            cmd.arg("--");
-            for arg in args {
-                cmd.arg(arg);
-            }
-        }
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+        let custom = build_fallback_explain_report(
+            &packet,
+            "no codex",
+            &json!({"error": "timeout"}),
+            None,
+            "## {process} ({element_count})\n{top_roles}\nWhy: {reason}\n{codex_attempt}\n{\"raw\": {unknown}}",
+            NumberLocale::default(),
+        );
+        assert_eq!(
+            custom,
+            "## Slack (42)\n  - AXButton: 7\nWhy: no codex\n## Codex attempt\n- Error: timeout\n{\"raw\": {unknown}}\n"
+        );
 
-        match cmd.spawn() {
-            Ok(mut child) => match child.wait_timeout(Duration::from_millis(timeout_ms)) {
-                Ok(Some(_)) => match child.wait_with_output() {
-                    Ok(output) => {
-                        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                        if output.status.success() && !stdout.is_empty() {
-                            return (
-                                Some(stdout),
-                                QueryDiagnostic {
-                                    ok: true,
-                                    attempts: attempt,
-                                    error_code: None,
-                                    message: None,
-                                },
-                            );
-                        }
+        let failed = json!({"attempted": true, "success": false, "returncode": 1,
+                            "log_path": "/tmp/codex.log", "stdout_tail": "",
+                            "stderr_tail": "error: not logged in\n", "error": "no report"});
+        assert_eq!(codex_log_attachment(&json!({"attempted": false})), None);
+        let logs = codex_log_attachment(&failed).unwrap();
+        assert_eq!(logs["stderr_tail"], "error: not logged in\n");
+        let attached = build_fallback_explain_report(
+            &packet,
+            "no report",
+            &failed,
+            Some(&logs),
+            "{codex_attempt}",
+            NumberLocale::default(),
+        );
+        assert_eq!(
+            attached,
+            "## Codex attempt\n- Error: no report\n- Return code: 1\n- Log: /tmp/codex.log\n\n### stderr\n```text\nerror: not logged in\n```\n"
+        );
+        assert_eq!(markdown_fence("see ```rust``` and ````"), "`````");
+        assert_eq!(markdown_fence("a `b`"), "```");
+    }
 
-                        if output.status.success() {
-                            last_code = Some("osascript_empty_stdout".to_string());
-                            last_message =
-                                Some("osascript succeeded but returned empty output".to_string());
-                        } else {
-                            let code = output.status.code().unwrap_or(1);
-                            last_code = Some(format!("osascript_exit_{code}"));
-                            last_message = Some(if stderr.is_empty() {
-                                format!("osascript failed with status {code}")
-                            } else {
-                                stderr
-                            });
-                        }
-                    }
-                    Err(err) => {
-                        last_code = Some("osascript_wait_output_failed".to_string());
-                        last_message = Some(err.to_string());
-                    }
-                },
-                Ok(None) => {
-                    let _ = child.kill();
-                    let _ = child.wait();
-                    last_code = Some("osascript_timeout".to_string());
-                    last_message = Some(format!(
-                        "osascript timed out after {}ms (attempt {attempt}/{max_attempts})",
-                        timeout_ms
-                    ));
-                }
-                Err(err) => {
-                    let _ = child.kill();
-                    let _ = child.wait();
-                    last_code = Some("osascript_wait_timeout_failed".to_string());
-                    last_message = Some(err.to_string());
-                }
-            },
-            Err(err) => {
-                last_code = Some("osascript_spawn_failed".to_string());
-                last_message = Some(err.to_string());
-            }
-        }
+    #[test]
+    fn ax_window_origin_offsets_nested_bounds() {
+        let dir = tempdir().unwrap();
+        let sidecar = dir.path().join("cap.json");
+        fs::write(
+            &sidecar,
+            r#"{"bounds": {"x": 100, "y": 40, "w": 800, "h": 600}}"#,
+        )
+        .unwrap();
+        let (origin, source) =
+            ax_window_origin("App", Some(&sidecar), &ScriptOverrides::default()).unwrap();
+        assert_eq!((origin, source), ((100, 40), "sidecar"));
+        fs::write(&sidecar, "{}").unwrap();
+        assert!(ax_window_origin("App", Some(&sidecar), &ScriptOverrides::default()).is_err());
+
+        let mut tree = vec![json!({
+            "bounds": {"x": 120, "y": 50, "w": 10, "h": 10, "units": "pt"},
+            "children": [{"bounds": {"x": 130, "y": 90, "w": 5, "h": 5}, "children": []},
+                         {"bounds": null, "children": []}],
+        })];
+        offset_ax_bounds(&mut tree, -origin.0, -origin.1);
+        assert_eq!(tree[0]["bounds"]["x"], json!(20));
+        assert_eq!(tree[0]["children"][0]["bounds"]["y"], json!(50));
+        assert_eq!(tree[0]["bounds"]["w"], json!(10));
+    }
 
-        if attempt < max_attempts {
-            let backoff = delay_ms.saturating_mul(u64::from(attempt));
-            thread::sleep(Duration::from_millis(backoff.max(10)));
+    #[test]
+    fn regions_collage_tiles_each_region_crop() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        let collage = dir.path().join("collage.png");
+        RgbaImage::from_pixel(200, 100, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(200, 100, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 10, 10, 29, 29, Rgba([255, 0, 0, 255]), None);
+        fill_rect_alpha(&mut img, 150, 60, 159, 69, Rgba([0, 0, 255, 255]), None);
+        img.save(&current).unwrap();
+
+        let options = DiffOptions {
+            bbox_min_area: 1,
+            regions_collage: Some(collage.clone()),
+            ..DiffOptions::default()
+        };
+        let out = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        assert_eq!(out["change_region_count"], json!(2));
+        assert!(out["regions_collage"]
+            .as_str()
+            .unwrap()
+            .ends_with("collage.png"));
+
+        // Two tiles side by side; each cell holds the largest crop (20px + 2px pad + 8px context).
+        let sheet = image::open(&collage).unwrap().to_rgba8();
+        assert_eq!(sheet.height(), 8 + 40 + 20 + 8);
+        let colors: std::collections::HashSet<[u8; 4]> = sheet.pixels().map(|p| p.0).collect();
+        assert!(colors.contains(&[255, 0, 0, 255]) && colors.contains(&[0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn identical_row_fast_path_matches_full_scan() {
+        let dir = tempdir().unwrap();
+        let (baseline, current, mask) = (
+            dir.path().join("a.png"),
+            dir.path().join("b.png"),
+            dir.path().join("mask.png"),
+        );
+        let mut base = RgbaImage::from_pixel(60, 400, Rgba([250, 250, 250, 255]));
+        for y in (0..400).step_by(20) {
+            fill_rect_alpha(&mut base, 4, y, 50, y + 6, Rgba([40, 40, 40, 255]), None);
         }
+        base.save(&baseline).unwrap();
+        let mut cur = base.clone();
+        fill_rect_alpha(&mut cur, 10, 100, 30, 112, Rgba([200, 0, 0, 255]), None);
+        cur.put_pixel(59, 399, Rgba([0, 0, 0, 255]));
+        cur.save(&current).unwrap();
+        let mut ignore = RgbaImage::from_pixel(60, 400, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut ignore, 0, 0, 59, 9, Rgba([0, 0, 0, 255]), None);
+        ignore.save(&mask).unwrap();
+
+        let run = |fast: bool| {
+            let options = DiffOptions {
+                bbox_min_area: 1,
+                mask: Some(mask.clone()),
+                histogram: true,
+                ignore_aa: true,
+                ignore_identical_rows: fast,
+                ..DiffOptions::default()
+            };
+            let mut out = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+                .unwrap()
+                .json;
+            let skipped = out["identical_rows_skipped"].take();
+            (out, skipped)
+        };
+        let (full, none) = run(false);
+        let (fast, skipped) = run(true);
+        assert_eq!(none, Value::Null);
+        assert_eq!(skipped, json!(400 - 13 - 1));
+        assert_eq!(fast, full);
+        assert_eq!(full["change_region_count"], json!(2));
     }
 
-    (
-        None,
-        QueryDiagnostic {
-            ok: false,
-            attempts: max_attempts,
-            error_code: last_code,
-            message: last_message,
-        },
-    )
-}
+    #[test]
+    fn actions_manifest_lists_actionable_items_with_px_centers() {
+        let items: Vec<Value> = [
+            json!({"type": "rect", "id": "save", "x": 10, "y": 20, "w": 30, "h": 10,
+                   "action": "click", "next_action": "recapture", "verify": "dialog closes"}),
+            json!({"type": "arrow", "x1": 0, "y1": 0, "x2": 50, "y2": 40, "next_action": "scroll"}),
+            json!({"type": "text", "x": 5, "y": 5, "text": "note"}),
+            // An anchor that never resolved leaves no point to act on.
+            json!({"type": "text", "anchor": "missing", "text": "label", "action": "read"}),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(idx, ann)| annotation_meta_item(idx, ann.as_object().unwrap(), 100, 80))
+        .collect();
+        let manifest = actions_manifest("annotate", Path::new("/tmp/out.png"), (100, 80), &items);
+        assert_eq!(manifest["action_count"], json!(2));
+        let save = &manifest["actions"][0];
+        assert_eq!(save["id"], json!("save"));
+        assert_eq!(save["center"], json!({"x": 25.0, "y": 25.0}));
+        assert_eq!(save["verify"], json!("dialog closes"));
+        assert_eq!(
+            manifest["actions"][1]["center"],
+            json!({"x": 50.0, "y": 40.0})
+        );
+        assert_eq!(manifest["actions"][1]["bbox"], Value::Null);
 
-fn run_osascript_exec_with_retry(
-    script: &str,
-    args: &[String],
-    attempts: u32,
-    delay_ms: u64,
-) -> QueryDiagnostic {
-    if !cfg!(target_os = "macos") {
-        return QueryDiagnostic {
-            ok: false,
-            attempts: 0,
-            error_code: Some("unsupported_platform".to_string()),
-            message: Some("osascript requires macOS".to_string()),
+        // annotate collects actions from resolved items, so anchored text acts on its anchor.
+        let dir = tempdir().unwrap();
+        let (input, output, spec, actions) = (
+            dir.path().join("in.png"),
+            dir.path().join("out.png"),
+            dir.path().join("spec.json"),
+            dir.path().join("actions.json"),
+        );
+        RgbaImage::from_pixel(100, 80, Rgba([255, 255, 255, 255]))
+            .save(&input)
+            .unwrap();
+        let annotations = json!({"annotations": [
+            {"type": "rect", "id": "save", "x": 10, "y": 20, "w": 30, "h": 10},
+            {"type": "text", "anchor": "save", "anchor_pos": "bottom", "text": "label",
+             "action": "read"},
+        ]});
+        fs::write(&spec, annotations.to_string()).unwrap();
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--no-meta",
+            "--actions-out",
+            actions.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        annotate_internal(&args, &OutputOptions::default()).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&actions).unwrap()).unwrap();
+        assert_eq!(written["action_count"], json!(1));
+        assert_eq!(written["actions"][0]["action"], json!("read"));
+        assert_eq!(
+            written["actions"][0]["center"],
+            json!({"x": 25.0, "y": 30.0})
+        );
+
+        let dir = tempdir().unwrap();
+        let (baseline, current, actions) = (
+            dir.path().join("a.png"),
+            dir.path().join("b.png"),
+            dir.path().join("actions.json"),
+        );
+        RgbaImage::from_pixel(40, 40, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(40, 40, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 10, 10, 19, 19, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+        let options = DiffOptions {
+            bbox_min_area: 1,
+            bbox_pad: 0,
+            actions_out: Some(actions.clone()),
+            ..DiffOptions::default()
         };
+        run_diff_internal(&baseline, &current, None, None, None, None, &options).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&actions).unwrap()).unwrap();
+        assert_eq!(written["source"], json!("diff"));
+        assert_eq!(written["action_count"], json!(1));
+        assert_eq!(written["actions"][0]["action"], json!("inspect"));
+        assert_eq!(
+            written["actions"][0]["center"],
+            json!({"x": 15.0, "y": 15.0})
+        );
     }
 
-    let max_attempts = attempts.max(1);
-    let timeout_ms = 1500u64;
-    let mut last_code = Some("osascript_exec_failed".to_string());
-    let mut last_message = Some("osascript execution failed".to_string());
+    #[test]
+    fn tile_report_buckets_changes_per_grid_cell() {
+        assert_eq!(parse_tile_grid(Some("4x6")).unwrap(), Some((4, 6)));
+        assert!(parse_tile_grid(Some("0x2")).is_err());
+        assert!(parse_tile_grid(Some("3")).is_err());
+        // 10 px over 3 tiles: [0,4) [4,7) [7,10), matching x * 3 / 10.
+        assert_eq!(
+            (0..3).map(|i| tile_span(i, 3, 10)).collect::<Vec<_>>(),
+            [(0, 4), (4, 7), (7, 10)]
+        );
 
-    for attempt in 1..=max_attempts {
-        let mut cmd = Command::new("osascript");
-        cmd.arg("-e").arg(script);
-        if !args.is_empty() {
-            cmd.arg("--");
-            for arg in args {
-                cmd.arg(arg);
+        let dir = tempdir().unwrap();
+        let (baseline, current, heatmap) = (
+            dir.path().join("a.png"),
+            dir.path().join("b.png"),
+            dir.path().join("heat.png"),
+        );
+        RgbaImage::from_pixel(90, 60, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(90, 60, Rgba([255, 255, 255, 255]));
+        // Fill the whole top-left tile and a quarter of the bottom-right one.
+        fill_rect_alpha(&mut img, 0, 0, 29, 29, Rgba([0, 0, 0, 255]), None);
+        fill_rect_alpha(&mut img, 75, 45, 89, 59, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+        let options = DiffOptions {
+            tiles: Some((2, 3)),
+            tiles_heatmap: Some(heatmap.clone()),
+            ..DiffOptions::default()
+        };
+        let out = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        let tiles = &out["tiles"];
+        assert_eq!(tiles["cells"].as_array().unwrap().len(), 6);
+        assert_eq!(tiles["cells"][0]["percent_changed"], json!(100.0));
+        assert_eq!(tiles["cells"][5]["percent_changed"], json!(25.0));
+        assert_eq!(tiles["cells"][5]["changed_pixels"], json!(225));
+        assert_eq!(tiles["changed_tile_count"], json!(2));
+        assert_eq!(
+            tiles["hottest"],
+            json!({"row": 0, "col": 0, "percent_changed": 100.0})
+        );
+        assert!(tiles["heatmap"].as_str().unwrap().ends_with("heat.png"));
+        let sheet = image::open(&heatmap).unwrap().to_rgba8();
+        assert_eq!(sheet.dimensions(), (90, 60));
+        assert_eq!(sheet.get_pixel(45, 15), &Rgba([255, 255, 255, 255]));
+        assert!(sheet.get_pixel(20, 20)[0] > sheet.get_pixel(20, 20)[1]);
+    }
+
+    #[test]
+    fn perceptual_hashes_tolerate_noise_but_not_layout_changes() {
+        let half = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 16 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
             }
+        }));
+        assert_eq!(hash_hex(&average_hash(&half, 4)), "3333");
+        assert_eq!(hash_hex(&block_hash(&half, 4)), "3333");
+
+        let screen = |seed: u32, moved: bool| {
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(120, 80, |x, y| {
+                let panel = if moved { x >= 70 } else { x < 50 };
+                let base: u32 = if panel && y > 10 { 40 } else { 160 };
+                let base = base + (x + y) / 4;
+                // Low-amplitude dither stands in for antialiasing/font rendering noise.
+                let noise = (x.wrapping_mul(7) ^ y.wrapping_mul(13) ^ seed) % 5;
+                let v = (base + noise) as u8;
+                Rgba([v, v, v, 255])
+            }))
+        };
+        let distance = |a: &[bool], b: &[bool]| a.iter().zip(b).filter(|(x, y)| x != y).count();
+        for hash in [average_hash, block_hash] {
+            let base = hash(&screen(0, false), 16);
+            assert_eq!(base.len(), 256);
+            // Within the default --max-distance (10% of the bits) despite different noise.
+            assert!(distance(&base, &hash(&screen(3, false), 16)) <= base.len() / 10);
+            assert!(distance(&base, &hash(&screen(0, true), 16)) > 60);
         }
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+    }
 
-        match cmd.spawn() {
-            Ok(mut child) => match child.wait_timeout(Duration::from_millis(timeout_ms)) {
-                Ok(Some(_)) => match child.wait_with_output() {
-                    Ok(output) => {
-                        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                        if output.status.success() {
-                            return QueryDiagnostic {
-                                ok: true,
-                                attempts: attempt,
-                                error_code: None,
-                                message: Some("executed".to_string()),
-                            };
-                        }
-                        let code = output.status.code().unwrap_or(1);
-                        last_code = Some(format!("osascript_exit_{code}"));
-                        last_message = Some(if stderr.is_empty() {
-                            format!("osascript failed with status {code}")
-                        } else {
-                            stderr
-                        });
-                    }
-                    Err(err) => {
-                        last_code = Some("osascript_wait_output_failed".to_string());
-                        last_message = Some(err.to_string());
-                    }
-                },
-                Ok(None) => {
-                    let _ = child.kill();
-                    let _ = child.wait();
-                    last_code = Some("osascript_timeout".to_string());
-                    last_message = Some(format!(
-                        "osascript timed out after {}ms (attempt {attempt}/{max_attempts})",
-                        timeout_ms
-                    ));
-                }
-                Err(err) => {
-                    let _ = child.kill();
-                    let _ = child.wait();
-                    last_code = Some("osascript_wait_timeout_failed".to_string());
-                    last_message = Some(err.to_string());
-                }
-            },
-            Err(err) => {
-                last_code = Some("osascript_spawn_failed".to_string());
-                last_message = Some(err.to_string());
-            }
+    #[test]
+    fn diff_dir_pairs_by_filename_and_ranks_worst() {
+        let dir = tempdir().unwrap();
+        let (base, cur) = (dir.path().join("base"), dir.path().join("cur"));
+        fs::create_dir_all(&base).unwrap();
+        fs::create_dir_all(&cur).unwrap();
+        let white = RgbaImage::from_pixel(40, 40, Rgba([255, 255, 255, 255]));
+        for name in ["same.png", "small.png", "big.png", "gone.png"] {
+            white.save(base.join(name)).unwrap();
         }
+        white.save(cur.join("same.png")).unwrap();
+        let mut small = white.clone();
+        fill_rect_alpha(&mut small, 0, 0, 9, 9, Rgba([0, 0, 0, 255]), None);
+        small.save(cur.join("small.png")).unwrap();
+        let mut big = white.clone();
+        fill_rect_alpha(&mut big, 0, 0, 19, 19, Rgba([0, 0, 0, 255]), None);
+        big.save(cur.join("big.png")).unwrap();
+        white.save(cur.join("new.png")).unwrap();
+        fs::write(cur.join("notes.txt"), "skip").unwrap();
+
+        let args = DiffDirArgs {
+            baseline_dir: base,
+            current_dir: cur,
+            out_dir: None,
+            resize: false,
+            bbox_threshold: 24,
+            bbox_min_area: 1,
+            annotated: false,
+            top: 5,
+        };
+        let out_dir = dir.path().join("out");
+        let summary = diff_dir_internal(&args, &out_dir, &OutputOptions::default()).unwrap();
+        assert_eq!(summary["pair_count"], json!(3));
+        assert_eq!(summary["changed_count"], json!(2));
+        assert_eq!(summary["identical_count"], json!(1));
+        assert_eq!(summary["total_changed_pixels"], json!(500));
+        assert_eq!(summary["worst"][0]["name"], json!("big.png"));
+        assert_eq!(summary["worst"][1]["name"], json!("small.png"));
+        assert_eq!(summary["only_in_baseline"], json!(["gone.png"]));
+        assert_eq!(summary["only_in_current"], json!(["new.png"]));
+        assert!(out_dir.join("reports/big.png.json").exists());
+    }
 
-        if attempt < max_attempts {
-            let backoff = delay_ms.saturating_mul(u64::from(attempt));
-            thread::sleep(Duration::from_millis(backoff.max(10)));
+    #[test]
+    fn shadow_border_trims_translucent_edges_only() {
+        let mut img = RgbaImage::from_pixel(40, 30, Rgba([0, 0, 0, 0]));
+        // Soft shadow: translucent ring around an opaque window with rounded-ish corners.
+        fill_rect_alpha(&mut img, 2, 1, 37, 27, Rgba([0, 0, 0, 60]), None);
+        for y in 4..24 {
+            for x in 6..34 {
+                img.put_pixel(x, y, Rgba([200, 200, 200, 255]));
+            }
         }
+        img.put_pixel(6, 4, Rgba([0, 0, 0, 60]));
+        img.put_pixel(33, 23, Rgba([0, 0, 0, 60]));
+        assert_eq!(shadow_border(&img), Some((6, 4, 6, 6)));
+
+        let opaque = RgbaImage::from_pixel(10, 10, Rgba([1, 2, 3, 255]));
+        assert_eq!(shadow_border(&opaque), None);
+        assert_eq!(shadow_border(&RgbaImage::new(10, 10)), None);
     }
 
-    QueryDiagnostic {
-        ok: false,
-        attempts: max_attempts,
-        error_code: last_code,
-        message: last_message,
+    #[test]
+    fn shadow_trim_keeps_redaction_on_the_same_pixels() {
+        let field = json!({"class": "AXSecureTextField",
+                           "bounds": {"x": 140, "y": 80, "w": 60, "h": 12}});
+        let bounds = (100, 50, 200, 100);
+        let (left, top, right, bottom) = (6, 4, 6, 8);
+        let before = secure_field_pixel_regions(std::slice::from_ref(&field), bounds, (400, 200));
+        let trimmed_size = (400 - left - right, 200 - top - bottom);
+        let trimmed = trim_window_bounds(bounds, (400, 200), (left, top, right, bottom));
+        assert_eq!(trimmed, (103, 52, 194, 94));
+        let after = secure_field_pixel_regions(&[field], trimmed, trimmed_size);
+        let shifted: Vec<_> = before
+            .iter()
+            .map(|&(x, y, w, h)| (x - left, y - top, w, h))
+            .collect();
+        assert_eq!(after, shifted);
+        // The reported scale is the same 2 px/pt before and after the trim.
+        assert_eq!(f64::from(trimmed_size.0) / trimmed.2 as f64, 2.0);
+
+        let windows = parse_cg_window_lines("7\t0\t100\t50\t200\t100\n9\t1\t100\t50\t200\t100");
+        assert_eq!(cg_window_id(&windows, bounds), Some(9));
+        assert_eq!(cg_window_id(&windows, (0, 0, 10, 10)), None);
     }
-}
 
-fn parse_coord_pair(raw: &str) -> Option<(i64, i64)> {
-    let mut parts = raw.split(',');
-    let x = parts.next()?.trim().parse::<f64>().ok()?.round() as i64;
-    let y = parts.next()?.trim().parse::<f64>().ok()?.round() as i64;
-    if parts.next().is_some() {
-        return None;
+    #[test]
+    fn png_compression_levels_keep_pixels_and_trade_size() {
+        let dir = tempdir().unwrap();
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(256, 128, |x, y| {
+            Rgba([(x % 16 * 16) as u8, (y / 8 * 16) as u8, 90, 255])
+        }));
+        let mut sizes = Vec::new();
+        for level in ["default", "fast", "best"] {
+            let path = dir.path().join(format!("{level}.png"));
+            save_png(&img, &path, level, true).unwrap();
+            assert_eq!(image::open(&path).unwrap().to_rgba8(), img.to_rgba8());
+            sizes.push(fs::metadata(&path).unwrap().len());
+        }
+        assert!(
+            sizes[2] <= sizes[1],
+            "best is no larger than fast: {sizes:?}"
+        );
+        // Non-PNG outputs ignore the setting.
+        let jpeg = dir.path().join("shot.jpg");
+        save_png(&img, &jpeg, "best", false).unwrap();
+        assert_eq!(image::open(&jpeg).unwrap().dimensions(), (256, 128));
+
+        for command in [
+            vec!["capture"],
+            vec!["annotate", "in.png", "--spec", "spec.json"],
+            vec!["diff", "a.png", "b.png"],
+        ] {
+            let parse = |level: &str| {
+                let argv = ["codex-visual-loop"]
+                    .into_iter()
+                    .chain(command.iter().copied())
+                    .chain(["--png-compression", level]);
+                Cli::try_parse_from(argv)
+            };
+            assert!(parse("fast").is_ok() && parse("best").is_ok());
+            assert!(parse("max").is_err(), "{command:?} rejects unknown levels");
+        }
     }
-    Some((x, y))
-}
 
-fn escape_osascript_string(input: &str) -> String {
-    input.replace('\\', "\\\\").replace('"', "\\\"")
-}
+    #[test]
+    fn verify_written_image_rejects_truncated_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("shot.png");
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 48, |x, y| {
+            Rgba([(x * 4) as u8, (y * 5) as u8, 90, 255])
+        }));
+        save_png(&img, &path, "default", true).unwrap();
+        verify_written_image(&path, (64, 48)).unwrap();
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "diff",
+            "a.png",
+            "b.png",
+            "--verify-output",
+        ]);
+        assert!(OutputOptions::from_cli(&cli.unwrap()).verify_output);
+        let wrong = verify_written_image(&path, (64, 49))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            wrong.contains("reads back as 64x48, expected 64x49"),
+            "{wrong}"
+        );
 
-fn parse_hotkey_combo(raw: &str) -> Result<HotkeySpec> {
-    let combo = raw.trim().to_string();
-    if combo.is_empty() {
-        bail!("hotkey combo is empty");
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        let truncated = verify_written_image(&path, (64, 48))
+            .unwrap_err()
+            .to_string();
+        assert!(truncated.contains("does not decode"), "{truncated}");
     }
-    let tokens: Vec<String> = combo
-        .split('+')
-        .map(|item| item.trim().to_ascii_lowercase())
-        .filter(|item| !item.is_empty())
-        .collect();
-    if tokens.is_empty() {
-        bail!("hotkey combo is empty");
+
+    #[test]
+    fn capture_meta_only_writes_sidecar_without_image() {
+        let dir = tempdir().unwrap();
+        let shot = dir.path().join("probe.png");
+        let sidecar = dir.path().join("probe.json");
+        let options = CaptureOptions {
+            meta_only: true,
+            ..CaptureOptions::default()
+        };
+        let payload = capture_internal(
+            &shot,
+            Some("App".to_string()),
+            None,
+            None,
+            Some(&sidecar),
+            &options,
+        )
+        .unwrap();
+        assert!(!shot.exists(), "no PNG, not even a placeholder");
+        assert_eq!(payload["capture_mode"], "meta_only");
+        assert_eq!(payload["fallback_used"], false);
+        assert!(payload["image_path"].is_null());
+        assert!(payload["image_size"]["w"].is_null() && payload["scale"].is_null());
+        let written: Value = serde_json::from_str(&fs::read_to_string(&sidecar).unwrap()).unwrap();
+        assert_eq!(written["capture_mode"], "meta_only");
+        let mut violations = Vec::new();
+        schema_violations(&capture_schema(), &payload, "capture", &mut violations);
+        assert!(violations.is_empty(), "{violations:#?}");
+
+        let conflict = ["codex-visual-loop", "capture", "--meta-only", "--strict"];
+        assert!(Cli::try_parse_from(conflict).is_err());
     }
 
-    let mut modifiers = Vec::<String>::new();
-    let mut key_token: Option<String> = None;
+    #[test]
+    fn capture_command_retries_transient_failures() {
+        assert_eq!(capture_retry_delay_ms(250, 1), 250);
+        assert_eq!(capture_retry_delay_ms(250, 3), 1000);
+        assert_eq!(capture_retry_delay_ms(250, 9), SCREENCAPTURE_RETRY_MAX_MS);
 
-    for token in tokens {
-        let mapped = match token.as_str() {
-            "cmd" | "command" => Some("command down"),
-            "ctrl" | "control" => Some("control down"),
-            "alt" | "option" | "opt" => Some("option down"),
-            "shift" => Some("shift down"),
-            "fn" | "function" => Some("fn down"),
-            _ => None,
-        };
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("shot.png");
+        let counter = dir.path().join("count");
+        // Fails twice, then writes the output path it was handed (as $0).
+        let script = format!(
+            "n=$(cat {c} 2>/dev/null || echo 0); n=$((n+1)); echo $n > {c}; [ $n -ge 3 ] && touch \"$0\"",
+            c = counter.display()
+        );
+        let args = ["-c".to_string(), script];
+        let failed = run_capture_command_with_retry("sh", &args, &out, 1, 1);
+        assert!(!failed.ok);
+        assert_eq!(failed.attempts, 2);
+        assert_eq!(failed.error_code.as_deref(), Some("sh_exit_1"));
+
+        fs::remove_file(&counter).unwrap();
+        let recovered = run_capture_command_with_retry("sh", &args, &out, 3, 1);
+        assert!(recovered.ok);
+        assert_eq!(recovered.attempts, 3);
+        assert!(out.is_file());
+    }
 
-        if let Some(modifier) = mapped {
-            if !modifiers.iter().any(|m| m == modifier) {
-                modifiers.push(modifier.to_string());
+    // Just enough JSON Schema (type/const/enum/anyOf/required/properties/items) to check the
+    // payload contracts; also rejects object keys the schema doesn't list.
+    fn schema_violations(schema: &Value, value: &Value, path: &str, out: &mut Vec<String>) {
+        if let Some(options) = schema.get("anyOf").and_then(Value::as_array) {
+            let matched = options.iter().any(|option| {
+                let mut inner = Vec::new();
+                schema_violations(option, value, path, &mut inner);
+                inner.is_empty()
+            });
+            if !matched {
+                out.push(format!("{path}: no anyOf branch matches {value}"));
+            }
+            return;
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                out.push(format!("{path}: expected {expected}, got {value}"));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                out.push(format!("{path}: {value} not in enum"));
+            }
+        }
+        let type_ok = match schema.get("type").and_then(Value::as_str) {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("boolean") => value.is_boolean(),
+            Some("number") => value.is_number(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("null") => value.is_null(),
+            _ => true,
+        };
+        if !type_ok {
+            out.push(format!("{path}: expected {}, got {value}", schema["type"]));
+            return;
+        }
+        if let (Some(obj), Some(properties)) = (
+            value.as_object(),
+            schema.get("properties").and_then(Value::as_object),
+        ) {
+            for key in schema["required"].as_array().into_iter().flatten() {
+                if !obj.contains_key(key.as_str().unwrap()) {
+                    out.push(format!("{path}.{}: missing", key.as_str().unwrap()));
+                }
+            }
+            for (key, item) in obj {
+                match properties.get(key) {
+                    Some(sub) => schema_violations(sub, item, &format!("{path}.{key}"), out),
+                    None => out.push(format!("{path}.{key}: not in schema")),
+                }
+            }
+        }
+        if let (Some(items), Some(schema)) = (value.as_array(), schema.get("items")) {
+            for (idx, item) in items.iter().enumerate() {
+                schema_violations(schema, item, &format!("{path}[{idx}]"), out);
             }
-        } else {
-            key_token = Some(token);
         }
     }
 
-    let key = key_token.ok_or_else(|| anyhow::anyhow!("hotkey requires a key token"))?;
-    let key_code = match key.as_str() {
-        "enter" | "return" => Some(36),
-        "tab" => Some(48),
-        "space" => Some(49),
-        "esc" | "escape" => Some(53),
-        "delete" | "backspace" => Some(51),
-        "left" => Some(123),
-        "right" => Some(124),
-        "down" => Some(125),
-        "up" => Some(126),
-        _ => None,
-    };
-
-    Ok(HotkeySpec {
-        combo,
-        key,
-        modifiers,
-        key_code,
-    })
-}
+    #[test]
+    fn payload_schemas_match_emitted_json() {
+        let dir = tempdir().unwrap();
+        let baseline = dir.path().join("a.png");
+        let current = dir.path().join("b.png");
+        RgbaImage::from_pixel(40, 30, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(40, 30, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 4, 4, 20, 20, Rgba([0, 0, 0, 255]), None);
+        img.save(&current).unwrap();
+
+        let check = |name: &str, value: &Value| {
+            let mut violations = Vec::new();
+            schema_violations(&payload_schema(name).unwrap(), value, name, &mut violations);
+            assert!(violations.is_empty(), "{violations:#?}");
+        };
+        let options = DiffOptions {
+            bbox_min_area: 1,
+            current_coords: true,
+            ..DiffOptions::default()
+        };
+        let diff = run_diff_internal(&baseline, &current, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        assert!(!diff["change_regions"].as_array().unwrap().is_empty());
+        check("diff", &diff);
+        let mut drifted = diff.clone();
+        drifted["identical"] = json!("no");
+        drifted["new_key"] = json!(1);
+        let mut violations = Vec::new();
+        schema_violations(
+            &payload_schema("diff").unwrap(),
+            &drifted,
+            "diff",
+            &mut violations,
+        );
+        assert_eq!(violations.len(), 2, "{violations:#?}");
+
+        let shot = dir.path().join("shot.png");
+        let sidecar = dir.path().join("shot.json");
+        let capture = capture_internal(
+            &shot,
+            Some("App".to_string()),
+            Some("before"),
+            None,
+            Some(&sidecar),
+            &CaptureOptions::default(),
+        )
+        .unwrap();
+        check("capture", &capture);
+
+        let spec = dir.path().join("spec.json");
+        fs::write(
+            &spec,
+            r#"{"annotations": [{"type": "rect", "x": 2, "y": 2, "w": 10, "h": 8, "intent": "cta"},
+                                {"type": "text", "x": 4, "y": 20, "text": "hi"}]}"#,
+        )
+        .unwrap();
+        let meta = dir.path().join("out.json");
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            baseline.to_str().unwrap(),
+            dir.path().join("out.png").to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--meta-out",
+            meta.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        annotate_internal(&args, &OutputOptions::default()).unwrap();
+        let meta: Value = serde_json::from_str(&fs::read_to_string(&meta).unwrap()).unwrap();
+        check("annotate-meta", &meta);
 
-fn hotkey_script(spec: &HotkeySpec) -> String {
-    let modifiers = if spec.modifiers.is_empty() {
-        None
-    } else {
-        Some(format!("{{{}}}", spec.modifiers.join(", ")))
-    };
+        assert!(SCHEMA_NAMES
+            .iter()
+            .all(|name| payload_schema(name).is_some()));
+        assert!(payload_schema("bogus").is_none());
+    }
 
-    if let Some(code) = spec.key_code {
-        if let Some(using) = modifiers {
-            format!("tell application \"System Events\" to key code {code} using {using}")
-        } else {
-            format!("tell application \"System Events\" to key code {code}")
-        }
-    } else {
-        let key_text = if spec.key == "space" {
-            " ".to_string()
-        } else {
-            escape_osascript_string(&spec.key)
+    #[test]
+    fn ax_spacing_measures_gaps_between_adjacent_siblings() {
+        let node = |index: u64, x: i64, y: i64, w: i64, h: i64| {
+            json!({"index": index, "bounds": {"x": x, "y": y, "w": w, "h": h, "units": "pt"},
+                   "children": []})
         };
-        if let Some(using) = modifiers {
-            format!("tell application \"System Events\" to keystroke \"{key_text}\" using {using}")
-        } else {
-            format!("tell application \"System Events\" to keystroke \"{key_text}\"")
+        // a | 8pt | b | 20pt | c in a row; d 12pt below a; e overlaps nothing.
+        let mut toolbar = node(0, 0, 0, 200, 100);
+        toolbar["children"] = json!([
+            node(1, 10, 10, 20, 10),
+            node(2, 38, 10, 20, 10),
+            node(3, 78, 12, 20, 10),
+            node(4, 10, 32, 20, 10),
+            node(5, 150, 80, 5, 5),
+        ]);
+        let ax = json!({"tree": [toolbar]});
+        let measures = ax_spacing_measures(&ax);
+        let gaps: Vec<(Value, Value, Value)> = measures
+            .iter()
+            .map(|m| (m["ax_from"].clone(), m["ax_to"].clone(), m["issue"].clone()))
+            .collect();
+        assert_eq!(
+            gaps,
+            [
+                (json!(1), json!(2), json!("8pt gap")),
+                (json!(1), json!(4), json!("12pt gap")),
+                (json!(2), json!(3), json!("20pt gap")),
+            ]
+        );
+        let first = &measures[0];
+        assert_eq!(
+            (first["x1"].clone(), first["x2"].clone()),
+            (json!(30.0), json!(38.0))
+        );
+        assert_eq!(first["y1"], json!(15.0));
+        assert_eq!(first["id"], json!("ax-gap-1"));
+        assert!(first.get("pt_origin").is_none());
+
+        // Resolved through pt units at 2x and drawn with a px label.
+        let mut ann = first.as_object().unwrap().clone();
+        let defaults = json!({"pt_scale": 2.0, "pt_origin": [0, 0]});
+        for (k, v) in defaults.as_object().unwrap() {
+            ann.insert(k.clone(), v.clone());
         }
+        resolve_annotation_units(&mut ann, 200, 100, defaults.as_object().unwrap());
+        assert_eq!(
+            (ann["x1"].clone(), ann["x2"].clone()),
+            (json!(60.0), json!(76.0))
+        );
+        let mut img = RgbaImage::from_pixel(200, 100, Rgba([255, 255, 255, 255]));
+        draw_measure_annotation(&mut img, &ann, 1.0, None);
+        assert_eq!(img.get_pixel(68, 30), &Rgba([255, 45, 85, 255]));
+        assert_eq!(img.get_pixel(68, 60), &Rgba([255, 255, 255, 255]));
+
+        let window = json!({"coordinate_space": {"space": "window"}, "tree": ax["tree"]});
+        assert_eq!(ax_spacing_measures(&window)[0]["pt_origin"], json!([0, 0]));
     }
-}
 
-fn parse_window_candidates(raw: &str) -> Vec<WindowCandidate> {
-    let mut items = Vec::new();
-    for line in raw.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            continue;
-        }
-        let parts: Vec<&str> = trimmed.split('\t').collect();
-        if parts.len() < 5 {
-            continue;
+    #[test]
+    fn clip_confines_annotation_drawing_to_its_rect() {
+        let white = Rgba([255, 255, 255, 255]);
+        let red = Rgba([255, 0, 0, 255]);
+        let draw = |ann: Value| {
+            let mut img = RgbaImage::from_pixel(40, 20, white);
+            let mut ann = ann.as_object().unwrap().clone();
+            resolve_annotation_units(&mut ann, 40, 20, &Map::new());
+            draw_prepared_annotation(&mut img, &ann, 1.0, &Map::new(), &FontCache::default());
+            img
+        };
+        let unclipped = draw(json!({"type": "rect", "x": 2, "y": 2, "w": 36, "h": 16,
+                                    "fill": "#FF0000", "outline": false}));
+        assert_eq!(unclipped.get_pixel(30, 10), &red);
+
+        let clipped = draw(json!({"type": "rect", "x": 2, "y": 2, "w": 36, "h": 16,
+                                  "fill": "#FF0000", "outline": false,
+                                  "clip": {"x": 0, "y": 0, "w": "50%", "h": 20}}));
+        assert_eq!(clipped.get_pixel(10, 10), &red);
+        assert_eq!(clipped.get_pixel(19, 10), &red);
+        assert_eq!(clipped.get_pixel(20, 10), &white);
+        assert_eq!(clipped.get_pixel(30, 10), &white);
+
+        // Text and its background stop at the clip edge too.
+        let text = json!({"type": "text", "x": 4, "y": 4, "text": "WWWW", "bg": "#000000",
+                          "color": "#FF0000", "outline": false,
+                          "clip": {"x": 0, "y": 0, "w": 12, "h": 20}});
+        let text = draw(text);
+        assert_ne!(text.get_pixel(6, 6), &white);
+        assert!((12..40).all(|x| (0..20).all(|y| text.get_pixel(x, y) == &white)));
+
+        let ann = json!({"clip": [-5, 5, 100, 4]});
+        assert_eq!(
+            annotation_clip(ann.as_object().unwrap(), 40, 20),
+            Some((0, 5, 40, 9))
+        );
+        let full = json!({"clip": {"x": 0, "y": 0, "w": 40, "h": 20}});
+        assert_eq!(annotation_clip(full.as_object().unwrap(), 40, 20), None);
+
+        let mut pt = json!({"units": "pt", "pt_scale": 2, "pt_origin": [10, 0],
+                            "clip": {"x": 12, "y": 1, "w": 5, "h": 5}})
+        .as_object()
+        .unwrap()
+        .clone();
+        resolve_annotation_units(&mut pt, 40, 20, &Map::new());
+        assert_eq!(
+            pt["clip"],
+            json!({"x": 4.0, "y": 2.0, "w": 10.0, "h": 10.0})
+        );
+    }
+
+    #[test]
+    fn cb_sim_collapses_red_green_for_dichromats() {
+        let mut img = RgbaImage::new(3, 1);
+        img.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 0, Rgba([0, 160, 0, 255]));
+        img.put_pixel(2, 0, Rgba([128, 128, 128, 200]));
+        let distance = |a: &Rgba<u8>, b: &Rgba<u8>| -> i32 {
+            (0..3)
+                .map(|i| (i32::from(a[i]) - i32::from(b[i])).abs())
+                .sum()
+        };
+        let original_gap = distance(img.get_pixel(0, 0), img.get_pixel(1, 0));
+        for kind in ["protanopia", "deuteranopia"] {
+            let sim = simulate_color_vision(&img, kind);
+            assert!(
+                distance(sim.get_pixel(0, 0), sim.get_pixel(1, 0)) < original_gap / 2,
+                "{kind}"
+            );
+            let gray = sim.get_pixel(2, 0);
+            assert!(
+                (0..3).all(|i| gray[i].abs_diff(128) <= 2),
+                "{kind} keeps neutrals"
+            );
+            assert_eq!(gray[3], 200);
         }
-        let index = match parts[0].trim().parse::<usize>() {
-            Ok(v) if v > 0 => v,
-            _ => continue,
+        let tritan = simulate_color_vision(&img, "tritanopia");
+        assert!(distance(tritan.get_pixel(0, 0), tritan.get_pixel(1, 0)) > original_gap / 2);
+
+        let dir = tempdir().unwrap();
+        let sim_path = write_cb_sim(
+            &img,
+            &dir.path().join("diff.png"),
+            "deuteranopia",
+            "fast",
+            false,
+        )
+        .unwrap();
+        assert!(sim_path.ends_with("diff.deuteranopia.png"));
+        assert_eq!(image::open(&sim_path).unwrap().dimensions(), (3, 1));
+    }
+
+    #[test]
+    fn await_element_polls_until_a_matching_node_appears() {
+        let element = |name: &str, role: &str| json!({"index": 0, "class": "UI element", "name": name, "role_description": role});
+        let result = |elements: Vec<Value>| AxQueryResult {
+            elements,
+            tree: Vec::new(),
+            diagnostics: QueryDiagnostic {
+                ok: true,
+                attempts: 1,
+                error_code: None,
+                message: None,
+            },
+            warnings: Warnings::default(),
         };
-        let x = match parts[1].trim().parse::<f64>() {
-            Ok(v) => v.round() as i64,
-            _ => continue,
+        let matcher = AxMatcher {
+            name: Some("save".to_string()),
+            role: Some("Button".to_string()),
         };
-        let y = match parts[2].trim().parse::<f64>() {
-            Ok(v) => v.round() as i64,
-            _ => continue,
+        assert!(matcher.matches(&element("Save Changes", "button")));
+        assert!(!matcher.matches(&element("Save Changes", "text field")));
+        assert!(!matcher.matches(&element("Cancel", "button")));
+        let by_class = AxMatcher {
+            name: None,
+            role: Some("BUTTON".to_string()),
         };
-        let w = match parts[3].trim().parse::<f64>() {
-            Ok(v) => v.round() as i64,
-            _ => continue,
+        assert!(by_class.matches(&json!({"class": "button", "name": null})));
+
+        let mut polls = 0;
+        let outcome = await_ax_element(
+            || {
+                polls += 1;
+                if polls < 3 {
+                    result(vec![element("Loading", "progress indicator")])
+                } else {
+                    result(vec![element("Cancel", "button"), element("Save", "button")])
+                }
+            },
+            &matcher,
+            1,
+            5000,
+        );
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(outcome.element.unwrap()["name"], json!("Save"));
+
+        let timed_out = await_ax_element(|| result(Vec::new()), &matcher, 5, 20);
+        assert!(timed_out.element.is_none());
+        assert!(timed_out.attempts >= 2);
+        assert!(timed_out.waited_ms >= 20);
+    }
+
+    // One-glyph TrueType font: 'A' maps to a 800x800-unit square on a 1000-unit em.
+    fn square_glyph_ttf() -> Vec<u8> {
+        let be16 = |v: i32| (v as u16).to_be_bytes();
+        let mut head = vec![0u8; 54];
+        head[18..20].copy_from_slice(&be16(1000));
+        head[50..52].copy_from_slice(&be16(1));
+        let mut hhea = vec![0u8; 36];
+        hhea[4..6].copy_from_slice(&be16(800));
+        hhea[6..8].copy_from_slice(&be16(-200));
+        hhea[34..36].copy_from_slice(&be16(2));
+        let maxp = [0, 0, 0x50, 0, 0, 2].to_vec();
+        let hmtx = [be16(500), be16(0), be16(1000), be16(0)].concat();
+        let square = [
+            [
+                be16(1),
+                be16(100),
+                be16(0),
+                be16(900),
+                be16(800),
+                be16(3),
+                be16(0),
+            ]
+            .concat(),
+            vec![1, 1, 1, 1],
+            [be16(100), be16(0), be16(800), be16(0)].concat(),
+            [be16(0), be16(800), be16(0), be16(-800)].concat(),
+        ]
+        .concat();
+        let loca = [0u32, 0, square.len() as u32]
+            .map(u32::to_be_bytes)
+            .concat();
+        let cmap = [
+            [be16(0), be16(1), be16(3), be16(1)].concat(),
+            12u32.to_be_bytes().to_vec(),
+            [
+                be16(4),
+                be16(32),
+                be16(0),
+                be16(4),
+                be16(0),
+                be16(0),
+                be16(0),
+            ]
+            .concat(),
+            [be16(0x41), be16(0xFFFF), be16(0), be16(0x41), be16(0xFFFF)].concat(),
+            [be16(1 - 0x41), be16(1), be16(0), be16(0)].concat(),
+        ]
+        .concat();
+        let tables: [(&[u8; 4], Vec<u8>); 7] = [
+            (b"cmap", cmap),
+            (b"glyf", square),
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"hmtx", hmtx),
+            (b"loca", loca),
+            (b"maxp", maxp),
+        ];
+        let mut font = [
+            0x0001_0000u32.to_be_bytes().to_vec(),
+            be16(7).to_vec(),
+            vec![0; 6],
+        ]
+        .concat();
+        let mut offset = 12 + tables.len() * 16;
+        let mut body = Vec::new();
+        for (tag, table) in &tables {
+            font.extend_from_slice(*tag);
+            font.extend_from_slice(&[0; 4]);
+            font.extend_from_slice(&(offset as u32).to_be_bytes());
+            font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+            body.extend_from_slice(table);
+            offset += table.len();
+        }
+        font.extend(body);
+        font
+    }
+
+    #[test]
+    fn text_annotation_font_overrides_global_and_falls_back() {
+        let dir = tempdir().unwrap();
+        let font_path = dir.path().join("global.ttf");
+        fs::write(&font_path, square_glyph_ttf()).unwrap();
+        let missing = dir.path().join("missing.ttf");
+
+        let mut fonts = FontCache::default();
+        let global = font_path.to_str().unwrap();
+        assert!(
+            std::sync::Arc::ptr_eq(&fonts.load(global).unwrap(), &fonts.load(global).unwrap()),
+            "cached by path"
+        );
+        assert!(fonts.load(missing.to_str().unwrap()).is_err());
+
+        let input = dir.path().join("in.png");
+        RgbaImage::from_pixel(40, 20, Rgba([0, 0, 0, 255]))
+            .save(&input)
+            .unwrap();
+        let spec = dir.path().join("spec.json");
+        let text = json!({"type": "text", "text": "A", "x": 2, "y": 2, "size": 10,
+                          "font": missing.display().to_string()});
+        fs::write(
+            &spec,
+            json!({"annotations": [text.clone(), text]}).to_string(),
+        )
+        .unwrap();
+        let output = dir.path().join("out.png");
+        let run = |font: Option<&Path>| {
+            let mut argv = vec![
+                "codex-visual-loop".to_string(),
+                "annotate".to_string(),
+                input.display().to_string(),
+                output.display().to_string(),
+                "--spec".to_string(),
+                spec.display().to_string(),
+                "--json".to_string(),
+            ];
+            if let Some(font) = font {
+                argv.extend(["--font".to_string(), font.display().to_string()]);
+            }
+            let Some(Commands::Annotate(args)) = Cli::try_parse_from(argv).unwrap().command else {
+                panic!("expected annotate");
+            };
+            annotate_internal(&args, &OutputOptions::default()).unwrap()
         };
-        let h = match parts[4].trim().parse::<f64>() {
-            Ok(v) => v.round() as i64,
-            _ => continue,
+
+        // Reported once per path, falling back to the global font when there is one.
+        let font_warnings = |result: &Value| -> Vec<String> {
+            result["warnings"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(Value::as_str)
+                .filter(|warning| warning.contains("missing.ttf"))
+                .map(str::to_string)
+                .collect()
         };
-        if w <= 0 || h <= 0 {
-            continue;
+        let with_global = run(Some(&font_path));
+        let warnings = font_warnings(&with_global);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].ends_with("using the global --font"));
+        let without = run(None);
+        assert!(font_warnings(&without)[0].ends_with("using the built-in bitmap font"));
+        // Without the ttf feature a loaded font is reported once as not rendered.
+        let unsupported = with_global["warnings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|warning| warning.as_str().unwrap().contains("--features ttf"))
+            .count();
+        assert_eq!(unsupported, usize::from(cfg!(not(feature = "ttf"))));
+    }
+
+    #[cfg(feature = "ttf")]
+    #[test]
+    fn ttf_text_uses_glyph_coverage_and_metrics() {
+        let dir = tempdir().unwrap();
+        let font_path = dir.path().join("square.ttf");
+        fs::write(&font_path, square_glyph_ttf()).unwrap();
+        let bogus = dir.path().join("bogus.ttf");
+        fs::write(&bogus, b"not a font").unwrap();
+
+        let mut fonts = FontCache::default();
+        let font = fonts.load(font_path.to_str().unwrap()).unwrap();
+        let mask = font_text_mask(&font, "A", 10.0, 0.0).unwrap();
+        assert_eq!((mask.width, mask.height), (10, 10));
+        assert!(mask.coverage[4 * 10 + 5] > 0.99, "inside the square");
+        assert_eq!(mask.coverage[4 * 10], 0.0, "left bearing");
+        assert_eq!(mask.coverage[9 * 10 + 5], 0.0, "below the baseline");
+        assert!(fonts.load(bogus.to_str().unwrap()).is_err());
+
+        // The square font draws a solid block, which the 8x8 bitmap 'A' never is.
+        let mut img = RgbaImage::from_pixel(40, 20, Rgba([0, 0, 0, 255]));
+        let ann = json!({"type": "text", "text": "A", "x": 2, "y": 2, "size": 10,
+                         "outline": false, "color": "#FF0000",
+                         "font": font_path.display().to_string()});
+        draw_text_annotation(&mut img, ann.as_object().unwrap(), 1.0, &fonts, None);
+        assert!((3..11).all(|x| img.get_pixel(x, 6)[0] > 200));
+        let mut rtl = ann.clone();
+        rtl["direction"] = json!("rtl");
+        assert!(text_ttf_mask(rtl.as_object().unwrap(), "A", 10.0, &fonts).is_none());
+
+        // A one-font .ttc: 16-byte collection header, then the font with table offsets shifted.
+        let mut face = square_glyph_ttf();
+        for i in 0..7 {
+            let rec = 12 + i * 16 + 8;
+            let offset = u32::from_be_bytes(face[rec..rec + 4].try_into().unwrap()) + 16;
+            face[rec..rec + 4].copy_from_slice(&offset.to_be_bytes());
         }
-        let title = parts
-            .get(5)
-            .map(|v| v.trim())
-            .filter(|v| !v.is_empty())
-            .map(ToString::to_string);
-        items.push(WindowCandidate {
-            index,
-            x,
-            y,
-            w,
-            h,
-            title,
-        });
+        let ttc = [
+            b"ttcf".to_vec(),
+            0x0001_0000u32.to_be_bytes().to_vec(),
+            1u32.to_be_bytes().to_vec(),
+            16u32.to_be_bytes().to_vec(),
+            face,
+        ]
+        .concat();
+        let ttc_path = dir.path().join("square.ttc");
+        fs::write(&ttc_path, ttc).unwrap();
+        assert!(fonts.load(ttc_path.to_str().unwrap()).is_ok());
+
+        let mut ann = json!({"type": "text", "text": "AA", "x": 4, "y": 6, "size": 10});
+        let scene = excalidraw_scene(&[(0, ann.as_object().unwrap().clone())], 1.0, &fonts);
+        assert_eq!(scene["elements"][0]["height"], json!(8), "bitmap metrics");
+        ann["font"] = json!(ttc_path.display().to_string());
+        let scene = excalidraw_scene(&[(0, ann.as_object().unwrap().clone())], 1.0, &fonts);
+        assert_eq!(scene["elements"][0]["width"], json!(20));
+        assert_eq!(scene["elements"][0]["height"], json!(10));
+    }
+
+    #[test]
+    fn preview_grid_tiles_one_annotation_per_cell() {
+        let base = RgbaImage::from_pixel(40, 20, Rgba([0, 0, 0, 255]));
+        let rect = |x: i32, id: &str| {
+            json!({"type": "rect", "x": x, "y": 4, "w": 8, "h": 12, "color": "#FF0000",
+                   "id": id})
+            .as_object()
+            .unwrap()
+            .clone()
+        };
+        let items = vec![(1, rect(28, "right")), (0, rect(2, "left"))];
+        let sheet = build_preview_grid(&base, &items, 1.0, &Map::new(), &FontCache::default());
+        // Two 40x20 tiles side by side, each under a 20px label row, with 8px gaps.
+        assert_eq!(sheet.dimensions(), (2 * 48 + 8, 20 + 20 + 8 + 8));
+        let red_in = |x0: u32, xs: std::ops::Range<u32>| {
+            xs.clone()
+                .any(|x| (28..48).any(|y| sheet.get_pixel(x0 + x, y)[0] > 200))
+        };
+        // Sorted by spec index: the first cell holds only the left rect, the second only the right.
+        assert!(red_in(8, 0..14) && !red_in(8, 22..40));
+        assert!(red_in(56, 22..40) && !red_in(56, 0..14));
+        assert!(
+            (8..48).any(|x| sheet.get_pixel(x, 14)[0] == 255),
+            "index label"
+        );
+
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("in.png");
+        base.save(&input).unwrap();
+        let spec = dir.path().join("spec.json");
+        fs::write(&spec, json!({"annotations": [rect(2, "left")]}).to_string()).unwrap();
+        let grid = dir.path().join("grid.png");
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            dir.path().join("out.png").to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--preview-grid",
+            grid.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        let summary = annotate_internal(&args, &OutputOptions::default()).unwrap();
+        assert_eq!(
+            summary["preview_grid_path"],
+            json!(abs_path(&grid).display().to_string())
+        );
+        assert_eq!(image::open(&grid).unwrap().dimensions(), (56, 56));
+    }
+
+    #[test]
+    fn annotate_json_summary_reports_paths_size_and_count() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("in.png");
+        RgbaImage::from_pixel(64, 32, Rgba([0, 0, 0, 255]))
+            .save(&input)
+            .unwrap();
+        let spec = dir.path().join("spec.json");
+        fs::write(
+            &spec,
+            json!({"annotations": [
+                {"type": "rect", "x": 2, "y": 2, "w": 10, "h": 10},
+                {"type": "text", "text": "Hi", "x": 20, "y": 4},
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+        let output = dir.path().join("out.png");
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--json",
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        assert!(args.json);
+        let summary = annotate_internal(&args, &OutputOptions::default()).unwrap();
+        assert_eq!(
+            summary["output_path"],
+            json!(abs_path(&output).display().to_string())
+        );
+        let meta_path = PathBuf::from(summary["meta_path"].as_str().unwrap());
+        assert!(output.exists() && meta_path.exists());
+        assert_eq!(
+            summary["size"],
+            json!({"width": 64, "height": 32, "units": "px"})
+        );
+        assert_eq!(summary["annotation_count"], json!(2));
+    }
+
+    #[test]
+    fn annotate_probe_reports_geometry_and_anchor_targets() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("in.png");
+        RgbaImage::from_pixel(200, 100, Rgba([30, 30, 30, 255]))
+            .save(&input)
+            .unwrap();
+        let spec = dir.path().join("spec.json");
+        fs::write(
+            &spec,
+            json!({"annotations": [
+                {"type": "rect", "id": "cta", "x": 20, "y": 30, "w": 60, "h": 20},
+                {"type": "text", "text": "Add", "anchor": "cta", "anchor_pos": "bottom_left"},
+                {"type": "arrow", "x1": 180, "y1": 90, "x2": 90, "y2": 40,
+                 "from": {"type": "spotlight"}, "to": {"nearest": true}},
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            dir.path().join("out.png").to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--probe",
+            "--trim",
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        let summary = annotate_internal(&args, &OutputOptions::default()).unwrap();
+        let probe = summary["probe"].as_array().unwrap();
+        assert_eq!(probe.len(), 3);
+        assert!(probe[0]["anchored_to"].is_null());
+
+        // --trim shifts geometry and anchor points alike.
+        let (rect_x, rect_y) = (
+            probe[0]["geometry"]["x"].clone(),
+            probe[0]["geometry"]["y"].clone(),
+        );
+        let text_anchor = &probe[1]["anchored_to"]["anchor"];
+        assert_eq!(text_anchor["id"], "cta");
+        assert_eq!(text_anchor["matched_by"], "id");
+        assert_eq!(text_anchor["pos"], "bottom_left");
+        assert_eq!(text_anchor["point"][0].as_f64(), rect_x.as_f64());
+        assert_eq!(
+            text_anchor["point"][1].as_f64().unwrap(),
+            rect_y.as_f64().unwrap() + 20.0
+        );
+        assert_eq!(probe[1]["geometry"]["x"], rect_x);
+
+        let arrow = &probe[2]["anchored_to"];
+        assert!(arrow["from"].is_null(), "no spotlight to attach to");
+        assert_eq!(arrow["to"]["index"], 0);
+        assert_eq!(arrow["to"]["matched_by"], "nearest");
+
+        let table = probe_table(probe);
+        assert_eq!(table.lines().count(), 3);
+        assert!(table.contains("| anchor -> rect #0 \"cta\" (id, bottom_left)"));
+        assert!(table.contains("| from -> unresolved | to -> rect #0"));
+
+        let meta: Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join("out.json")).unwrap())
+                .unwrap();
+        assert!(meta["annotations"][1].get("anchored_to").is_none());
     }
-    items
-}
 
-fn select_window_candidate<'a>(
-    candidates: &'a [WindowCandidate],
-    min_width: i64,
-    min_height: i64,
-    min_area: i64,
-) -> (&'a WindowCandidate, &'static str, usize) {
-    const MIN_REASONABLE_XY: i64 = -5_000;
-    const MAX_REASONABLE_XY: i64 = 50_000;
+    #[test]
+    fn merge_specs_layers_defaults_and_concatenates_annotations() {
+        let merged = merge_specs(vec![
+            json!({"defaults": {"color": "#ff0000", "width": 3},
+                   "annotations": [{"type": "rect", "id": "grid"}]}),
+            json!({"defaults": {"color": "#00ff00"},
+                   "annotations": [{"type": "arrow", "id": "run"}]}),
+        ]);
+        assert_eq!(merged["defaults"], json!({"color": "#00ff00", "width": 3}));
+        let ids: Vec<&str> = merged["annotations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|ann| ann["id"].as_str())
+            .collect();
+        assert_eq!(ids, ["grid", "run"]);
+    }
 
-    fn area(candidate: &WindowCandidate) -> i64 {
-        candidate.w.saturating_mul(candidate.h)
+    #[test]
+    fn placeholder_settings_read_overrides_and_keep_defaults() {
+        let (dims, fill, invalid) = placeholder_settings(None, None);
+        assert_eq!((dims, fill), ((1280, 720), Rgba([255, 255, 255, 255])));
+        assert!(invalid.is_empty());
+
+        let (dims, fill, invalid) = placeholder_settings(Some("390x844"), Some("#202020"));
+        assert_eq!((dims, fill), ((390, 844), Rgba([32, 32, 32, 255])));
+        assert!(invalid.is_empty());
+
+        let (dims, fill, invalid) = placeholder_settings(Some("0x10"), Some("nope"));
+        assert_eq!((dims, fill), ((1280, 720), Rgba([255, 255, 255, 255])));
+        assert_eq!(invalid.len(), 2);
     }
 
-    fn cmp_window(a: &WindowCandidate, b: &WindowCandidate) -> std::cmp::Ordering {
-        area(b)
-            .cmp(&area(a))
-            .then_with(|| b.w.cmp(&a.w))
-            .then_with(|| b.h.cmp(&a.h))
-            .then_with(|| a.index.cmp(&b.index))
+    #[test]
+    fn window_thresholds_layer_flags_over_env_over_defaults() {
+        let (thresholds, invalid) = window_thresholds((None, None, None), (None, None, None));
+        assert_eq!(thresholds, WindowThresholds::default());
+        assert!(invalid.is_empty());
+
+        let (thresholds, invalid) = window_thresholds(
+            (Some(80), None, None),
+            (Some("300"), Some("60"), Some("4800")),
+        );
+        assert_eq!(
+            thresholds,
+            WindowThresholds {
+                min_width: 80,
+                min_height: 60,
+                min_area: 4800
+            }
+        );
+        assert!(invalid.is_empty());
+
+        let (thresholds, invalid) =
+            window_thresholds((None, None, None), (Some("wide"), Some("-1"), None));
+        assert_eq!(thresholds, WindowThresholds::default());
+        assert_eq!(invalid.len(), 2);
+
+        // A 180x200 tool palette only counts as usable once the thresholds come down.
+        let windows = vec![WindowCandidate {
+            index: 1,
+            x: 10,
+            y: 10,
+            w: 180,
+            h: 200,
+            title: Some("Tools".to_string()),
+        }];
+        let (_, mode, _) = select_window_candidate(&windows, 220, 140, 40_000);
+        assert_eq!(mode, "largest_any");
+        let (_, mode, usable) = select_window_candidate(&windows, 80, 60, 4800);
+        assert_eq!((mode, usable), ("largest_usable", 1));
     }
 
-    let is_usable = |candidate: &WindowCandidate| {
-        candidate.w >= min_width
-            && candidate.h >= min_height
-            && area(candidate) >= min_area
-            && candidate.x >= MIN_REASONABLE_XY
-            && candidate.y >= MIN_REASONABLE_XY
-            && candidate.x <= MAX_REASONABLE_XY
-            && candidate.y <= MAX_REASONABLE_XY
-    };
+    #[test]
+    fn parse_capture_region_requires_positive_size() {
+        assert_eq!(
+            parse_capture_region("10, 20,300,40"),
+            Some((10, 20, 300, 40))
+        );
+        assert_eq!(parse_capture_region("10,20,0,40"), None);
+        assert_eq!(parse_capture_region("10,20,300"), None);
+    }
 
-    let mut usable: Vec<&WindowCandidate> = candidates.iter().filter(|c| is_usable(c)).collect();
-    usable.sort_by(|a, b| cmp_window(a, b));
-    let usable_count = usable.len();
-    if let Some(candidate) = usable.first().copied() {
-        return (candidate, "largest_usable", usable_count);
+    #[test]
+    fn unfocused_dim_keeps_every_box_visible() {
+        let mut img = RgbaImage::from_pixel(40, 20, Rgba([255, 255, 255, 255]));
+        let mut defaults = Map::new();
+        defaults.insert("dim_opacity".to_string(), json!(1.0));
+        let mut rect = Map::new();
+        rect.insert("type".to_string(), json!("rect"));
+        for (k, v) in [("x", 2), ("y", 2), ("w", 6), ("h", 6)] {
+            rect.insert(k.to_string(), json!(v));
+        }
+        let mut spot = rect.clone();
+        spot.insert("type".to_string(), json!("spotlight"));
+        spot.insert("x".to_string(), json!(30));
+        let items = vec![(0, rect), (1, spot)];
+
+        draw_unfocused_dim(&mut img, &items, 1.0, &defaults);
+
+        assert_eq!(img.get_pixel(4, 4), &Rgba([255, 255, 255, 255]));
+        assert_eq!(img.get_pixel(32, 4), &Rgba([255, 255, 255, 255]));
+        assert_eq!(img.get_pixel(20, 15), &Rgba([0, 0, 0, 255]));
     }
 
-    let mut all: Vec<&WindowCandidate> = candidates.iter().collect();
-    all.sort_by(|a, b| cmp_window(a, b));
-    if let Some(candidate) = all.first().copied() {
-        return (candidate, "largest_any", usable_count);
+    #[test]
+    fn change_region_centroid_anchors_diff_labels() {
+        let (width, height) = (10u32, 10u32);
+        let mut gray = vec![0u8; (width * height) as usize];
+        // L-shaped change: the centroid sits closer to the corner than the bbox center.
+        for x in 2..6 {
+            gray[(2 * width + x) as usize] = 200;
+        }
+        for y in 3..6 {
+            gray[(y * width + 2) as usize] = 200;
+        }
+        let regions = extract_change_regions(&gray, width, height, 24, 1, 0, 4);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].centroid, [2.86, 2.86]);
+
+        let spec = build_annotate_spec(&regions, &DiffOptions::default(), (width, height));
+        let rect = spec["annotations"][0].as_object().unwrap();
+        let target = AnchorTarget {
+            id: Some(regions[0].id.clone()),
+            index: 0,
+            ann_type: "rect".to_string(),
+            bbox: bbox_from_ann(rect).unwrap(),
+            centroid: parse_offset_value(rect.get("centroid")),
+        };
+        assert_eq!(spec["annotations"][1]["anchor_pos"], json!("centroid"));
+        assert_eq!(anchor_target_point(&target, "centroid"), (2.86, 2.86));
+        assert_eq!(anchor_target_point(&target, "top_left"), (2.0, 2.0));
     }
 
-    // parse_window_candidates guarantees non-empty when this is called.
-    (&candidates[0], "window_1", usable_count)
-}
+    #[test]
+    fn append_json_line_accumulates_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("logs").join("audit.jsonl");
+        append_json_line(&path, &json!({"command": "diff", "success": true})).unwrap();
+        append_json_line(&path, &json!({"command": "annotate", "success": false})).unwrap();
+
+        let raw = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = raw
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["command"], json!("annotate"));
+    }
 
-fn frontmost_app_name() -> Option<String> {
-    if !cfg!(target_os = "macos") {
-        return None;
+    #[test]
+    fn secure_fields_map_to_pixels_and_blur() {
+        let elements = vec![
+            json!({"class": "text field", "bounds": {"x": 110, "y": 220, "w": 50, "h": 10}}),
+            json!({"class": "text field", "role_description": "secure text field",
+                   "bounds": {"x": 110, "y": 240, "w": 50, "h": 10}}),
+        ];
+        let regions = secure_field_pixel_regions(&elements, (100, 200, 200, 100), (400, 200));
+        assert_eq!(regions, vec![(20, 80, 100, 20)]);
+
+        let mut img = RgbaImage::from_fn(8, 4, |x, _| {
+            if x % 2 == 0 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        box_blur_region(&mut img, 0, 0, 4, 4, 1);
+        let mid = img.get_pixel(1, 1).0[0];
+        assert!(
+            mid > 60 && mid < 200,
+            "expected blurred mid-tone, got {mid}"
+        );
+        assert_eq!(img.get_pixel(5, 1), &Rgba([255, 255, 255, 255]));
     }
 
-    run_osascript_with_retry(
-        "tell application \"System Events\" to get name of first process whose frontmost is true",
-        &[],
-        1,
-        20,
-    )
-    .0
-}
+    #[test]
+    fn arrow_avoid_bends_around_crossed_rect() {
+        let boxes = [
+            (100.0, 300.0, 220.0, 360.0),
+            (500.0, 280.0, 660.0, 400.0),
+            (1000.0, 300.0, 1120.0, 360.0),
+        ];
+        let (cx, cy) = arrow_avoid_control((160.0, 330.0), (1060.0, 330.0), &boxes, 12.0).unwrap();
+        assert!((cx - 610.0).abs() < 1e-6);
+        // Obstacle center sits below the line, so the curve bows upward past its top edge.
+        let peak = quad_bezier_point((160.0, 330.0), (cx, cy), (1060.0, 330.0), 0.5);
+        assert!(
+            peak.1 <= 280.0 - 12.0 + 1e-6,
+            "peak {peak:?} still inside obstacle"
+        );
 
-fn query_ax_tree(process: &str, depth: u32) -> AxQueryResult {
-    if !cfg!(target_os = "macos") {
-        return AxQueryResult {
-            elements: Vec::new(),
-            tree: Vec::new(),
-            diagnostics: QueryDiagnostic {
-                ok: false,
-                attempts: 0,
-                error_code: Some("unsupported_platform".to_string()),
-                message: Some("AX tree extraction requires macOS".to_string()),
-            },
-            warnings: vec!["ax-tree is only available on macOS; emitted empty payload".to_string()],
-        };
+        assert_eq!(
+            arrow_avoid_control((160.0, 100.0), (1060.0, 100.0), &boxes, 12.0),
+            None
+        );
     }
 
-    let script = r#"
-on sanitize(v)
-  try
-    set t to v as text
-  on error
-    set t to ""
-  end try
-  set AppleScript's text item delimiters to {return, linefeed, tab}
-  set parts to text items of t
-  set AppleScript's text item delimiters to " "
-  set clean to parts as text
-  set AppleScript's text item delimiters to ""
-  return clean
-end sanitize
+    #[test]
+    fn assign_annotation_id_generates_and_dedupes() {
+        let mut used = std::collections::HashSet::new();
+        let mut first = Map::new();
+        first.insert("id".to_string(), json!("cta"));
+        let mut second = first.clone();
+        let mut third = Map::new();
+
+        assert_eq!(assign_annotation_id(&mut first, 0, &mut used), None);
+        let warning = assign_annotation_id(&mut second, 1, &mut used).unwrap();
+        assert!(warning.contains("\"cta-2\""));
+        assert_eq!(assign_annotation_id(&mut third, 2, &mut used), None);
+
+        assert_eq!(first["id"], json!("cta"));
+        assert_eq!(second["id"], json!("cta-2"));
+        assert_eq!(third["id"], json!("ann-2"));
+    }
 
-on emitLine(depthVal, clsVal, nameVal, roleVal, enabledVal, xVal, yVal, wVal, hVal)
-  return (depthVal as text) & tab & my sanitize(clsVal) & tab & my sanitize(nameVal) & tab & my sanitize(roleVal) & tab & my sanitize(enabledVal) & tab & (xVal as text) & tab & (yVal as text) & tab & (wVal as text) & tab & (hVal as text)
-end emitLine
+    #[test]
+    fn relative_to_resolves_against_reference_box() {
+        let targets = vec![AnchorTarget {
+            id: Some("card".to_string()),
+            index: 0,
+            ann_type: "rect".to_string(),
+            bbox: (200.0, 100.0, 600.0, 300.0),
+            centroid: None,
+        }];
+        let mut ann = Map::new();
+        ann.insert("type".to_string(), json!("rect"));
+        ann.insert("relative_to".to_string(), json!("card"));
+        for (k, v) in [
+            ("x", json!(0.25)),
+            ("y", json!("50%")),
+            ("w", json!(0.5)),
+            ("h", json!("20px")),
+        ] {
+            ann.insert(k.to_string(), v);
+        }
 
-on walkNode(nodeRef, depthVal, maxDepth)
-  set linesOut to {}
-  try
-    set clsVal to class of nodeRef as text
-  on error
-    set clsVal to "unknown"
-  end try
-  try
-    set nameVal to name of nodeRef
-  on error
-    set nameVal to ""
-  end try
-  try
-    set roleVal to role description of nodeRef
-  on error
-    set roleVal to ""
-  end try
-  try
-    set enabledVal to enabled of nodeRef
-  on error
-    set enabledVal to ""
-  end try
+        let key = relative_reference_key(&ann).unwrap();
+        let frame = resolve_reference_bbox(&key, &targets).unwrap();
+        resolve_relative_units(&mut ann, frame);
+        assert_eq!(bbox_from_ann(&ann), Some((300.0, 200.0, 500.0, 220.0)));
+
+        let meta = annotation_meta_item(1, &ann, 1000, 1000);
+        assert_eq!(meta["geometry_ref"]["x"], json!(0.25));
+        assert_eq!(meta["geometry_ref"]["h"], json!(0.1));
+
+        // bottom_left measures up from the box's bottom edge; pt numbers are scaled lengths.
+        let mut flipped = json!({"type": "rect", "relative_to": "card", "x": 0.25, "y": 0.1,
+                                 "w": 0.5, "h": "20px", "anchor_offset": [0, 0.1],
+                                 "coordinate_origin": "bottom_left"})
+        .as_object()
+        .unwrap()
+        .clone();
+        resolve_relative_units(&mut flipped, frame);
+        assert_eq!(bbox_from_ann(&flipped), Some((300.0, 260.0, 500.0, 280.0)));
+        assert_eq!(flipped["anchor_offset"], json!([0.0, -20.0]));
+
+        let mut points = json!({"type": "rect", "relative_to": "card", "x": 10, "y": 5, "w": 20,
+                                "h": 10, "units": "pt", "pt_scale": 2, "pt_origin": [999, 999],
+                                "coordinate_origin": "bottom_left"})
+        .as_object()
+        .unwrap()
+        .clone();
+        resolve_relative_units(&mut points, frame);
+        assert_eq!(bbox_from_ann(&points), Some((220.0, 270.0, 260.0, 290.0)));
+
+        let mut literal = Map::new();
+        literal.insert("relative_to".to_string(), json!(true));
+        literal.insert(
+            "reference".to_string(),
+            json!({"x": 10, "y": 20, "w": 30, "h": 40}),
+        );
+        let key = relative_reference_key(&literal).unwrap();
+        assert_eq!(
+            resolve_reference_bbox(&key, &[]),
+            Some((10.0, 20.0, 40.0, 60.0))
+        );
+    }
 
-  set xVal to ""
-  set yVal to ""
-  set wVal to ""
-  set hVal to ""
-  try
-    set posVal to position of nodeRef
-    set xVal to item 1 of posVal
-    set yVal to item 2 of posVal
-  end try
-  try
-    set sizeVal to size of nodeRef
-    set wVal to item 1 of sizeVal
-    set hVal to item 2 of sizeVal
-  end try
+    #[test]
+    fn antialiased_edge_pixels_are_detected() {
+        // Hard vertical edge at x=4 in the baseline; the current frame softens column 4.
+        let baseline = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let mut current = baseline.clone();
+        for y in 0..8 {
+            current.put_pixel(4, y, Rgba([128, 128, 128, 255]));
+        }
+        assert!(is_antialiased(&current, &baseline, 4, 3));
+
+        // An isolated changed pixel in a flat area is a real change.
+        let flat = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+        let mut dotted = flat.clone();
+        dotted.put_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        assert!(!is_antialiased(&dotted, &flat, 2, 2));
+        assert!(!is_antialiased(&flat, &dotted, 2, 2));
+    }
 
-  set end of linesOut to my emitLine(depthVal, clsVal, nameVal, roleVal, enabledVal, xVal, yVal, wVal, hVal)
-  if depthVal < maxDepth then
-    try
-      set childrenRefs to UI elements of nodeRef
-      repeat with childRef in childrenRefs
-        set childLines to my walkNode(childRef, depthVal + 1, maxDepth)
-        set linesOut to linesOut & childLines
-      end repeat
-    end try
-  end if
-  return linesOut
-end walkNode
+    #[test]
+    fn frame_sequence_path_suffixes_only_multi_frame_runs() {
+        let path = Path::new("/tmp/cap/app.png");
+        assert_eq!(
+            frame_sequence_path(path, 0, 1),
+            PathBuf::from("/tmp/cap/app.png")
+        );
+        assert_eq!(
+            frame_sequence_path(path, 7, 12),
+            PathBuf::from("/tmp/cap/app-f007.png")
+        );
+    }
 
-on run argv
-  set procName to item 1 of argv
-  set depthLimit to item 2 of argv as integer
-  tell application "System Events"
-    tell process procName
-      set targetWindow to window 1
-      set linesOut to my walkNode(targetWindow, 0, depthLimit)
-    end tell
-  end tell
-  set AppleScript's text item delimiters to linefeed
-  set joined to linesOut as text
-  set AppleScript's text item delimiters to ""
-  return joined
-end run
-"#;
+    #[test]
+    fn rel_stroke_units_scale_with_image_diagonal() {
+        let img = RgbaImage::new(300, 400);
+        let mut ann = Map::new();
+        ann.insert("width".to_string(), json!(0.01));
+        assert_eq!(rel_stroke_px(&ann, "width", &img), None);
 
-    let args = vec![process.to_string(), depth.to_string()];
-    let (raw_lines, diagnostics) = run_osascript_with_retry(script, &args, 2, 80);
-    let mut warnings = Vec::new();
+        ann.insert("stroke_units".to_string(), json!("rel"));
+        assert_eq!(rel_stroke_px(&ann, "width", &img), Some(5.0));
+        assert_eq!(rel_stroke_px(&ann, "head_len", &img), None);
+    }
 
-    let Some(lines) = raw_lines else {
-        if let Some(code) = diagnostics.error_code.as_deref() {
-            warnings.push(format!("ax_tree_query:{code}"));
-        }
-        if let Some(message) = diagnostics.message.as_deref() {
-            warnings.push(format!("AX tree unavailable: {message}"));
+    #[test]
+    fn pixel_distance_supports_perceptual_colorspaces() {
+        let black = Rgba([0, 0, 0, 255]);
+        let white = Rgba([255, 255, 255, 255]);
+        assert_eq!(pixel_distance(&black, &white, "rgb"), 255);
+        assert_eq!(pixel_distance(&black, &white, "lab"), 255);
+        assert_eq!(pixel_distance(&black, &white, "ycbcr"), 255);
+
+        // RGB ranks a blue-channel bump on yellow above a dark-gray step; LAB reverses that.
+        let (yellow, yellow_shift) = (Rgba([250, 250, 0, 255]), Rgba([250, 250, 30, 255]));
+        let (dark, dark_step) = (Rgba([20, 20, 20, 255]), Rgba([40, 40, 40, 255]));
+        assert!(
+            pixel_distance(&yellow, &yellow_shift, "rgb")
+                > pixel_distance(&dark, &dark_step, "rgb")
+        );
+        assert!(
+            pixel_distance(&yellow, &yellow_shift, "lab")
+                < pixel_distance(&dark, &dark_step, "lab")
+        );
+        assert_eq!(pixel_distance(&white, &white, "lab"), 0);
+    }
+
+    #[test]
+    fn loop_explicit_baseline_refuses_implicit_seed_until_reset() {
+        let dir = tempdir().unwrap();
+        let loop_dir = dir.path().join("loop");
+        let frame = |name: &str, shade: u8| {
+            let path = dir.path().join(name);
+            RgbaImage::from_pixel(8, 8, Rgba([shade, shade, shade, 255]))
+                .save(&path)
+                .unwrap();
+            path
+        };
+        let (first, second) = (frame("first.png", 10), frame("second.png", 200));
+        let run = |current: &Path, flags: &[&str]| {
+            let mut argv = vec![
+                "codex-visual-loop",
+                "loop",
+                current.to_str().unwrap(),
+                "home",
+                "--loop-dir",
+                loop_dir.to_str().unwrap(),
+            ];
+            argv.extend_from_slice(flags);
+            let Some(Commands::Loop(args)) = Cli::try_parse_from(argv).unwrap().command else {
+                panic!("expected loop");
+            };
+            command_loop(args, &OutputOptions::default())
+        };
+        let baseline = loop_dir.join("baselines").join("home.png");
+
+        let err = run(&first, &["--explicit-baseline"]).unwrap_err();
+        assert_eq!(exit_code_for(&err), EXIT_INPUT);
+        assert!(format!("{err:#}").contains("--reset-baseline"));
+        assert!(!baseline.exists());
+        assert_eq!(fs::read_dir(loop_dir.join("history")).unwrap().count(), 0);
+
+        run(&first, &["--explicit-baseline", "--reset-baseline"]).unwrap();
+        assert_eq!(
+            image::open(&baseline).unwrap().to_rgba8().get_pixel(0, 0)[0],
+            10
+        );
+        run(&second, &["--explicit-baseline"]).unwrap();
+        assert_eq!(
+            image::open(&baseline).unwrap().to_rgba8().get_pixel(0, 0)[0],
+            10
+        );
+        run(&second, &["--reset-baseline"]).unwrap();
+        assert_eq!(
+            image::open(&baseline).unwrap().to_rgba8().get_pixel(0, 0)[0],
+            200
+        );
+
+        // --region-label-position reaches the generated change spec.
+        run(&first, &["--region-label-position", "top_left"]).unwrap();
+        let spec_path = fs::read_dir(loop_dir.join("reports"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().ends_with("-change-spec.json"))
+            .unwrap();
+        let spec: Value = serde_json::from_str(&fs::read_to_string(spec_path).unwrap()).unwrap();
+        let label = spec["annotations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|ann| ann["type"] == "text")
+            .unwrap();
+        assert_eq!(label["anchor_pos"], "top_left");
+
+        let conflict = [
+            "codex-visual-loop",
+            "loop",
+            "a.png",
+            "home",
+            "--reset-baseline",
+            "--update-baseline",
+        ];
+        assert!(Cli::try_parse_from(conflict).is_err());
+    }
+
+    #[test]
+    fn loop_process_takes_the_positional_as_baseline_name() {
+        let inputs = |argv: &[&str]| {
+            let argv = ["codex-visual-loop", "loop"].iter().chain(argv);
+            let Some(Commands::Loop(args)) = Cli::try_parse_from(argv).unwrap().command else {
+                panic!("expected loop");
+            };
+            loop_inputs(&args)
+        };
+        assert_eq!(
+            inputs(&["--process", "Safari", "home"]).unwrap(),
+            (None, "home".to_string())
+        );
+        assert_eq!(
+            inputs(&["shot.png", "home"]).unwrap(),
+            (Some(PathBuf::from("shot.png")), "home".to_string())
+        );
+        assert!(inputs(&["shot.png", "home", "--process", "Safari"]).is_err());
+        assert!(inputs(&["shot.png"]).is_err());
+        assert!(inputs(&["--process", "Safari"]).is_err());
+
+        if cfg!(target_os = "macos") {
+            return;
         }
-        return AxQueryResult {
-            elements: Vec::new(),
-            tree: Vec::new(),
-            diagnostics,
-            warnings,
+        // Off macOS every capture is a placeholder, which must not seed a baseline.
+        let dir = tempdir().unwrap();
+        let loop_dir = dir.path().join("loop");
+        let argv = [
+            "codex-visual-loop",
+            "loop",
+            "--process",
+            "NoSuchApp",
+            "home",
+            "--loop-dir",
+            loop_dir.to_str().unwrap(),
+        ];
+        let Some(Commands::Loop(args)) = Cli::try_parse_from(argv).unwrap().command else {
+            panic!("expected loop");
         };
-    };
+        let err = command_loop(args, &OutputOptions::default()).unwrap_err();
+        assert_eq!(exit_code_for(&err), EXIT_CAPTURE);
+        assert!(!loop_dir.join("baselines").join("home.png").exists());
+        assert_eq!(fs::read_dir(loop_dir.join("latest")).unwrap().count(), 0);
+    }
 
-    let flat_nodes = parse_ax_lines(&lines);
-    let elements: Vec<Value> = flat_nodes.iter().map(ax_element_value).collect();
-    let tree = ax_tree_values(&flat_nodes);
+    #[test]
+    fn loop_ci_lines_report_status_and_github_warning() {
+        let changed = json!({"percent_changed": 2.3, "change_region_count": 4});
+        assert_eq!(
+            loop_ci_lines("home", &changed, true),
+            vec![
+                "CVLP baseline=home changed=2.3% regions=4 status=CHANGED".to_string(),
+                "::warning title=CVLP home::2.3% of pixels changed across 4 region(s)".to_string(),
+            ]
+        );
+        let clean = json!({"percent_changed": 0.0, "change_region_count": 0});
+        assert_eq!(
+            loop_ci_lines("home", &clean, true),
+            vec!["CVLP baseline=home changed=0% regions=0 status=UNCHANGED".to_string()]
+        );
+    }
 
-    if flat_nodes.is_empty() {
-        warnings.push("AX tree query returned no elements".to_string());
+    #[test]
+    fn ignore_mask_resizes_and_flags_black_or_transparent() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("mask.png");
+        let mask = RgbaImage::from_fn(2, 2, |x, y| match (x, y) {
+            (0, 0) => Rgba([0, 0, 0, 255]),
+            (1, 0) => Rgba([255, 255, 255, 0]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        mask.save(&path).unwrap();
+        let (flags, resized) = load_ignore_mask(&path, 4, 4).unwrap();
+
+        assert!(resized);
+        assert_eq!(flags.len(), 16);
+        assert!(flags[0] && flags[1] && flags[2] && flags[3]);
+        assert!(!flags[8] && !flags[15]);
+    }
+
+    #[test]
+    fn cli_failures_map_to_exit_codes_through_context() {
+        let err = cli_failure(EXIT_CAPTURE, "fallback").context("while capturing");
+        assert_eq!(exit_code_for(&err), EXIT_CAPTURE);
+        assert_eq!(exit_code_for(&anyhow::anyhow!("plain")), EXIT_ERROR);
+        assert!(EXIT_CODES
+            .iter()
+            .any(|(code, name, _)| *code == EXIT_INPUT && *name == "input"));
+    }
+
+    // Golden renders live in tests/golden; regenerate with CVLP_UPDATE_GOLDEN=1 cargo test.
+    fn assert_golden(name: &str, img: &RgbaImage) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("golden")
+            .join(format!("{name}.png"));
+        if env::var("CVLP_UPDATE_GOLDEN").is_ok() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            img.save(&path).unwrap();
+            return;
+        }
+        let golden = image::open(&path)
+            .unwrap_or_else(|err| panic!("missing golden {}: {err}", path.display()))
+            .to_rgba8();
+        assert_eq!(
+            golden.dimensions(),
+            img.dimensions(),
+            "{name}: size mismatch"
+        );
+        let mismatched = golden
+            .pixels()
+            .zip(img.pixels())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(
+            mismatched, 0,
+            "{name}: {mismatched} pixels differ from golden"
+        );
     }
 
-    AxQueryResult {
-        elements,
-        tree,
-        diagnostics,
-        warnings,
+    fn golden_canvas() -> RgbaImage {
+        RgbaImage::from_pixel(64, 40, Rgba([240, 240, 240, 255]))
     }
-}
 
-fn parse_ax_lines(raw: &str) -> Vec<AxFlatNode> {
-    let mut rows = Vec::new();
-    for (index, line) in raw.lines().enumerate() {
-        if line.trim().is_empty() {
-            continue;
-        }
-        let mut parts: Vec<&str> = line.split('\t').collect();
-        while parts.len() < 9 {
-            parts.push("");
-        }
+    #[test]
+    fn golden_rect_annotation() {
+        let mut img = golden_canvas();
+        let ann = json!({"type": "rect", "x": 8, "y": 6, "w": 40, "h": 24, "width": 2,
+                         "fill": "rgba(255,59,48,0.25)"});
+        draw_rect_annotation(&mut img, ann.as_object().unwrap(), 1.0, None);
+        assert_golden("rect", &img);
+    }
 
-        let depth = parts[0].trim().parse::<usize>().unwrap_or(0);
-        let class_name = parts[1].trim().to_string();
-        let name = (!parts[2].trim().is_empty()).then(|| parts[2].trim().to_string());
-        let role_description = (!parts[3].trim().is_empty()).then(|| parts[3].trim().to_string());
-        let enabled = (!parts[4].trim().is_empty()).then(|| parts[4].trim().to_string());
+    #[test]
+    fn golden_arrow_primitive() {
+        let mut img = golden_canvas();
+        draw_arrow_primitive(
+            &mut img,
+            6.0,
+            32.0,
+            56.0,
+            8.0,
+            Rgba([10, 132, 255, 255]),
+            3.0,
+            10.0,
+            8.0,
+            "triangle",
+            None,
+            None,
+        );
+        assert_golden("arrow", &img);
+    }
 
-        let x = parts[5]
-            .trim()
-            .parse::<f64>()
-            .ok()
-            .map(|v| v.round() as i64);
-        let y = parts[6]
-            .trim()
-            .parse::<f64>()
-            .ok()
-            .map(|v| v.round() as i64);
-        let w = parts[7]
-            .trim()
-            .parse::<f64>()
-            .ok()
-            .map(|v| v.round() as i64);
-        let h = parts[8]
-            .trim()
-            .parse::<f64>()
-            .ok()
-            .map(|v| v.round() as i64);
-        let bounds = match (x, y, w, h) {
-            (Some(x), Some(y), Some(w), Some(h)) => Some((x, y, w, h)),
-            _ => None,
+    #[test]
+    fn arrow_head_shapes_cover_distinct_pixels() {
+        let draw = |shape: &str| {
+            let mut img = RgbaImage::from_pixel(64, 40, Rgba([255, 255, 255, 255]));
+            let ann = json!({"type": "arrow", "x1": 5, "y1": 20, "x2": 55, "y2": 20, "width": 2,
+                             "head_len": 20, "head_width": 20, "outline": false,
+                             "head_shape": shape, "color": "#000000"});
+            draw_arrow_annotation(&mut img, ann.as_object().unwrap(), 1.0, None);
+            // Probes: inside the triangle only, the diamond's front half, and past the dot.
+            [(37, 27), (45, 23), (50, 27), (52, 20)].map(|(x, y)| img.get_pixel(x, y)[0] < 128)
         };
+        assert_eq!(draw("triangle"), [true, true, false, true]);
+        assert_eq!(draw("open"), [false, false, false, true]);
+        assert_eq!(draw("diamond"), [false, true, false, true]);
+        assert_eq!(draw("dot"), [false, true, true, true]);
+        assert_eq!(draw("bogus"), draw("triangle"));
+    }
 
-        rows.push(AxFlatNode {
-            index,
-            depth,
-            class_name,
-            name,
-            role_description,
-            enabled,
-            bounds,
-        });
+    #[test]
+    fn golden_text_annotation() {
+        let mut img = golden_canvas();
+        let ann = json!({"type": "text", "x": 4, "y": 14, "text": "Hi 42", "size": 8,
+                         "text_bg": "rgba(0,0,0,0.6)"});
+        draw_text_annotation(
+            &mut img,
+            ann.as_object().unwrap(),
+            1.0,
+            &FontCache::default(),
+            None,
+        );
+        assert_golden("text", &img);
     }
-    rows
-}
 
-fn ax_bounds_value(bounds: Option<(i64, i64, i64, i64)>) -> Value {
-    match bounds {
-        Some((x, y, w, h)) => json!({
-            "x": x,
-            "y": y,
-            "w": w,
-            "h": h,
-            "units": "pt",
-        }),
-        None => Value::Null,
+    #[test]
+    fn ruler_draws_ticks_and_labels_along_top_and_left_edges() {
+        let white = Rgba([255, 255, 255, 255]);
+        let ink = Rgba([28, 28, 30, 255]);
+        let mut untouched = RgbaImage::from_pixel(200, 120, white);
+        draw_ruler(&mut untouched, &Map::new());
+        assert!(untouched.pixels().all(|p| *p == white));
+
+        let defaults = json!({"ruler": true, "ruler_step": 50});
+        let mut img = RgbaImage::from_pixel(200, 120, white);
+        draw_ruler(&mut img, defaults.as_object().unwrap());
+        assert_eq!(img.get_pixel(50, 13), &ink, "major tick on the top edge");
+        assert_ne!(img.get_pixel(50, 6), &ink, "major tick is half the strip");
+        assert_eq!(img.get_pixel(60, 13), &ink, "minor tick");
+        assert_eq!(
+            img.get_pixel(60, 10),
+            img.get_pixel(55, 10),
+            "minor ticks are short"
+        );
+        assert_eq!(img.get_pixel(13, 100), &ink, "major tick on the left edge");
+        let inked = |x0: u32, y0: u32, x1: u32, y1: u32| {
+            (y0..y1).any(|y| (x0..x1).any(|x| img.get_pixel(x, y) == &ink))
+        };
+        assert!(inked(52, 2, 68, 10), "\"50\" label beside its tick");
+        assert!(inked(2, 52, 10, 68), "\"50\" label stacked downward");
+        assert!(
+            !inked(0, 102, 10, 120),
+            "\"100\" would overflow the left edge"
+        );
+        assert!(!inked(20, 20, 200, 120), "content area is untouched");
+
+        // A step that is not a multiple of 5 keeps every major tick: 0, 33, 66, 99, ...
+        let defaults = json!({"ruler": true, "ruler_step": 33});
+        let mut img = RgbaImage::from_pixel(200, 120, white);
+        draw_ruler(&mut img, defaults.as_object().unwrap());
+        for major in [33, 66, 99, 132, 165, 198] {
+            assert_eq!(img.get_pixel(major, 8), &ink, "major tick at {major}");
+        }
+        assert_eq!(img.get_pixel(40, 13), &ink, "minor tick at 33 + 7");
+        assert_ne!(img.get_pixel(40, 8), &ink, "minor tick is short");
     }
-}
 
-fn ax_element_value(node: &AxFlatNode) -> Value {
-    json!({
-        "index": node.index,
-        "depth": node.depth,
-        "class": node.class_name,
-        "name": node.name,
-        "role_description": node.role_description,
-        "enabled": node.enabled,
-        "bounds": ax_bounds_value(node.bounds),
-    })
-}
+    #[test]
+    fn multiline_text_advances_each_line_below_the_previous() {
+        let white = Rgba([255, 255, 255, 255]);
+        let rows_with_ink = |spacing: i64| {
+            let ann = json!({"type": "text", "x": 2, "y": 2, "text": "a\nb", "size": 8,
+                             "color": "#FFFFFF", "outline": false, "line_spacing": spacing});
+            let mut img = RgbaImage::from_pixel(20, 30, Rgba([0, 0, 0, 255]));
+            draw_text_annotation(
+                &mut img,
+                ann.as_object().unwrap(),
+                1.0,
+                &FontCache::default(),
+                None,
+            );
+            (0..img.height())
+                .filter(|&y| (0..img.width()).any(|x| img.get_pixel(x, y) == &white))
+                .collect::<Vec<u32>>()
+        };
+        // "a" inks glyph rows 2..=6 of its cell and "b" rows 0..=6; b's cell starts one line
+        // (8 px, plus line_spacing) below a's instead of overdrawing it.
+        let first_line = [4, 5, 6, 7, 8];
+        let tight = rows_with_ink(0);
+        assert_eq!(tight[..5], first_line);
+        assert_eq!(tight[5..], [10, 11, 12, 13, 14, 15, 16]);
+        let spaced = rows_with_ink(4);
+        assert_eq!(spaced[..5], first_line);
+        assert_eq!(spaced[5..], [14, 15, 16, 17, 18, 19, 20]);
+        assert_eq!(
+            text_bbox_directed(2, 2, "a\nb", 1, "ltr", 4),
+            (2, 2, 10, 22)
+        );
+    }
 
-fn ax_tree_node_value(node: &AxTreeNode) -> Value {
-    let children: Vec<Value> = node.children.iter().map(ax_tree_node_value).collect();
-    json!({
-        "index": node.index,
-        "class": node.class_name,
-        "name": node.name,
-        "role_description": node.role_description,
-        "enabled": node.enabled,
-        "bounds": ax_bounds_value(node.bounds),
-        "children": children,
-    })
-}
+    #[test]
+    fn text_align_centers_anchored_text_on_the_anchor_point() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("in.png");
+        RgbaImage::from_pixel(80, 40, Rgba([0, 0, 0, 255]))
+            .save(&input)
+            .unwrap();
+        let spec = dir.path().join("spec.json");
+        fs::write(
+            &spec,
+            json!({"annotations": [
+                {"type": "rect", "id": "cta", "x": 20, "y": 10, "w": 40, "h": 20, "fit": false},
+                {"type": "text", "text": "ab", "size": 8, "anchor": "cta", "anchor_pos": "center",
+                 "align": "center", "valign": "middle", "outline": false, "bg": "#0000FF",
+                 "padding": 0},
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+        let output = dir.path().join("out.png");
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        annotate_internal(&args, &OutputOptions::default()).unwrap();
+        let img = image::open(&output).unwrap().to_rgba8();
+        let blue = |x: u32, y: u32| img.get_pixel(x, y) == &Rgba([0, 0, 255, 255]);
+        // The bg box of "ab" (16x8, edges inclusive) is centered on the rect's center (40, 20).
+        assert!(blue(32, 16) && blue(48, 16) && blue(32, 24));
+        assert!(!blue(31, 16) && !blue(49, 16) && !blue(32, 15) && !blue(32, 25));
+
+        let right = json!({"type": "text", "text": "ab", "x": 30, "y": 20, "size": 8,
+                           "align": "right", "valign": "bottom"});
+        let right = right.as_object().unwrap();
+        assert_eq!(text_align_shift(right, 30, 20, (30, 20, 46, 28)), (-16, -8));
+        let scene = excalidraw_scene(&[(0, right.clone())], 1.0, &FontCache::default());
+        assert_eq!(scene["elements"][0]["x"], json!(14.0));
+        assert_eq!(scene["elements"][0]["y"], json!(12.0));
+        let plain = json!({"type": "text", "direction": "rtl"});
+        assert_eq!(
+            text_align_shift(plain.as_object().unwrap(), 30, 20, (30, 20, 46, 28)),
+            (0, 0),
+            "no align keys keep the origin"
+        );
+    }
 
-fn ax_tree_values(rows: &[AxFlatNode]) -> Vec<Value> {
-    let mut roots: Vec<AxTreeNode> = Vec::new();
-    let mut stack: Vec<(usize, Vec<usize>)> = Vec::new();
+    #[test]
+    fn text_direction_lays_out_rtl_and_vertical() {
+        assert_eq!(
+            text_bbox_directed(10, 20, "ab\ncde", 1, "rtl", 0),
+            (10, 20, 34, 36)
+        );
+        assert_eq!(
+            text_bbox_directed(10, 20, "ab\ncde", 1, "vertical", 0),
+            (10, 20, 26, 44)
+        );
 
-    for row in rows {
-        let node = AxTreeNode {
-            index: row.index,
-            class_name: row.class_name.clone(),
-            name: row.name.clone(),
-            role_description: row.role_description.clone(),
-            enabled: row.enabled.clone(),
-            bounds: row.bounds,
-            children: Vec::new(),
+        let origins = |direction| {
+            text_glyph_origins(10, 20, "ab\ncde", 1, direction, 0)
+                .into_iter()
+                .map(|(_, x, y)| (x, y))
+                .collect::<Vec<_>>()
         };
+        // rtl: each line right-aligned to the longest, first char rightmost.
+        assert_eq!(
+            origins("rtl"),
+            [(26, 20), (18, 20), (26, 28), (18, 28), (10, 28)]
+        );
+        // vertical: first line is the rightmost column, chars stacked downward.
+        assert_eq!(
+            origins("vertical"),
+            [(18, 20), (18, 28), (10, 20), (10, 28), (10, 36)]
+        );
 
-        while stack
-            .last()
-            .map(|(depth, _)| *depth >= row.depth)
-            .unwrap_or(false)
-        {
-            stack.pop();
-        }
-
-        if let Some((_, parent_path)) = stack.last().cloned() {
-            if let Some(parent) = ax_tree_node_mut(&mut roots, &parent_path) {
-                parent.children.push(node);
-                let mut node_path = parent_path;
-                node_path.push(parent.children.len() - 1);
-                stack.push((row.depth, node_path));
-            } else {
-                roots.push(node);
-                stack.push((row.depth, vec![roots.len() - 1]));
-            }
-        } else {
-            roots.push(node);
-            stack.push((row.depth, vec![roots.len() - 1]));
-        }
+        let ann = json!({"type": "text", "x": 2, "y": 2, "text": "II", "size": 8,
+                         "direction": "vertical", "outline": false, "color": "#FF0000"});
+        let mut img = RgbaImage::from_pixel(24, 24, Rgba([0, 0, 0, 255]));
+        draw_text_annotation(
+            &mut img,
+            ann.as_object().unwrap(),
+            1.0,
+            &FontCache::default(),
+            None,
+        );
+        let red_rows: Vec<u32> = (0..24)
+            .filter(|y| (0..24).any(|x| img.get_pixel(x, *y).0[0] > 0))
+            .collect();
+        let red_cols: Vec<u32> = (0..24)
+            .filter(|x| (0..24).any(|y| img.get_pixel(*x, y).0[0] > 0))
+            .collect();
+        assert!(
+            *red_rows.last().unwrap() >= 10,
+            "second glyph sits below the first"
+        );
+        assert!(
+            *red_cols.last().unwrap() < 10,
+            "both glyphs share one column"
+        );
     }
 
-    roots.iter().map(ax_tree_node_value).collect()
-}
+    #[test]
+    fn rect_radius_rounds_fill_outline_and_stroke() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let clear = Rgba([0, 0, 0, 255]);
+        let rect = json!({"type": "rect", "x": 4, "y": 4, "w": 24, "h": 16, "width": 2,
+                          "outline": false, "color": "#FF0000", "fill": "#0000FF", "radius": 6});
+        let mut img = RgbaImage::from_pixel(32, 24, clear);
+        draw_prepared_annotation(
+            &mut img,
+            rect.as_object().unwrap(),
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+        );
+        assert_eq!(img.get_pixel(4, 4), &clear, "corner is cut");
+        assert_eq!(img.get_pixel(3, 3), &clear);
+        assert_eq!(img.get_pixel(16, 4), &red, "straight top edge");
+        assert_eq!(img.get_pixel(16, 3), &red, "stroke grows outward as before");
+        assert_eq!(img.get_pixel(4, 12), &red);
+        assert_eq!(img.get_pixel(6, 6), &red, "stroke follows the arc");
+        assert_eq!(img.get_pixel(16, 12), &blue, "filled inside");
+
+        let huge = json!({"radius": 100});
+        assert_eq!(
+            rect_corner_radius(huge.as_object().unwrap(), 24.0, 16.0),
+            8.0
+        );
+        let figma = figma_scene(
+            &[(0, rect.as_object().unwrap().clone())],
+            32,
+            24,
+            1.0,
+            &FontCache::default(),
+        );
+        assert_eq!(figma["children"][0]["cornerRadius"], 6.0);
 
-fn ax_tree_node_mut<'a>(nodes: &'a mut [AxTreeNode], path: &[usize]) -> Option<&'a mut AxTreeNode> {
-    let (head, tail) = path.split_first()?;
-    let node = nodes.get_mut(*head)?;
-    if tail.is_empty() {
-        Some(node)
-    } else {
-        ax_tree_node_mut(node.children.as_mut_slice(), tail)
+        let mut sharp = rect.as_object().unwrap().clone();
+        sharp.remove("radius");
+        let mut img = RgbaImage::from_pixel(32, 24, clear);
+        draw_prepared_annotation(&mut img, &sharp, 1.0, &Map::new(), &FontCache::default());
+        assert_eq!(img.get_pixel(4, 4), &red, "no radius keeps sharp corners");
     }
-}
 
-fn copy_file(src: &Path, dst: &Path) -> Result<()> {
-    ensure_parent_dir(dst)?;
-    fs::copy(src, dst)
-        .with_context(|| format!("failed to copy {} -> {}", src.display(), dst.display()))?;
-    Ok(())
-}
+    #[test]
+    fn dash_pattern_breaks_rect_and_arrow_strokes() {
+        let red = Rgba([255, 0, 0, 255]);
+        let clear = Rgba([0, 0, 0, 255]);
+        let rect = json!({"type": "rect", "x": 4, "y": 4, "w": 30, "h": 12, "width": 2,
+                          "outline": false, "color": "#FF0000", "dash": [4, 3]});
+        let mut img = RgbaImage::from_pixel(40, 24, clear);
+        draw_prepared_annotation(
+            &mut img,
+            rect.as_object().unwrap(),
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+        );
+        let top: Vec<bool> = (4..18).map(|x| img.get_pixel(x, 4) == &red).collect();
+        assert_eq!(
+            top,
+            [
+                true, true, true, true, false, false, false, true, true, true, true, false, false,
+                false
+            ]
+        );
+        assert_eq!(img.get_pixel(8, 3), &clear, "both rings share the pattern");
+
+        let arrow = json!({"type": "arrow", "x1": 2, "y1": 20, "x2": 38, "y2": 20, "width": 1,
+                           "head_len": 6, "outline": false, "color": "#FF0000", "dash": 5});
+        let mut img = RgbaImage::from_pixel(40, 24, clear);
+        draw_prepared_annotation(
+            &mut img,
+            arrow.as_object().unwrap(),
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+        );
+        assert_eq!(img.get_pixel(4, 20), &red);
+        assert_eq!(img.get_pixel(9, 20), &clear, "gap in the shaft");
+        assert_eq!(img.get_pixel(36, 20), &red, "head stays solid");
+
+        let solid = json!({"dash": [0, 4]});
+        assert_eq!(dash_pattern(solid.as_object().unwrap()), None);
+        let scene = excalidraw_scene(
+            &[(0, rect.as_object().unwrap().clone())],
+            1.0,
+            &FontCache::default(),
+        );
+        assert_eq!(scene["elements"][0]["strokeStyle"], "dashed");
+    }
 
-fn write_json_pretty(path: &Path, value: &Value) -> Result<()> {
-    ensure_parent_dir(path)?;
-    let raw = serde_json::to_string_pretty(value)?;
-    fs::write(path, raw).with_context(|| format!("failed to write JSON: {}", path.display()))?;
-    Ok(())
-}
+    #[test]
+    fn ellipse_annotation_draws_inscribed_ring_with_rect_geometry() {
+        let ann = json!({"type": "circle", "x": 10, "y": 10, "w": 40, "h": 20, "width": 2,
+                         "outline": false, "color": "#FF0000", "fill": "#0000FF"});
+        let ann = ann.as_object().unwrap();
+        let mut img = RgbaImage::from_pixel(60, 40, Rgba([0, 0, 0, 255]));
+        draw_prepared_annotation(&mut img, ann, 1.0, &Map::new(), &FontCache::default());
+        assert_eq!(
+            img.get_pixel(30, 20),
+            &Rgba([0, 0, 255, 255]),
+            "filled center"
+        );
+        assert_eq!(
+            img.get_pixel(30, 10),
+            &Rgba([255, 0, 0, 255]),
+            "stroke at the top"
+        );
+        assert_eq!(
+            img.get_pixel(10, 20),
+            &Rgba([255, 0, 0, 255]),
+            "stroke at the left"
+        );
+        assert_eq!(
+            img.get_pixel(11, 11),
+            &Rgba([0, 0, 0, 255]),
+            "box corner stays clear"
+        );
+        assert_eq!(
+            img.get_pixel(30, 8),
+            &Rgba([0, 0, 0, 255]),
+            "nothing outside the box"
+        );
 
-fn ensure_parent_dir(path: &Path) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent).with_context(|| {
-                format!("failed to create parent directory: {}", parent.display())
-            })?;
-        }
+        let item = annotation_meta_item(0, ann, 60, 40);
+        assert_eq!(
+            item["geometry"],
+            json!({"x": 10, "y": 10, "w": 40, "h": 20})
+        );
+        assert_eq!(item["geometry_rel"]["bbox"]["w"], json!(0.666667));
+        let scene = excalidraw_scene(&[(0, ann.clone())], 1.0, &FontCache::default());
+        assert_eq!(scene["elements"][0]["type"], "ellipse");
     }
-    Ok(())
-}
 
-fn default_sidecar_for(path: &Path) -> PathBuf {
-    let stem = path
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("output")
-        .to_string();
-    let parent = path.parent().unwrap_or_else(|| Path::new(""));
-    parent.join(format!("{stem}.json"))
-}
+    #[test]
+    fn theme_palette_seeds_colors_per_type() {
+        assert_eq!(theme_palette("default", "rect"), Some(Map::new()));
+        assert!(theme_palette("sepia", "rect").is_none());
+
+        let dark_text = theme_palette("dark", "text").unwrap();
+        assert_eq!(dark_text["outline_color"], json!("#F2F2F7"));
+        assert!(dark_text.contains_key("text_bg"));
+
+        let contrast = theme_palette("high-contrast", "arrow").unwrap();
+        assert_eq!(contrast["color"], json!("#FFD60A"));
+        assert!(!contrast.contains_key("text_bg"));
+        assert!(theme_palette("dark", "spotlight").unwrap().is_empty());
+    }
 
-fn out_root() -> PathBuf {
-    env::var("CVLP_OUT_DIR")
-        .ok()
-        .filter(|v| !v.trim().is_empty())
-        .map(PathBuf::from)
-        .or_else(|| {
-            env::var("CVLP_TMP_DIR")
-                .ok()
-                .filter(|v| !v.trim().is_empty())
-                .map(PathBuf::from)
-        })
-        .unwrap_or_else(|| PathBuf::from(".codex-visual-loop"))
-}
+    #[test]
+    fn severity_badge_drawn_only_when_enabled() {
+        let ann = json!({"type": "rect", "x": 4, "y": 4, "w": 56, "h": 28, "width": 2,
+                         "outline": false, "severity": "high"});
+        let mut plain = golden_canvas();
+        draw_rect_annotation(&mut plain, ann.as_object().unwrap(), 1.0, None);
+
+        let mut with_badge = ann.as_object().unwrap().clone();
+        with_badge.insert("show_severity".to_string(), json!(true));
+        let mut badged = golden_canvas();
+        draw_rect_annotation(&mut badged, &with_badge, 1.0, None);
+
+        // Badge background sits just inside the top-right corner.
+        assert_eq!(plain.get_pixel(56, 7), &Rgba([240, 240, 240, 255]));
+        assert_ne!(badged.get_pixel(56, 7), plain.get_pixel(56, 7));
+        assert_eq!(severity_badge_color("High"), severity_badge_color("major"));
+        assert_ne!(
+            severity_badge_color("low"),
+            severity_badge_color("critical")
+        );
+    }
 
-fn abs_path(path: &Path) -> PathBuf {
-    if path.is_absolute() {
-        return path.to_path_buf();
+    #[test]
+    fn percent_changed_stats_summarize_pairs() {
+        let stats = percent_changed_stats(&[0.0, 0.5, 1.0]);
+        assert_eq!(stats["min"], json!(0.0));
+        assert_eq!(stats["max"], json!(1.0));
+        assert_eq!(stats["mean"], json!(0.5));
+        assert_eq!(stats["stddev"], json!(0.408));
+        assert_eq!(percent_changed_stats(&[])["mean"], Value::Null);
     }
-    env::current_dir()
-        .unwrap_or_else(|_| PathBuf::from("."))
-        .join(path)
-}
 
-fn sanitize_baseline_name(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
-    for ch in input.chars() {
-        if ch.is_ascii_alphanumeric() || matches!(ch, '.' | '_' | '-') {
-            out.push(ch);
-        } else if matches!(ch, ' ' | '/' | ':') {
-            out.push('_');
+    #[test]
+    fn design_exports_keep_resolved_geometry() {
+        let items: Vec<(usize, Map<String, Value>)> = [
+            json!({"type": "rect", "id": "cta", "x": 10, "y": 20, "w": 30, "h": 40, "color": "#FF3B30"}),
+            json!({"type": "arrow", "x1": 5, "y1": 5, "x2": 25, "y2": 15}),
+            json!({"type": "text", "x": 8, "y": 70, "text": "Hi"}),
+            json!({"type": "arrow", "x1": 0, "y1": 0, "cx": 20, "cy": 40, "x2": 40, "y2": 0}),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(idx, ann)| (idx, ann.as_object().unwrap().clone()))
+        .collect();
+
+        let scene = excalidraw_scene(&items, 1.0, &FontCache::default());
+        let elements = scene["elements"].as_array().unwrap();
+        assert_eq!(elements.len(), 4);
+        assert_eq!(elements[0]["type"], json!("rectangle"));
+        assert_eq!(elements[0]["id"], json!("cta"));
+        assert_eq!(elements[0]["strokeColor"], json!("#ff3b30"));
+        assert_eq!(elements[1]["points"], json!([[0.0, 0.0], [20.0, 10.0]]));
+        assert_eq!(elements[2]["text"], json!("Hi"));
+        // The curve passes through (20, 20), not its control point (20, 40).
+        assert_eq!(
+            elements[3]["points"],
+            json!([[0.0, 0.0], [20.0, 20.0], [40.0, 0.0]])
+        );
+        assert_eq!(elements[3]["roundness"], json!({"type": 2}));
+        assert_eq!(elements[3]["height"], json!(20.0));
+        assert_eq!(elements[1]["roundness"], Value::Null);
+
+        let frame = figma_scene(&items, 100, 80, 1.0, &FontCache::default());
+        let children = frame["children"].as_array().unwrap();
+        assert_eq!(frame["width"], json!(100));
+        assert_eq!(children[0]["type"], json!("RECTANGLE"));
+        assert_eq!(children[0]["height"], json!(40.0));
+        assert_eq!(
+            children[1]["vectorPaths"][0]["data"],
+            json!("M 0 0 L 20 10")
+        );
+        assert_eq!(children[2]["characters"], json!("Hi"));
+    }
+
+    #[test]
+    fn decoded_diff_matches_diff_from_paths() {
+        let dir = tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.png"), dir.path().join("b.png"));
+        RgbaImage::from_pixel(20, 10, Rgba([0, 0, 0, 255]))
+            .save(&a)
+            .unwrap();
+        let mut changed = RgbaImage::from_pixel(20, 10, Rgba([0, 0, 0, 255]));
+        for x in 0..10 {
+            changed.put_pixel(x, 0, Rgba([255, 255, 255, 255]));
+        }
+        changed.save(&b).unwrap();
+        let options = DiffOptions::default();
+        let from_paths = run_diff_internal(&a, &b, None, None, None, None, &options).unwrap();
+        let frame = |path: &Path| {
+            (
+                DynamicImage::ImageRgba8(image::open(path).unwrap().to_rgba8()),
+                false,
+            )
+        };
+        let decoded = run_diff_decoded(
+            Instant::now(),
+            (&a, frame(&a)),
+            (&b, frame(&b)),
+            None,
+            None,
+            None,
+            None,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(decoded.json["percent_changed"], json!(5.0));
+        for key in ["percent_changed", "change_regions", "baseline", "current"] {
+            assert_eq!(decoded.json[key], from_paths.json[key], "{key}");
         }
     }
-    if out.is_empty() {
-        "baseline".to_string()
-    } else {
-        out
+
+    #[test]
+    fn pixel_budget_rejects_or_downscales_large_inputs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("big.png");
+        RgbaImage::from_pixel(40, 20, Rgba([0, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+
+        let err = open_image_within_budget(&path, "input", 200, false).unwrap_err();
+        assert_eq!(exit_code_for(&err), EXIT_INPUT);
+        assert!(err.to_string().contains("40x20"));
+
+        let (image, downscaled) = open_image_within_budget(&path, "input", 200, true).unwrap();
+        assert!(downscaled);
+        assert_eq!(image.dimensions(), (20, 10));
+
+        let (image, downscaled) = open_image_within_budget(&path, "input", 0, false).unwrap();
+        assert!(!downscaled);
+        assert_eq!(image.dimensions(), (40, 20));
+
+        // The format comes from the file's bytes, not its extension.
+        let renamed = dir.path().join("big.bin");
+        fs::rename(&path, &renamed).unwrap();
+        let err = open_image_within_budget(&renamed, "input", 200, false).unwrap_err();
+        assert!(err.to_string().contains("40x20"));
     }
-}
 
-fn slugify(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
-    for ch in input.chars() {
-        let lower = ch.to_ascii_lowercase();
-        if lower.is_ascii_alphanumeric() || matches!(lower, '.' | '_' | '-') {
-            out.push(lower);
-        } else if lower.is_ascii_whitespace() {
-            out.push('-');
-        }
+    #[test]
+    fn auto_update_baseline_only_below_threshold() {
+        assert!(should_auto_update_baseline(0.04, Some(0.5)));
+        assert!(!should_auto_update_baseline(0.5, Some(0.5)));
+        assert!(!should_auto_update_baseline(3.2, Some(0.5)));
+        assert!(!should_auto_update_baseline(0.0, None));
     }
-    if out.is_empty() {
-        "app".to_string()
-    } else {
-        out
+
+    #[test]
+    fn warnings_serialize_as_text_or_structured_objects() {
+        let mut warnings = Warnings::default();
+        warnings.push_diagnostic(
+            "window_query_failed",
+            "window_query",
+            "window query unavailable",
+            &QueryDiagnostic {
+                ok: false,
+                attempts: 2,
+                error_code: Some("timeout".to_string()),
+                message: Some("System Events did not respond".to_string()),
+            },
+        );
+        warnings.error("placeholder_capture", "capture failed");
+
+        // Text stays byte-for-byte what capture printed before warnings had codes.
+        assert_eq!(
+            warnings.to_value(&OutputOptions::default()),
+            json!([
+                "window_query:timeout",
+                "window query unavailable: System Events did not respond",
+                "capture failed"
+            ])
+        );
+
+        let structured = warnings.to_value(&OutputOptions {
+            warnings_json: true,
+            ..OutputOptions::default()
+        });
+        assert_eq!(
+            structured,
+            json!([
+                {"code": "window_query_failed", "severity": "warning",
+                 "message": "window query unavailable (timeout): System Events did not respond"},
+                {"code": "placeholder_capture", "message": "capture failed", "severity": "error"},
+            ])
+        );
+
+        let mut merged = Warnings::default();
+        merged.extend_from_value(Some(&structured));
+        merged.extend_from_value(Some(&json!(["legacy text"])));
+        assert_eq!(merged.0[1], warnings.0[1]);
+        assert_eq!(merged.0[0].message, warnings.0[0].message);
+        assert_eq!(merged.0[2].code, "unclassified");
     }
-}
 
-fn timestamp_compact() -> String {
-    Utc::now().format("%Y%m%d-%H%M%S").to_string()
-}
+    #[test]
+    fn warnings_below_min_severity_are_hidden() {
+        let mut warnings = Warnings::default();
+        warnings.info("unsupported_platform", "placeholder on non-macOS");
+        warnings.warn("screen_fallback", "used full-screen capture");
+        warnings.error("placeholder_capture", "capture failed");
+        warnings.push_with("notice", "custom", "unknown severity");
+
+        let codes = |min: &str| -> Vec<String> {
+            warnings
+                .visible(warning_severity_rank(min))
+                .into_iter()
+                .map(|w| w.code.clone())
+                .collect()
+        };
+        assert_eq!(codes("info").len(), 4);
+        assert_eq!(
+            codes("warning"),
+            ["screen_fallback", "placeholder_capture", "custom"]
+        );
+        assert_eq!(codes("error"), ["placeholder_capture"]);
 
-fn timestamp_iso() -> String {
-    Utc::now().to_rfc3339()
-}
+        let quiet = OutputOptions {
+            min_warning_rank: warning_severity_rank("warning"),
+            ..OutputOptions::default()
+        };
+        assert_eq!(
+            warnings.to_value(&quiet),
+            json!([
+                "used full-screen capture",
+                "capture failed",
+                "unknown severity"
+            ])
+        );
+        assert_eq!(
+            warnings.hidden_messages(&quiet),
+            ["placeholder on non-macOS"]
+        );
+        assert!(warnings
+            .hidden_messages(&OutputOptions::default())
+            .is_empty());
+    }
 
-fn round_to(v: f64, digits: u32) -> f64 {
-    let factor = 10f64.powi(digits as i32);
-    (v * factor).round() / factor
-}
+    #[test]
+    fn bench_inputs_differ_and_time_every_iteration() {
+        let (baseline, current) = bench_images(64, 48);
+        assert_eq!(baseline.dimensions(), current.dimensions());
+        assert_ne!(baseline, current);
+        assert_eq!(bench_annotations(64, 48, 5).len(), 5);
+
+        let mut calls = 0;
+        let timings = bench_timed(3, || {
+            calls += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(timings.len(), 3);
+        assert_eq!(calls, 4);
+    }
 
-fn command_exists(name: &str) -> bool {
-    Command::new("bash")
-        .arg("-lc")
-        .arg(format!("command -v {name} >/dev/null 2>&1"))
-        .status()
-        .map(|s| s.success())
-        .unwrap_or(false)
-}
+    #[test]
+    fn point_marker_labels_its_coordinates() {
+        let ann = json!({"type": "point", "x": 20, "y": 30, "show_coords": true});
+        assert_eq!(
+            point_coords_label(20.0, 30.0, 64, 40, ann.as_object().unwrap()),
+            "(20, 30)"
+        );
+        let rel = json!({"coords_rel": true});
+        assert_eq!(
+            point_coords_label(16.0, 10.0, 64, 40, rel.as_object().unwrap()),
+            "(16, 10) rel(0.250, 0.250)"
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
+        let mut img = golden_canvas();
+        draw_point_annotation(&mut img, ann.as_object().unwrap(), 1.0, None);
+        assert_eq!(img.get_pixel(20, 30), &Rgba([255, 59, 48, 255]));
+        // Too wide for the right side, so the label flips left and clamps to the edge.
+        assert_ne!(img.get_pixel(34, 14), &Rgba([240, 240, 240, 255]));
+    }
 
     #[test]
-    fn rel_units_convert_to_pixels() {
-        let mut ann = Map::new();
-        ann.insert("units".to_string(), json!("rel"));
-        ann.insert("x".to_string(), json!(0.1));
-        ann.insert("y".to_string(), json!(0.2));
-        ann.insert("w".to_string(), json!(0.5));
-        ann.insert("h".to_string(), json!(0.4));
-        let defaults = Map::new();
-        resolve_annotation_units(&mut ann, 200, 100, &defaults);
+    fn display_info_reports_points_and_pixels() {
+        let displays = parse_display_lines("0\t0\t1512\t982\t2\n1512\t-98\t1920\t1080\t1\n");
+        let main = display_info_json(&displays[0]);
+        assert_eq!(main["main"], json!(true));
+        assert_eq!(main["pixels"], json!({"width": 3024, "height": 1964}));
+        let external = display_info_json(&displays[1]);
+        assert_eq!(external["points"]["x"], json!(1512));
+        assert_eq!(external["scale"], json!(1.0));
+    }
 
-        assert_eq!(ann.get("x").and_then(Value::as_f64), Some(20.0));
-        assert_eq!(ann.get("y").and_then(Value::as_f64), Some(20.0));
-        assert_eq!(ann.get("w").and_then(Value::as_f64), Some(100.0));
-        assert_eq!(ann.get("h").and_then(Value::as_f64), Some(40.0));
+    #[test]
+    fn window_maps_to_display_backing_scale() {
+        // Main 1512x982 Retina display plus a 1920x1080 @1x display to its right, bottom-aligned.
+        let displays = parse_display_lines("0\t0\t1512\t982\t2\n1512\t-98\t1920\t1080\t1\n");
+        assert_eq!(displays.len(), 2);
+        assert_eq!((displays[1].x, displays[1].y), (1512, 0));
+
+        let on_external = display_for_window(&displays, (1600, 100, 800, 600)).unwrap();
+        assert_eq!((on_external.index, on_external.scale), (1, 1.0));
+        // Straddling both: the larger overlap wins.
+        let straddling = display_for_window(&displays, (1200, 100, 400, 300)).unwrap();
+        assert_eq!(straddling.scale, 2.0);
+        assert_eq!(
+            display_for_window(&displays, (0, 0, 0, 0)).unwrap().index,
+            0
+        );
+        assert!(parse_display_lines("").is_empty());
     }
 
     #[test]
-    fn diff_detects_change_regions() {
-        let mut gray = vec![0u8; 100 * 60];
-        for y in 10..30 {
-            for x in 20..40 {
-                gray[y * 100 + x] = 255;
-            }
-        }
-        let regions = extract_change_regions(&gray, 100, 60, 1, 10, 2, 8);
-        assert!(!regions.is_empty());
-        let first = &regions[0];
-        assert!(first.x <= 20);
-        assert!(first.y <= 10);
-        assert!(first.w >= 20);
-        assert!(first.h >= 20);
+    fn overlay_layer_keeps_marks_on_transparency() {
+        let rect = json!({"type": "rect", "x": 8, "y": 6, "w": 20, "h": 12, "outline": false,
+                          "fill": "rgba(255,0,0,0.5)"});
+        let mut overlay = RgbaImage::new(64, 40);
+        render_annotations(
+            &mut overlay,
+            &[(0, rect.as_object().unwrap().clone())],
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+        );
+        // Half-transparent fill stays pure red at half alpha instead of darkening toward black.
+        assert_eq!(overlay.get_pixel(15, 12), &Rgba([255, 0, 0, 128]));
+        assert_eq!(overlay.get_pixel(40, 30)[3], 0);
+
+        let spotlight = json!({"type": "spotlight", "x": 40, "y": 4, "w": 16, "h": 16});
+        let mut overlay = RgbaImage::new(64, 40);
+        render_annotations(
+            &mut overlay,
+            &[(0, spotlight.as_object().unwrap().clone())],
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+        );
+        // Spotlight leaves a dim layer outside its hole and transparency inside it.
+        assert!(overlay.get_pixel(2, 36)[3] > 0);
+        assert_eq!(overlay.get_pixel(48, 12)[3], 0);
+
+        // Opaque destinations keep the original lerp, rounding included.
+        let src = Rgba([255, 0, 0, 77]);
+        assert_eq!(
+            blend_pixel(Rgba([10, 200, 31, 255]), src),
+            Rgba([84, 140, 22, 255])
+        );
+        assert_eq!(blend_pixel(Rgba([0, 0, 0, 0]), src), Rgba([255, 0, 0, 77]));
     }
 
     #[test]
-    fn writes_json_pretty() {
+    fn annotate_bundle_out_inlines_png_and_sidecar() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"M"), "TQ==");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+
         let dir = tempdir().unwrap();
-        let target = dir.path().join("a").join("b.json");
-        write_json_pretty(&target, &json!({"ok": true})).unwrap();
-        assert!(target.exists());
+        let input = dir.path().join("in.png");
+        RgbaImage::from_pixel(24, 16, Rgba([255, 255, 255, 255]))
+            .save(&input)
+            .unwrap();
+        let spec = dir.path().join("spec.json");
+        fs::write(
+            &spec,
+            r#"{"annotations": [{"type": "rect", "x": 2, "y": 2, "w": 10, "h": 8}]}"#,
+        )
+        .unwrap();
+        let output = dir.path().join("out.png");
+        let bundle = dir.path().join("bundle.json");
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--no-meta",
+            "--bundle-out",
+            bundle.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        let summary = annotate_internal(&args, &OutputOptions::default()).unwrap();
+        assert_eq!(summary["meta_path"], Value::Null);
+        assert!(summary["bundle_path"]
+            .as_str()
+            .unwrap()
+            .ends_with("bundle.json"));
+        assert!(!dir.path().join("out.json").exists());
+
+        let bundle: Value = serde_json::from_str(&fs::read_to_string(&bundle).unwrap()).unwrap();
+        assert_eq!(bundle["format"], "png");
+        assert_eq!(
+            bundle["image_base64"].as_str().unwrap(),
+            base64_encode(&fs::read(&output).unwrap())
+        );
+        assert_eq!(bundle["meta"]["meta_path"], Value::Null);
+        assert_eq!(bundle["meta"]["annotations"][0]["geometry"]["w"], 10);
+        let mut violations = Vec::new();
+        schema_violations(
+            &annotate_meta_schema(),
+            &bundle["meta"],
+            "meta",
+            &mut violations,
+        );
+        assert!(violations.is_empty(), "{violations:?}");
+
+        let jpeg = dir.path().join("out.jpg");
+        let bundle_path = dir.path().join("bundle.json");
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "annotate",
+            input.to_str().unwrap(),
+            jpeg.to_str().unwrap(),
+            "--spec",
+            spec.to_str().unwrap(),
+            "--no-meta",
+            "--bundle-out",
+            bundle_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Commands::Annotate(args)) = cli.command else {
+            panic!("expected annotate");
+        };
+        annotate_internal(&args, &OutputOptions::default()).unwrap();
+        let bundle: Value =
+            serde_json::from_str(&fs::read_to_string(&bundle_path).unwrap()).unwrap();
+        assert_eq!(
+            bundle["format"], "jpg",
+            "names the encoder the output went through"
+        );
+        assert_eq!(
+            bundle["image_base64"].as_str().unwrap(),
+            base64_encode(&fs::read(&jpeg).unwrap())
+        );
     }
 
     #[test]
-    fn parse_window_candidates_reads_tsv_rows() {
-        let raw = "1\t0\t38\t902\t1079\tMain\n2\t8\t8\t30\t23\tTiny";
-        let parsed = parse_window_candidates(raw);
-        assert_eq!(parsed.len(), 2);
-        assert_eq!(parsed[0].index, 1);
-        assert_eq!(parsed[0].w, 902);
-        assert_eq!(parsed[1].h, 23);
+    fn layered_tiff_writes_one_named_page_per_layer() {
+        let backdrop = RgbaImage::from_pixel(64, 20, Rgba([10, 20, 30, 255]));
+        let queue: Vec<(usize, Map<String, Value>)> = [
+            json!({"type": "rect", "x": 2, "y": 2, "w": 8, "h": 8}),
+            json!({"type": "text", "x": 40, "y": 2, "text": "A"}),
+            json!({"type": "rect", "x": 20, "y": 10, "w": 8, "h": 6}),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(idx, ann)| (idx, ann.as_object().unwrap().clone()))
+        .collect();
+        let combined = annotation_layers(
+            &backdrop,
+            &queue,
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+            false,
+        );
+        assert_eq!(combined.len(), 1);
+        let by_type = annotation_layers(
+            &backdrop,
+            &queue,
+            1.0,
+            &Map::new(),
+            &FontCache::default(),
+            true,
+        );
+        let names: Vec<&str> = by_type.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["rect", "text"]);
+        assert_eq!(
+            by_type[0].1.get_pixel(44, 6)[3],
+            0,
+            "rect layer has no text"
+        );
+        assert!(by_type[1].1.pixels().any(|p| p[3] > 0));
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("layers.tiff");
+        let mut layers = vec![("backdrop".to_string(), backdrop.clone())];
+        layers.extend(by_type);
+        // "dim" plus its NUL fits in the 4-byte entry value, the inline-name case.
+        layers.push(("dim".to_string(), RgbaImage::new(64, 20)));
+        write_layered_tiff(&path, &layers, true).unwrap();
+
+        let mut decoder = tiff::decoder::Decoder::new(fs::File::open(&path).unwrap()).unwrap();
+        let mut pages = Vec::new();
+        loop {
+            assert_eq!(decoder.dimensions().unwrap(), (64, 20));
+            assert_eq!(decoder.colortype().unwrap(), tiff::ColorType::RGBA(8));
+            let tiff::decoder::DecodingResult::U8(pixels) = decoder.read_image().unwrap() else {
+                panic!("expected 8-bit samples");
+            };
+            assert_eq!(&pixels, layers[pages.len()].1.as_raw());
+            pages.push(
+                decoder
+                    .get_tag_ascii_string(tiff::tags::Tag::Unknown(285))
+                    .unwrap(),
+            );
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().unwrap();
+        }
+        assert_eq!(pages, ["backdrop", "rect", "text", "dim"]);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 64]).unwrap();
+        let sizes = vec![(64, 20); layers.len()];
+        let truncated = verify_layered_tiff(&path, &sizes).unwrap_err().to_string();
+        assert!(truncated.starts_with("--verify-output: "), "{truncated}");
     }
 
     #[test]
-    fn select_window_candidate_prefers_largest_usable() {
-        let windows = vec![
-            WindowCandidate {
-                index: 1,
-                x: 0,
-                y: 0,
-                w: 30,
-                h: 23,
-                title: Some("tiny".to_string()),
-            },
-            WindowCandidate {
-                index: 2,
-                x: 10,
-                y: 10,
-                w: 640,
-                h: 480,
-                title: Some("main".to_string()),
-            },
-            WindowCandidate {
-                index: 3,
-                x: 20,
-                y: 20,
-                w: 800,
-                h: 600,
-                title: Some("largest".to_string()),
-            },
-        ];
-        let (selected, mode, usable_count) = select_window_candidate(&windows, 220, 140, 40_000);
-        assert_eq!(selected.index, 3);
-        assert_eq!(mode, "largest_usable");
-        assert_eq!(usable_count, 2);
+    fn diff_overlay_threshold_hides_faint_changes() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("overlay.png");
+        let current = RgbaImage::from_pixel(3, 1, Rgba([255, 255, 255, 255]));
+        let gray = [0, 20, 200];
+        let tinted = |threshold: u8| {
+            write_diff_overlay(
+                &current, &gray, 3, 1, threshold, false, "default", false, &out,
+            )
+            .unwrap()
+        };
+        let noisy = tinted(0);
+        assert_ne!(noisy.get_pixel(1, 0), &Rgba([255, 255, 255, 255]));
+        let clean = tinted(50);
+        assert_eq!(clean.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(
+            clean.get_pixel(1, 0),
+            &Rgba([255, 255, 255, 255]),
+            "below N"
+        );
+        assert_eq!(
+            clean.get_pixel(2, 0),
+            noisy.get_pixel(2, 0),
+            "at or above N"
+        );
+
+        let cli = Cli::try_parse_from([
+            "codex-visual-loop",
+            "diff",
+            "a.png",
+            "b.png",
+            "--overlay-threshold",
+            "50",
+        ])
+        .unwrap();
+        let Some(Commands::Diff(args)) = cli.command else {
+            panic!("expected diff");
+        };
+        assert_eq!(args.overlay_threshold, 50);
+        // Region extraction still sees the faint change.
+        let regions = extract_change_regions(&gray, 3, 1, 10, 1, 0, 1);
+        assert_eq!(regions.len(), 1);
     }
 
     #[test]
-    fn select_window_candidate_uses_largest_any_when_all_tiny() {
-        let windows = vec![
-            WindowCandidate {
-                index: 1,
-                x: 0,
-                y: 0,
-                w: 30,
-                h: 23,
-                title: None,
-            },
-            WindowCandidate {
-                index: 2,
-                x: 0,
-                y: 0,
-                w: 120,
-                h: 90,
-                title: None,
-            },
-        ];
-        let (selected, mode, usable_count) = select_window_candidate(&windows, 220, 140, 40_000);
-        assert_eq!(selected.index, 2);
-        assert_eq!(mode, "largest_any");
-        assert_eq!(usable_count, 0);
+    fn diff_overlay_transparent_keeps_only_changed_pixels() {
+        let dir = tempdir().unwrap();
+        let (baseline, current, overlay) = (
+            dir.path().join("a.png"),
+            dir.path().join("b.png"),
+            dir.path().join("overlay.png"),
+        );
+        RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]))
+            .save(&baseline)
+            .unwrap();
+        let mut img = RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]));
+        fill_rect_alpha(&mut img, 2, 2, 5, 5, Rgba([0, 0, 0, 255]), None);
+        img.put_pixel(15, 5, Rgba([235, 235, 235, 255]));
+        img.save(&current).unwrap();
+        let options = DiffOptions {
+            overlay_transparent: true,
+            overlay_threshold: 1,
+            ..DiffOptions::default()
+        };
+        run_diff_internal(
+            &baseline,
+            &current,
+            Some(&overlay),
+            None,
+            None,
+            None,
+            &options,
+        )
+        .unwrap();
+        let out = image::open(&overlay).unwrap().to_rgba8();
+        assert_eq!(out.get_pixel(3, 3), &Rgba([255, 0, 0, 255]));
+        assert_eq!(
+            out.get_pixel(10, 5)[3],
+            0,
+            "unchanged pixels are transparent"
+        );
+        let faint = out.get_pixel(15, 5);
+        assert_eq!(faint.0[..3], [255, 0, 0]);
+        assert!(
+            faint[3] > 0 && faint[3] < 64,
+            "alpha follows the diff magnitude"
+        );
+
+        run_diff_internal(
+            &baseline,
+            &current,
+            Some(&overlay),
+            None,
+            None,
+            None,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        let opaque = image::open(&overlay).unwrap().to_rgba8();
+        assert_eq!(opaque.get_pixel(10, 5), &Rgba([255, 255, 255, 255]));
     }
 
     #[test]
-    fn parse_hotkey_combo_parses_modifiers_and_key() {
-        let spec = parse_hotkey_combo("cmd+shift+p").unwrap();
-        assert_eq!(spec.key, "p");
-        assert_eq!(spec.key_code, None);
-        assert_eq!(spec.modifiers, vec!["command down", "shift down"]);
+    fn diff_shape_mode_ignores_recolors_but_catches_silhouette_changes() {
+        let dir = tempdir().unwrap();
+        let icon = |color: [u8; 3], w: u32| {
+            RgbaImage::from_fn(40, 40, |x, y| {
+                if (10..10 + w).contains(&x) && (10..30).contains(&y) {
+                    Rgba([color[0], color[1], color[2], 255])
+                } else {
+                    Rgba([0, 0, 0, 0])
+                }
+            })
+        };
+        let baseline = dir.path().join("baseline.png");
+        let recolored = dir.path().join("recolored.png");
+        let wider = dir.path().join("wider.png");
+        icon([200, 30, 30], 20).save(&baseline).unwrap();
+        icon([30, 30, 200], 20).save(&recolored).unwrap();
+        icon([30, 30, 200], 26).save(&wider).unwrap();
+        let options = DiffOptions {
+            shape_mode: true,
+            bbox_min_area: 1,
+            ..DiffOptions::default()
+        };
+
+        let same = run_diff_internal(&baseline, &recolored, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        assert_eq!(same["identical"], true);
+        assert_eq!(same["mode"], "shape");
+        assert_eq!(same["shape"]["channel"], "alpha");
+        assert_eq!(same["shape"]["baseline_pixels"], 400);
+        let color = run_diff_internal(
+            &baseline,
+            &recolored,
+            None,
+            None,
+            None,
+            None,
+            &DiffOptions::default(),
+        )
+        .unwrap()
+        .json;
+        assert_eq!(color["changed_pixels"], 400);
+
+        let grown = run_diff_internal(&baseline, &wider, None, None, None, None, &options)
+            .unwrap()
+            .json;
+        assert_eq!(grown["changed_pixels"], 120);
+        assert_eq!(grown["change_region_count"], 1);
+        assert_eq!(grown["change_regions"][0]["x"], 28);
+
+        assert!(shape_ink(&Rgba([20, 20, 20, 255]), "luma", 128));
+        assert!(!shape_ink(&Rgba([240, 240, 240, 255]), "luma", 128));
     }
 
     #[test]
-    fn parse_hotkey_combo_maps_special_keys_to_keycode() {
-        let spec = parse_hotkey_combo("ctrl+enter").unwrap();
-        assert_eq!(spec.key, "enter");
-        assert_eq!(spec.key_code, Some(36));
-        assert_eq!(spec.modifiers, vec!["control down"]);
+    fn diff_histogram_reports_bins_and_percentiles() {
+        let mut bins = vec![0u64; 256];
+        bins[0] = 90;
+        bins[4] = 8;
+        bins[200] = 2;
+        let report = diff_histogram_report(&bins, 24);
+        assert_eq!(report["bins"].as_array().unwrap().len(), 256);
+        assert_eq!(report["counted_pixels"], json!(100));
+        assert_eq!(report["nonzero_pixels"], json!(10));
+        assert_eq!(report["at_or_above_threshold"], json!(2));
+        assert_eq!(report["nonzero_p50"], json!(4));
+        assert_eq!(report["nonzero_p90"], json!(200));
+        assert_eq!(
+            diff_histogram_report(&[0; 256], 24)["nonzero_p50"],
+            Value::Null
+        );
     }
 
     #[test]
-    fn parse_coord_pair_requires_two_values() {
-        assert_eq!(parse_coord_pair("120,80"), Some((120, 80)));
-        assert_eq!(parse_coord_pair("120"), None);
-        assert_eq!(parse_coord_pair("120,80,1"), None);
+    fn trim_crops_uniform_margins_and_offsets_geometry() {
+        let mut img = RgbaImage::from_pixel(40, 30, Rgba([250, 250, 250, 255]));
+        fill_rect_alpha(&mut img, 10, 8, 19, 15, Rgba([20, 20, 20, 255]), None);
+        // Near-white noise within tolerance still counts as border.
+        img.put_pixel(2, 2, Rgba([246, 250, 250, 255]));
+        assert_eq!(trim_bounds(&img, 8, 0), Some((10, 8, 10, 8)));
+        assert_eq!(trim_bounds(&img, 8, 4), Some((6, 4, 18, 16)));
+        assert_eq!(trim_bounds(&img, 8, 100), None);
+        assert_eq!(
+            trim_bounds(&RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 255])), 0, 0),
+            None
+        );
+
+        let ann = json!({"type": "arrow", "x1": 12, "y1": 10, "x2": 18.5, "y2": 14});
+        let shifted = offset_annotation(ann.as_object().unwrap(), -10.0, -8.0);
+        assert_eq!(shifted["x1"], json!(2.0));
+        assert_eq!(shifted["y2"], json!(6.0));
+        assert_eq!(shifted["x2"], json!(8.5));
+
+        let rect = json!({"type": "rect", "x": 12, "y": 10, "w": 4, "h": 4, "centroid": [14, 12]});
+        let shifted = offset_annotation(rect.as_object().unwrap(), -10.0, -8.0);
+        assert_eq!(shifted["centroid"], json!([4.0, 4.0]));
     }
 }